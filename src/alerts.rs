@@ -0,0 +1,76 @@
+use crate::config::AlertConfig;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use std::process::Command;
+
+/// Delivers alerts according to the alert configuration, using the shared HTTP
+/// client for the webhook
+#[derive(Debug, Clone)]
+pub struct Alerter {
+    config: AlertConfig,
+    client: Client,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig, client: Client) -> Self {
+        Alerter { config, client }
+    }
+
+    /// Reports a problem that an operator should know about. Alerts are always
+    /// logged, and are also passed to the alert command and webhook if those
+    /// are configured. Failures to deliver an alert are logged but otherwise
+    /// ignored.
+    pub fn raise(&self, source: &str, message: &str) {
+        raise(&self.config, &self.client, source, message)
+    }
+}
+
+fn raise(config: &AlertConfig, client: &Client, source: &str, message: &str) {
+    warning!("alert", "[{}] {}", source, message);
+
+    if let Some(command) = config.command.as_ref() {
+        match Command::new(command).arg(source).arg(message).output() {
+            Ok(output) if !output.status.success() => {
+                warning!(
+                    "alert",
+                    "Alert command {} failed with {}",
+                    command.display(),
+                    output.status
+                );
+            }
+            Err(error) => {
+                warning!(
+                    "alert",
+                    "Could not invoke alert command {}: {}",
+                    command.display(),
+                    error
+                );
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(webhook) = config.webhook.as_ref() {
+        let mut document = json::object::Object::new();
+        document.insert("source", json::JsonValue::String(source.to_string()));
+        document.insert("message", json::JsonValue::String(message.to_string()));
+
+        let result = client
+            .post(webhook)
+            .header(CONTENT_TYPE, "application/json")
+            .body(json::stringify(json::JsonValue::Object(document)))
+            .send();
+
+        match result {
+            Ok(response) if !response.status().is_success() => {
+                warning!(
+                    "alert",
+                    "Webhook returned unexpected status code {}",
+                    response.status().as_u16()
+                );
+            }
+            Err(error) => warning!("alert", "Could not deliver webhook: {}", error),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use crate::client;
+use crate::config::ApiConfig;
+use crate::events::Event;
+use crate::server;
+use crate::utils;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long the worker waits for a client before checking whether the server
+/// has shut down
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a client can take to send its request, and the server to answer it
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest that the request line and headers can be together
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+/// The largest request body that is accepted
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// How many clients can be served at once. Any more are turned away until one
+/// of them finishes.
+const MAX_CLIENTS: usize = 16;
+
+/// The commands whose limits are per listener. Every HTTP request is a
+/// connection of its own, so listeners are told apart by their address instead
+/// of by whatever they say they are.
+const LISTENER_COMMANDS: &[&str] = &["request-track", "submit-message", "vote-skip", "vote-keep"];
+
+/// Where the start of a track is served, followed by the track's path
+const PREVIEW_PATH: &str = "/preview/";
+
+/// How much of a track /preview sends, in seconds
+const PREVIEW_SECONDS: u64 = 30;
+
+/// The bitrates of MPEG-1 and MPEG-2 Layer III frames in kbit/s, indexed by
+/// the four bits in the frame header
+const MPEG1_BITRATES: [u64; 16] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+];
+const MPEG2_BITRATES: [u64; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0,
+];
+
+/// The parts of an HTTP request that are needed to turn it into a command
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub body: Vec<u8>,
+
+    /// The value of the Authorization header, if there was one
+    pub authorization: Option<String>,
+}
+
+/// A request that couldn't be passed on to the server, given as the HTTP
+/// status code and the status reported in the JSON body
+pub struct HttpError(pub u16, pub &'static str);
+
+/// Reads one line of the request head, as long as it fits in what is left of
+/// the limit on the head's size
+fn read_head_line(
+    reader: &mut BufReader<&TcpStream>,
+    remaining: &mut u64,
+) -> Result<String, HttpError> {
+    let mut line = String::new();
+    match (&mut *reader).take(*remaining).read_line(&mut line) {
+        Ok(count) if line.ends_with('\n') => {
+            *remaining -= count as u64;
+            Ok(line.trim_end().to_string())
+        }
+        _ => Err(HttpError(400, "bad-request")),
+    }
+}
+
+/// Reads the request line, headers and body of a request. Only Content-Length
+/// and Authorization matter out of the headers, since every connection is
+/// closed after one response.
+pub fn read_request(stream: &TcpStream) -> Result<HttpRequest, HttpError> {
+    let mut reader = BufReader::new(stream);
+    let mut remaining = MAX_HEAD_BYTES;
+
+    let request_line = read_head_line(&mut reader, &mut remaining)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            (method.to_string(), target.to_string())
+        }
+        _ => return Err(HttpError(400, "bad-request")),
+    };
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let header = read_head_line(&mut reader, &mut remaining)?;
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| HttpError(400, "bad-request"))?;
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err(HttpError(413, "body-too-large"));
+    }
+
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| HttpError(400, "bad-request"))?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (target, String::new()),
+    };
+
+    Ok(HttpRequest {
+        method,
+        path,
+        query,
+        body,
+        authorization,
+    })
+}
+
+/// Reads a parameter from the query string. Numbers and booleans are passed
+/// on as they are, so that `?steps=2` works like `"steps": 2`, and anything
+/// else is passed on as a string.
+fn query_value(value: &str) -> json::JsonValue {
+    match json::parse(value) {
+        Ok(parsed) if parsed.is_number() || parsed.is_boolean() => parsed,
+        _ => json::JsonValue::String(value.to_string()),
+    }
+}
+
+/// Turns a request into an IPC command. The path names the command, and its
+/// parameters come from the JSON object in the body followed by the query
+/// string. Parameters in the body win over the query string. An auth token
+/// can be given as an "auth" parameter like any other, or as a bearer token.
+fn build_command(request: &HttpRequest, peer: IpAddr) -> Result<json::JsonValue, HttpError> {
+    if request.method != "GET" && request.method != "POST" {
+        return Err(HttpError(405, "method-not-allowed"));
+    }
+
+    let command = request.path.trim_start_matches('/');
+    if command.is_empty() || command.contains('/') {
+        return Err(HttpError(404, "not-found"));
+    }
+
+    let mut document = if request.body.is_empty() {
+        json::JsonValue::new_object()
+    } else {
+        match std::str::from_utf8(&request.body).map(json::parse) {
+            Ok(Ok(parsed)) if parsed.is_object() => parsed,
+            _ => return Err(HttpError(400, "bad-request")),
+        }
+    };
+
+    for (key, value) in url::form_urlencoded::parse(request.query.as_bytes()) {
+        if !document.has_key(&key) {
+            document[key.as_ref()] = query_value(&value);
+        }
+    }
+
+    if LISTENER_COMMANDS.contains(&command.to_ascii_lowercase().as_str()) {
+        document["listener"] = json::JsonValue::String(format!("ip-{}", peer));
+    }
+
+    document["command"] = json::JsonValue::String(command.to_string());
+    Ok(document)
+}
+
+/// Picks the HTTP status code for a response from the server. Errors in the
+/// request itself get a 4xx code, and everything else is a 200 whose body
+/// says what happened.
+fn response_code(response: &[u8]) -> u16 {
+    let first_line = response.split(|byte| *byte == b'\n').next().unwrap_or(&[]);
+    let status = std::str::from_utf8(first_line)
+        .ok()
+        .and_then(|line| json::parse(line).ok())
+        .and_then(|document| document["status"].as_str().map(str::to_string));
+
+    match status.as_deref() {
+        Some("invalid-request") | Some("invalid-parameter") => 400,
+        Some("unauthorized") => 401,
+        Some("forbidden") => 403,
+        Some("unknown-command") => 404,
+        _ => 200,
+    }
+}
+
+/// What a request is answered with, once it has been read
+enum Reply {
+    /// A browser asking whether it can call the API, which only needs the
+    /// CORS headers
+    Empty,
+
+    /// A command to pass on to the server, and the auth token that the
+    /// client gave for it
+    Command(json::JsonValue, Option<String>),
+
+    /// The start of a track for /preview
+    Clip(Clip),
+}
+
+/// The part of a track that /preview sends
+struct Clip {
+    file: fs::File,
+    length: u64,
+    content_type: &'static str,
+}
+
+/// Decodes the %XX escapes in a part of a URL, if they are all valid
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+/// The bearer token in a request's Authorization header, if it has one
+fn bearer_token(request: &HttpRequest) -> Option<String> {
+    request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// Reads a parameter from the query string as it was given
+fn query_param(request: &HttpRequest, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(request.query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Checks that a /preview request gives the configured token, either as a
+/// bearer token or in the query string for players that can't set headers
+fn check_token(request: &HttpRequest, expected: &str) -> Result<(), HttpError> {
+    // The bearer token might be the server's auth token instead
+    let given = [bearer_token(request), query_param(request, "token")];
+    if given
+        .iter()
+        .flatten()
+        .any(|token| utils::tokens_match(token, expected))
+    {
+        Ok(())
+    } else {
+        Err(HttpError(403, "forbidden"))
+    }
+}
+
+/// Asks the server about a track, which also makes sure that it is on one of
+/// the playlists so that /preview can't be used to read any other file. The
+/// client's own auth token goes along with it, since the API has none of its
+/// own.
+fn track_info(
+    config: &ApiConfig,
+    track: &Path,
+    auth: Option<&str>,
+) -> Result<json::JsonValue, HttpError> {
+    let mut command = json::JsonValue::new_object();
+    command["command"] = "track-info".into();
+    command["track"] = track.to_string_lossy().to_string().into();
+
+    let response =
+        client::send_command(&config.ipc_socket, auth, &command, TIMEOUT).map_err(|error| {
+            warning!("api", "{}", error);
+            HttpError(502, "server-unavailable")
+        })?;
+
+    let first_line = response.split(|byte| *byte == b'\n').next().unwrap_or(&[]);
+    let mut document = std::str::from_utf8(first_line)
+        .ok()
+        .and_then(|line| json::parse(line).ok())
+        .ok_or(HttpError(502, "server-unavailable"))?;
+
+    if document["track"].is_object() {
+        Ok(document["track"].take())
+    } else if document["status"] == "unauthorized" {
+        Err(HttpError(401, "unauthorized"))
+    } else {
+        Err(HttpError(404, "no-such-track"))
+    }
+}
+
+/// Reads the bitrate of an MP3 from the header of its first frame, after any
+/// ID3v2 tag, and returns where the audio starts and how many bytes it takes
+/// up per second
+fn mp3_byte_rate(file: &mut fs::File) -> Option<(u64, u64)> {
+    let mut header = [0; 10];
+    file.read_exact(&mut header).ok()?;
+
+    let start = if &header[..3] == b"ID3" {
+        let size = header[6..10]
+            .iter()
+            .fold(0, |size, byte| (size << 7) | (*byte & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        10 + size + footer
+    } else {
+        0
+    };
+
+    let mut frame = [0; 4];
+    file.seek(io::SeekFrom::Start(start)).ok()?;
+    file.read_exact(&mut frame).ok()?;
+
+    // Eleven set bits start a frame, followed by the version and a layer of 1
+    // (which is Layer III)
+    if frame[0] != 0xff || frame[1] & 0xe0 != 0xe0 || frame[1] & 0x06 != 0x02 {
+        return None;
+    }
+
+    let index = (frame[2] >> 4) as usize;
+    let kbps = match (frame[1] >> 3) & 0x03 {
+        0x03 => MPEG1_BITRATES[index],
+        0x02 | 0x00 => MPEG2_BITRATES[index],
+        _ => 0,
+    };
+
+    if kbps == 0 {
+        return None;
+    }
+
+    Some((start, kbps * 1000 / 8))
+}
+
+/// The Content-Type of a track, going by its extension
+fn content_type(track: &Path) -> &'static str {
+    let extension = track
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") | Some("oga") | Some("opus") => "audio/ogg",
+        Some("flac") => "audio/flac",
+        Some("wav") => "audio/wav",
+        Some("m4a") | Some("aac") => "audio/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Finds the start of a track for /preview. The track's length comes from
+/// its playlist file or its tags if they give one, which covers any format,
+/// and otherwise from the bitrate of an MP3.
+fn preview_clip(config: &ApiConfig, request: &HttpRequest, path: &str) -> Result<Clip, HttpError> {
+    let token = config
+        .preview_token
+        .as_deref()
+        .ok_or(HttpError(404, "not-found"))?;
+
+    if request.method != "GET" {
+        return Err(HttpError(405, "method-not-allowed"));
+    }
+
+    check_token(request, token)?;
+
+    let mut track = PathBuf::from("/");
+    track.push(OsStr::from_bytes(
+        &percent_decode(path).ok_or(HttpError(400, "bad-request"))?,
+    ));
+
+    // The bearer token can only be the server's token when the preview token
+    // was given in the query string instead
+    let auth = query_param(request, "auth").or_else(|| match query_param(request, "token") {
+        Some(_) => bearer_token(request),
+        None => None,
+    });
+
+    let info = track_info(config, &track, auth.as_deref())?;
+    let mut file = fs::File::open(&track).map_err(|error| {
+        warning!("api", "Could not open {}: {}", track.display(), error);
+        HttpError(404, "no-such-track")
+    })?;
+
+    let size = file
+        .metadata()
+        .map_err(|_| HttpError(404, "no-such-track"))?
+        .len();
+
+    let seconds = info["duration"]
+        .as_u64()
+        .or_else(|| info["id3"]["length"].as_u64());
+    let length = match seconds {
+        Some(seconds) if seconds > PREVIEW_SECONDS => {
+            (size as u128 * PREVIEW_SECONDS as u128 / seconds as u128) as u64
+        }
+        Some(_) => size,
+        None => match mp3_byte_rate(&mut file) {
+            Some((start, rate)) => size.min(start + rate * PREVIEW_SECONDS),
+            None => return Err(HttpError(415, "unknown-length")),
+        },
+    };
+
+    file.seek(io::SeekFrom::Start(0))
+        .map_err(|_| HttpError(404, "no-such-track"))?;
+
+    info!("api", "Previewing {}", track.display());
+    Ok(Clip {
+        file,
+        length,
+        content_type: content_type(&track),
+    })
+}
+
+/// Writes the status line and headers of a response
+fn send_head(
+    mut stream: &TcpStream,
+    config: &ApiConfig,
+    code: u16,
+    content_type: &str,
+    length: u64,
+) -> io::Result<()> {
+    let reason = match code {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        code, reason, content_type, length
+    );
+
+    if let Some(origin) = config.allow_origin.as_ref() {
+        head.push_str(&format!(
+            "Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Methods: GET, POST\r\nAccess-Control-Allow-Headers: Content-Type, Authorization\r\n",
+            origin
+        ));
+    }
+
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())
+}
+
+/// Writes a response and closes the connection
+fn send_response(stream: &TcpStream, config: &ApiConfig, code: u16, body: &[u8]) -> io::Result<()> {
+    send_head(stream, config, code, "application/json", body.len() as u64)?;
+    (&*stream).write_all(body)?;
+    (&*stream).flush()
+}
+
+/// Sends the start of a track, straight from the file
+fn send_clip(mut stream: &TcpStream, config: &ApiConfig, clip: Clip) -> io::Result<()> {
+    send_head(stream, config, 200, clip.content_type, clip.length)?;
+    io::copy(&mut clip.file.take(clip.length), &mut stream)?;
+    stream.flush()
+}
+
+/// Answers a single HTTP request by passing it on to the server, or by
+/// sending the start of a track for /preview
+fn process_client(config: &ApiConfig, stream: TcpStream, peer: IpAddr) {
+    if let Err(error) = stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+    {
+        warning!("api", "Could not set socket timeout: {}", error);
+        return;
+    }
+
+    let result = read_request(&stream).and_then(|request| {
+        // Browsers ask before sending a JSON body to another origin
+        if request.method == "OPTIONS" {
+            return Ok(Reply::Empty);
+        }
+
+        if let Some(path) = request.path.strip_prefix(PREVIEW_PATH) {
+            return preview_clip(config, &request, path).map(Reply::Clip);
+        }
+
+        build_command(&request, peer).map(|command| {
+            let auth =
+                bearer_token(&request).or_else(|| command["auth"].as_str().map(str::to_string));
+            Reply::Command(command, auth)
+        })
+    });
+
+    let (code, body) = match result {
+        Ok(Reply::Empty) => (204, Vec::new()),
+        Ok(Reply::Clip(clip)) => {
+            if let Err(error) = send_clip(&stream, config, clip) {
+                warning!("api", "Could not send preview: {}", error);
+            }
+
+            return;
+        }
+        Ok(Reply::Command(command, _))
+            if config.refuse_admin && server::is_admin_command(&command) =>
+        {
+            warning!(
+                "api",
+                "Refusing {} over HTTP, since admin commands are limited to the socket",
+                command["command"]
+            );
+            (403, b"{\"status\": \"forbidden\"}\n".to_vec())
+        }
+        Ok(Reply::Command(command, auth)) => {
+            match client::send_command(&config.ipc_socket, auth.as_deref(), &command, TIMEOUT) {
+                Ok(response) => (response_code(&response), response),
+                Err(error) => {
+                    warning!("api", "{}", error);
+                    (502, b"{\"status\": \"server-unavailable\"}\n".to_vec())
+                }
+            }
+        }
+        Err(HttpError(code, status)) => (
+            code,
+            format!("{{\"status\": \"{}\"}}\n", status).into_bytes(),
+        ),
+    };
+
+    if let Err(error) = send_response(&stream, config, code, &body) {
+        warning!("api", "Could not send response: {}", error);
+    }
+}
+
+/// Holds one of the MAX_CLIENTS places while a client is being served, and
+/// gives it back when dropped, even if serving the client panics
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits up to the timeout for a client to connect, returning whether one did
+pub fn wait_for_client(listener: &TcpListener, timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // Safe since exactly one entry is passed
+    let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+    if ready < 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+
+        return Err(error);
+    }
+
+    Ok(ready > 0)
+}
+
+/// Serves the IPC commands over HTTP until the server shuts down. Each client
+/// gets a thread of its own, so that a slow one doesn't hold up the others,
+/// and its request is passed on to the server over its IPC socket, so that the
+/// server still only has to deal with one client at a time.
+pub fn api_worker(config: ApiConfig, listener: TcpListener, events: Receiver<Event>) {
+    let config = Arc::new(config);
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(Event::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        match wait_for_client(&listener, POLL_INTERVAL) {
+            Ok(false) => continue,
+            Ok(true) => (),
+            Err(error) => {
+                error!("api", "Could not wait for clients: {}", error);
+                return;
+            }
+        }
+
+        let (stream, address) = match listener.accept() {
+            Ok(client) => client,
+            Err(error) => {
+                warning!("api", "Could not accept client: {}", error);
+                continue;
+            }
+        };
+
+        // Only this thread takes places, so the count can't go over the limit
+        // between checking it and adding to it
+        if clients.load(Ordering::SeqCst) >= MAX_CLIENTS {
+            let body = b"{\"status\": \"busy\"}\n";
+            if let Err(error) = send_response(&stream, &config, 503, body) {
+                warning!("api", "Could not send response: {}", error);
+            }
+            continue;
+        }
+
+        clients.fetch_add(1, Ordering::SeqCst);
+        let slot = ClientSlot(Arc::clone(&clients));
+        let config = Arc::clone(&config);
+        thread::spawn(move || {
+            let _slot = slot;
+            process_client(&config, stream, address.ip());
+        });
+    }
+}
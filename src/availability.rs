@@ -0,0 +1,84 @@
+use crate::config::{AvailabilityConfig, AvailabilityWindow};
+use chrono::{Duration, NaiveDateTime};
+use std::path::Path;
+
+impl AvailabilityWindow {
+    /// Checks whether the given time falls inside of this window
+    pub fn contains(&self, when: &NaiveDateTime) -> bool {
+        let after_start = match self.not_before {
+            Some(start) => *when >= start,
+            None => true,
+        };
+
+        let before_end = match self.not_after {
+            Some(end) => *when <= end,
+            None => true,
+        };
+
+        after_start && before_end
+    }
+
+    /// Checks whether this window is open now but will close before the given
+    /// amount of time has elapsed
+    pub fn expires_within(&self, when: &NaiveDateTime, within: Duration) -> bool {
+        match self.not_after {
+            Some(end) => self.contains(when) && end - *when <= within,
+            None => false,
+        }
+    }
+}
+
+impl AvailabilityConfig {
+    /// Checks whether a track can be played at the given time, when it is being
+    /// played from the given playlist. Both the playlist's window and the
+    /// track's window must be open.
+    pub fn is_available(&self, playlist: &str, track: &Path, when: &NaiveDateTime) -> bool {
+        let playlist_open = match self.playlists.get(playlist) {
+            Some(window) => window.contains(when),
+            None => true,
+        };
+
+        let track_open = match self.tracks.get(track) {
+            Some(window) => window.contains(when),
+            None => true,
+        };
+
+        playlist_open && track_open
+    }
+
+    /// Logs a warning for each of the given playlists and tracks whose windows
+    /// close within the warning period
+    pub fn report_expiries<'a>(
+        &self,
+        playlists: impl Iterator<Item = (&'a String, &'a [std::path::PathBuf])>,
+        when: &NaiveDateTime,
+    ) {
+        let within = Duration::days(self.warn_days as i64);
+        for (name, tracks) in playlists {
+            if let Some(window) = self.playlists.get(name) {
+                if window.expires_within(when, within) {
+                    info!(
+                        "availability",
+                        "Playlist {} expires at {}",
+                        name,
+                        window.not_after.unwrap()
+                    );
+                }
+            }
+
+            for track in tracks {
+                if let Some(window) = self.tracks.get(track) {
+                    if window.expires_within(when, within) {
+                        info!(
+                            "availability",
+                            "Track {} on playlist {} expires at {}",
+                            track.display(),
+                            name,
+                            window.not_after.unwrap()
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::server;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long the server can take to answer a command from the command line
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends a command to the server over its IPC socket and returns the
+/// response. The connection is switched to length-prefixed framing first, so
+/// that streamed responses can be read whole without knowing how many lines
+/// they have. Both requests give the auth token, if the server has one, and if
+/// the server refuses the first (for want of the right token, say) that is
+/// the response.
+pub fn send_command(
+    socket: &Path,
+    auth: Option<&str>,
+    command: &json::JsonValue,
+    timeout: Duration,
+) -> Result<Vec<u8>, String> {
+    let sock = UnixStream::connect(socket)
+        .map_err(|error| format!("Could not connect to {}: {}", socket.display(), error))?;
+
+    sock.set_read_timeout(Some(timeout))
+        .and_then(|_| sock.set_write_timeout(Some(timeout)))
+        .map_err(|error| format!("Could not set socket timeout: {}", error))?;
+
+    let mut framing = json::JsonValue::new_object();
+    framing["command"] = "set-framing".into();
+    framing["framing"] = "length".into();
+
+    let mut command = command.clone();
+    if let Some(auth) = auth {
+        framing["auth"] = auth.into();
+        command["auth"] = auth.into();
+    }
+
+    let mut reader = BufReader::new(sock);
+    reader
+        .get_mut()
+        .write_all(format!("{}\n", json::stringify(framing)).as_bytes())
+        .map_err(|error| format!("Could not set framing: {}", error))?;
+
+    let mut framing_response = String::new();
+    reader
+        .read_line(&mut framing_response)
+        .map_err(|error| format!("No response to set-framing: {}", error))?;
+
+    match json::parse(&framing_response) {
+        Ok(document) if document["status"] == "ok" => (),
+        Ok(document) if document["status"].is_string() => return Ok(framing_response.into_bytes()),
+        _ => {
+            return Err(format!(
+                "Unexpected response to set-framing: {}",
+                framing_response.trim()
+            ))
+        }
+    }
+
+    reader
+        .get_mut()
+        .write_all(format!("{}\n", json::stringify(command)).as_bytes())
+        .map_err(|error| format!("Could not send command: {}", error))?;
+
+    let mut length = [0; 4];
+    reader
+        .read_exact(&mut length)
+        .map_err(|error| format!("No response to command: {}", error))?;
+
+    let mut response = vec![0; u32::from_be_bytes(length) as usize];
+    reader
+        .read_exact(&mut response)
+        .map_err(|error| format!("Incomplete response to command: {}", error))?;
+
+    Ok(response)
+}
+
+/// Sends a command given on the command line to the server and prints its
+/// response, indented for reading. Returns whether the server reported
+/// success, which is when the response has no status or a status of ok or
+/// pong.
+pub fn run_client(socket: &Path, auth: Option<&str>, args: &[String]) -> Result<bool, String> {
+    let (command, params) = args
+        .split_first()
+        .ok_or_else(|| "Usage: shuffled [CONFIG] client COMMAND [PARAM...]".to_string())?;
+
+    let request = server::command_from_args(command, params)?;
+    let response = send_command(socket, auth, &request, TIMEOUT)?;
+
+    let mut success = true;
+    for (index, line) in String::from_utf8_lossy(&response).lines().enumerate() {
+        let document = json::parse(line)
+            .map_err(|error| format!("Could not parse response {}: {}", line, error))?;
+
+        if index == 0 {
+            success = match document["status"].as_str() {
+                None | Some("ok") | Some("pong") => true,
+                Some(_) => false,
+            };
+        }
+
+        println!("{}", json::stringify_pretty(document, 2));
+    }
+
+    Ok(success)
+}
@@ -1,18 +1,36 @@
+use crate::logging;
+use crate::privileges;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
 use std::io::prelude::*;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use toml::Value;
 use url::Url;
 
 /// The basic configuration used by the server, regardless of what modules are running
 #[derive(Debug, PartialEq)]
 pub struct ServiceConfig {
+    /// The name of this instance of the server, which the default paths are
+    /// derived from so that several instances can run on the same host
+    pub instance_name: String,
+
     /// The directory where the playlist files are stored according to the wildcard *.m3u
     pub playlist_dir: PathBuf,
 
+    /// The directory whose subdirectories are each read as a playlist of the
+    /// audio files within them, or None if only playlist files are read
+    pub content_root: Option<PathBuf>,
+
     /// The location of the Unix IPC socket
     pub ipc_socket: PathBuf,
 
+    /// How long (in seconds) to wait after a playlist file changes before the
+    /// playlists are reloaded, or None if they are only reloaded on request
+    pub auto_reload: Option<u32>,
+
     /// Whether the watchdog module is currently enabled
     pub watchdog_enabled: bool,
 
@@ -21,24 +39,279 @@ pub struct ServiceConfig {
 
     /// Whether the clock module is currently enabled
     pub clock_enabled: bool,
+
+    /// Whether the disk space and library monitor is currently enabled
+    pub monitor_enabled: bool,
+
+    /// The directory (or mount) where the music files live, which has to be
+    /// available before the playlists are reloaded
+    pub library_dir: Option<PathBuf>,
+
+    /// The largest percentage of tracks that a reload can remove without being
+    /// forced
+    pub reload_max_removed: u32,
+
+    /// How many of the playlist states replaced by reloads are kept for
+    /// rollback-playlists
+    pub reload_snapshots: usize,
+
+    /// How many of the tracks returned by next-track are remembered for
+    /// previous-track and get-history
+    pub track_history: usize,
+
+    /// A file that every track returned by next-track is appended to
+    pub history_file: Option<PathBuf>,
+
+    /// The address to accept IPC requests on over TCP, besides the Unix socket
+    pub tcp_listen: Option<SocketAddr>,
+
+    /// The token that every IPC request has to give as its auth, if any
+    pub auth_token: Option<String>,
+
+    /// The users and groups that are allowed to send the admin commands over
+    /// the Unix socket. When both are empty, anyone who can connect can.
+    pub admin_users: Vec<libc::uid_t>,
+    pub admin_groups: Vec<libc::gid_t>,
+
+    /// The shape of the responses on connections that don't ask for another
+    pub protocol_version: ProtocolVersion,
+
+    /// Where the order and position of each playlist is saved, so that they
+    /// carry on from the same place after a restart
+    pub state_file: Option<PathBuf>,
+
+    /// How often (in seconds) the state file is saved, if anything has changed
+    pub state_save_interval: u32,
+
+    /// A file that a summary of each day's activity is kept in
+    pub stats_file: Option<PathBuf>,
+
+    /// Where the PID of the server is written while it's running
+    pub pid_file: Option<PathBuf>,
+
+    /// Where the log goes when running in the background with --daemon, or
+    /// when it's written to a file
+    pub log_file: Option<PathBuf>,
+
+    /// How much goes into the log and where it's written
+    pub log: LogConfig,
+
+    /// The user and group to switch to once the sockets are bound, when
+    /// started as root
+    pub user: Option<String>,
+    pub group: Option<String>,
+
+    /// How the playlists are put into a random order
+    pub shuffle: ShuffleMode,
+
+    /// The playlists made up of the tracks on the other playlists whose ID3
+    /// tags match a query, by name
+    pub smart_playlists: HashMap<String, SmartPlaylist>,
+
+    /// What each playlist's shuffles are seeded from, so that they come out
+    /// the same every time, or none to seed them at random
+    pub shuffle_seed: Option<u64>,
+}
+
+/// The ID3 tags that a track needs to be on a smart playlist. A track has to
+/// match every criterion that is given, and one of the entries of each list.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SmartPlaylist {
+    /// The ID3 genres that the track can have, ignoring case
+    pub genres: Vec<String>,
+
+    /// The artists that the track can be by, ignoring case
+    pub artists: Vec<String>,
+
+    /// The earliest year that the track can be from
+    pub year_min: Option<u16>,
+
+    /// The latest year that the track can be from
+    pub year_max: Option<u16>,
+
+    /// How many days ago a reload can have added the track at most
+    pub added_within_days: Option<u32>,
+}
+
+/// How the server shuffles its playlists
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ShuffleMode {
+    /// Every order is equally likely
+    Uniform,
+
+    /// Tracks that have been played less, and less recently, come first
+    Weighted,
+}
+
+/// Which shape IPC responses take, from the oldest to the newest. Each
+/// version only adds fields to the one before it.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum ProtocolVersion {
+    /// The responses as they first were, without the meta, so that
+    /// next-track is only {"track": ...}
+    Original = 1,
+
+    /// Tracks come with their kind, and every response with its meta
+    Meta = 2,
+
+    /// Tracks also come with their ID3 tags and their duration, if known
+    Detailed = 3,
+}
+
+impl ProtocolVersion {
+    /// Finds the version by its number, as service.protocol_version and
+    /// set-protocol give it
+    pub fn from_number(number: i64) -> Option<Self> {
+        match number {
+            1 => Some(ProtocolVersion::Original),
+            2 => Some(ProtocolVersion::Meta),
+            3 => Some(ProtocolVersion::Detailed),
+            _ => None,
+        }
+    }
+}
+
+/// How much detail goes into the log, from the least to the most
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+/// Where the log is written
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogOutput {
+    /// Standard error, which --daemon sends to the log file
+    Stderr,
+
+    /// The systemd journal, with the level and component of each message as
+    /// fields
+    Journald,
+
+    /// The log file, which is rotated as it grows
+    File,
+}
+
+/// The configuration options for the log
+#[derive(Debug, PartialEq, Clone)]
+pub struct LogConfig {
+    /// The most detailed level that is logged for every component
+    pub level: LogLevel,
+
+    /// Overrides of the level for individual components, indexed by name
+    pub components: HashMap<String, LogLevel>,
+    pub output: LogOutput,
+
+    /// How large (in MB) the log file can get before it is rotated, or 0 to
+    /// never rotate it
+    pub rotate_size: u32,
+
+    /// How many rotated log files are kept
+    pub rotate_keep: u32,
 }
 
 /// The configuration options available for all of the "special" music entries,
 /// currently weather and music
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SpecialBaseConfig {
     /// The directory used by the special processes for storing output
     pub working_dir: PathBuf,
 
+    /// What the names of the files in the working directory start with, so
+    /// that several instances can share the same directory
+    pub file_prefix: String,
+
     /// How often (in minutes) play through the special entries. Note that this
     /// only gives the time between *this* special entry and the *next* special
     /// entry. The whole list can be cycled through after several multiples of
     /// this interval (depending upon how many special entries are enabled)
     pub interval: u32,
+
+    /// What happens when the interval has elapsed more than once since the
+    /// last special entry, as when nothing has asked for a track in a while
+    pub missed: MissedSpecials,
+
+    /// The language that the clock and weather announcements are spoken in
+    pub locale: Locale,
+
+    /// The programs used to turn announcements into MP3 files
+    pub tools: AnnouncerTools,
+
+    /// How many times faster than real time the special interval and the
+    /// weather's refreshes go, from debug.time_scale
+    pub time_scale: u32,
+}
+
+/// What the special queue does with entries that came due while nothing was
+/// asking for tracks
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MissedSpecials {
+    /// Play the next entry once and start the interval over
+    One,
+
+    /// Play an entry for every interval that elapsed, one after another, up
+    /// to one pass through the entries
+    All,
+
+    /// Drop the entries that were missed and start the interval over
+    Skip,
+}
+
+/// What the weather worker does once its report is too old to be read
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StaleWeather {
+    /// Remove the report, so that the weather's turn is passed over
+    Skip,
+
+    /// Replace the report with an announcement that the weather is
+    /// unavailable
+    Announce,
+}
+
+/// The external programs which the clock and weather run to generate their
+/// audio. Paths without a slash are looked up on the PATH.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AnnouncerTools {
+    pub espeak: PathBuf,
+    pub sox: PathBuf,
+    pub lame: PathBuf,
+
+    /// The loudness that announcements are raised or lowered to, in LUFS, so
+    /// that they aren't much quieter than the music around them. They are
+    /// left as espeak made them if this isn't set.
+    pub loudness_lufs: Option<f64>,
+
+    /// The sample rate and number of channels of the generated MP3s, which
+    /// should match the stream so that it doesn't have to re-encode them
+    pub sample_rate: u32,
+    pub channels: u32,
+
+    /// The bitrate of the generated MP3s in kbps, or lame's default if None
+    pub bitrate: Option<u32>,
+}
+
+/// The sample rates that MP3 files can have
+const MP3_SAMPLE_RATES: &[i64] = &[8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+/// The bitrates that lame can encode MP3 files at, in kbps
+const MP3_BITRATES: &[i64] = &[
+    8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 192, 224, 256, 320,
+];
+
+/// The languages that spoken announcements can be generated in. Each one has
+/// its own translation table in the i18n module.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Locale {
+    English,
+    German,
+    French,
+    Spanish,
 }
 
 /// The configuration options available for the watchdog service
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct WatchdogConfig {
     /// How often to probe the server to see if the stream is active
     pub interval: u32,
@@ -49,13 +322,97 @@ pub struct WatchdogConfig {
     /// The URL path of the Icecast stream we're monitoring
     pub path: String,
 
+    /// The full URL of the Icecast stream we're monitoring, used for the Host
+    /// header and for resolving redirects
+    pub url: Url,
+
     /// The name of the systemd service which runs the ezstream instance
     /// that we are servicing
     pub service: String,
+
+    /// How many minutes can pass without a next-track request while the
+    /// stream is up before raising a dead air alert, or None to disable it
+    pub dead_air: Option<u32>,
+
+    /// The IPC socket to send status requests to on every probe, to check
+    /// that the server is still responding, or None to disable the check
+    pub self_check: Option<PathBuf>,
+
+    /// The token that the status requests give, if the server wants one
+    pub auth_token: Option<String>,
+
+    /// What to do when the probe finds that the stream is down
+    pub action: WatchdogAction,
+
+    /// The media type that the stream has to be served as for the probe to
+    /// pass, like audio/mpeg, or None to accept any
+    pub content_type: Option<String>,
+
+    /// How many bytes of the body the probe has to read before it passes
+    pub min_body_bytes: usize,
+
+    /// How many seconds past each multiple of the interval (counting from the
+    /// epoch) the probes are run at, or None to count the interval from the
+    /// end of the last probe
+    pub offset: Option<u32>,
+
+    /// The most seconds that each probe is delayed by at random, so that
+    /// instances probing the same server drift apart
+    pub jitter: u32,
+
+    /// How many seconds after the service is restarted, either by the watchdog
+    /// or by an operator who announced it with the restarting command, that
+    /// the stream isn't probed
+    pub restart_grace: u32,
+
+    /// The systemctl program used to restart the service, which is looked up
+    /// on the PATH if it has no slash. Not used when restarts go over D-Bus.
+    #[cfg_attr(feature = "systemd-dbus", allow(dead_code))]
+    pub systemctl: PathBuf,
+
+    /// How many times faster than real time the probe interval, offset and
+    /// jitter go, from debug.time_scale
+    pub time_scale: u32,
+}
+
+/// What the watchdog does when the stream stops responding
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchdogAction {
+    /// Restart the ezstream service
+    Restart,
+
+    /// Only raise an alert, leaving the recovery to an operator
+    Alert,
+}
+
+/// The configuration options for delivering alerts to operators. Alerts are
+/// always logged, these are only for sending them elsewhere.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AlertConfig {
+    /// A program to run for each alert, which is given the source and the
+    /// message as arguments
+    pub command: Option<PathBuf>,
+
+    /// A URL to POST a JSON document describing each alert to
+    pub webhook: Option<String>,
+}
+
+/// The configuration options for the HTTP client shared by every module that
+/// makes HTTP requests (currently weather and alerts)
+#[derive(Debug, PartialEq, Clone)]
+pub struct HttpConfig {
+    /// How long to wait for a whole request to complete, in seconds
+    pub timeout: u32,
+
+    /// The URL of a proxy to send all requests through
+    pub proxy: Option<String>,
+
+    /// The User-Agent header to send with every request
+    pub user_agent: String,
 }
 
 /// The configuration options available for the weather special service
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SpecialWeatherConfig {
     /// What region to report the weather on
     pub region: String,
@@ -67,6 +424,364 @@ pub struct SpecialWeatherConfig {
     /// is just a cooldown for cases where the API calls are successful;
     /// when they aren't, we poll once every hour until we get a response
     pub interval: u32,
+
+    /// The template for the whole report, with {high}, {low} and {periods}
+    /// placeholders, or None to use the one for the configured locale
+    pub template: Option<String>,
+
+    /// Extra words to replace in the forecast text before it is read, in
+    /// addition to (or overriding) the built-in abbreviations
+    pub substitutions: HashMap<String, String>,
+
+    /// Whether each period is introduced by the name the forecast gives it,
+    /// like "Tonight", rather than the hour that it starts
+    pub period_names: bool,
+
+    /// The longest that the spoken report can be, in seconds. Periods are
+    /// dropped from the end of the forecast until it fits.
+    pub max_length: Option<u32>,
+
+    /// The longest that the spoken report can be, as a percentage of the time
+    /// between special entries. The shorter of this and max_length applies.
+    pub max_length_pct: Option<u32>,
+
+    /// How many hours after the forecast was issued it stops being read, or
+    /// None to read it no matter how old it is
+    pub max_age: Option<u32>,
+
+    /// What happens to the report once the forecast is older than max_age
+    pub stale: StaleWeather,
+}
+
+/// A range of local time when a track or playlist is allowed to be played.
+/// Either end can be left open.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AvailabilityWindow {
+    /// The earliest time that the entry can be played
+    pub not_before: Option<NaiveDateTime>,
+
+    /// The latest time that the entry can be played
+    pub not_after: Option<NaiveDateTime>,
+}
+
+/// The configuration options for restricting when tracks and playlists can
+/// be played
+#[derive(Debug, PartialEq)]
+pub struct AvailabilityConfig {
+    /// Windows that apply to every track on the named playlists
+    pub playlists: HashMap<String, AvailabilityWindow>,
+
+    /// Windows that apply to individual tracks, regardless of which playlist
+    /// they appear on
+    pub tracks: HashMap<PathBuf, AvailabilityWindow>,
+
+    /// How many days ahead of an expiry to start warning about it
+    pub warn_days: u32,
+}
+
+/// The tracks that a tag applies to. A track has the tag if it matches any
+/// of these criteria.
+#[derive(Debug, PartialEq)]
+pub struct TagDefinition {
+    /// Every track on these playlists has the tag
+    pub playlists: Vec<String>,
+
+    /// These individual tracks have the tag
+    pub tracks: Vec<PathBuf>,
+
+    /// Every track with one of these ID3 genres has the tag
+    pub genres: Vec<String>,
+}
+
+/// A single rule that restricts which tracks can be played next
+#[derive(Debug, PartialEq)]
+pub enum SeparationRule {
+    /// Two tracks with the given tag can't be played one after another
+    NoRepeatTag(String),
+
+    /// Tracks with the given tag can't be played from the start hour up until
+    /// the end hour. The range wraps around midnight if the start is after
+    /// the end.
+    ForbidTagHours(String, u32, u32),
+
+    /// Two tracks from the same album can't be played within this many
+    /// minutes of each other
+    AlbumSeparation(u32),
+}
+
+/// The configuration options for tagging tracks and the rules that use them
+#[derive(Debug, PartialEq)]
+pub struct RulesConfig {
+    /// The tags which can be used by the rules, indexed by name
+    pub tags: HashMap<String, TagDefinition>,
+
+    /// The rules which must all pass for a track to be played
+    pub rules: Vec<SeparationRule>,
+}
+
+/// The bounds on how many tracks of a genre a playlist should play each hour
+#[derive(Debug, PartialEq)]
+pub struct GenreQuota {
+    /// The ID3 genre that the quota counts
+    pub genre: String,
+
+    /// At least this many tracks of the genre should be played each hour
+    pub min: Option<u32>,
+
+    /// No more than this many tracks of the genre can be played each hour
+    pub max: Option<u32>,
+}
+
+/// The configuration options for per-hour genre quotas
+#[derive(Debug, PartialEq)]
+pub struct QuotaConfig {
+    /// The quotas for each playlist, indexed by playlist name
+    pub playlists: HashMap<String, Vec<GenreQuota>>,
+}
+
+/// The configuration options for getting the tracks that a reload adds on the
+/// air soon, instead of after everything else on the playlist
+#[derive(Debug, PartialEq)]
+pub struct RampConfig {
+    /// How many plays the new tracks on every playlist are spread out over, or
+    /// None to put them at the end of the playlist
+    pub plays: Option<u32>,
+
+    /// Overrides of plays for individual playlists, indexed by playlist name,
+    /// where 0 puts the new tracks at the end
+    pub playlists: HashMap<String, u32>,
+}
+
+/// What happens once a playlist has played its last track
+#[derive(Debug, PartialEq, Clone)]
+pub enum PlaylistEnd {
+    /// The playlist starts over from its first track, in the same order
+    Repeat,
+
+    /// The playlist is shuffled again before it starts over
+    Reshuffle,
+
+    /// The named playlist takes over, and this one starts over from its first
+    /// track whenever it is switched back to
+    Switch(String),
+
+    /// Nothing more is served until another playlist is switched to
+    Stop,
+}
+
+/// The configuration options for what happens at the end of each playlist
+#[derive(Debug, PartialEq)]
+pub struct PlaylistEndConfig {
+    /// What happens at the end of the playlists that aren't listed
+    pub action: PlaylistEnd,
+
+    /// Overrides of the action for individual playlists, indexed by playlist
+    /// name
+    pub playlists: HashMap<String, PlaylistEnd>,
+
+    /// The playlists that play each of their tracks only once, and reach
+    /// their end when every track has been played
+    pub one_shot: HashSet<String>,
+}
+
+impl PlaylistEndConfig {
+    /// What happens at the end of the given playlist
+    pub fn action(&self, playlist: &str) -> &PlaylistEnd {
+        self.playlists.get(playlist).unwrap_or(&self.action)
+    }
+
+    pub fn is_one_shot(&self, playlist: &str) -> bool {
+        self.one_shot.contains(playlist)
+    }
+}
+
+/// The configuration options for keeping the parts of multi-part works
+/// together, and in order, when the playlists are shuffled
+#[derive(Debug, PartialEq, Default)]
+pub struct PartsConfig {
+    /// Whether works are found from the titles and track numbers in the
+    /// tracks' tags
+    pub detect: bool,
+
+    /// The file that lists works by hand, if any
+    pub file: Option<PathBuf>,
+}
+
+/// When an individual track stops being played
+#[derive(Debug, PartialEq)]
+pub struct RetirementRule {
+    /// How many plays the track gets, overriding the section's max_plays
+    pub max_plays: Option<u32>,
+
+    /// The time after which the track isn't played anymore
+    pub after: Option<NaiveDateTime>,
+}
+
+/// The configuration options for retiring tracks once they've been played
+/// enough, or once their time is up
+#[derive(Debug, PartialEq)]
+pub struct RetirementConfig {
+    /// How many plays every track gets before it is retired, or None to let
+    /// them play forever
+    pub max_plays: Option<u32>,
+
+    /// Rules for individual tracks, indexed by their absolute paths
+    pub tracks: HashMap<PathBuf, RetirementRule>,
+}
+
+/// The configuration options for listener votes on the current track
+#[derive(Debug, PartialEq)]
+pub struct VoteConfig {
+    /// How many more skip votes than keep votes a track needs to get before
+    /// the action is taken, or None to only record the votes
+    pub skip_threshold: Option<u32>,
+
+    /// What happens to a track once it has enough skip votes
+    pub action: VoteAction,
+}
+
+/// What is done with a track that listeners have voted to skip
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VoteAction {
+    /// Move the track to the end of the playlist, so it plays as late as
+    /// possible next time around
+    Deprioritize,
+
+    /// Stop playing the track until the server is restarted, unless nothing
+    /// else is playable
+    Blocklist,
+}
+
+/// The configuration options for track requests from listeners
+#[derive(Debug, PartialEq)]
+pub struct RequestConfig {
+    /// Whether requests wait for an operator to approve them before they are
+    /// queued to play
+    pub moderated: bool,
+
+    /// How long a listener has to wait after making a request before they can
+    /// make another one, in minutes
+    pub interval: u32,
+
+    /// The most characters that a message to be read out on the air can have
+    pub message_length: usize,
+}
+
+/// Where the now-playing text for an RDS encoder is sent
+#[derive(Debug, PartialEq, Clone)]
+pub enum RdsTarget {
+    /// A file which is replaced with the text on every update
+    File(PathBuf),
+
+    /// A serial port which the encoder is attached to, which must already be
+    /// set up with the right baud rate
+    Serial(PathBuf),
+
+    /// An encoder listening for UDP datagrams
+    Udp(SocketAddr),
+}
+
+/// The configuration options for sending now-playing text to an RDS or HD
+/// Radio encoder
+#[derive(Debug, PartialEq, Clone)]
+pub struct RdsConfig {
+    pub target: RdsTarget,
+
+    /// How to describe a track, with {artist}, {title} and {album} replaced by
+    /// its tags
+    pub template: String,
+
+    /// What to send while a special entry is playing, or for tracks without a
+    /// title
+    pub station_text: String,
+
+    /// The encoder command that the text is sent with on serial ports and UDP
+    pub command: String,
+
+    /// The most characters that the encoder can show
+    pub max_length: usize,
+}
+
+/// The IRC channel that now-playing notices are posted to
+#[derive(Debug, PartialEq, Clone)]
+pub struct IrcConfig {
+    /// The host:port of the IRC server, which must accept plaintext
+    /// connections
+    pub server: String,
+
+    /// The channel to join and post in
+    pub channel: String,
+
+    /// The nickname to connect with
+    pub nick: String,
+}
+
+/// The configuration options for posting now-playing notices to chat
+#[derive(Debug, PartialEq, Clone)]
+pub struct NotifyConfig {
+    /// A Discord webhook URL to post notices to
+    pub discord_webhook: Option<String>,
+
+    /// An IRC channel to post notices to
+    pub irc: Option<IrcConfig>,
+
+    /// How to announce a track, with {artist}, {title}, {album} and {file}
+    /// replaced by its tags and filename
+    pub track_template: String,
+
+    /// How to announce a special entry, with {kind} replaced by its kind
+    pub special_template: String,
+
+    /// The least number of seconds between two notices. Anything that plays
+    /// in between is folded into the next notice.
+    pub interval: u32,
+}
+
+/// The configuration options for the HTTP frontend to the IPC commands
+#[derive(Debug, PartialEq, Clone)]
+pub struct ApiConfig {
+    /// The address to accept HTTP requests on
+    pub listen: SocketAddr,
+
+    /// The IPC socket that requests are passed on to
+    pub ipc_socket: PathBuf,
+
+    /// Whether the admin commands are turned away, since service.admin_users
+    /// or service.admin_groups keep them to clients on the Unix socket
+    pub refuse_admin: bool,
+
+    /// The origin that browsers are allowed to call the API from, so that a
+    /// dashboard can be served from somewhere else
+    pub allow_origin: Option<String>,
+
+    /// The token that /preview requests have to give, which also turns the
+    /// endpoint on
+    pub preview_token: Option<String>,
+}
+
+/// The configuration options for the Prometheus metrics endpoint
+#[derive(Debug, PartialEq, Clone)]
+pub struct MetricsConfig {
+    /// The address to serve /metrics on
+    pub listen: SocketAddr,
+}
+
+/// The configuration options for the disk space and library monitor
+#[derive(Debug, PartialEq, Clone)]
+pub struct MonitorConfig {
+    /// How many minutes to wait between checks
+    pub interval: u32,
+
+    /// The directory whose free space is checked, which is the special
+    /// working directory
+    pub working_dir: PathBuf,
+
+    /// How little free space the working directory can have before an alert is
+    /// raised, in megabytes
+    pub min_free_mb: u64,
+
+    /// The music library to check on, from service.library_dir
+    pub library_dir: Option<PathBuf>,
 }
 
 /// The combined server settings stored in the configuration file
@@ -76,6 +791,210 @@ pub struct Config {
     pub special_base: SpecialBaseConfig,
     pub special_weather: SpecialWeatherConfig,
     pub watchdog: WatchdogConfig,
+    pub alerts: AlertConfig,
+    pub http: HttpConfig,
+    pub availability: AvailabilityConfig,
+    pub rules: RulesConfig,
+    pub quotas: QuotaConfig,
+    pub ramp: RampConfig,
+    pub playlist_end: PlaylistEndConfig,
+    pub parts: PartsConfig,
+    pub retirement: RetirementConfig,
+    pub votes: VoteConfig,
+    pub requests: RequestConfig,
+    pub rds: Option<RdsConfig>,
+    pub notify: Option<NotifyConfig>,
+    pub api: Option<ApiConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub monitor: MonitorConfig,
+
+    /// The name of the [station.*] section that this was read from, if the
+    /// file has any
+    pub station: Option<String>,
+
+    /// Anything in the file that still works but should be changed, in the
+    /// order it was found
+    pub warnings: Vec<ConfigWarning>,
+}
+
+/// Something in the configuration that is only accepted for compatibility
+/// with older versions of the format
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConfigWarning {
+    /// A key was given by the name it had before the config version that
+    /// renamed it, and was read as its new name
+    RenamedKey {
+        old: &'static str,
+        new: &'static str,
+        version: i64,
+    },
+
+    /// A key was given by both its old and new names, so the old one was
+    /// ignored
+    ShadowedKey {
+        old: &'static str,
+        new: &'static str,
+    },
+
+    /// The intervals have been sped up with debug.time_scale or
+    /// SHUFFLED_TIME_SCALE, which is only meant for testing
+    TimeScaled { scale: u32 },
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigWarning::RenamedKey { old, new, version } => write!(
+                f,
+                "'{}' is deprecated, it was renamed to '{}' in config_version {}",
+                old, new, version
+            ),
+            ConfigWarning::ShadowedKey { old, new } => write!(
+                f,
+                "'{}' is ignored since its new name '{}' is also set",
+                old, new
+            ),
+            ConfigWarning::TimeScaled { scale } => write!(
+                f,
+                "Intervals pass {} times faster than real time, which is only meant for testing",
+                scale
+            ),
+        }
+    }
+}
+
+/// The parts of the configuration which are only read at startup, since the
+/// sockets and workers that use them are only set up once. A reload compares
+/// them against the new configuration to find which changes it can't apply.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StartupSettings {
+    instance_name: String,
+    ipc_socket: PathBuf,
+    tcp_listen: Option<SocketAddr>,
+    auth_token: Option<String>,
+    tasks: [bool; 4],
+    auto_reload: Option<u32>,
+    state_file: Option<PathBuf>,
+    state_save_interval: u32,
+    stats_file: Option<PathBuf>,
+    pid_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    log_output: LogOutput,
+    log_rotate: (u32, u32),
+    user: Option<String>,
+    group: Option<String>,
+
+    /// The special settings, which are fixed if the weather worker was given
+    /// a copy of them
+    special: Option<SpecialBaseConfig>,
+    watchdog: WatchdogConfig,
+    weather: SpecialWeatherConfig,
+    alerts: AlertConfig,
+    http: HttpConfig,
+    rds: Option<RdsConfig>,
+    notify: Option<NotifyConfig>,
+    api: Option<ApiConfig>,
+    metrics: Option<MetricsConfig>,
+    monitor: MonitorConfig,
+}
+
+impl StartupSettings {
+    /// Lists the keys and sections that are different in another set of
+    /// settings, in the order they appear in the example config
+    pub fn changes(&self, other: &StartupSettings) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        let fields = [
+            (
+                "service.instance_name",
+                self.instance_name == other.instance_name,
+            ),
+            ("service.ipc_socket", self.ipc_socket == other.ipc_socket),
+            ("service.tcp_listen", self.tcp_listen == other.tcp_listen),
+            ("service.auth_token", self.auth_token == other.auth_token),
+            ("service.tasks", self.tasks == other.tasks),
+            ("service.auto_reload", self.auto_reload == other.auto_reload),
+            ("service.state_file", self.state_file == other.state_file),
+            (
+                "service.state_save_sec",
+                self.state_save_interval == other.state_save_interval,
+            ),
+            ("service.stats_file", self.stats_file == other.stats_file),
+            ("service.pid_file", self.pid_file == other.pid_file),
+            ("service.log_file", self.log_file == other.log_file),
+            ("service.log_output", self.log_output == other.log_output),
+            (
+                "service.log_rotate_mb",
+                self.log_rotate.0 == other.log_rotate.0,
+            ),
+            ("service.log_keep", self.log_rotate.1 == other.log_rotate.1),
+            ("service.user", self.user == other.user),
+            ("service.group", self.group == other.group),
+            (
+                "special",
+                self.special.is_none() || self.special == other.special,
+            ),
+            ("watchdog", self.watchdog == other.watchdog),
+            ("weather", self.weather == other.weather),
+            ("alerts", self.alerts == other.alerts),
+            ("http", self.http == other.http),
+            ("rds", self.rds == other.rds),
+            ("notify", self.notify == other.notify),
+            ("api", self.api == other.api),
+            ("metrics", self.metrics == other.metrics),
+            ("monitor", self.monitor == other.monitor),
+        ];
+
+        for (name, same) in fields.iter() {
+            if !same {
+                changes.push(*name);
+            }
+        }
+
+        changes
+    }
+}
+
+impl Config {
+    /// Copies out the settings that can only be changed by a restart
+    pub fn startup_settings(&self) -> StartupSettings {
+        let service = &self.service;
+        StartupSettings {
+            instance_name: service.instance_name.clone(),
+            ipc_socket: service.ipc_socket.clone(),
+            tcp_listen: service.tcp_listen,
+            auth_token: service.auth_token.clone(),
+            tasks: [
+                service.watchdog_enabled,
+                service.weather_enabled,
+                service.clock_enabled,
+                service.monitor_enabled,
+            ],
+            auto_reload: service.auto_reload,
+            state_file: service.state_file.clone(),
+            state_save_interval: service.state_save_interval,
+            stats_file: service.stats_file.clone(),
+            pid_file: service.pid_file.clone(),
+            log_file: service.log_file.clone(),
+            log_output: service.log.output,
+            log_rotate: (service.log.rotate_size, service.log.rotate_keep),
+            user: service.user.clone(),
+            group: service.group.clone(),
+            special: if service.weather_enabled {
+                Some(self.special_base.clone())
+            } else {
+                None
+            },
+            watchdog: self.watchdog.clone(),
+            weather: self.special_weather.clone(),
+            alerts: self.alerts.clone(),
+            http: self.http.clone(),
+            rds: self.rds.clone(),
+            notify: self.notify.clone(),
+            api: self.api.clone(),
+            metrics: self.metrics.clone(),
+            monitor: self.monitor.clone(),
+        }
+    }
 }
 
 /// Utility functions for working with dot-separated paths and type corecions
@@ -103,10 +1022,26 @@ trait ConfigUtils {
     /// given path if not
     fn require_int(&self, path: &str) -> Result<i64, String>;
 
+    /// Requires that the current Value is a number, either a float or an
+    /// integer, reporting an Err with the given path if not
+    fn require_float(&self, path: &str) -> Result<f64, String>;
+
     /// Like as_pathbuf, but reports an Err with the given path if the value is
     /// not a string
     fn require_pathbuf(&self, path: &str) -> Result<PathBuf, String>;
 
+    /// Requires that the current Value is a boolean, reporting an Err with the
+    /// given path if not
+    fn require_bool(&self, path: &str) -> Result<bool, String>;
+
+    /// Requires that the current Value is an array of strings, reporting an Err
+    /// with the given path if not
+    fn require_str_array(&self, path: &str) -> Result<Vec<String>, String>;
+
+    /// Requires that the current Value is a local date or datetime, either as a
+    /// TOML date or a string, reporting an Err with the given path if not
+    fn require_datetime(&self, path: &str) -> Result<NaiveDateTime, String>;
+
     /// Converts the current Value to a PathBuf, if it is a string
     fn as_pathbuf(&self) -> Option<PathBuf>;
 }
@@ -154,6 +1089,15 @@ impl ConfigUtils for Value {
         ))
     }
 
+    fn require_float(&self, path: &str) -> Result<f64, String> {
+        self.as_float()
+            .or_else(|| self.as_integer().map(|i| i as f64))
+            .ok_or(format!(
+                "Could not parse config: '{}' must be a number",
+                path
+            ))
+    }
+
     fn require_pathbuf(&self, path: &str) -> Result<PathBuf, String> {
         self.as_pathbuf().ok_or(format!(
             "Could not parse config: '{}' must be a file path",
@@ -161,29 +1105,414 @@ impl ConfigUtils for Value {
         ))
     }
 
-    fn as_pathbuf(&self) -> Option<PathBuf> {
-        self.as_str().map(PathBuf::from)
+    fn require_bool(&self, path: &str) -> Result<bool, String> {
+        self.as_bool().ok_or(format!(
+            "Could not parse config: '{}' must be a boolean",
+            path
+        ))
     }
-}
 
-/// Builds the service section of the configuration, which contains the
-/// following options:
-///
-/// - playlist_dir, which is the directory containing the .m3u playlist files
-///
+    fn require_str_array(&self, path: &str) -> Result<Vec<String>, String> {
+        let mut strings = Vec::new();
+        for entry in self.require_array(path)? {
+            strings.push(entry.require_str(&format!("{}.*", path))?.to_string());
+        }
+
+        Ok(strings)
+    }
+
+    fn require_datetime(&self, path: &str) -> Result<NaiveDateTime, String> {
+        let text = match self {
+            Value::String(text) => text.to_string(),
+            Value::Datetime(datetime) => datetime.to_string(),
+            _ => return Err(format!("Could not parse config: '{}' must be a date", path)),
+        };
+
+        NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(&text, "%Y-%m-%d %H:%M:%S"))
+            .or_else(|_| NaiveDate::parse_from_str(&text, "%Y-%m-%d").map(|d| d.and_hms(0, 0, 0)))
+            .map_err(|_| {
+                format!(
+                    "Could not parse config: '{}' must be a date like 2020-12-01 or 2020-12-01T08:00:00",
+                    path
+                )
+            })
+    }
+
+    fn as_pathbuf(&self) -> Option<PathBuf> {
+        self.as_str().map(PathBuf::from)
+    }
+}
+
+/// Reads the path of an external program, which defaults to looking up the
+/// program's name on the PATH
+fn parse_tool(root: &Value, path: &str, name: &str) -> Result<PathBuf, String> {
+    match root.get_at_path(path) {
+        Some(entry) => entry.require_pathbuf(path),
+        None => Ok(PathBuf::from(name)),
+    }
+}
+
+/// Replaces each ${NAME} in a secret with the value of that environment
+/// variable
+fn expand_env(path: &str, text: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let (name, after) = rest[start + 2..].split_once('}').ok_or(format!(
+            "Could not parse config: '{}' has a ${{ without a matching }}",
+            path
+        ))?;
+
+        let value = std::env::var(name).map_err(|_| {
+            format!(
+                "Could not parse config: '{}' uses ${{{}}}, which is not set",
+                path, name
+            )
+        })?;
+
+        expanded.push_str(&value);
+        rest = after;
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Reads a setting that may be a secret, so that it doesn't have to be kept in
+/// the config file itself. It can be given directly, with ${NAME} replaced by
+/// the environment variable NAME, or by the path of a file holding it in
+/// <path>_file (ignoring any line break at the end of the file), but not both.
+fn get_secret(root: &Value, path: &str) -> Result<Option<String>, String> {
+    let file_path = format!("{}_file", path);
+    match (root.get_at_path(path), root.get_at_path(&file_path)) {
+        (Some(_), Some(_)) => Err(format!(
+            "Could not parse config: '{}' and '{}' can't both be set",
+            path, file_path
+        )),
+        (Some(entry), None) => expand_env(path, entry.require_str(path)?).map(Some),
+        (None, Some(entry)) => {
+            let secret_path = entry.require_pathbuf(&file_path)?;
+            let secret = fs::read_to_string(&secret_path).map_err(|error| {
+                format!(
+                    "Could not parse config: could not read '{}' from {}: {}",
+                    file_path,
+                    secret_path.display(),
+                    error
+                )
+            })?;
+
+            let secret = secret.trim_end_matches(&['\n', '\r'][..]);
+            if secret.is_empty() {
+                return Err(format!(
+                    "Could not parse config: '{}' at {} is empty",
+                    file_path,
+                    secret_path.display()
+                ));
+            }
+
+            Ok(Some(secret.to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Reads the loudness that announcements are matched to, if there is one. It
+/// has to be below full scale and above the level that counts as silence.
+fn parse_loudness(root: &Value) -> Result<Option<f64>, String> {
+    let entry = match root.get_at_path("special.loudness_lufs") {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    let loudness = entry.require_float("special.loudness_lufs")?;
+    if !(loudness > -70.0 && loudness < 0.0) {
+        return Err(
+            "Could not parse config: 'special.loudness_lufs' must be between -70 and 0".to_string(),
+        );
+    }
+
+    Ok(Some(loudness))
+}
+
+/// Reads an integer which has to be one of a few allowed values, if it is set
+fn parse_choice(root: &Value, path: &str, allowed: &[i64]) -> Result<Option<u32>, String> {
+    let value = match root.get_at_path(path) {
+        Some(entry) => entry.require_int(path)?,
+        None => return Ok(None),
+    };
+
+    if !allowed.contains(&value) {
+        let allowed = allowed
+            .iter()
+            .map(|choice| choice.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return Err(format!(
+            "Could not parse config: '{}' must be one of {}",
+            path, allowed
+        ));
+    }
+
+    Ok(Some(value as u32))
+}
+
+/// Reads the name of a user or group, if it is set
+fn parse_account(root: &Value, path: &str) -> Result<Option<String>, String> {
+    let name = match root.get_at_path(path) {
+        Some(entry) => entry.require_str(path)?,
+        None => return Ok(None),
+    };
+
+    if name.is_empty() || name.contains('\0') {
+        return Err(format!(
+            "Could not parse config: '{}' must be the name of a {}",
+            path,
+            path.trim_start_matches("service.")
+        ));
+    }
+
+    Ok(Some(name.to_string()))
+}
+
+/// Reads an array of users or groups, each given either by name or by ID, as
+/// their IDs. Names are looked up right away, so that one which doesn't exist
+/// is caught before it can lock anyone out.
+fn parse_ids(
+    root: &Value,
+    path: &str,
+    lookup: fn(&str) -> Result<u32, String>,
+) -> Result<Vec<u32>, String> {
+    let entries = match root.get_at_path(path) {
+        Some(entry) => entry.require_array(path)?,
+        None => return Ok(Vec::new()),
+    };
+
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Value::Integer(id) if *id >= 0 && *id <= u32::MAX as i64 => Ok(*id as u32),
+            Value::String(name) => lookup(name).map_err(|error| {
+                format!(
+                    "Could not parse config: '{}' could not be resolved: {}",
+                    path, error
+                )
+            }),
+            _ => Err(format!(
+                "Could not parse config: '{}' must be an array of names and IDs",
+                path
+            )),
+        })
+        .collect()
+}
+
+/// Finds the first of the environment variables that names a directory, for
+/// picking default paths. systemd sets RUNTIME_DIRECTORY and friends when the
+/// unit asks for them, and they can hold several directories separated by
+/// colons, in which case the first one is used.
+fn directory_from_env(variables: &[&str]) -> Option<PathBuf> {
+    variables.iter().find_map(|variable| {
+        let value = std::env::var_os(variable)?;
+        let first = value.to_str()?.split(':').next()?;
+        let path = PathBuf::from(first);
+        if path.is_absolute() {
+            Some(path)
+        } else {
+            None
+        }
+    })
+}
+
+/// Builds the service section of the configuration, which contains the
+/// following options:
+///
+/// - instance_name, which names this instance of shuffled in the default paths
+///   (default shuffled). Each station is named <instance_name>-<station>.
+///
+/// - playlist_dir, which is the directory containing the .m3u playlist files
+///
+/// - directory_playlists, which turns each directory in the content root into
+///   a playlist of every audio file beneath it, named after the directory
+///   (default false)
+///
+/// - content_root, which is the directory whose subdirectories are read as
+///   playlists when directory_playlists is on (default playlist_dir)
+///
 /// - ipc_socket, which is a path where shuffled will a Unix domain socket used
-///   for sending IPC requests
+///   for sending IPC requests (default <instance_name>.socket in
+///   $RUNTIME_DIRECTORY, $XDG_RUNTIME_DIR or /tmp, whichever is set first)
+///
+/// - auto_reload, which reloads the playlists whenever a .m3u8 file in
+///   playlist_dir is added, removed or changed (default false)
+///
+/// - auto_reload_delay_sec, which is how many seconds the playlist directory
+///   has to be left alone before an automatic reload, so that a file being
+///   saved in pieces is only read once it's done (default 2)
+///
+/// - tasks, which is an array of the services (watchdog/weather/clock/monitor)
+///   run by shuffled
+///
+/// - library_dir, which is the directory holding the music files. Reloads are
+///   refused while it is missing, empty or not responding (default none, no
+///   check)
+///
+/// - reload_max_removed_pct, which is the largest percentage of the tracks in
+///   the playlists that a reload can remove unless it is forced (default 50,
+///   100 turns the check off)
+///
+/// - reload_snapshots, which is how many of the playlist states from before
+///   each reload are kept so that they can be rolled back to (default 5)
+///
+/// - track_history, which is how many of the tracks returned by next-track
+///   are remembered for previous-track and get-history (default 10)
+///
+/// - history_file, which is a file that a line of JSON is added to for every
+///   track returned by next-track (default none)
+///
+/// - tcp_listen, which is a host:port to also accept IPC requests on over TCP
+///   (default none, only the Unix socket is used)
+///
+/// - auth_token, which is a secret that every IPC request has to include as
+///   its auth, over the Unix socket and TCP alike (default none, anyone who
+///   can connect can send any command)
+///
+/// - admin_users and admin_groups, which are arrays of the users and groups
+///   (by name or ID) that can send the commands that change what's played or
+///   control shuffled, going by the credentials of the client on the Unix
+///   socket. Root and the user shuffled runs as always can, and TCP clients
+///   never can (default empty, anyone who can connect can send any command)
+///
+/// - protocol_version, which is the shape that IPC responses take unless a
+///   connection asks for another with set-protocol: 1 for the original
+///   responses, 2 to add each track's kind and the meta, or 3 to also add
+///   each track's tags and duration (default 2)
 ///
-/// - tasks, which is an array of the services (watchdog/weather/clock) run by
-///   shuffled
-fn parse_service_section(root: &Value) -> Result<ServiceConfig, String> {
+/// - state_file, which is where the order and position of each playlist, and
+///   the play counts of the tracks, are saved and restored from at startup (default none, playlists are shuffled
+///   afresh on every start)
+///
+/// - state_save_sec, which is how many seconds apart the state file is saved
+///   (default 60)
+///
+/// - stats_file, which is a CSV file that the tracks served, breaks played,
+///   reloads, restarts and uptime of each day are kept in (default none)
+///
+/// - pid_file, which is where the PID of shuffled is written while it's
+///   running. shuffled refuses to start if the file names a process that is
+///   still running (default none)
+///
+/// - log_file, which is the file that the log is appended to when shuffled
+///   is started with --daemon or log_output is "file" (default none, the log
+///   is thrown away with --daemon)
+///
+/// - log_level, which is the most detailed level of message that is logged,
+///   one of "error", "warning", "info" or "debug" (default "info")
+///
+/// - log_levels, which is a table of components (like server, weather or
+///   watchdog) and the level to log each of them at instead of log_level
+///
+/// - log_output, which is "stderr" to write the log to standard error,
+///   "journald" to send it to the systemd journal, or "file" to append it to
+///   log_file (default "stderr")
+///
+/// - log_rotate_mb, which is how large log_file can get before it is renamed
+///   to log_file.1 and a new one is started, when log_output is "file"
+///   (default 10, 0 never rotates it)
+///
+/// - log_keep, which is how many rotated log files are kept (default 5)
+///
+/// - user and group, which are the user and group that shuffled switches to
+///   once it has bound its sockets, when it is started as root. The group
+///   defaults to the user's own group (default none, shuffled keeps running as
+///   whoever started it)
+///
+/// - shuffle, which is either "uniform" to make every order of a playlist
+///   equally likely, or "weighted" to favor tracks that have been played less
+///   often and less recently (default "uniform")
+///
+/// - shuffle_seed, which is a number that the shuffles are seeded from, so
+///   that each playlist is shuffled into the same order every time shuffled
+///   starts with the same playlists (default none, the shuffles are seeded at
+///   random)
+fn parse_service_section(root: &Value, station: Option<&str>) -> Result<ServiceConfig, String> {
+    let instance_name = if let Some(entry) = root.get_at_path("service.instance_name") {
+        let name = entry.require_str("service.instance_name")?;
+        if name.is_empty() || name.contains('/') {
+            return Err(
+                "Could not parse config: 'service.instance_name' must be a non-empty name without slashes"
+                    .to_string(),
+            );
+        }
+
+        name.to_string()
+    } else {
+        "shuffled".to_string()
+    };
+
+    // Each station is an instance of its own, so that the defaults named
+    // after the instance don't collide
+    let instance_name = match station {
+        Some(station) => format!("{}-{}", instance_name, station),
+        None => instance_name,
+    };
+
     let playlist_dir = root
         .require_at_path("service.playlist_dir")
         .and_then(|p| p.require_pathbuf("service.playlist_dir"))?;
 
-    let ipc_socket = root
-        .require_at_path("service.ipc_socket")
-        .and_then(|p| p.require_pathbuf("service.ipc_socket"))?;
+    let directory_playlists = match root.get_at_path("service.directory_playlists") {
+        Some(entry) => entry.require_bool("service.directory_playlists")?,
+        None => false,
+    };
+
+    let content_root = match root.get_at_path("service.content_root") {
+        Some(_) if !directory_playlists => {
+            return Err(
+                "Could not parse config: 'service.content_root' is only used with 'service.directory_playlists'"
+                    .to_string(),
+            )
+        }
+        Some(entry) => Some(entry.require_pathbuf("service.content_root")?),
+        None if directory_playlists => Some(playlist_dir.clone()),
+        None => None,
+    };
+
+    let ipc_socket = if let Some(entry) = root.get_at_path("service.ipc_socket") {
+        entry.require_pathbuf("service.ipc_socket")?
+    } else {
+        directory_from_env(&["RUNTIME_DIRECTORY", "XDG_RUNTIME_DIR"])
+            .unwrap_or_else(|| PathBuf::from("/tmp"))
+            .join(format!("{}.socket", instance_name))
+    };
+
+    let auto_reload = match root.get_at_path("service.auto_reload") {
+        Some(entry) => entry.require_bool("service.auto_reload")?,
+        None => false,
+    };
+
+    let auto_reload = match root.get_at_path("service.auto_reload_delay_sec") {
+        Some(_) if !auto_reload => {
+            return Err(
+                "Could not parse config: 'service.auto_reload_delay_sec' is only used with 'service.auto_reload'"
+                    .to_string(),
+            )
+        }
+        Some(entry) => {
+            let seconds = entry.require_int("service.auto_reload_delay_sec")?;
+            if seconds < 0 {
+                return Err(
+                    "Could not parse config: 'service.auto_reload_delay_sec' must be non-negative"
+                        .to_string(),
+                );
+            }
+
+            Some(seconds as u32)
+        }
+        None if auto_reload => Some(2),
+        None => None,
+    };
 
     let tasks = root
         .require_at_path("service.tasks")
@@ -192,6 +1521,7 @@ fn parse_service_section(root: &Value) -> Result<ServiceConfig, String> {
     let mut watchdog_enabled = false;
     let mut weather_enabled = false;
     let mut clock_enabled = false;
+    let mut monitor_enabled = false;
 
     for task in tasks {
         let task_name = task.require_str("service.tasks.*")?;
@@ -200,6 +1530,7 @@ fn parse_service_section(root: &Value) -> Result<ServiceConfig, String> {
             "watchdog" => watchdog_enabled = true,
             "weather" => weather_enabled = true,
             "clock" => clock_enabled = true,
+            "monitor" => monitor_enabled = true,
             _ => {
                 return Err(format!(
                     "Could not parse config: '{}' not valid task",
@@ -209,30 +1540,446 @@ fn parse_service_section(root: &Value) -> Result<ServiceConfig, String> {
         }
     }
 
+    let library_dir = match root.get_at_path("service.library_dir") {
+        Some(entry) => Some(entry.require_pathbuf("service.library_dir")?),
+        None => None,
+    };
+
+    let reload_max_removed = match root.get_at_path("service.reload_max_removed_pct") {
+        Some(entry) => {
+            let percent = entry.require_int("service.reload_max_removed_pct")?;
+            if !(0..=100).contains(&percent) {
+                return Err(
+                    "Could not parse config: 'service.reload_max_removed_pct' must be between 0 and 100"
+                        .to_string(),
+                );
+            }
+
+            percent as u32
+        }
+        None => 50,
+    };
+
+    let reload_snapshots = match root.get_at_path("service.reload_snapshots") {
+        Some(entry) => {
+            let count = entry.require_int("service.reload_snapshots")?;
+            if count < 0 {
+                return Err(
+                    "Could not parse config: 'service.reload_snapshots' must be non-negative"
+                        .to_string(),
+                );
+            }
+
+            count as usize
+        }
+        None => 5,
+    };
+
+    let track_history = match root.get_at_path("service.track_history") {
+        Some(entry) => {
+            let count = entry.require_int("service.track_history")?;
+            if count < 0 {
+                return Err(
+                    "Could not parse config: 'service.track_history' must be non-negative"
+                        .to_string(),
+                );
+            }
+
+            count as usize
+        }
+        None => 10,
+    };
+
+    let history_file = match root.get_at_path("service.history_file") {
+        Some(entry) => Some(entry.require_pathbuf("service.history_file")?),
+        None => None,
+    };
+
+    let tcp_listen = match root.get_at_path("service.tcp_listen") {
+        Some(entry) => {
+            let address = entry.require_str("service.tcp_listen")?;
+            let resolved = address
+                .to_socket_addrs()
+                .map_err(|_| {
+                    format!(
+                        "Could not parse config: 'service.tcp_listen' must be a host:port, not '{}'",
+                        address
+                    )
+                })?
+                .next()
+                .ok_or_else(|| {
+                    "Could not parse config: 'service.tcp_listen' did not resolve to any addresses"
+                        .to_string()
+                })?;
+
+            Some(resolved)
+        }
+        None => None,
+    };
+
+    let auth_token = get_secret(root, "service.auth_token")?;
+    if auth_token.as_deref() == Some("") {
+        return Err("Could not parse config: 'service.auth_token' must not be empty".to_string());
+    }
+
+    let admin_users = parse_ids(root, "service.admin_users", privileges::user_id)?;
+    let admin_groups = parse_ids(root, "service.admin_groups", privileges::group_id)?;
+
+    let protocol_version = match root.get_at_path("service.protocol_version") {
+        Some(entry) => {
+            let version = entry.require_int("service.protocol_version")?;
+            ProtocolVersion::from_number(version).ok_or(
+                "Could not parse config: 'service.protocol_version' must be 1, 2 or 3".to_string(),
+            )?
+        }
+        None => ProtocolVersion::Meta,
+    };
+
+    let state_file = match root.get_at_path("service.state_file") {
+        Some(entry) => Some(entry.require_pathbuf("service.state_file")?),
+        None => None,
+    };
+
+    let state_save_interval = match root.get_at_path("service.state_save_sec") {
+        Some(entry) => {
+            let seconds = entry.require_int("service.state_save_sec")?;
+            if seconds <= 0 {
+                return Err(
+                    "Could not parse config: 'service.state_save_sec' must be positive".to_string(),
+                );
+            }
+
+            seconds as u32
+        }
+        None => 60,
+    };
+
+    let stats_file = match root.get_at_path("service.stats_file") {
+        Some(entry) => Some(entry.require_pathbuf("service.stats_file")?),
+        None => None,
+    };
+
+    let pid_file = match root.get_at_path("service.pid_file") {
+        Some(entry) => Some(entry.require_pathbuf("service.pid_file")?),
+        None => None,
+    };
+
+    let log_file = match root.get_at_path("service.log_file") {
+        Some(entry) => Some(entry.require_pathbuf("service.log_file")?),
+        None => None,
+    };
+
+    let log = parse_log(root, log_file.as_deref())?;
+    let user = parse_account(root, "service.user")?;
+    let group = parse_account(root, "service.group")?;
+
+    let shuffle = match root.get_at_path("service.shuffle") {
+        Some(entry) => match entry.require_str("service.shuffle")? {
+            "uniform" => ShuffleMode::Uniform,
+            "weighted" => ShuffleMode::Weighted,
+            other => {
+                return Err(format!(
+                    "Could not parse config: 'service.shuffle' must be 'uniform' or 'weighted', not '{}'",
+                    other
+                ))
+            }
+        },
+        None => ShuffleMode::Uniform,
+    };
+
+    let smart_playlists = parse_smart_playlists(root)?;
+
+    let shuffle_seed = match root.get_at_path("service.shuffle_seed") {
+        Some(entry) => Some(entry.require_int("service.shuffle_seed")? as u64),
+        None => None,
+    };
+
     Ok(ServiceConfig {
+        instance_name,
         playlist_dir,
+        content_root,
         ipc_socket,
+        auto_reload,
         watchdog_enabled,
         weather_enabled,
         clock_enabled,
+        monitor_enabled,
+        library_dir,
+        reload_max_removed,
+        reload_snapshots,
+        track_history,
+        history_file,
+        tcp_listen,
+        auth_token,
+        admin_users,
+        admin_groups,
+        protocol_version,
+        state_file,
+        state_save_interval,
+        stats_file,
+        pid_file,
+        log_file,
+        log,
+        user,
+        group,
+        shuffle,
+        smart_playlists,
+        shuffle_seed,
+    })
+}
+
+fn parse_log_level(entry: &Value, path: &str) -> Result<LogLevel, String> {
+    match entry.require_str(path)? {
+        "error" => Ok(LogLevel::Error),
+        "warning" => Ok(LogLevel::Warning),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        other => Err(format!(
+            "Could not parse config: '{}' must be 'error', 'warning', 'info' or 'debug', not '{}'",
+            path, other
+        )),
+    }
+}
+
+/// Reads the log options of the service section, which are described along
+/// with the rest of it
+fn parse_log(root: &Value, log_file: Option<&Path>) -> Result<LogConfig, String> {
+    let level = match root.get_at_path("service.log_level") {
+        Some(entry) => parse_log_level(entry, "service.log_level")?,
+        None => LogLevel::Info,
+    };
+
+    let mut components = HashMap::new();
+    if let Some(entry) = root.get_at_path("service.log_levels") {
+        for (name, level) in entry.require_table("service.log_levels")? {
+            let path = format!("service.log_levels.{}", name);
+            if !logging::COMPONENTS.contains(&name.as_str()) {
+                return Err(format!(
+                    "Could not parse config: '{}' is not a component, which are {}",
+                    path,
+                    logging::COMPONENTS.join(", ")
+                ));
+            }
+
+            components.insert(name.to_string(), parse_log_level(level, &path)?);
+        }
+    }
+
+    let output = match root.get_at_path("service.log_output") {
+        Some(entry) => match entry.require_str("service.log_output")? {
+            "stderr" => LogOutput::Stderr,
+            "journald" => LogOutput::Journald,
+            "file" => LogOutput::File,
+            other => {
+                return Err(format!(
+                    "Could not parse config: 'service.log_output' must be 'stderr', 'journald' or 'file', not '{}'",
+                    other
+                ))
+            }
+        },
+        None => LogOutput::Stderr,
+    };
+
+    if output == LogOutput::File && log_file.is_none() {
+        return Err(
+            "Could not parse config: 'service.log_output' is 'file', but 'service.log_file' is not set"
+                .to_string(),
+        );
+    }
+
+    let require_count = |path: &str, default: u32| match root.get_at_path(path) {
+        Some(entry) => entry.require_int(path).and_then(|i| {
+            if i >= 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err(format!(
+                    "Could not parse config: '{}' must not be negative",
+                    path
+                ))
+            }
+        }),
+        None => Ok(default),
+    };
+
+    let rotate_size = require_count("service.log_rotate_mb", 10)?;
+    let rotate_keep = require_count("service.log_keep", 5)?;
+
+    Ok(LogConfig {
+        level,
+        components,
+        output,
+        rotate_size,
+        rotate_keep,
     })
 }
 
+/// Builds the smart playlists section of the configuration, which is a table
+/// of playlist names. Each playlist has at least one of the following options,
+/// and is made up of the tracks on the playlist files which match all of them:
+///
+/// - genre: The ID3 genres that the tracks can have
+///
+/// - artist: The artists that the tracks can be by
+///
+/// - year_min: The earliest year that the tracks can be from
+///
+/// - year_max: The latest year that the tracks can be from
+///
+/// - added_within_days: How many days ago a reload can have added the tracks
+///   at most. Tracks count as added when a reload puts them on a playlist and
+///   they weren't on any before, so every track that is there at the first
+///   start counts as old. Keep a state file for this to carry over restarts.
+fn parse_smart_playlists(root: &Value) -> Result<HashMap<String, SmartPlaylist>, String> {
+    let mut smart_playlists = HashMap::new();
+    let entries = match root.get_at_path("playlist") {
+        Some(entry) => entry.require_table("playlist")?,
+        None => return Ok(smart_playlists),
+    };
+
+    for (name, query) in entries {
+        let path = format!("playlist.{}", name);
+        query.require_table(&path)?;
+
+        let genres = match query.get_at_path("genre") {
+            Some(entry) => entry.require_str_array(&format!("{}.genre", path))?,
+            None => Vec::new(),
+        };
+
+        let artists = match query.get_at_path("artist") {
+            Some(entry) => entry.require_str_array(&format!("{}.artist", path))?,
+            None => Vec::new(),
+        };
+
+        let parse_year = |key: &str| match query.get_at_path(key) {
+            Some(entry) => {
+                let key_path = format!("{}.{}", path, key);
+                let year = entry.require_int(&key_path)?;
+                if year <= 0 || year > u16::MAX as i64 {
+                    return Err(format!(
+                        "Could not parse config: '{}' must be a year",
+                        key_path
+                    ));
+                }
+
+                Ok(Some(year as u16))
+            }
+            None => Ok(None),
+        };
+
+        let year_min = parse_year("year_min")?;
+        let year_max = parse_year("year_max")?;
+
+        let added_within_days = match query.get_at_path("added_within_days") {
+            Some(entry) => {
+                let key_path = format!("{}.added_within_days", path);
+                let days = entry.require_int(&key_path)?;
+                if days <= 0 || days >= u32::MAX as i64 {
+                    return Err(format!(
+                        "Could not parse config: '{}' must be positive",
+                        key_path
+                    ));
+                }
+
+                Some(days as u32)
+            }
+            None => None,
+        };
+
+        // Without any criteria every track would match, which is more likely
+        // to be a misspelled key than what was meant
+        if genres.is_empty()
+            && artists.is_empty()
+            && year_min.is_none()
+            && year_max.is_none()
+            && added_within_days.is_none()
+        {
+            return Err(format!(
+                "Could not parse config: '{}' must have at least one of genre, artist, year_min, year_max or added_within_days",
+                path
+            ));
+        }
+
+        smart_playlists.insert(
+            name.to_string(),
+            SmartPlaylist {
+                genres,
+                artists,
+                year_min,
+                year_max,
+                added_within_days,
+            },
+        );
+    }
+
+    Ok(smart_playlists)
+}
+
 /// Builds the special service section of the configuration, which contains the
 /// following options:
 ///
 /// - working_dir: Reports the path used by the weather and clock processes for
-///   generating audio (default /tmp)
+///   generating audio (default $CACHE_DIRECTORY, $STATE_DIRECTORY,
+///   $RUNTIME_DIRECTORY, $XDG_RUNTIME_DIR or /tmp, whichever is set first)
+///
+/// - file_prefix: Added to the start of the names of the files in the working
+///   directory (default is the instance name)
 ///
 /// - interval_min: How many minutes to wait between playing the weather/clock
 ///   files (default 30)
-fn parse_special_base(root: &Value) -> Result<SpecialBaseConfig, String> {
+///
+/// - missed: What to do when the interval has elapsed several times since the
+///   last weather/clock entry: 'one' plays a single entry, 'all' plays one per
+///   missed interval and 'skip' plays none of them (default one)
+///
+/// - locale: The language code (en/de/fr/es) to speak announcements in
+///   (default en)
+///
+/// - espeak, sox, lame: The paths to the programs used to generate the audio
+///   (default is to look them up on the PATH)
+///
+/// - loudness_lufs: The integrated loudness that announcements are adjusted
+///   to, such as -16 to match most streams (default none, announcements are
+///   left at espeak's volume)
+///
+/// - sample_rate_hz, channels, bitrate_kbps: The format of the generated MP3s,
+///   which should match the stream (default 44100 Hz stereo at lame's default
+///   bitrate)
+fn parse_special_base(
+    root: &Value,
+    service: &ServiceConfig,
+    time_scale: u32,
+) -> Result<SpecialBaseConfig, String> {
+    let default_prefix = service.instance_name.clone();
+    let default_working_dir = directory_from_env(&[
+        "CACHE_DIRECTORY",
+        "STATE_DIRECTORY",
+        "RUNTIME_DIRECTORY",
+        "XDG_RUNTIME_DIR",
+    ])
+    .unwrap_or_else(|| PathBuf::from("/tmp"));
+
+    let tools = AnnouncerTools {
+        espeak: parse_tool(root, "special.espeak", "espeak")?,
+        sox: parse_tool(root, "special.sox", "sox")?,
+        lame: parse_tool(root, "special.lame", "lame")?,
+        loudness_lufs: parse_loudness(root)?,
+        sample_rate: parse_choice(root, "special.sample_rate_hz", MP3_SAMPLE_RATES)?
+            .unwrap_or(44100),
+        channels: parse_choice(root, "special.channels", &[1, 2])?.unwrap_or(2),
+        bitrate: parse_choice(root, "special.bitrate_kbps", MP3_BITRATES)?,
+    };
+
     match root.get_at_path("special") {
         Some(special) => special.require_table("special")?,
         None => {
             return Ok(SpecialBaseConfig {
-                working_dir: PathBuf::from("/tmp"),
+                working_dir: default_working_dir,
+                file_prefix: default_prefix,
                 interval: 30,
+                missed: MissedSpecials::One,
+                locale: Locale::English,
+                tools,
+                time_scale,
             })
         }
     };
@@ -240,7 +1987,13 @@ fn parse_special_base(root: &Value) -> Result<SpecialBaseConfig, String> {
     let working_dir = if let Some(entry) = root.get_at_path("special.working_dir") {
         entry.require_pathbuf("special.working_dir")?
     } else {
-        PathBuf::from("/tmp")
+        default_working_dir
+    };
+
+    let file_prefix = if let Some(entry) = root.get_at_path("special.file_prefix") {
+        entry.require_str("special.file_prefix")?.to_string()
+    } else {
+        default_prefix
     };
 
     let interval = if let Some(entry) = root.get_at_path("special.interval_min") {
@@ -255,9 +2008,42 @@ fn parse_special_base(root: &Value) -> Result<SpecialBaseConfig, String> {
         30
     };
 
+    let missed = if let Some(entry) = root.get_at_path("special.missed") {
+        match entry.require_str("special.missed")? {
+            "one" => MissedSpecials::One,
+            "all" => MissedSpecials::All,
+            "skip" => MissedSpecials::Skip,
+            other => {
+                return Err(format!(
+                "Could not parse config: 'special.missed' must be 'one', 'all' or 'skip', not '{}'",
+                other
+            ))
+            }
+        }
+    } else {
+        MissedSpecials::One
+    };
+
+    let locale = if let Some(entry) = root.get_at_path("special.locale") {
+        let code = entry.require_str("special.locale")?;
+        Locale::from_code(code).ok_or_else(|| {
+            format!(
+                "Could not parse config: 'special.locale' has no translations for '{}'",
+                code
+            )
+        })?
+    } else {
+        Locale::English
+    };
+
     Ok(SpecialBaseConfig {
         working_dir,
+        file_prefix,
         interval,
+        missed,
+        locale,
+        tools,
+        time_scale,
     })
 }
 
@@ -272,7 +2058,40 @@ fn parse_special_base(root: &Value) -> Result<SpecialBaseConfig, String> {
 ///
 /// - url: The URL where the stream is mounted on the Icecast server, this is
 ///   is probed every interval
-fn parse_watchdog(root: &Value) -> Result<WatchdogConfig, String> {
+///
+/// - dead_air_min: How many minutes can pass without any next-track requests
+///   while the stream is up before raising an alert (default disabled)
+///
+/// - self_check: Whether to send a status request to the IPC socket on every
+///   probe, raising an alert if the server doesn't respond (default false)
+///
+/// - action: Either "restart" to restart the service when the stream is down,
+///   or "alert" to only raise an alert (default "restart")
+///
+/// - content_type: The Content-Type the stream must be served with, ignoring
+///   any parameters after it, for the probe to pass (default any)
+///
+/// - min_body_bytes: How many bytes of the stream the probe must read for it
+///   to pass (default 1)
+///
+/// - offset_sec: Runs the probes on a fixed schedule, this many seconds after
+///   each multiple of interval_min on the clock, instead of interval_min after
+///   the last probe finished. Must be less than the interval (default none)
+///
+/// - jitter_sec: The most seconds that each probe is delayed by at random,
+///   which must be less than the interval (default 0)
+///
+/// - restart_grace_sec: How many seconds to wait after the service is
+///   restarted before probing it again, giving ezstream time to reconnect.
+///   This covers restarts by the watchdog and ones announced over the
+///   restarting command (default 120)
+///
+/// - systemctl: The path to systemctl (default is to look it up on the PATH)
+fn parse_watchdog(
+    root: &Value,
+    service_config: &ServiceConfig,
+    time_scale: u32,
+) -> Result<WatchdogConfig, String> {
     let interval = if let Some(entry) = root.get_at_path("watchdog.interval_min") {
         entry.require_int("watchdog.interval_min").and_then(|i| {
             if i > 0 && i < (u32::MAX as i64) {
@@ -313,26 +2132,257 @@ fn parse_watchdog(root: &Value) -> Result<WatchdogConfig, String> {
         );
     }
 
-    Ok(WatchdogConfig {
-        interval,
-        service: service.to_string(),
-        addr: addr[0],
-        path: stream_endpoint.path().to_string(),
-    })
+    let dead_air = if let Some(entry) = root.get_at_path("watchdog.dead_air_min") {
+        Some(entry.require_int("watchdog.dead_air_min").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'watchdog.dead_air_min' must be positive".to_string())
+            }
+        })?)
+    } else {
+        None
+    };
+
+    let self_check = if let Some(entry) = root.get_at_path("watchdog.self_check") {
+        entry.require_bool("watchdog.self_check")?
+    } else {
+        false
+    };
+
+    let self_check = if self_check {
+        Some(service_config.ipc_socket.clone())
+    } else {
+        None
+    };
+
+    let action = if let Some(entry) = root.get_at_path("watchdog.action") {
+        match entry.require_str("watchdog.action")? {
+            "restart" => WatchdogAction::Restart,
+            "alert" => WatchdogAction::Alert,
+            other => {
+                return Err(format!(
+                "Could not parse config: 'watchdog.action' must be 'restart' or 'alert', not '{}'",
+                other
+            ))
+            }
+        }
+    } else {
+        WatchdogAction::Restart
+    };
+
+    let content_type = if let Some(entry) = root.get_at_path("watchdog.content_type") {
+        let content_type = entry.require_str("watchdog.content_type")?.trim();
+        if content_type.is_empty() {
+            return Err(
+                "Could not parse config: 'watchdog.content_type' must not be empty".to_string(),
+            );
+        }
+
+        Some(content_type.to_ascii_lowercase())
+    } else {
+        None
+    };
+
+    let min_body_bytes = if let Some(entry) = root.get_at_path("watchdog.min_body_bytes") {
+        entry.require_int("watchdog.min_body_bytes").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as usize)
+            } else {
+                Err(
+                    "Could not parse config: 'watchdog.min_body_bytes' must be positive"
+                        .to_string(),
+                )
+            }
+        })?
+    } else {
+        1
+    };
+
+    let interval_sec = interval as i64 * 60;
+    let offset = if let Some(entry) = root.get_at_path("watchdog.offset_sec") {
+        Some(entry.require_int("watchdog.offset_sec").and_then(|i| {
+            if i >= 0 && i < interval_sec {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'watchdog.offset_sec' must be non-negative and less than the interval".to_string())
+            }
+        })?)
+    } else {
+        None
+    };
+
+    let jitter = if let Some(entry) = root.get_at_path("watchdog.jitter_sec") {
+        entry.require_int("watchdog.jitter_sec").and_then(|i| {
+            if i >= 0 && i < interval_sec {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'watchdog.jitter_sec' must be non-negative and less than the interval".to_string())
+            }
+        })?
+    } else {
+        0
+    };
+
+    let restart_grace = if let Some(entry) = root.get_at_path("watchdog.restart_grace_sec") {
+        entry
+            .require_int("watchdog.restart_grace_sec")
+            .and_then(|i| {
+                if i >= 0 && i < (u32::MAX as i64) {
+                    Ok(i as u32)
+                } else {
+                    Err(
+                        "Could not parse config: 'watchdog.restart_grace_sec' must be non-negative"
+                            .to_string(),
+                    )
+                }
+            })?
+    } else {
+        120
+    };
+
+    Ok(WatchdogConfig {
+        interval,
+        service: service.to_string(),
+        addr: addr[0],
+        path: stream_endpoint.path().to_string(),
+        url: stream_endpoint,
+        dead_air,
+        self_check,
+        auth_token: service_config.auth_token.clone(),
+        action,
+        content_type,
+        min_body_bytes,
+        offset,
+        jitter,
+        restart_grace,
+        systemctl: parse_tool(root, "watchdog.systemctl", "systemctl")?,
+        time_scale,
+    })
+}
+
+/// Builds the HTTP section of the configuration, which contains the following
+/// options:
+///
+/// - timeout_sec: How many seconds to wait for a request to complete before
+///   giving up on it (default 30)
+///
+/// - proxy: An HTTP, HTTPS or SOCKS URL to send all requests through (default
+///   none). Since it can hold a password, it can be read from the file in
+///   proxy_file instead.
+///
+/// - user_agent: What to send as the User-Agent header. weather.gov asks that
+///   this include a way to contact you (default "shuffled")
+fn parse_http(root: &Value) -> Result<HttpConfig, String> {
+    let timeout = if let Some(entry) = root.get_at_path("http.timeout_sec") {
+        entry.require_int("http.timeout_sec").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'http.timeout_sec' must be positive".to_string())
+            }
+        })?
+    } else {
+        30
+    };
+
+    let proxy = match get_secret(root, "http.proxy")? {
+        Some(url) => {
+            Url::parse(&url).or(Err(
+                "Could not parse config: 'http.proxy' was not a valid URL".to_string(),
+            ))?;
+            Some(url)
+        }
+        None => None,
+    };
+
+    let user_agent = match root.get_at_path("http.user_agent") {
+        Some(entry) => entry.require_str("http.user_agent")?.to_string(),
+        None => "shuffled".to_string(),
+    };
+
+    Ok(HttpConfig {
+        timeout,
+        proxy,
+        user_agent,
+    })
+}
+
+/// Builds the alerts section of the configuration, which contains the
+/// following options:
+///
+/// - command: A program to run for every alert. It receives the name of the
+///   module raising the alert and the alert message as arguments.
+///
+/// - webhook: An HTTP or HTTPS URL which receives a POST with a JSON document
+///   for every alert. This is a secret, so it can also be read from the file
+///   named by webhook_file.
+fn parse_alerts(root: &Value) -> Result<AlertConfig, String> {
+    let command = match root.get_at_path("alerts.command") {
+        Some(entry) => Some(entry.require_pathbuf("alerts.command")?),
+        None => None,
+    };
+
+    let webhook = match get_secret(root, "alerts.webhook")? {
+        Some(url) => {
+            let parsed = Url::parse(&url).or(Err(
+                "Could not parse config: 'alerts.webhook' was not a valid URL".to_string(),
+            ))?;
+
+            if parsed.scheme() != "http" && parsed.scheme() != "https" {
+                return Err(
+                    "Could not parse config: 'alerts.webhook' must be an HTTP or HTTPS URL"
+                        .to_string(),
+                );
+            }
+
+            Some(url)
+        }
+        None => None,
+    };
+
+    Ok(AlertConfig { command, webhook })
 }
 
 /// Builds the weather service section of the configuration, which contains the
 /// following options:
 ///
 /// - region: The weather.gov grid ID and coordinates of the region to request
-///   a forecast for (default RAH/57,62)
+///   a forecast for, in the form CWA/X,Y (default RAH/57,62)
 ///
 /// - duration_hr: How many hours to create a forecast summary for on each run
 ///   (default 12)
 ///
 /// - interval_hr: How many hours to wait between fetching a forecast. Note that
 ///   this only controls the delay after a successful request; failed requests
-///   trigger a retry after every hour until a success (default 8)
+///   trigger a retry after every hour until a success, or after however long
+///   the API asks for when it is rate limiting requests (default 8)
+///
+/// - template: How to arrange the report, with {high} and {low} replaced by
+///   the temperatures and {periods} by the forecast text (default depends on
+///   special.locale)
+///
+/// - substitutions: A table of words in the forecast text and what to read
+///   them as instead, added to the built-in list of abbreviations (default
+///   empty)
+///
+/// - period_names: Whether each period is introduced by its name in the
+///   forecast, like "Tonight" or "Thursday", instead of the hour that it
+///   starts (default true)
+///
+/// - max_length_sec: The longest that the spoken report can be, with later
+///   periods left out of the report to make it fit (default none)
+///
+/// - max_length_pct: The longest that the spoken report can be, as a
+///   percentage of special.interval_min (default none)
+///
+/// - max_age_hr: How many hours after weather.gov issued the forecast it is
+///   still read, which matters when the API has been failing for a while
+///   (default none, no limit)
+///
+/// - stale: Either "skip" to pass over the weather once the forecast is too
+///   old, or "announce" to say that the weather is unavailable instead
+///   (default "skip")
 fn parse_weather(root: &Value) -> Result<SpecialWeatherConfig, String> {
     let region = if let Some(region) = root.get_at_path("weather.region") {
         region.require_str("weather.region")?
@@ -340,6 +2390,28 @@ fn parse_weather(root: &Value) -> Result<SpecialWeatherConfig, String> {
         "RAH/57,62"
     };
 
+    // Typos that can't be a grid ID at all are caught without having to ask
+    // weather.gov about them
+    let valid_region = match region.split_once('/') {
+        Some((office, coords)) => {
+            let valid_office = office.len() == 3 && office.chars().all(|c| c.is_ascii_alphabetic());
+            let valid_coords = match coords.split_once(',') {
+                Some((x, y)) => x.parse::<u32>().is_ok() && y.parse::<u32>().is_ok(),
+                None => false,
+            };
+
+            valid_office && valid_coords
+        }
+        None => false,
+    };
+
+    if !valid_region {
+        return Err(format!(
+            "Could not parse config: 'weather.region' must be in the form CWA/X,Y, like RAH/57,62, not '{}'",
+            region
+        ));
+    }
+
     let duration = if let Some(duration) = root.get_at_path("weather.duration_hr") {
         duration.require_int("weather.duration_hr").and_then(|d| {
             if d > 0 && d < (u32::MAX as i64) {
@@ -364,46 +2436,1464 @@ fn parse_weather(root: &Value) -> Result<SpecialWeatherConfig, String> {
         8
     };
 
+    let template = if let Some(template) = root.get_at_path("weather.template") {
+        Some(template.require_str("weather.template")?.to_string())
+    } else {
+        None
+    };
+
+    let mut substitutions = HashMap::new();
+    if let Some(table) = root.get_at_path("weather.substitutions") {
+        for (word, replacement) in table.require_table("weather.substitutions")? {
+            let context = format!("weather.substitutions.{}", word);
+            substitutions.insert(
+                word.to_string(),
+                replacement.require_str(&context)?.to_string(),
+            );
+        }
+    }
+
+    let period_names = match root.get_at_path("weather.period_names") {
+        Some(entry) => entry.require_bool("weather.period_names")?,
+        None => true,
+    };
+
+    let max_length = match root.get_at_path("weather.max_length_sec") {
+        Some(entry) => {
+            let seconds = entry.require_int("weather.max_length_sec")?;
+            if !(seconds > 0 && seconds < (u32::MAX as i64)) {
+                return Err(
+                    "Could not parse config: 'weather.max_length_sec' must be positive".to_string(),
+                );
+            }
+
+            Some(seconds as u32)
+        }
+        None => None,
+    };
+
+    let max_length_pct = match root.get_at_path("weather.max_length_pct") {
+        Some(entry) => {
+            let percent = entry.require_int("weather.max_length_pct")?;
+            if !(1..=100).contains(&percent) {
+                return Err(
+                    "Could not parse config: 'weather.max_length_pct' must be between 1 and 100"
+                        .to_string(),
+                );
+            }
+
+            Some(percent as u32)
+        }
+        None => None,
+    };
+
+    let max_age = match root.get_at_path("weather.max_age_hr") {
+        Some(entry) => {
+            let hours = entry.require_int("weather.max_age_hr")?;
+            if !(hours > 0 && hours < (u32::MAX as i64)) {
+                return Err(
+                    "Could not parse config: 'weather.max_age_hr' must be positive".to_string(),
+                );
+            }
+
+            Some(hours as u32)
+        }
+        None => None,
+    };
+
+    let stale = match root.get_at_path("weather.stale") {
+        Some(entry) => match entry.require_str("weather.stale")? {
+            "skip" => StaleWeather::Skip,
+            "announce" => StaleWeather::Announce,
+            other => {
+                return Err(format!(
+                "Could not parse config: 'weather.stale' must be 'skip' or 'announce', not '{}'",
+                other
+            ))
+            }
+        },
+        None => StaleWeather::Skip,
+    };
+
     Ok(SpecialWeatherConfig {
         region: region.to_string(),
         duration,
         interval,
+        template,
+        substitutions,
+        period_names,
+        max_length,
+        max_length_pct,
+        max_age,
+        stale,
     })
 }
 
-pub fn parse(stream: &mut impl Read) -> Result<Config, String> {
-    let mut buffer = Vec::new();
-    if let Err(reason) = stream.read_to_end(&mut buffer) {
-        return Err(format!("Could not read config: {}", reason));
+/// Builds a single availability window out of a table containing the
+/// not_before and not_after keys
+fn parse_availability_window(table: &Value, path: &str) -> Result<AvailabilityWindow, String> {
+    let not_before = match table.get_at_path("not_before") {
+        Some(entry) => Some(entry.require_datetime(&format!("{}.not_before", path))?),
+        None => None,
+    };
+
+    let not_after = match table.get_at_path("not_after") {
+        Some(entry) => Some(entry.require_datetime(&format!("{}.not_after", path))?),
+        None => None,
+    };
+
+    if let (Some(start), Some(end)) = (not_before, not_after) {
+        if start > end {
+            return Err(format!(
+                "Could not parse config: '{}.not_before' must come before '{}.not_after'",
+                path, path
+            ));
+        }
     }
 
-    let content = String::from_utf8(buffer)
-        .or_else(|error| Err(format!("Could not load config: {}", error)))?;
+    Ok(AvailabilityWindow {
+        not_before,
+        not_after,
+    })
+}
 
-    let table = &content
-        .parse::<Value>()
-        .or_else(|error| Err(format!("Could not parse config: {}", error)))?;
+/// Builds the availability section of the configuration, which contains the
+/// following options:
+///
+/// - warn_days: How many days before a window closes to start warning about
+///   it when playlists are loaded (default 7)
+///
+/// - playlists: A table of playlist names, each with an optional not_before
+///   and not_after date
+///
+/// - tracks: An array of tables, each with a path to an audio file and an
+///   optional not_before and not_after date
+fn parse_availability(root: &Value) -> Result<AvailabilityConfig, String> {
+    let warn_days = if let Some(entry) = root.get_at_path("availability.warn_days") {
+        entry.require_int("availability.warn_days").and_then(|i| {
+            if i >= 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err(
+                    "Could not parse config: 'availability.warn_days' must not be negative"
+                        .to_string(),
+                )
+            }
+        })?
+    } else {
+        7
+    };
+
+    let mut playlists = HashMap::new();
+    if let Some(entry) = root.get_at_path("availability.playlists") {
+        for (name, window) in entry.require_table("availability.playlists")? {
+            let path = format!("availability.playlists.{}", name);
+            window.require_table(&path)?;
+            playlists.insert(name.to_string(), parse_availability_window(window, &path)?);
+        }
+    }
+
+    let mut tracks = HashMap::new();
+    if let Some(entry) = root.get_at_path("availability.tracks") {
+        for window in entry.require_array("availability.tracks")? {
+            window.require_table("availability.tracks.*")?;
+            let track = window
+                .get_at_path("path")
+                .ok_or_else(|| {
+                    "Could not parse config: 'availability.tracks.*.path' is required".to_string()
+                })
+                .and_then(|p| p.require_pathbuf("availability.tracks.*.path"))?;
+
+            if !track.is_absolute() {
+                return Err(
+                    "Could not parse config: 'availability.tracks.*.path' must be absolute"
+                        .to_string(),
+                );
+            }
+
+            let window = parse_availability_window(window, "availability.tracks.*")?;
+            tracks.insert(track, window);
+        }
+    }
+
+    Ok(AvailabilityConfig {
+        playlists,
+        tracks,
+        warn_days,
+    })
+}
+
+/// Builds the tags section of the configuration, which is a table of tag
+/// names. Each tag contains the following options, all of which are optional:
+///
+/// - playlists: The names of playlists whose tracks all have the tag
+///
+/// - tracks: The absolute paths of tracks which have the tag
+///
+/// - genres: The ID3 genres whose tracks all have the tag
+fn parse_tags(root: &Value) -> Result<HashMap<String, TagDefinition>, String> {
+    let mut tags = HashMap::new();
+    let entries = match root.get_at_path("tags") {
+        Some(entry) => entry.require_table("tags")?,
+        None => return Ok(tags),
+    };
+
+    for (name, tag) in entries {
+        let path = format!("tags.{}", name);
+        tag.require_table(&path)?;
+
+        let playlists = match tag.get_at_path("playlists") {
+            Some(entry) => entry.require_str_array(&format!("{}.playlists", path))?,
+            None => Vec::new(),
+        };
+
+        let tracks = match tag.get_at_path("tracks") {
+            Some(entry) => entry
+                .require_str_array(&format!("{}.tracks", path))?
+                .drain(..)
+                .map(PathBuf::from)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let genres = match tag.get_at_path("genres") {
+            Some(entry) => entry.require_str_array(&format!("{}.genres", path))?,
+            None => Vec::new(),
+        };
+
+        tags.insert(
+            name.to_string(),
+            TagDefinition {
+                playlists,
+                tracks,
+                genres,
+            },
+        );
+    }
+
+    Ok(tags)
+}
+
+/// Builds the rules section of the configuration, which is an array of tables.
+/// Each rule has a type that determines what other options it takes:
+///
+/// - no-repeat-tag: Requires a tag, and prevents two tracks with that tag from
+///   playing back-to-back
+///
+/// - forbid-tag-hours: Requires a tag, a start_hour and an end_hour, and
+///   prevents tracks with that tag from playing between those hours
+///
+/// - album-separation: Requires minutes, and prevents two tracks from the same
+///   album from playing within that many minutes of each other
+fn parse_rules(root: &Value) -> Result<RulesConfig, String> {
+    let tags = parse_tags(root)?;
+    let mut rules = Vec::new();
+
+    let entries = match root.get_at_path("rules") {
+        Some(entry) => entry.require_array("rules")?,
+        None => return Ok(RulesConfig { tags, rules }),
+    };
+
+    for entry in entries {
+        entry.require_table("rules.*")?;
+        let rule_type = entry
+            .get_at_path("type")
+            .ok_or_else(|| "Could not parse config: 'rules.*.type' is required".to_string())
+            .and_then(|t| t.require_str("rules.*.type"))?;
+
+        let require_tag = || {
+            let tag = entry
+                .get_at_path("tag")
+                .ok_or_else(|| "Could not parse config: 'rules.*.tag' is required".to_string())
+                .and_then(|t| t.require_str("rules.*.tag"))?;
+
+            if tags.contains_key(tag) {
+                Ok(tag.to_string())
+            } else {
+                Err(format!(
+                    "Could not parse config: 'rules.*.tag' refers to unknown tag '{}'",
+                    tag
+                ))
+            }
+        };
+
+        let require_bounded = |key: &str, min: i64, max: i64| {
+            let path = format!("rules.*.{}", key);
+            entry
+                .get_at_path(key)
+                .ok_or(format!("Could not parse config: '{}' is required", path))
+                .and_then(|i| i.require_int(&path))
+                .and_then(|i| {
+                    if i >= min && i <= max {
+                        Ok(i as u32)
+                    } else {
+                        Err(format!(
+                            "Could not parse config: '{}' must be between {} and {}",
+                            path, min, max
+                        ))
+                    }
+                })
+        };
+
+        let rule = match rule_type {
+            "no-repeat-tag" => SeparationRule::NoRepeatTag(require_tag()?),
+            "forbid-tag-hours" => SeparationRule::ForbidTagHours(
+                require_tag()?,
+                require_bounded("start_hour", 0, 23)?,
+                require_bounded("end_hour", 0, 23)?,
+            ),
+            "album-separation" => {
+                SeparationRule::AlbumSeparation(require_bounded("minutes", 1, 24 * 60)?)
+            }
+            _ => {
+                return Err(format!(
+                    "Could not parse config: '{}' not valid rule type",
+                    rule_type
+                ))
+            }
+        };
+
+        rules.push(rule);
+    }
+
+    Ok(RulesConfig { tags, rules })
+}
+
+/// Builds the quotas section of the configuration, which is a table of
+/// playlist names. Each playlist has an array of quotas which contain the
+/// following options:
+///
+/// - genre: The ID3 genre that the quota applies to (required)
+///
+/// - min: How many tracks of the genre should be played each hour at minimum
+///
+/// - max: How many tracks of the genre can be played each hour at maximum
+fn parse_quotas(root: &Value) -> Result<QuotaConfig, String> {
+    let mut playlists = HashMap::new();
+    let entries = match root.get_at_path("quotas") {
+        Some(entry) => entry.require_table("quotas")?,
+        None => return Ok(QuotaConfig { playlists }),
+    };
+
+    for (playlist, quota_entries) in entries {
+        let path = format!("quotas.{}", playlist);
+        let mut quotas = Vec::new();
+
+        for quota in quota_entries.require_array(&path)? {
+            quota.require_table(&format!("{}.*", path))?;
+
+            let genre = quota
+                .get_at_path("genre")
+                .ok_or(format!(
+                    "Could not parse config: '{}.*.genre' is required",
+                    path
+                ))
+                .and_then(|g| g.require_str(&format!("{}.*.genre", path)))?;
+
+            let require_count = |key: &str| match quota.get_at_path(key) {
+                Some(entry) => {
+                    let key_path = format!("{}.*.{}", path, key);
+                    entry.require_int(&key_path).and_then(|i| {
+                        if i >= 0 && i < (u32::MAX as i64) {
+                            Ok(Some(i as u32))
+                        } else {
+                            Err(format!(
+                                "Could not parse config: '{}' must not be negative",
+                                key_path
+                            ))
+                        }
+                    })
+                }
+                None => Ok(None),
+            };
+
+            let min = require_count("min")?;
+            let max = require_count("max")?;
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(format!(
+                        "Could not parse config: '{}.*.min' cannot be larger than max",
+                        path
+                    ));
+                }
+            }
+
+            quotas.push(GenreQuota {
+                genre: genre.to_string(),
+                min,
+                max,
+            });
+        }
+
+        playlists.insert(playlist.to_string(), quotas);
+    }
+
+    Ok(QuotaConfig { playlists })
+}
+
+/// Builds the ramp section of the configuration, which contains the following
+/// options:
+///
+/// - plays: How many plays the tracks that a reload adds to a playlist are
+///   spread out over, so that they're all played within that many plays of
+///   the reload. If more tracks are added than that, they're all played
+///   before anything else (default none, they go at the end of the playlist)
+///
+/// - playlists: A table of playlist names, each with the number of plays to
+///   use for that playlist instead, or 0 to put its new tracks at the end
+fn parse_ramp(root: &Value) -> Result<RampConfig, String> {
+    let require_plays = |entry: &Value, path: &str| {
+        entry.require_int(path).and_then(|i| {
+            if i >= 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err(format!(
+                    "Could not parse config: '{}' must not be negative",
+                    path
+                ))
+            }
+        })
+    };
+
+    let plays = match root.get_at_path("ramp.plays") {
+        Some(entry) => match require_plays(entry, "ramp.plays")? {
+            0 => None,
+            plays => Some(plays),
+        },
+        None => None,
+    };
+
+    let mut playlists = HashMap::new();
+    if let Some(entry) = root.get_at_path("ramp.playlists") {
+        for (name, plays) in entry.require_table("ramp.playlists")? {
+            let path = format!("ramp.playlists.{}", name);
+            playlists.insert(name.to_string(), require_plays(plays, &path)?);
+        }
+    }
+
+    Ok(RampConfig { plays, playlists })
+}
+
+/// Reads what happens at the end of a playlist, which is either "repeat",
+/// "reshuffle" or "stop", or a table with the playlist to switch to
+fn parse_playlist_end_action(entry: &Value, path: &str) -> Result<PlaylistEnd, String> {
+    if let Some(table) = entry.as_table() {
+        let target = table
+            .get("switch")
+            .ok_or_else(|| format!("Could not parse config: '{}.switch' is required", path))
+            .and_then(|target| target.require_str(&format!("{}.switch", path)))?;
+
+        if table.len() > 1 {
+            return Err(format!(
+                "Could not parse config: '{}' can only have 'switch'",
+                path
+            ));
+        }
+
+        return Ok(PlaylistEnd::Switch(target.to_string()));
+    }
+
+    match entry.require_str(path)? {
+        "repeat" => Ok(PlaylistEnd::Repeat),
+        "reshuffle" => Ok(PlaylistEnd::Reshuffle),
+        "stop" => Ok(PlaylistEnd::Stop),
+        other => Err(format!(
+            "Could not parse config: '{}' must be 'repeat', 'reshuffle', 'stop' or {{ switch = \"<playlist>\" }}, not '{}'",
+            path, other
+        )),
+    }
+}
+
+/// Builds the playlist_end section of the configuration, which contains the
+/// following options:
+///
+/// - action: What happens once a playlist has played its last track, which is
+///   "repeat" to start over in the same order, "reshuffle" to shuffle it again
+///   first, "stop" to serve nothing until another playlist is switched to, or
+///   { switch = "<playlist>" } to carry on with another playlist (default
+///   "repeat")
+///
+/// - playlists: A table of playlist names, each with the action to use for
+///   that playlist instead
+///
+/// - one_shot: The names of playlists that play each track only once. The
+///   tracks they have played are passed over until the playlist has played
+///   all of them, which is when its action happens, and it starts over
+///   unless it stops (default none)
+fn parse_playlist_end(root: &Value) -> Result<PlaylistEndConfig, String> {
+    let action = match root.get_at_path("playlist_end.action") {
+        Some(entry) => parse_playlist_end_action(entry, "playlist_end.action")?,
+        None => PlaylistEnd::Repeat,
+    };
+
+    let mut playlists = HashMap::new();
+    if let Some(entry) = root.get_at_path("playlist_end.playlists") {
+        for (name, action) in entry.require_table("playlist_end.playlists")? {
+            let path = format!("playlist_end.playlists.{}", name);
+            playlists.insert(name.to_string(), parse_playlist_end_action(action, &path)?);
+        }
+    }
+
+    let one_shot = match root.get_at_path("playlist_end.one_shot") {
+        Some(entry) => entry
+            .require_str_array("playlist_end.one_shot")?
+            .into_iter()
+            .collect(),
+        None => HashSet::new(),
+    };
+
+    Ok(PlaylistEndConfig {
+        action,
+        playlists,
+        one_shot,
+    })
+}
+
+/// Builds the parts section of the configuration, which contains the
+/// following options:
+///
+/// - detect: Whether tracks are found to be parts of one work from their
+///   tags, when they have the same artist and album and titles that end in a
+///   part like "Echoes (Pt. 1)" or "Echoes, Part 1/2", or that name a movement
+///   like "Symphony No. 5: I. Allegro con brio" (default false)
+///
+/// - file: A file that lists works by hand, each as the paths of its tracks
+///   in order with a blank line between works. It's read again on every
+///   reload (default none)
+fn parse_parts(root: &Value) -> Result<PartsConfig, String> {
+    let detect = match root.get_at_path("parts.detect") {
+        Some(entry) => entry.require_bool("parts.detect")?,
+        None => false,
+    };
+
+    let file = match root.get_at_path("parts.file") {
+        Some(entry) => Some(entry.require_pathbuf("parts.file")?),
+        None => None,
+    };
+
+    Ok(PartsConfig { detect, file })
+}
+
+/// Builds the retirement section of the configuration, which contains the
+/// following options:
+///
+/// - max_plays: How many plays a track gets before it stops being played
+///   (optional, by default tracks are never retired because of their plays)
+///
+/// - tracks: An array of tables, each with a path to an audio file and at
+///   least one of max_plays, which overrides the section's, or after, a date
+///   after which the track isn't played
+fn parse_retirement(root: &Value) -> Result<RetirementConfig, String> {
+    let require_max_plays = |entry: &Value, path: &str| {
+        entry.require_int(path).and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err(format!(
+                    "Could not parse config: '{}' must be positive",
+                    path
+                ))
+            }
+        })
+    };
+
+    let max_plays = match root.get_at_path("retirement.max_plays") {
+        Some(entry) => Some(require_max_plays(entry, "retirement.max_plays")?),
+        None => None,
+    };
+
+    let mut tracks = HashMap::new();
+    if let Some(entry) = root.get_at_path("retirement.tracks") {
+        for rule in entry.require_array("retirement.tracks")? {
+            rule.require_table("retirement.tracks.*")?;
+            let track = rule
+                .get_at_path("path")
+                .ok_or_else(|| {
+                    "Could not parse config: 'retirement.tracks.*.path' is required".to_string()
+                })
+                .and_then(|p| p.require_pathbuf("retirement.tracks.*.path"))?;
+
+            if !track.is_absolute() {
+                return Err(
+                    "Could not parse config: 'retirement.tracks.*.path' must be absolute"
+                        .to_string(),
+                );
+            }
+
+            let max_plays = match rule.get_at_path("max_plays") {
+                Some(entry) => Some(require_max_plays(entry, "retirement.tracks.*.max_plays")?),
+                None => None,
+            };
+
+            let after = match rule.get_at_path("after") {
+                Some(entry) => Some(entry.require_datetime("retirement.tracks.*.after")?),
+                None => None,
+            };
+
+            if max_plays.is_none() && after.is_none() {
+                return Err(
+                    "Could not parse config: 'retirement.tracks.*' must have at least one of max_plays or after"
+                        .to_string(),
+                );
+            }
+
+            tracks.insert(track, RetirementRule { max_plays, after });
+        }
+    }
+
+    Ok(RetirementConfig { max_plays, tracks })
+}
+
+/// Builds the votes section of the configuration, which contains the following
+/// options:
+///
+/// - skip_threshold: How many more skip votes than keep votes the current
+///   track needs before the action is taken (default none, votes are only
+///   recorded)
+///
+/// - action: Either "deprioritize" to move the track to the end of the
+///   playlist, or "blocklist" to stop playing it (default "deprioritize")
+fn parse_votes(root: &Value) -> Result<VoteConfig, String> {
+    let skip_threshold = if let Some(entry) = root.get_at_path("votes.skip_threshold") {
+        Some(entry.require_int("votes.skip_threshold").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'votes.skip_threshold' must be positive".to_string())
+            }
+        })?)
+    } else {
+        None
+    };
+
+    let action = if let Some(entry) = root.get_at_path("votes.action") {
+        match entry.require_str("votes.action")? {
+            "deprioritize" => VoteAction::Deprioritize,
+            "blocklist" => VoteAction::Blocklist,
+            other => {
+                return Err(format!(
+                    "Could not parse config: 'votes.action' must be 'deprioritize' or 'blocklist', not '{}'",
+                    other
+                ))
+            }
+        }
+    } else {
+        VoteAction::Deprioritize
+    };
+
+    Ok(VoteConfig {
+        skip_threshold,
+        action,
+    })
+}
+
+/// Builds the requests section of the configuration, which contains the
+/// following options:
+///
+/// - moderated: Whether requests have to be approved by an operator before
+///   they are played (default true)
+///
+/// - interval_min: How many minutes a listener has to wait between requests
+///   (default 15, 0 for no limit)
+///
+/// - message_length: The most characters that a message submitted to be read
+///   out on the air can have (default 200)
+fn parse_requests(root: &Value) -> Result<RequestConfig, String> {
+    let moderated = if let Some(entry) = root.get_at_path("requests.moderated") {
+        entry.require_bool("requests.moderated")?
+    } else {
+        true
+    };
+
+    let interval = if let Some(entry) = root.get_at_path("requests.interval_min") {
+        entry.require_int("requests.interval_min").and_then(|i| {
+            if i >= 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err(
+                    "Could not parse config: 'requests.interval_min' must be non-negative"
+                        .to_string(),
+                )
+            }
+        })?
+    } else {
+        15
+    };
+
+    let message_length = if let Some(entry) = root.get_at_path("requests.message_length") {
+        entry.require_int("requests.message_length").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as usize)
+            } else {
+                Err(
+                    "Could not parse config: 'requests.message_length' must be positive"
+                        .to_string(),
+                )
+            }
+        })?
+    } else {
+        200
+    };
+
+    Ok(RequestConfig {
+        moderated,
+        interval,
+        message_length,
+    })
+}
+
+/// Builds the RDS section of the configuration, if there is one. It contains
+/// the following options:
+///
+/// - file, serial, udp: Where to send the text, either a file to write it to,
+///   a serial port to write it to, or the host:port of an encoder to send it
+///   to over UDP. Exactly one of these is required.
+///
+/// - template: How to describe a track, with {artist}, {title} and {album}
+///   replaced by the track's tags (default "{artist} - {title}")
+///
+/// - station_text: What to send during special entries and for tracks that
+///   don't have a title (default service.instance_name)
+///
+/// - command: The encoder command that the text is sent with over serial
+///   ports and UDP (default "RT")
+///
+/// - max_length: The most characters that are sent (default 64, the length
+///   of RDS RadioText)
+fn parse_rds(root: &Value, service_config: &ServiceConfig) -> Result<Option<RdsConfig>, String> {
+    if root.get_at_path("rds").is_none() {
+        return Ok(None);
+    }
+
+    let mut targets = Vec::new();
+    if let Some(entry) = root.get_at_path("rds.file") {
+        targets.push(RdsTarget::File(entry.require_pathbuf("rds.file")?));
+    }
+
+    if let Some(entry) = root.get_at_path("rds.serial") {
+        targets.push(RdsTarget::Serial(entry.require_pathbuf("rds.serial")?));
+    }
+
+    if let Some(entry) = root.get_at_path("rds.udp") {
+        let address = entry.require_str("rds.udp")?;
+        let resolved = address
+            .to_socket_addrs()
+            .map_err(|_| {
+                format!(
+                    "Could not parse config: 'rds.udp' must be a host:port, not '{}'",
+                    address
+                )
+            })?
+            .next()
+            .ok_or_else(|| {
+                "Could not parse config: 'rds.udp' did not resolve to any addresses".to_string()
+            })?;
+
+        targets.push(RdsTarget::Udp(resolved));
+    }
+
+    if targets.len() != 1 {
+        return Err(
+            "Could not parse config: exactly one of 'rds.file', 'rds.serial' and 'rds.udp' is required"
+                .to_string(),
+        );
+    }
+
+    let template = match root.get_at_path("rds.template") {
+        Some(entry) => entry.require_str("rds.template")?.to_string(),
+        None => "{artist} - {title}".to_string(),
+    };
+
+    let station_text = match root.get_at_path("rds.station_text") {
+        Some(entry) => entry.require_str("rds.station_text")?.to_string(),
+        None => service_config.instance_name.to_string(),
+    };
+
+    let command = match root.get_at_path("rds.command") {
+        Some(entry) => entry.require_str("rds.command")?.to_string(),
+        None => "RT".to_string(),
+    };
+
+    let max_length = if let Some(entry) = root.get_at_path("rds.max_length") {
+        entry.require_int("rds.max_length").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as usize)
+            } else {
+                Err("Could not parse config: 'rds.max_length' must be positive".to_string())
+            }
+        })?
+    } else {
+        64
+    };
+
+    Ok(Some(RdsConfig {
+        target: targets.pop().unwrap(),
+        template,
+        station_text,
+        command,
+        max_length,
+    }))
+}
+
+/// Builds the notify section of the configuration, if there is one. It
+/// contains the following options:
+///
+/// - discord_webhook: The URL of a Discord webhook to post notices to, which
+///   is a secret and can be read from the file in discord_webhook_file instead
+///
+/// - irc_server, irc_channel, irc_nick: The host:port of an IRC server, the
+///   channel to post notices in and the nickname to use (irc_nick defaults
+///   to service.instance_name). The server and channel are required together.
+///
+/// At least one of the Discord webhook and the IRC channel is required.
+///
+/// - track_template: How to announce a track, with {artist}, {title}, {album}
+///   and {file} replaced (default "Now playing: {artist} - {title}")
+///
+/// - special_template: How to announce a special entry, with {kind} replaced
+///   by its kind (default "Station break: {kind}")
+///
+/// - interval_sec: The least number of seconds between two notices (default
+///   30)
+fn parse_notify(
+    root: &Value,
+    service_config: &ServiceConfig,
+) -> Result<Option<NotifyConfig>, String> {
+    if root.get_at_path("notify").is_none() {
+        return Ok(None);
+    }
+
+    let discord_webhook = match get_secret(root, "notify.discord_webhook")? {
+        Some(url) => {
+            let parsed = Url::parse(&url).or(Err(
+                "Could not parse config: 'notify.discord_webhook' was not a valid URL".to_string(),
+            ))?;
+
+            if parsed.scheme() != "http" && parsed.scheme() != "https" {
+                return Err(
+                    "Could not parse config: 'notify.discord_webhook' must be an HTTP or HTTPS URL"
+                        .to_string(),
+                );
+            }
+
+            Some(url)
+        }
+        None => None,
+    };
+
+    let irc_server = match root.get_at_path("notify.irc_server") {
+        Some(entry) => Some(entry.require_str("notify.irc_server")?.to_string()),
+        None => None,
+    };
+
+    let irc_channel = match root.get_at_path("notify.irc_channel") {
+        Some(entry) => Some(entry.require_str("notify.irc_channel")?.to_string()),
+        None => None,
+    };
+
+    let irc_nick = match root.get_at_path("notify.irc_nick") {
+        Some(entry) => entry.require_str("notify.irc_nick")?.to_string(),
+        None => service_config.instance_name.to_string(),
+    };
+
+    let irc = match (irc_server, irc_channel) {
+        (Some(server), Some(channel)) => {
+            if !channel.starts_with('#') && !channel.starts_with('&') {
+                return Err(format!(
+                    "Could not parse config: 'notify.irc_channel' must start with # or &, not '{}'",
+                    channel
+                ));
+            }
+
+            Some(IrcConfig {
+                server,
+                channel,
+                nick: irc_nick,
+            })
+        }
+        (None, None) => None,
+        _ => {
+            return Err(
+                "Could not parse config: 'notify.irc_server' and 'notify.irc_channel' must be given together"
+                    .to_string(),
+            )
+        }
+    };
+
+    if discord_webhook.is_none() && irc.is_none() {
+        return Err(
+            "Could not parse config: 'notify' needs a Discord webhook or an IRC channel"
+                .to_string(),
+        );
+    }
+
+    let track_template = match root.get_at_path("notify.track_template") {
+        Some(entry) => entry.require_str("notify.track_template")?.to_string(),
+        None => "Now playing: {artist} - {title}".to_string(),
+    };
+
+    let special_template = match root.get_at_path("notify.special_template") {
+        Some(entry) => entry.require_str("notify.special_template")?.to_string(),
+        None => "Station break: {kind}".to_string(),
+    };
+
+    let interval = if let Some(entry) = root.get_at_path("notify.interval_sec") {
+        entry.require_int("notify.interval_sec").and_then(|i| {
+            if i >= 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err(
+                    "Could not parse config: 'notify.interval_sec' must be non-negative"
+                        .to_string(),
+                )
+            }
+        })?
+    } else {
+        30
+    };
+
+    Ok(Some(NotifyConfig {
+        discord_webhook,
+        irc,
+        track_template,
+        special_template,
+        interval,
+    }))
+}
+
+/// Builds the api section of the configuration, if there is one. It contains
+/// the following options:
+///
+/// - listen: The host:port to accept HTTP requests on (required)
+///
+/// - allow_origin: The origin sent in Access-Control-Allow-Origin, so that a
+///   page from another origin can call the API (default none, only pages from
+///   the same origin)
+///
+/// - preview_token: The token that /preview/<track> requests must give to be
+///   sent the start of a track (default none, /preview is off)
+fn parse_api(root: &Value, service_config: &ServiceConfig) -> Result<Option<ApiConfig>, String> {
+    if root.get_at_path("api").is_none() {
+        return Ok(None);
+    }
+
+    let address = root
+        .require_at_path("api.listen")
+        .and_then(|entry| entry.require_str("api.listen"))?;
+
+    let listen = address
+        .to_socket_addrs()
+        .map_err(|_| {
+            format!(
+                "Could not parse config: 'api.listen' must be a host:port, not '{}'",
+                address
+            )
+        })?
+        .next()
+        .ok_or_else(|| {
+            "Could not parse config: 'api.listen' did not resolve to any addresses".to_string()
+        })?;
+
+    let allow_origin = match root.get_at_path("api.allow_origin") {
+        Some(entry) => Some(entry.require_str("api.allow_origin")?.to_string()),
+        None => None,
+    };
+
+    let preview_token = match root.get_at_path("api.preview_token") {
+        Some(entry) => {
+            let token = entry.require_str("api.preview_token")?;
+            if token.is_empty() {
+                return Err(
+                    "Could not parse config: 'api.preview_token' must not be empty".to_string(),
+                );
+            }
+
+            Some(token.to_string())
+        }
+        None => None,
+    };
+
+    Ok(Some(ApiConfig {
+        listen,
+        ipc_socket: service_config.ipc_socket.clone(),
+        refuse_admin: !service_config.admin_users.is_empty()
+            || !service_config.admin_groups.is_empty(),
+        allow_origin,
+        preview_token,
+    }))
+}
+
+/// Builds the metrics section of the configuration, if there is one. It
+/// contains the following options:
+///
+/// - listen: The host:port to serve /metrics on for Prometheus to scrape
+///   (required)
+fn parse_metrics(root: &Value) -> Result<Option<MetricsConfig>, String> {
+    if root.get_at_path("metrics").is_none() {
+        return Ok(None);
+    }
+
+    let address = root
+        .require_at_path("metrics.listen")
+        .and_then(|entry| entry.require_str("metrics.listen"))?;
+
+    let listen = address
+        .to_socket_addrs()
+        .map_err(|_| {
+            format!(
+                "Could not parse config: 'metrics.listen' must be a host:port, not '{}'",
+                address
+            )
+        })?
+        .next()
+        .ok_or_else(|| {
+            "Could not parse config: 'metrics.listen' did not resolve to any addresses".to_string()
+        })?;
+
+    Ok(Some(MetricsConfig { listen }))
+}
+
+/// Builds the monitor section of the configuration, which contains the
+/// following options:
+///
+/// - interval_min: How many minutes to wait between checks (default 5)
+///
+/// - min_free_mb: How many megabytes of free space the special working
+///   directory needs to have before an alert is raised (default 100)
+fn parse_monitor(
+    root: &Value,
+    service_config: &ServiceConfig,
+    special_config: &SpecialBaseConfig,
+) -> Result<MonitorConfig, String> {
+    let interval = if let Some(entry) = root.get_at_path("monitor.interval_min") {
+        entry.require_int("monitor.interval_min").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'monitor.interval_min' must be positive".to_string())
+            }
+        })?
+    } else {
+        5
+    };
+
+    let min_free_mb = if let Some(entry) = root.get_at_path("monitor.min_free_mb") {
+        entry.require_int("monitor.min_free_mb").and_then(|i| {
+            if i >= 0 {
+                Ok(i as u64)
+            } else {
+                Err(
+                    "Could not parse config: 'monitor.min_free_mb' must be non-negative"
+                        .to_string(),
+                )
+            }
+        })?
+    } else {
+        100
+    };
+
+    Ok(MonitorConfig {
+        interval,
+        working_dir: special_config.working_dir.to_path_buf(),
+        min_free_mb,
+        library_dir: service_config.library_dir.clone(),
+    })
+}
+
+/// The newest version of the configuration format, which is what the file
+/// declares with config_version once it has been brought up to date
+const CONFIG_VERSION: i64 = 2;
+
+/// A key that was renamed, along with the config version it was renamed in
+struct RenamedKey {
+    old: &'static str,
+    new: &'static str,
+    version: i64,
+}
+
+/// Every key that has been renamed, oldest first. Files older than the version
+/// that renamed a key can keep using its old name.
+const RENAMED_KEYS: &[RenamedKey] = &[RenamedKey {
+    old: "requests.interval",
+    new: "requests.interval_min",
+    version: 2,
+}];
+
+/// Removes the value at a dot-separated path, if there is one
+fn take_at_path(root: &mut Value, path: &str) -> Option<Value> {
+    let (parent, key) = path.rsplit_once('.')?;
+    let mut element = root;
+    for node in parent.split('.') {
+        element = element.as_table_mut()?.get_mut(node)?;
+    }
+
+    element.as_table_mut()?.remove(key)
+}
+
+/// Stores a value at a dot-separated path, adding any tables along the way
+/// that don't exist yet
+fn insert_at_path(root: &mut Value, path: &str, value: Value) {
+    let (parent, key) = path.rsplit_once('.').unwrap_or(("", path));
+    let mut element = root;
+    for node in parent.split('.').filter(|node| !node.is_empty()) {
+        element = match element.as_table_mut() {
+            Some(table) => table
+                .entry(node.to_string())
+                .or_insert_with(|| Value::Table(toml::map::Map::new())),
+            None => return,
+        };
+    }
+
+    if let Some(table) = element.as_table_mut() {
+        table.insert(key.to_string(), value);
+    }
+}
+
+/// The environment variable which overrides debug.time_scale, so that a test
+/// can speed up a configuration without editing it
+const TIME_SCALE_VARIABLE: &str = "SHUFFLED_TIME_SCALE";
+
+/// Reads the debug section of the configuration, which contains the
+/// following options:
+///
+/// - time_scale: How many times faster than real time the special interval,
+///   the watchdog's probes and the weather's refreshes go, so that a day of
+///   announcements can be run through in a few minutes while testing. The
+///   SHUFFLED_TIME_SCALE environment variable takes precedence over it
+///   (default 1)
+fn parse_debug(root: &Value) -> Result<u32, String> {
+    if let Ok(value) = std::env::var(TIME_SCALE_VARIABLE) {
+        return match value.trim().parse::<u32>() {
+            Ok(scale) if scale > 0 => Ok(scale),
+            _ => Err(format!(
+                "Could not parse config: {} must be a positive integer, not '{}'",
+                TIME_SCALE_VARIABLE, value
+            )),
+        };
+    }
+
+    match root.get_at_path("debug.time_scale") {
+        Some(entry) => entry.require_int("debug.time_scale").and_then(|i| {
+            if i > 0 && i < (u32::MAX as i64) {
+                Ok(i as u32)
+            } else {
+                Err("Could not parse config: 'debug.time_scale' must be positive".to_string())
+            }
+        }),
+        None => Ok(1),
+    }
+}
+
+/// Brings a configuration written for an older version of the format up to
+/// date, by moving any renamed keys to their new names. Files without a
+/// config_version are taken to be version 1. Once a file declares a version,
+/// the keys which were renamed in it or earlier are only accepted by their new
+/// names.
+fn migrate(root: &mut Value) -> Result<Vec<ConfigWarning>, String> {
+    let version = if let Some(entry) = root.get_at_path("config_version") {
+        entry.require_int("config_version").and_then(|i| {
+            if (1..=CONFIG_VERSION).contains(&i) {
+                Ok(i)
+            } else {
+                Err(format!(
+                    "Could not parse config: 'config_version' must be between 1 and {}",
+                    CONFIG_VERSION
+                ))
+            }
+        })?
+    } else {
+        1
+    };
+
+    let mut warnings = Vec::new();
+    for renamed in RENAMED_KEYS {
+        let value = match take_at_path(root, renamed.old) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if version >= renamed.version {
+            return Err(format!(
+                "Could not parse config: '{}' was renamed to '{}' in config_version {}",
+                renamed.old, renamed.new, renamed.version
+            ));
+        }
+
+        if root.get_at_path(renamed.new).is_some() {
+            warnings.push(ConfigWarning::ShadowedKey {
+                old: renamed.old,
+                new: renamed.new,
+            });
+        } else {
+            insert_at_path(root, renamed.new, value);
+            warnings.push(ConfigWarning::RenamedKey {
+                old: renamed.old,
+                new: renamed.new,
+                version: renamed.version,
+            });
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// The options that belong to the daemon as a whole, which a station can't
+/// give its own value for
+const DAEMON_KEYS: &[&str] = &["instance_name", "pid_file"];
+
+/// Lays a station's section over the rest of the configuration, so that the
+/// station only has to give what is different about it. Plain values in the
+/// section are options for [service], and tables (or arrays of tables) are
+/// sections whose options replace the ones given outside of the station.
+fn overlay_station(root: &Value, name: &str, station: &Value) -> Result<Value, String> {
+    let section = station.as_table().ok_or(format!(
+        "Could not parse config: 'station.{}' must be a table",
+        name
+    ))?;
+
+    let mut merged = root.clone();
+    for (key, value) in section {
+        let is_section = value.is_table()
+            || value
+                .as_array()
+                .map(|array| !array.is_empty() && array.iter().all(Value::is_table))
+                .unwrap_or(false);
+
+        if !is_section {
+            if DAEMON_KEYS.contains(&key.as_str()) {
+                return Err(format!(
+                    "Could not parse config: 'station.{}.{}' can only be set in [service]",
+                    name, key
+                ));
+            }
+
+            insert_at_path(&mut merged, &format!("service.{}", key), value.clone());
+        } else if let (Some(options), Some(Value::Table(existing))) =
+            (value.as_table(), merged.get_mut(key))
+        {
+            if key == "service" {
+                if let Some(key) = DAEMON_KEYS.iter().find(|key| options.contains_key(**key)) {
+                    return Err(format!(
+                        "Could not parse config: 'station.{}.service.{}' can only be set in [service]",
+                        name, key
+                    ));
+                }
+            }
+
+            for (option, option_value) in options {
+                existing.insert(option.to_string(), option_value.clone());
+            }
+        } else {
+            insert_at_path(&mut merged, key, value.clone());
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Reads every station in the configuration. A file without any [station.*]
+/// sections is a single station with no name. Otherwise there is one for each
+/// section, built from the rest of the file with the section laid over it.
+pub fn parse_stations(stream: &mut impl Read) -> Result<Vec<Config>, String> {
+    let mut buffer = Vec::new();
+    if let Err(reason) = stream.read_to_end(&mut buffer) {
+        return Err(format!("Could not read config: {}", reason));
+    }
+
+    let content = String::from_utf8(buffer)
+        .or_else(|error| Err(format!("Could not load config: {}", error)))?;
+
+    let mut root = content
+        .parse::<Value>()
+        .map_err(|error| format!("Could not parse config: {}", error))?;
+
+    let stations = match root
+        .as_table_mut()
+        .and_then(|table| table.remove("station"))
+    {
+        Some(Value::Table(stations)) if !stations.is_empty() => stations,
+        Some(_) => return Err(
+            "Could not parse config: 'station' must contain at least one [station.<name>] section"
+                .to_string(),
+        ),
+        None => return Ok(vec![parse_root(root, None)?]),
+    };
+
+    let mut configs: Vec<Config> = Vec::new();
+    for (name, station) in stations.iter() {
+        if name.is_empty() || name.contains('/') {
+            return Err(format!(
+                "Could not parse config: station '{}' must be named without slashes",
+                name
+            ));
+        }
+
+        let config = overlay_station(&root, name, station)
+            .and_then(|merged| parse_root(merged, Some(name)))
+            .map_err(|error| {
+                error.replacen(
+                    "Could not parse config:",
+                    &format!("Could not parse config for station '{}':", name),
+                    1,
+                )
+            })?;
+
+        // Stations can share most files, but not the ones they write to
+        for other in configs.iter() {
+            let service = &config.service;
+            let other_service = &other.service;
+            let paths = [
+                (
+                    "ipc_socket",
+                    Some(&service.ipc_socket),
+                    Some(&other_service.ipc_socket),
+                ),
+                (
+                    "state_file",
+                    service.state_file.as_ref(),
+                    other_service.state_file.as_ref(),
+                ),
+                (
+                    "history_file",
+                    service.history_file.as_ref(),
+                    other_service.history_file.as_ref(),
+                ),
+                (
+                    "stats_file",
+                    service.stats_file.as_ref(),
+                    other_service.stats_file.as_ref(),
+                ),
+            ];
+
+            for (key, path, other_path) in paths.iter() {
+                if let (Some(path), Some(other_path)) = (path, other_path) {
+                    if path == other_path {
+                        return Err(format!(
+                            "Could not parse config: stations '{}' and '{}' both use {} for 'service.{}'",
+                            other.station.as_deref().unwrap_or(""),
+                            name,
+                            path.display(),
+                            key
+                        ));
+                    }
+                }
+            }
+        }
+
+        configs.push(config);
+    }
+
+    Ok(configs)
+}
+
+/// Picks one station out of the ones in the configuration, which has to be
+/// named if there are any
+pub fn select_station(configs: Vec<Config>, station: Option<&str>) -> Result<Config, String> {
+    let names = configs
+        .iter()
+        .filter_map(|config| config.station.as_deref())
+        .collect::<Vec<_>>();
+
+    match station {
+        None if names.is_empty() => Ok(configs.into_iter().next().unwrap()),
+        None => Err(format!(
+            "The configuration has the stations {}, pick one with --station",
+            names.join(", ")
+        )),
+        Some(station) if !names.contains(&station) => Err(format!(
+            "The configuration has no station named '{}'",
+            station
+        )),
+        Some(station) => Ok(configs
+            .into_iter()
+            .find(|config| config.station.as_deref() == Some(station))
+            .unwrap()),
+    }
+}
+
+/// Reads the configuration of one station, or of the only one if the file
+/// doesn't have any [station.*] sections
+pub fn parse_station(stream: &mut impl Read, station: Option<&str>) -> Result<Config, String> {
+    select_station(parse_stations(stream)?, station)
+}
+
+/// Builds a station's configuration out of the file, once any station section
+/// has been laid over the rest of it
+fn parse_root(mut root: Value, station: Option<&str>) -> Result<Config, String> {
+    let mut warnings = migrate(&mut root)?;
+    let table = &root;
+
+    let time_scale = parse_debug(table)?;
+    if time_scale > 1 {
+        warnings.push(ConfigWarning::TimeScaled { scale: time_scale });
+    }
 
-    let service = parse_service_section(&table)?;
-    let special_base = parse_special_base(&table)?;
+    let service = parse_service_section(table, station)?;
+    let special_base = parse_special_base(table, &service, time_scale)?;
 
     let watchdog = if service.watchdog_enabled {
-        parse_watchdog(&table)?
+        parse_watchdog(table, &service, time_scale)?
     } else {
         WatchdogConfig {
             interval: 0,
             service: "".to_string(),
             addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 80),
             path: "/".to_string(),
+            url: Url::parse("http://localhost/").unwrap(),
+            dead_air: None,
+            self_check: None,
+            auth_token: None,
+            action: WatchdogAction::Restart,
+            content_type: None,
+            min_body_bytes: 1,
+            offset: None,
+            jitter: 0,
+            restart_grace: 120,
+            systemctl: PathBuf::from("systemctl"),
+            time_scale,
         }
     };
 
     let special_weather = parse_weather(&table)?;
+    let alerts = parse_alerts(table)?;
+    let http = parse_http(table)?;
+    let availability = parse_availability(table)?;
+    let rules = parse_rules(table)?;
+    let quotas = parse_quotas(table)?;
+    let ramp = parse_ramp(table)?;
+    let playlist_end = parse_playlist_end(table)?;
+    let parts = parse_parts(table)?;
+    let retirement = parse_retirement(table)?;
+    let votes = parse_votes(table)?;
+    let requests = parse_requests(table)?;
+    let rds = parse_rds(table, &service)?;
+    let notify = parse_notify(table, &service)?;
+    let api = parse_api(table, &service)?;
+    let metrics = parse_metrics(table)?;
+    let monitor = parse_monitor(table, &service, &special_base)?;
 
     Ok(Config {
         service,
         special_base,
         special_weather,
         watchdog,
+        alerts,
+        http,
+        availability,
+        rules,
+        quotas,
+        ramp,
+        playlist_end,
+        parts,
+        retirement,
+        votes,
+        requests,
+        rds,
+        notify,
+        api,
+        metrics,
+        monitor,
+        station: station.map(str::to_string),
+        warnings,
     })
 }
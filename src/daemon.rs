@@ -0,0 +1,108 @@
+use crate::storage;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+
+/// Forks twice, so that shuffled is left running in the background in its own
+/// session, without a terminal that could come back and claim it. Standard
+/// input is read from /dev/null and standard output and error go to the log
+/// file, or are thrown away if there isn't one.
+///
+/// This has to be done before any threads are started, since only the thread
+/// that forks carries on in the child. The working directory is left alone,
+/// since relative paths in the configuration are relative to it.
+pub fn daemonize(log_file: Option<&Path>) -> Result<(), String> {
+    // Both are opened before forking, so that a log that can't be written is
+    // still reported to the terminal
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|error| format!("Could not open /dev/null: {}", error))?;
+
+    let log = match log_file {
+        Some(path) => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|error| format!("Could not open log file {}: {}", path.display(), error))?,
+        None => null
+            .try_clone()
+            .map_err(|error| format!("Could not open /dev/null: {}", error))?,
+    };
+
+    fork_and_exit_parent()?;
+
+    // Safe since it takes no arguments
+    if unsafe { libc::setsid() } < 0 {
+        return Err(format!(
+            "Could not start a new session: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    fork_and_exit_parent()?;
+
+    let redirects = [
+        (null.as_raw_fd(), libc::STDIN_FILENO),
+        (log.as_raw_fd(), libc::STDOUT_FILENO),
+        (log.as_raw_fd(), libc::STDERR_FILENO),
+    ];
+
+    for (from, to) in redirects.iter() {
+        // Safe since both descriptors are open for the length of the call
+        if unsafe { libc::dup2(*from, *to) } < 0 {
+            return Err(format!(
+                "Could not redirect output: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Forks, with the parent exiting straight away and the child returning
+fn fork_and_exit_parent() -> Result<(), String> {
+    // Safe since no other threads are running yet
+    match unsafe { libc::fork() } {
+        -1 => Err(format!(
+            "Could not fork into the background: {}",
+            io::Error::last_os_error()
+        )),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+/// Checks that a PID file doesn't name a process which is still running. A
+/// file left behind by a process that has since exited is fine.
+pub fn check_pid_file(path: &Path) -> Result<(), String> {
+    if let Ok(contents) = fs::read_to_string(path) {
+        if let Ok(pid) = contents.trim().parse::<libc::pid_t>() {
+            // Safe since signal 0 only checks whether the process exists. A
+            // process that belongs to someone else can't be signalled, but is
+            // still running.
+            let running = pid > 0
+                && (unsafe { libc::kill(pid, 0) } == 0
+                    || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM));
+            if running {
+                return Err(format!(
+                    "PID file {} says shuffled is already running as process {}",
+                    path.display(),
+                    pid
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the PID of this process to a file, once check_pid_file has passed
+pub fn write_pid_file(path: &Path) -> Result<(), String> {
+    storage::write_atomic(path, format!("{}\n", process::id()).as_bytes())
+        .map_err(|error| format!("Could not write PID file {}: {}", path.display(), error))
+}
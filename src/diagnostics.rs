@@ -0,0 +1,93 @@
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::SystemTime;
+
+/// How many of the most recent IPC commands are kept for the crash report
+const MAX_COMMANDS: usize = 10;
+
+/// What the server was doing, kept up to date so that a panic can say what
+/// led up to it
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    playlist: String,
+    position: usize,
+
+    /// The last few commands, with the most recent at the back
+    commands: VecDeque<(SystemTime, &'static str)>,
+}
+
+impl Diagnostics {
+    /// Records a command that the server is about to run, along with where
+    /// the current playlist was when it started
+    pub fn record_command(&mut self, command: &'static str, playlist: &str, position: usize) {
+        if self.commands.len() == MAX_COMMANDS {
+            self.commands.pop_front();
+        }
+
+        self.commands.push_back((SystemTime::now(), command));
+        self.playlist.clear();
+        self.playlist.push_str(playlist);
+        self.position = position;
+    }
+
+    fn log(&self) {
+        error!(
+            "panic",
+            "Current playlist: '{}' at position {}", self.playlist, self.position
+        );
+
+        if self.commands.is_empty() {
+            error!("panic", "No commands have been received");
+        }
+
+        for (time, command) in self.commands.iter() {
+            let ago = time.elapsed().map(|ago| ago.as_secs()).unwrap_or(0);
+            error!("panic", "Command {}, {} seconds ago", command, ago);
+        }
+    }
+}
+
+/// Replaces the default panic hook with one that logs the panic along with
+/// the server's state and a backtrace, since a panic on any thread otherwise
+/// leaves little more than its message behind
+pub fn install_panic_hook(diagnostics: Arc<Mutex<Diagnostics>>) {
+    panic::set_hook(Box::new(move |info| {
+        let thread = thread::current();
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "(no message)".to_string());
+
+        let location = info
+            .location()
+            .map(|location| format!("{}:{}", location.file(), location.line()))
+            .unwrap_or_else(|| "an unknown location".to_string());
+
+        error!(
+            "panic",
+            "Thread '{}' panicked at {}: {}",
+            thread.name().unwrap_or("unnamed"),
+            location,
+            message
+        );
+
+        // The panic may have happened while the server was updating the
+        // diagnostics, in which case waiting for them would never finish
+        match diagnostics.try_lock() {
+            Ok(diagnostics) => diagnostics.log(),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().log(),
+            Err(TryLockError::WouldBlock) => {
+                error!("panic", "Server state is unavailable, it was being updated")
+            }
+        }
+
+        for line in Backtrace::force_capture().to_string().lines() {
+            error!("panic", "{}", line);
+        }
+    }));
+}
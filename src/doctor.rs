@@ -0,0 +1,368 @@
+use crate::config::{self, Config};
+use crate::environment::Environment;
+use crate::http;
+use crate::monitor;
+use crate::server;
+use crate::tags;
+use crate::watchdog;
+use crate::weather;
+use shuffled::playlist;
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// How many tracks have their tags read, spread out over all of the playlists
+const SAMPLE_TRACKS: usize = 10;
+
+/// How much a problem found by the doctor matters, in the order they're listed
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+enum Severity {
+    /// Keeps shuffled, or one of its tasks, from working
+    Error,
+
+    /// Doesn't stop anything, but probably isn't what was meant
+    Warning,
+}
+
+/// The problems found so far. Each check prints a line as it passes, and the
+/// problems are listed together at the end.
+#[derive(Debug, Default)]
+struct Report {
+    problems: Vec<(Severity, String)>,
+}
+
+impl Report {
+    fn pass(&self, message: String) {
+        println!("[ok] {}", message);
+    }
+
+    fn error(&mut self, message: String) {
+        println!("[error] {}", message);
+        self.problems.push((Severity::Error, message));
+    }
+
+    fn warning(&mut self, message: String) {
+        println!("[warning] {}", message);
+        self.problems.push((Severity::Warning, message));
+    }
+
+    /// Prints the problems with the errors first, and returns whether there
+    /// were any errors
+    fn finish(mut self) -> bool {
+        println!();
+        if self.problems.is_empty() {
+            println!("No problems found");
+            return true;
+        }
+
+        // Stable, so problems of the same severity stay in the order found
+        self.problems.sort_by_key(|(severity, _)| *severity);
+        println!("Problems found:");
+        for (index, (severity, message)) in self.problems.iter().enumerate() {
+            let label = match severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+
+            println!("{}. {}: {}", index + 1, label, message);
+        }
+
+        self.problems
+            .iter()
+            .all(|(severity, _)| *severity != Severity::Error)
+    }
+}
+
+fn check_config(report: &mut Report, path: &Path, station: Option<&str>) -> Option<Config> {
+    let config = fs::File::open(path)
+        .map_err(|error| format!("Could not open configuration: {}", error))
+        .and_then(|mut file| config::parse_station(&mut file, station));
+
+    match config {
+        Ok(config) => {
+            match station {
+                Some(station) => report.pass(format!(
+                    "Configuration of station {} at {} is valid",
+                    station,
+                    path.display()
+                )),
+                None => report.pass(format!("Configuration at {} is valid", path.display())),
+            }
+            for warning in &config.warnings {
+                report.warning(warning.to_string());
+            }
+
+            Some(config)
+        }
+        Err(error) => {
+            report.error(error);
+            None
+        }
+    }
+}
+
+/// Reads every playlist the way the server would, and reports the ones that
+/// are empty. Returns all of the tracks.
+fn check_playlists(report: &mut Report, config: &Config) -> Vec<PathBuf> {
+    let service = &config.service;
+    if let Err(error) = server::validate_playlist_dirs(service) {
+        report.error(error);
+        return Vec::new();
+    }
+
+    if let Some(library) = service.library_dir.as_ref() {
+        match monitor::check_library(library) {
+            Ok(()) => report.pass(format!("Library at {} is available", library.display())),
+            Err(error) => report.error(format!(
+                "Library at {} is unavailable: {}",
+                library.display(),
+                error
+            )),
+        }
+    }
+
+    let playlists =
+        match playlist::read_playlists(&service.playlist_dir, service.content_root.as_deref()) {
+            Ok((playlists, _)) => playlists,
+            Err(error) => {
+                report.error(error);
+                return Vec::new();
+            }
+        };
+
+    let mut names = playlists.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut tracks = Vec::new();
+    for name in names {
+        let songs = &playlists[name];
+        if songs.is_empty() {
+            report.warning(format!("Playlist '{}' has no tracks", name));
+            continue;
+        }
+
+        tracks.extend(songs.iter().cloned());
+    }
+
+    report.pass(format!(
+        "Read {} playlists with {} tracks",
+        playlists.len(),
+        tracks.len()
+    ));
+    tracks
+}
+
+/// Reads the tags of a few tracks spread out over the playlists
+fn check_tags(report: &mut Report, tracks: &[PathBuf]) {
+    if tracks.is_empty() {
+        return;
+    }
+
+    let step = (tracks.len() / SAMPLE_TRACKS).max(1);
+    let sample = tracks.iter().step_by(step).take(SAMPLE_TRACKS);
+
+    let mut read = 0;
+    let mut failures = Vec::new();
+    for track in sample {
+        let result = fs::File::open(track)
+            .map_err(|error| error.to_string())
+            .and_then(|mut file| tags::read_tags(&mut file));
+
+        match result {
+            Ok(_) => read += 1,
+            Err(error) => failures.push((track, error)),
+        }
+    }
+
+    if let Some((track, error)) = failures.first() {
+        report.warning(format!(
+            "Could not read the tags of {} of {} sampled tracks, such as {}: {}",
+            failures.len(),
+            read + failures.len(),
+            track.display(),
+            error
+        ));
+    } else {
+        report.pass(format!("Read the tags of {} sampled tracks", read));
+    }
+}
+
+/// Checks that the announcer tools run and that their output has somewhere to
+/// go, as long as the clock or weather will be using them
+fn check_announcers(report: &mut Report, config_path: &Path, config: &Config) {
+    let service = &config.service;
+    if !service.clock_enabled && !service.weather_enabled {
+        return;
+    }
+
+    let working_dir = &config.special_base.working_dir;
+    if working_dir.is_dir() {
+        report.pass(format!(
+            "Working directory {} exists",
+            working_dir.display()
+        ));
+    } else {
+        report.error(format!(
+            "Working directory {} is not a directory",
+            working_dir.display()
+        ));
+    }
+
+    let environment = Environment::detect(config_path, config);
+    for tool in environment.tools.iter() {
+        match tool.version.as_ref() {
+            Some(version) => report.pass(format!("{}: {}", tool.name, version)),
+            None => report.error(format!(
+                "{} could not be run ({})",
+                tool.name,
+                tool.path.display()
+            )),
+        }
+    }
+}
+
+fn check_weather(report: &mut Report, config: &Config) {
+    if !config.service.weather_enabled {
+        return;
+    }
+
+    let region = &config.special_weather.region;
+    let client = match http::build_client(&config.http) {
+        Ok(client) => client,
+        Err(error) => {
+            report.error(error);
+            return;
+        }
+    };
+
+    match weather::check_region(&client, region) {
+        weather::RegionCheck::Valid => report.pass(format!("Weather region {} is valid", region)),
+        weather::RegionCheck::Invalid(reason) => report.error(format!(
+            "Weather region {} was rejected: {}",
+            region, reason
+        )),
+        weather::RegionCheck::Unknown(reason) => report.error(format!(
+            "Could not reach weather.gov to check region {}: {}",
+            region, reason
+        )),
+    }
+}
+
+fn check_icecast(report: &mut Report, config: &Config) {
+    if !config.service.watchdog_enabled {
+        return;
+    }
+
+    let watchdog = &config.watchdog;
+    match watchdog::probe_icecast(watchdog, 10) {
+        Ok(probe) => report.pass(format!(
+            "Icecast stream at {} answered with {}",
+            watchdog.url, probe.status_line
+        )),
+        Err(()) => report.error(format!(
+            "Icecast stream at {} could not be probed, see the [watchdog] lines in the log",
+            watchdog.url
+        )),
+    }
+}
+
+/// Checks that sockets can be created at all, and that the IPC socket and TCP
+/// ports are free. If shuffled is already running on the IPC socket then its
+/// ports are expected to be taken.
+fn check_sockets(report: &mut Report, config: &Config) {
+    let test_path = env::temp_dir().join(format!("shuffled-doctor-{}.socket", process::id()));
+    match UnixListener::bind(&test_path) {
+        Ok(_) => {
+            let _ = fs::remove_file(&test_path);
+            report.pass(format!(
+                "Created a Unix socket in {}",
+                env::temp_dir().display()
+            ));
+        }
+        Err(error) => report.error(format!(
+            "Could not create a Unix socket in {}: {}",
+            env::temp_dir().display(),
+            error
+        )),
+    }
+
+    let ipc_socket = &config.service.ipc_socket;
+    let running = ipc_socket.exists() && UnixStream::connect(ipc_socket).is_ok();
+    if running {
+        report.pass(format!(
+            "shuffled is already running on {}",
+            ipc_socket.display()
+        ));
+        return;
+    }
+
+    let metadata = fs::symlink_metadata(ipc_socket);
+    if let Ok(metadata) = metadata {
+        if metadata.file_type().is_socket() {
+            report.warning(format!(
+                "IPC socket {} was left behind by an earlier run and will be removed at startup",
+                ipc_socket.display()
+            ));
+        } else {
+            report.error(format!(
+                "IPC socket {} already exists and is not a socket",
+                ipc_socket.display()
+            ));
+        }
+    } else {
+        match ipc_socket.parent() {
+            Some(parent) if parent.is_dir() => {
+                report.pass(format!("IPC socket can be created in {}", parent.display()))
+            }
+            _ => report.error(format!(
+                "The directory for IPC socket {} does not exist",
+                ipc_socket.display()
+            )),
+        }
+    }
+
+    let addresses = [
+        ("service.tcp_listen", config.service.tcp_listen),
+        ("api.listen", config.api.as_ref().map(|api| api.listen)),
+        (
+            "metrics.listen",
+            config.metrics.as_ref().map(|metrics| metrics.listen),
+        ),
+    ];
+
+    for (name, address) in addresses.iter() {
+        if let Some(address) = address {
+            match TcpListener::bind(address) {
+                Ok(_) => report.pass(format!("Can listen on {} for '{}'", address, name)),
+                Err(error) => report.error(format!(
+                    "Could not listen on {} for '{}': {}",
+                    address, name, error
+                )),
+            }
+        }
+    }
+}
+
+/// Runs every check against a configuration file and prints what was found,
+/// with the problems listed by how much they matter. Returns whether nothing
+/// was found that would keep shuffled or one of its tasks from working. A
+/// configuration with stations is checked one station at a time.
+pub fn run(config_path: &Path, station: Option<&str>) -> bool {
+    let mut report = Report::default();
+    let config = match check_config(&mut report, config_path, station) {
+        Some(config) => config,
+        None => return report.finish(),
+    };
+
+    let tracks = check_playlists(&mut report, &config);
+    check_tags(&mut report, &tracks);
+    check_announcers(&mut report, config_path, &config);
+    check_weather(&mut report, &config);
+    check_icecast(&mut report, &config);
+    check_sockets(&mut report, &config);
+    report.finish()
+}
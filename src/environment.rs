@@ -0,0 +1,184 @@
+use crate::config::Config;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// One of the external programs that shuffled runs, along with the version it
+/// reported
+#[derive(Debug)]
+pub struct ToolVersion {
+    pub name: &'static str,
+    pub path: PathBuf,
+
+    /// The first line that the program printed for --version, or None if it
+    /// couldn't be run
+    pub version: Option<String>,
+}
+
+/// What shuffled was started with, which is logged at startup and reported by
+/// status so that whoever is helping run a station can see it without logging
+/// into the host
+#[derive(Debug)]
+pub struct Environment {
+    pub version: &'static str,
+    pub config_path: PathBuf,
+
+    /// The station that was read from the configuration, if it has any
+    pub station: Option<String>,
+
+    /// The tasks that are enabled, followed by the optional sections that are
+    /// configured
+    pub modules: Vec<&'static str>,
+    pub tools: Vec<ToolVersion>,
+}
+
+/// Runs a program with --version and returns the first line it prints. Some
+/// programs print their version to stderr, so that is used if stdout is empty.
+fn detect_version(path: &Path) -> Option<String> {
+    let output = Command::new(path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+impl Environment {
+    /// Works out the environment from the configuration, running each of the
+    /// announcer tools to find its version
+    pub fn detect(config_path: &Path, config: &Config) -> Self {
+        let service = &config.service;
+        let mut modules = Vec::new();
+        let tasks = [
+            ("clock", service.clock_enabled),
+            ("weather", service.weather_enabled),
+            ("watchdog", service.watchdog_enabled),
+            ("monitor", service.monitor_enabled),
+            ("rds", config.rds.is_some()),
+            ("notify", config.notify.is_some()),
+            ("api", config.api.is_some()),
+            ("auto_reload", service.auto_reload.is_some()),
+        ];
+
+        for (name, enabled) in tasks.iter() {
+            if *enabled {
+                modules.push(*name);
+            }
+        }
+
+        let tools = &config.special_base.tools;
+        let tools = [
+            ("espeak", &tools.espeak),
+            ("sox", &tools.sox),
+            ("lame", &tools.lame),
+        ]
+        .iter()
+        .map(|(name, path)| ToolVersion {
+            name,
+            path: path.to_path_buf(),
+            version: detect_version(path),
+        })
+        .collect();
+
+        Environment {
+            version: env!("CARGO_PKG_VERSION"),
+            config_path: fs::canonicalize(config_path).unwrap_or_else(|_| config_path.into()),
+            station: config.station.clone(),
+            modules,
+            tools,
+        }
+    }
+
+    /// Writes the environment to the log, along with what the server loaded
+    pub fn log(&self, playlists: usize, cached_tags: usize) {
+        info!(
+            "server",
+            "shuffled {} running with {}",
+            self.version,
+            self.config_path.display()
+        );
+
+        if let Some(station) = self.station.as_ref() {
+            info!("server", "Station: {}", station);
+        }
+
+        if self.modules.is_empty() {
+            info!("server", "Modules: none");
+        } else {
+            info!("server", "Modules: {}", self.modules.join(", "));
+        }
+
+        info!(
+            "server",
+            "Loaded {} playlists, with tags for {} tracks", playlists, cached_tags
+        );
+
+        for tool in self.tools.iter() {
+            match tool.version.as_ref() {
+                Some(version) => info!(
+                    "server",
+                    "{}: {} ({})",
+                    tool.name,
+                    version,
+                    tool.path.display()
+                ),
+                None => warning!(
+                    "server",
+                    "{}: could not be run ({})",
+                    tool.name,
+                    tool.path.display()
+                ),
+            }
+        }
+    }
+
+    /// Describes the environment for the status command
+    pub fn report(&self, playlists: usize, cached_tags: usize) -> json::JsonValue {
+        let mut tools = json::object::Object::new();
+        for tool in self.tools.iter() {
+            let mut tool_obj = json::object::Object::new();
+            tool_obj.insert(
+                "path",
+                json::JsonValue::String(tool.path.to_string_lossy().to_string()),
+            );
+            tool_obj.insert(
+                "version",
+                match tool.version.as_ref() {
+                    Some(version) => json::JsonValue::String(version.to_string()),
+                    None => json::JsonValue::Null,
+                },
+            );
+            tools.insert(tool.name, json::JsonValue::Object(tool_obj));
+        }
+
+        let modules = self
+            .modules
+            .iter()
+            .map(|module| json::JsonValue::String(module.to_string()))
+            .collect();
+
+        let mut report = json::object::Object::new();
+        report.insert("version", json::JsonValue::String(self.version.to_string()));
+        report.insert(
+            "config",
+            json::JsonValue::String(self.config_path.to_string_lossy().to_string()),
+        );
+        if let Some(station) = self.station.as_ref() {
+            report.insert("station", json::JsonValue::String(station.to_string()));
+        }
+
+        report.insert("modules", json::JsonValue::Array(modules));
+        report.insert("playlists", json::JsonValue::Number(playlists.into()));
+        report.insert("cached_tags", json::JsonValue::Number(cached_tags.into()));
+        report.insert("tools", json::JsonValue::Object(tools));
+        json::JsonValue::Object(report)
+    }
+}
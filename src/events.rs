@@ -0,0 +1,140 @@
+use crate::skips::SkipReason;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// The ID3 tags of a track that was served, copied out of the server's tag
+/// cache so that subscribers don't need access to it
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TrackTags {
+    pub artist: String,
+    pub title: String,
+    pub album: String,
+    pub genre: String,
+}
+
+/// Changes in the stream's health noticed by the watchdog
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchdogEvent {
+    /// A probe of the stream failed after the last one succeeded
+    StreamDown,
+
+    /// A probe of the stream succeeded after the last one failed
+    StreamUp,
+
+    /// The stream was down and the service was restarted
+    Restarted,
+
+    /// The server stopped answering IPC requests
+    ServerUnresponsive,
+
+    /// The server started answering IPC requests again
+    ServerResponding,
+
+    /// No tracks have been requested in this many minutes while the stream
+    /// was up
+    DeadAir(u64),
+}
+
+/// Changes in the disk space and library noticed by the monitor
+#[derive(Debug, PartialEq, Clone)]
+pub enum MonitorEvent {
+    /// The working directory has dropped below the configured free space, and
+    /// has this many megabytes left
+    LowDiskSpace(u64),
+
+    /// The working directory has enough free space again
+    DiskSpaceOk,
+
+    /// The music library could not be read, for the given reason
+    LibraryUnavailable(String),
+
+    /// The music library can be read again
+    LibraryAvailable,
+}
+
+/// Something that happened in one of the workers which other parts of the
+/// daemon might want to react to
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    /// next-track returned a song, either from the playlist or because a
+    /// listener requested it
+    TrackServed {
+        track: PathBuf,
+        playlist: String,
+        tags: Option<TrackTags>,
+        requested: bool,
+    },
+
+    /// next-track passed over a track on the playlist, for the given reason
+    TrackSkipped {
+        track: PathBuf,
+        playlist: String,
+        reason: SkipReason,
+        detail: String,
+    },
+
+    /// next-track returned a special entry, given by its kind
+    SpecialServed {
+        track: PathBuf,
+        kind: &'static str,
+    },
+
+    /// The current playlist was changed
+    PlaylistSwitched {
+        from: String,
+        to: String,
+    },
+
+    /// The playlists were reloaded from disk
+    PlaylistsReloaded,
+
+    Watchdog(WatchdogEvent),
+
+    Monitor(MonitorEvent),
+
+    /// A new weather report was generated
+    WeatherRefreshed(PathBuf),
+
+    /// weather.gov is rate limiting requests, and will be asked again in this
+    /// many seconds
+    WeatherRateLimited(u64),
+
+    /// The forecast could not be fetched, for some reason other than rate
+    /// limiting
+    WeatherFetchFailed,
+
+    /// The server is shutting down, so subscribers should stop
+    Shutdown,
+}
+
+/// Passes events from the workers that publish them to every subscriber.
+/// Clones share the same subscribers, so each publisher gets its own clone.
+/// Every subscriber has a channel of its own, so a slow one only delays
+/// itself.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// Returns a channel that receives every event published from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends an event to every subscriber, forgetting about any subscribers
+    /// which have gone away
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,23 @@
+use crate::config::HttpConfig;
+use reqwest::blocking::Client;
+use reqwest::Proxy;
+use std::time::Duration;
+
+/// Builds the HTTP client that is shared by every module. Clones of the client
+/// share the same connection pool, so connections to the same host are reused
+/// across requests and modules.
+pub fn build_client(config: &HttpConfig) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(config.timeout as u64))
+        .user_agent(config.user_agent.as_str());
+
+    if let Some(proxy) = config.proxy.as_ref() {
+        let proxy = Proxy::all(proxy.as_str())
+            .map_err(|error| format!("Could not use proxy {}: {}", proxy, error))?;
+        builder = builder.proxy(proxy);
+    }
+
+    builder
+        .build()
+        .map_err(|error| format!("Could not create HTTP client: {}", error))
+}
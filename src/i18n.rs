@@ -0,0 +1,644 @@
+use crate::config::Locale;
+
+/// The pieces of generated speech that have to be translated
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Phrase {
+    /// The clock announcement, given the {time} in words
+    Clock,
+
+    /// Introduces one period of the weather forecast, given the {time} that it
+    /// starts in words and the {forecast} itself
+    WeatherPeriod,
+
+    /// Introduces a period of the weather forecast that starts on a later day
+    /// than the report, given the {date} and {time} that it starts in words
+    /// and the {forecast} itself
+    WeatherLaterPeriod,
+
+    /// Introduces one period of the weather forecast by the {name} that the
+    /// forecast gives it, like "Tonight", along with the {forecast} itself
+    WeatherNamedPeriod,
+
+    /// The whole weather report, given the {high} and {low} temperatures and
+    /// the forecast {periods}. Can be replaced by weather.template.
+    WeatherReport,
+
+    /// Read instead of the weather report when the forecast is too old
+    WeatherUnavailable,
+}
+
+/// The translation tables for each locale. Placeholders in braces are filled
+/// in by the caller, and can be moved around to suit the language.
+const ENGLISH: &[(Phrase, &str)] = &[
+    (
+        Phrase::Clock,
+        "The current time is {time}. Repeat, the current time is {time}",
+    ),
+    (Phrase::WeatherPeriod, "At {time}, {forecast} "),
+    (
+        Phrase::WeatherLaterPeriod,
+        "On {date} at {time}, {forecast} ",
+    ),
+    (Phrase::WeatherNamedPeriod, "{name}: {forecast} "),
+    (
+        Phrase::WeatherReport,
+        "High of {high}, low of {low}. {periods}",
+    ),
+    (
+        Phrase::WeatherUnavailable,
+        "The weather report is temporarily unavailable.",
+    ),
+];
+
+const GERMAN: &[(Phrase, &str)] = &[
+    (
+        Phrase::Clock,
+        "Es ist jetzt {time}. Ich wiederhole, es ist jetzt {time}",
+    ),
+    (Phrase::WeatherPeriod, "Um {time}, {forecast} "),
+    (
+        Phrase::WeatherLaterPeriod,
+        "Am {date} um {time}, {forecast} ",
+    ),
+    (Phrase::WeatherNamedPeriod, "{name}: {forecast} "),
+    (
+        Phrase::WeatherReport,
+        "Höchstwert {high}, Tiefstwert {low}. {periods}",
+    ),
+    (
+        Phrase::WeatherUnavailable,
+        "Der Wetterbericht ist vorübergehend nicht verfügbar.",
+    ),
+];
+
+const FRENCH: &[(Phrase, &str)] = &[
+    (Phrase::Clock, "Il est {time}. Je répète, il est {time}"),
+    (Phrase::WeatherPeriod, "À {time}, {forecast} "),
+    (
+        Phrase::WeatherLaterPeriod,
+        "Le {date} à {time}, {forecast} ",
+    ),
+    (Phrase::WeatherNamedPeriod, "{name} : {forecast} "),
+    (
+        Phrase::WeatherReport,
+        "Maximum de {high}, minimum de {low}. {periods}",
+    ),
+    (
+        Phrase::WeatherUnavailable,
+        "Le bulletin météo est temporairement indisponible.",
+    ),
+];
+
+const SPANISH: &[(Phrase, &str)] = &[
+    (Phrase::Clock, "Son las {time}. Repito, son las {time}"),
+    (Phrase::WeatherPeriod, "A las {time}, {forecast} "),
+    (
+        Phrase::WeatherLaterPeriod,
+        "El {date} a las {time}, {forecast} ",
+    ),
+    (Phrase::WeatherNamedPeriod, "{name}: {forecast} "),
+    (
+        Phrase::WeatherReport,
+        "Máxima de {high}, mínima de {low}. {periods}",
+    ),
+    (
+        Phrase::WeatherUnavailable,
+        "El pronóstico del tiempo no está disponible por el momento.",
+    ),
+];
+
+const ENGLISH_ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+
+const ENGLISH_TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+const GERMAN_ONES: [&str; 20] = [
+    "null",
+    "eins",
+    "zwei",
+    "drei",
+    "vier",
+    "fünf",
+    "sechs",
+    "sieben",
+    "acht",
+    "neun",
+    "zehn",
+    "elf",
+    "zwölf",
+    "dreizehn",
+    "vierzehn",
+    "fünfzehn",
+    "sechzehn",
+    "siebzehn",
+    "achtzehn",
+    "neunzehn",
+];
+
+const GERMAN_TENS: [&str; 10] = [
+    "", "", "zwanzig", "dreißig", "vierzig", "fünfzig", "sechzig", "siebzig", "achtzig", "neunzig",
+];
+
+const FRENCH_ONES: [&str; 17] = [
+    "zéro", "un", "deux", "trois", "quatre", "cinq", "six", "sept", "huit", "neuf", "dix", "onze",
+    "douze", "treize", "quatorze", "quinze", "seize",
+];
+
+const FRENCH_TENS: [&str; 7] = [
+    "",
+    "",
+    "vingt",
+    "trente",
+    "quarante",
+    "cinquante",
+    "soixante",
+];
+
+const SPANISH_ONES: [&str; 30] = [
+    "cero",
+    "uno",
+    "dos",
+    "tres",
+    "cuatro",
+    "cinco",
+    "seis",
+    "siete",
+    "ocho",
+    "nueve",
+    "diez",
+    "once",
+    "doce",
+    "trece",
+    "catorce",
+    "quince",
+    "dieciséis",
+    "diecisiete",
+    "dieciocho",
+    "diecinueve",
+    "veinte",
+    "veintiuno",
+    "veintidós",
+    "veintitrés",
+    "veinticuatro",
+    "veinticinco",
+    "veintiséis",
+    "veintisiete",
+    "veintiocho",
+    "veintinueve",
+];
+
+const SPANISH_TENS: [&str; 10] = [
+    "",
+    "",
+    "",
+    "treinta",
+    "cuarenta",
+    "cincuenta",
+    "sesenta",
+    "setenta",
+    "ochenta",
+    "noventa",
+];
+
+const ENGLISH_MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const GERMAN_MONTHS: [&str; 12] = [
+    "Januar",
+    "Februar",
+    "März",
+    "April",
+    "Mai",
+    "Juni",
+    "Juli",
+    "August",
+    "September",
+    "Oktober",
+    "November",
+    "Dezember",
+];
+
+const FRENCH_MONTHS: [&str; 12] = [
+    "janvier",
+    "février",
+    "mars",
+    "avril",
+    "mai",
+    "juin",
+    "juillet",
+    "août",
+    "septembre",
+    "octobre",
+    "novembre",
+    "décembre",
+];
+
+const SPANISH_MONTHS: [&str; 12] = [
+    "enero",
+    "febrero",
+    "marzo",
+    "abril",
+    "mayo",
+    "junio",
+    "julio",
+    "agosto",
+    "septiembre",
+    "octubre",
+    "noviembre",
+    "diciembre",
+];
+
+/// Spells out a number from 0 to 99 in French, which counts by twenties past
+/// sixty
+fn french_number(n: u32) -> String {
+    match n {
+        0..=16 => FRENCH_ONES[n as usize].to_string(),
+        17..=19 => format!("dix-{}", FRENCH_ONES[(n - 10) as usize]),
+        20..=69 if n.is_multiple_of(10) => FRENCH_TENS[(n / 10) as usize].to_string(),
+        20..=69 if n % 10 == 1 => format!("{} et un", FRENCH_TENS[(n / 10) as usize]),
+        20..=69 => format!(
+            "{}-{}",
+            FRENCH_TENS[(n / 10) as usize],
+            FRENCH_ONES[(n % 10) as usize]
+        ),
+        71 => "soixante et onze".to_string(),
+        70..=79 => format!("soixante-{}", french_number(n - 60)),
+        80 => "quatre-vingts".to_string(),
+        _ => format!("quatre-vingt-{}", french_number(n - 80)),
+    }
+}
+
+impl Locale {
+    /// Finds the locale for a language code, like "en" or "de"
+    pub fn from_code(code: &str) -> Option<Locale> {
+        match code.to_ascii_lowercase().as_str() {
+            "en" => Some(Locale::English),
+            "de" => Some(Locale::German),
+            "fr" => Some(Locale::French),
+            "es" => Some(Locale::Spanish),
+            _ => None,
+        }
+    }
+
+    /// The espeak voice that reads announcements in this locale
+    pub fn voice(&self) -> &'static str {
+        match self {
+            Locale::English => "en",
+            Locale::German => "de",
+            Locale::French => "fr",
+            Locale::Spanish => "es",
+        }
+    }
+
+    /// Spells out a number from 0 to 99, anything larger is left as digits
+    pub fn number_words(&self, n: u32) -> String {
+        if n > 99 {
+            return n.to_string();
+        }
+
+        let ones = (n % 10) as usize;
+        let tens = (n / 10) as usize;
+        match self {
+            Locale::English if n < 20 => ENGLISH_ONES[n as usize].to_string(),
+            Locale::English if ones == 0 => ENGLISH_TENS[tens].to_string(),
+            Locale::English => format!("{}-{}", ENGLISH_TENS[tens], ENGLISH_ONES[ones]),
+
+            Locale::German if n < 20 => GERMAN_ONES[n as usize].to_string(),
+            Locale::German if ones == 0 => GERMAN_TENS[tens].to_string(),
+            Locale::German if ones == 1 => format!("einund{}", GERMAN_TENS[tens]),
+            Locale::German => format!("{}und{}", GERMAN_ONES[ones], GERMAN_TENS[tens]),
+
+            Locale::French => french_number(n),
+
+            Locale::Spanish if n < 30 => SPANISH_ONES[n as usize].to_string(),
+            Locale::Spanish if ones == 0 => SPANISH_TENS[tens].to_string(),
+            Locale::Spanish => format!("{} y {}", SPANISH_TENS[tens], SPANISH_ONES[ones]),
+        }
+    }
+
+    /// Spells out a time of day the way it would be read aloud in this
+    /// locale. English uses a 12-hour clock ("three oh seven in the
+    /// afternoon"), while the others use a 24-hour clock.
+    pub fn time_words(&self, hour: u32, minute: u32) -> String {
+        match self {
+            Locale::English => {
+                let hour_12 = if hour.is_multiple_of(12) {
+                    12
+                } else {
+                    hour % 12
+                };
+                let time = match minute {
+                    0 => format!("{} o'clock", self.number_words(hour_12)),
+                    1..=9 => format!(
+                        "{} oh {}",
+                        self.number_words(hour_12),
+                        self.number_words(minute)
+                    ),
+                    _ => format!(
+                        "{} {}",
+                        self.number_words(hour_12),
+                        self.number_words(minute)
+                    ),
+                };
+
+                let period = match hour {
+                    0..=11 => "in the morning",
+                    12..=17 => "in the afternoon",
+                    _ => "in the evening",
+                };
+
+                format!("{} {}", time, period)
+            }
+
+            Locale::German => {
+                // "Ein Uhr", but the minutes are still counted as "eins"
+                let hour = if hour == 1 {
+                    "ein".to_string()
+                } else {
+                    self.number_words(hour)
+                };
+
+                match minute {
+                    0 => format!("{} Uhr", hour),
+                    _ => format!("{} Uhr {}", hour, self.number_words(minute)),
+                }
+            }
+
+            Locale::French => {
+                // Hours and minutes are both feminine, so "un" becomes "une"
+                let feminine = |n: u32| {
+                    let words = self.number_words(n);
+                    if words.ends_with("un") {
+                        format!("{}e", words)
+                    } else {
+                        words
+                    }
+                };
+
+                let hours = if hour < 2 { "heure" } else { "heures" };
+                match minute {
+                    0 => format!("{} {}", feminine(hour), hours),
+                    _ => format!("{} {} {}", feminine(hour), hours, feminine(minute)),
+                }
+            }
+
+            Locale::Spanish => {
+                let hour = if hour == 1 {
+                    "una".to_string()
+                } else {
+                    self.number_words(hour)
+                };
+
+                match minute {
+                    0 => format!("{} en punto", hour),
+                    _ => format!("{} y {}", hour, self.number_words(minute)),
+                }
+            }
+        }
+    }
+
+    /// Spells out an ordinal number from 1 to 99, like "fourteenth". German
+    /// ordinals take the ending they have after "am" ("vierzehnten"). French
+    /// and Spanish only use an ordinal for the first of the month, so they
+    /// only have "premier" and "primero" and otherwise fall back to the
+    /// cardinal number.
+    pub fn ordinal_words(&self, n: u32) -> String {
+        let words = self.number_words(n);
+        match self {
+            Locale::English => {
+                // Only the last word takes the ending, as in "twenty-first"
+                let (head, last) = match words.rfind('-') {
+                    Some(index) => words.split_at(index + 1),
+                    None => ("", words.as_str()),
+                };
+
+                let last = match last {
+                    "one" => "first".to_string(),
+                    "two" => "second".to_string(),
+                    "three" => "third".to_string(),
+                    "five" => "fifth".to_string(),
+                    "eight" => "eighth".to_string(),
+                    "nine" => "ninth".to_string(),
+                    "twelve" => "twelfth".to_string(),
+                    _ if last.ends_with('y') => format!("{}ieth", &last[..last.len() - 1]),
+                    _ => format!("{}th", last),
+                };
+
+                format!("{}{}", head, last)
+            }
+
+            Locale::German => match n {
+                1 => "ersten".to_string(),
+                3 => "dritten".to_string(),
+                7 => "siebten".to_string(),
+                8 => "achten".to_string(),
+                2..=19 => format!("{}ten", words),
+                _ => format!("{}sten", words),
+            },
+
+            Locale::French if n == 1 => "premier".to_string(),
+            Locale::Spanish if n == 1 => "primero".to_string(),
+            Locale::French | Locale::Spanish => words,
+        }
+    }
+
+    /// Spells out a day of the year the way it would be read aloud in this
+    /// locale, like "October fourteenth" or "quatorze octobre". The month
+    /// counts from 1.
+    pub fn date_words(&self, month: u32, day: u32) -> String {
+        let month = (month.clamp(1, 12) - 1) as usize;
+        let day = self.ordinal_words(day);
+        match self {
+            Locale::English => format!("{} {}", ENGLISH_MONTHS[month], day),
+            Locale::German => format!("{} {}", day, GERMAN_MONTHS[month]),
+            Locale::French => format!("{} {}", day, FRENCH_MONTHS[month]),
+            Locale::Spanish => format!("{} de {}", day, SPANISH_MONTHS[month]),
+        }
+    }
+
+    fn table(&self) -> &'static [(Phrase, &'static str)] {
+        match self {
+            Locale::English => ENGLISH,
+            Locale::German => GERMAN,
+            Locale::French => FRENCH,
+            Locale::Spanish => SPANISH,
+        }
+    }
+
+    /// Translates a phrase, filling in each of its placeholders from the
+    /// arguments. Phrases missing from a table fall back to English.
+    pub fn phrase(&self, phrase: Phrase, args: &[(&str, &str)]) -> String {
+        let template = self
+            .table()
+            .iter()
+            .chain(ENGLISH.iter())
+            .find(|(key, _)| *key == phrase)
+            .map(|(_, template)| *template)
+            .unwrap_or("");
+
+        fill(template, args)
+    }
+}
+
+/// Fills in each of the placeholders in braces within the template from the
+/// arguments
+pub fn fill(template: &str, args: &[(&str, &str)]) -> String {
+    args.iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn english_minutes_under_ten_are_read_with_oh() {
+        assert_eq!(
+            Locale::English.time_words(15, 7),
+            "three oh seven in the afternoon"
+        );
+        assert_eq!(
+            Locale::English.time_words(9, 45),
+            "nine forty-five in the morning"
+        );
+    }
+
+    #[test]
+    fn english_hours_are_read_as_o_clock() {
+        assert_eq!(
+            Locale::English.time_words(9, 0),
+            "nine o'clock in the morning"
+        );
+        assert_eq!(
+            Locale::English.time_words(12, 0),
+            "twelve o'clock in the afternoon"
+        );
+        assert_eq!(
+            Locale::English.time_words(0, 0),
+            "twelve o'clock in the morning"
+        );
+        assert_eq!(
+            Locale::English.time_words(21, 0),
+            "nine o'clock in the evening"
+        );
+    }
+
+    #[test]
+    fn ordinal_dates_are_read_in_each_locale() {
+        assert_eq!(Locale::English.date_words(10, 14), "October fourteenth");
+        assert_eq!(Locale::English.date_words(1, 1), "January first");
+        assert_eq!(Locale::English.date_words(3, 2), "March second");
+        assert_eq!(Locale::English.date_words(5, 12), "May twelfth");
+        assert_eq!(Locale::English.date_words(6, 20), "June twentieth");
+        assert_eq!(Locale::English.date_words(12, 23), "December twenty-third");
+        assert_eq!(Locale::English.date_words(8, 31), "August thirty-first");
+
+        assert_eq!(Locale::German.date_words(10, 14), "vierzehnten Oktober");
+        assert_eq!(Locale::German.date_words(1, 1), "ersten Januar");
+        assert_eq!(Locale::German.date_words(3, 3), "dritten März");
+        assert_eq!(Locale::German.date_words(7, 8), "achten Juli");
+        assert_eq!(
+            Locale::German.date_words(12, 24),
+            "vierundzwanzigsten Dezember"
+        );
+
+        assert_eq!(Locale::French.date_words(10, 14), "quatorze octobre");
+        assert_eq!(Locale::French.date_words(5, 1), "premier mai");
+        assert_eq!(Locale::French.date_words(8, 21), "vingt et un août");
+
+        assert_eq!(Locale::Spanish.date_words(10, 14), "catorce de octubre");
+        assert_eq!(Locale::Spanish.date_words(1, 1), "primero de enero");
+        assert_eq!(
+            Locale::Spanish.date_words(12, 31),
+            "treinta y uno de diciembre"
+        );
+    }
+
+    #[test]
+    fn german_numbers_and_times() {
+        assert_eq!(Locale::German.number_words(21), "einundzwanzig");
+        assert_eq!(Locale::German.number_words(37), "siebenunddreißig");
+        assert_eq!(Locale::German.time_words(1, 0), "ein Uhr");
+        assert_eq!(Locale::German.time_words(15, 7), "fünfzehn Uhr sieben");
+        assert_eq!(Locale::German.time_words(13, 1), "dreizehn Uhr eins");
+    }
+
+    #[test]
+    fn french_numbers_and_times() {
+        assert_eq!(Locale::French.number_words(21), "vingt et un");
+        assert_eq!(Locale::French.number_words(71), "soixante et onze");
+        assert_eq!(Locale::French.number_words(80), "quatre-vingts");
+        assert_eq!(Locale::French.number_words(97), "quatre-vingt-dix-sept");
+        assert_eq!(Locale::French.time_words(1, 0), "une heure");
+        assert_eq!(
+            Locale::French.time_words(21, 21),
+            "vingt et une heures vingt et une"
+        );
+    }
+
+    #[test]
+    fn spanish_numbers_and_times() {
+        assert_eq!(Locale::Spanish.number_words(21), "veintiuno");
+        assert_eq!(Locale::Spanish.number_words(45), "cuarenta y cinco");
+        assert_eq!(Locale::Spanish.time_words(1, 0), "una en punto");
+        assert_eq!(Locale::Spanish.time_words(15, 7), "quince y siete");
+    }
+
+    #[test]
+    fn each_locale_has_a_code_and_a_voice() {
+        for (code, locale) in &[
+            ("en", Locale::English),
+            ("de", Locale::German),
+            ("fr", Locale::French),
+            ("es", Locale::Spanish),
+        ] {
+            assert_eq!(Locale::from_code(code), Some(*locale));
+            assert_eq!(Locale::from_code(&code.to_uppercase()), Some(*locale));
+            assert_eq!(locale.voice(), *code);
+        }
+
+        assert_eq!(Locale::from_code("xx"), None);
+    }
+
+    #[test]
+    fn missing_placeholders_are_left_alone() {
+        let text = Locale::English.phrase(Phrase::WeatherPeriod, &[("time", "noon")]);
+        assert_eq!(text, "At noon, {forecast} ");
+    }
+}
@@ -0,0 +1,8 @@
+//! The playlist engine behind shuffled, for other playout tools that want to
+//! shuffle and reload playlists the same way without running the daemon.
+//!
+//! Only the modules exported here are meant to be used from other crates. Until
+//! shuffled reaches 1.0, changes which break them bump the minor version.
+
+pub mod playlist;
+pub mod rng;
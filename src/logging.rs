@@ -0,0 +1,359 @@
+use crate::config::{LogConfig, LogLevel, LogOutput};
+use chrono::Local;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Logs a message for a component, which goes in front of it in brackets. The
+/// rest of the arguments are the same as format!'s.
+macro_rules! log {
+    ($level:expr, $component:expr, $($arg:tt)+) => {
+        $crate::logging::write($level, $component, format_args!($($arg)+))
+    };
+}
+
+macro_rules! error {
+    ($component:expr, $($arg:tt)+) => {
+        log!($crate::config::LogLevel::Error, $component, $($arg)+)
+    };
+}
+
+macro_rules! warning {
+    ($component:expr, $($arg:tt)+) => {
+        log!($crate::config::LogLevel::Warning, $component, $($arg)+)
+    };
+}
+
+macro_rules! info {
+    ($component:expr, $($arg:tt)+) => {
+        log!($crate::config::LogLevel::Info, $component, $($arg)+)
+    };
+}
+
+macro_rules! debug {
+    ($component:expr, $($arg:tt)+) => {
+        log!($crate::config::LogLevel::Debug, $component, $($arg)+)
+    };
+}
+
+/// The components that messages are logged for, which can each be given
+/// their own level
+pub const COMPONENTS: &[&str] = &[
+    "alert",
+    "api",
+    "availability",
+    "config",
+    "main",
+    "metrics",
+    "monitor",
+    "notify",
+    "panic",
+    "quotas",
+    "rds",
+    "reloader",
+    "requests",
+    "server",
+    "service",
+    "skips",
+    "systemd",
+    "usage",
+    "votes",
+    "watchdog",
+    "weather",
+];
+
+/// Where journald accepts messages in its native protocol
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// A log file that is renamed out of the way once it gets too large, with the
+/// older ones numbered from 1 up
+struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+
+    /// How large (in bytes) the file can get, or 0 for no limit
+    max_bytes: u64,
+    keep: u32,
+}
+
+fn open_append(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Checks whether a descriptor refers to the same file as another, like
+/// standard error does to the log file after --daemon
+fn same_file(fd: libc::c_int, file: &fs::File) -> bool {
+    let metadata = match file.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+
+    // Safe since fstat only writes to the buffer it's given
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return false;
+    }
+
+    stat.st_dev == metadata.dev() && stat.st_ino == metadata.ino()
+}
+
+impl LogFile {
+    fn numbered(&self, number: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+
+    /// Moves each older file up a number, dropping the oldest, and starts a
+    /// new file. Anything else that was writing to the old file, like
+    /// standard output and error with --daemon, follows along.
+    fn rotate(&mut self) -> io::Result<()> {
+        let followers = [libc::STDOUT_FILENO, libc::STDERR_FILENO]
+            .iter()
+            .copied()
+            .filter(|fd| same_file(*fd, &self.file))
+            .collect::<Vec<_>>();
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for number in (1..self.keep).rev() {
+                match fs::rename(self.numbered(number), self.numbered(number + 1)) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => (),
+                }
+            }
+
+            fs::rename(&self.path, self.numbered(1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        for fd in followers {
+            // Safe since both descriptors are open for the length of the call
+            if unsafe { libc::dup2(self.file.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        // The length is read from the file itself, since it's shared with
+        // anything else that writes to standard error
+        let size = self.file.metadata()?.len();
+        if self.max_bytes > 0 && size > 0 && size + line.len() as u64 >= self.max_bytes {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)
+    }
+}
+
+enum Sink {
+    Stderr,
+    Journald(UnixDatagram),
+    File(LogFile),
+}
+
+struct Logger {
+    level: LogLevel,
+    components: HashMap<String, LogLevel>,
+    sink: Sink,
+
+    /// What the journal lists the messages as coming from
+    identifier: String,
+}
+
+/// The log as set up from the configuration. Until then, everything at info
+/// and above goes to standard error.
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warning => "WARNING",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        };
+
+        f.pad(name)
+    }
+}
+
+/// The syslog priorities that journald expects for each level
+fn journald_priority(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warning => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug => 7,
+    }
+}
+
+/// Adds a field in journald's native format. Values with newlines in them
+/// have to be given with their length in front instead of after an equals
+/// sign.
+fn journald_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+    buffer.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buffer.push(b'\n');
+        buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buffer.push(b'=');
+    }
+
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(b'\n');
+}
+
+fn send_journald(
+    socket: &UnixDatagram,
+    identifier: &str,
+    level: LogLevel,
+    component: &str,
+    message: &str,
+) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    journald_field(
+        &mut buffer,
+        "PRIORITY",
+        &journald_priority(level).to_string(),
+    );
+    journald_field(&mut buffer, "SYSLOG_IDENTIFIER", identifier);
+    journald_field(&mut buffer, "SHUFFLED_COMPONENT", component);
+    journald_field(
+        &mut buffer,
+        "MESSAGE",
+        &format!("[{}] {}", component, message),
+    );
+
+    socket.send(&buffer).map(|_| ())
+}
+
+/// Sets up the log as the configuration says. The log file is opened here, so
+/// this should be done before --daemon forks so that any problem with it is
+/// reported on the terminal.
+pub fn init(config: &LogConfig, log_file: Option<&Path>, identifier: &str) -> Result<(), String> {
+    let sink = match (config.output, log_file) {
+        (LogOutput::Stderr, _) => Sink::Stderr,
+        (LogOutput::Journald, _) => {
+            let socket = UnixDatagram::unbound()
+                .and_then(|socket| socket.connect(JOURNALD_SOCKET).map(|_| socket))
+                .map_err(|error| {
+                    format!(
+                        "Could not connect to journald at {}: {}",
+                        JOURNALD_SOCKET, error
+                    )
+                })?;
+            Sink::Journald(socket)
+        }
+        (LogOutput::File, Some(path)) => {
+            let file = open_append(path).map_err(|error| {
+                format!("Could not open log file {}: {}", path.display(), error)
+            })?;
+
+            Sink::File(LogFile {
+                path: path.to_path_buf(),
+                file,
+                max_bytes: config.rotate_size as u64 * 1024 * 1024,
+                keep: config.rotate_keep,
+            })
+        }
+        (LogOutput::File, None) => return Err("There is no log file to write to".to_string()),
+    };
+
+    *LOGGER.lock().unwrap_or_else(|error| error.into_inner()) = Some(Logger {
+        level: config.level,
+        components: config.components.clone(),
+        sink,
+        identifier: identifier.to_string(),
+    });
+
+    Ok(())
+}
+
+/// Takes on the levels from a re-read configuration, keeping the log where it
+/// is. The log is set up to go to standard error if it hasn't been already.
+pub fn set_levels(config: &LogConfig) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|error| error.into_inner());
+    match logger.as_mut() {
+        Some(logger) => {
+            logger.level = config.level;
+            logger.components = config.components.clone();
+        }
+        None => {
+            *logger = Some(Logger {
+                level: config.level,
+                components: config.components.clone(),
+                sink: Sink::Stderr,
+                identifier: String::new(),
+            })
+        }
+    }
+}
+
+/// Writes a message if its component logs at its level. Use the macros
+/// instead of calling this directly.
+pub fn write(level: LogLevel, component: &str, message: fmt::Arguments) {
+    // A thread that panicked while logging can't have left the logger in a
+    // state that would keep it from logging the panic
+    let mut logger = LOGGER.lock().unwrap_or_else(|error| error.into_inner());
+    let threshold = logger
+        .as_ref()
+        .map(|logger| {
+            logger
+                .components
+                .get(component)
+                .copied()
+                .unwrap_or(logger.level)
+        })
+        .unwrap_or(LogLevel::Info);
+
+    if level > threshold {
+        return;
+    }
+
+    let message = message.to_string();
+    let line = || {
+        format!(
+            "{} {:<7} [{}] {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            level,
+            component,
+            message
+        )
+    };
+
+    // If the log can't be written where it's meant to go then standard error
+    // is better than nothing
+    let result = match logger.as_mut() {
+        Some(Logger {
+            sink: Sink::Journald(socket),
+            identifier,
+            ..
+        }) => send_journald(socket, identifier, level, component, &message),
+        Some(Logger {
+            sink: Sink::File(file),
+            ..
+        }) => file.write_line(&line()),
+        _ => {
+            eprintln!("{}", line());
+            Ok(())
+        }
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", line());
+        eprintln!("Could not write to log: {}", error);
+    }
+}
@@ -0,0 +1,189 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+/// How long each block that loudness is measured over lasts, in seconds
+const BLOCK_SEC: f64 = 0.4;
+
+/// How far apart the blocks start, which has them overlap by 75%
+const STEP_SEC: f64 = 0.1;
+
+/// Blocks quieter than this are silence and aren't counted at all
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Blocks this much quieter than the rest are pauses and aren't counted
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// A second-order filter, in the direct form I with a0 normalized to 1
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+}
+
+impl Biquad {
+    /// Builds the two stages of the K-weighting filter for the sample rate: a
+    /// shelf modelling the head's effect on high frequencies and a high-pass
+    /// cutting out the lows. These are worked out from the analog filters that
+    /// BS.1770 is based on, since it only lists coefficients for 48 kHz.
+    fn k_weighting(rate: f64) -> (Self, Self) {
+        let (corner, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * corner / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+
+        let (corner, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * corner / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+
+        (shelf, high_pass)
+    }
+
+    fn apply(&self, samples: &[f64]) -> Vec<f64> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        samples
+            .iter()
+            .map(|&x| {
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[1] * y1
+                    - self.a[2] * y2;
+                x2 = x1;
+                x1 = x;
+                y2 = y1;
+                y1 = y;
+                y
+            })
+            .collect()
+    }
+}
+
+/// Reads the samples out of a 16-bit PCM WAV file, mixing the channels down
+/// to one, along with the sample rate
+fn read_wav(path: &Path) -> Result<(Vec<f64>, u32), String> {
+    let data =
+        fs::read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(format!("{} is not a WAV file", path.display()));
+    }
+
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+
+        // espeak doesn't know the length up front when writing, so the data
+        // chunk can claim more than the file holds
+        let body = &data[offset + 8..data.len().min(offset + 8 + size)];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let tag = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                if tag != 1 || bits != 16 || channels == 0 {
+                    return Err(format!("{} is not 16-bit PCM", path.display()));
+                }
+
+                format = Some((channels as usize, rate));
+            }
+            b"data" => {
+                let (channels, rate) = format
+                    .ok_or_else(|| format!("{} has no format before its data", path.display()))?;
+
+                let samples = body
+                    .chunks_exact(2 * channels)
+                    .map(|frame| {
+                        let total = frame
+                            .chunks_exact(2)
+                            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f64)
+                            .sum::<f64>();
+                        total / channels as f64 / 32768.0
+                    })
+                    .collect();
+
+                return Ok((samples, rate));
+            }
+            _ => (),
+        }
+
+        // Chunks are padded to an even length
+        offset += 8 + size + (size % 2);
+    }
+
+    Err(format!("{} has no audio data", path.display()))
+}
+
+/// Converts the mean square of a block into LUFS
+fn to_lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Measures the integrated loudness of a mono signal, following ITU-R BS.1770:
+/// the signal is K-weighted, its power is taken over overlapping blocks, and
+/// the silent and quiet blocks are gated out before averaging the rest.
+/// Returns None if the whole signal is silent.
+fn integrated_loudness(samples: &[f64], rate: u32) -> Option<f64> {
+    let rate = rate as f64;
+    let (shelf, high_pass) = Biquad::k_weighting(rate);
+    let weighted = high_pass.apply(&shelf.apply(samples));
+
+    let block = (BLOCK_SEC * rate) as usize;
+    let step = (STEP_SEC * rate) as usize;
+    if block == 0 || step == 0 || weighted.len() < block {
+        return None;
+    }
+
+    let powers = (0..=(weighted.len() - block) / step)
+        .map(|index| {
+            let start = index * step;
+            weighted[start..start + block]
+                .iter()
+                .map(|sample| sample * sample)
+                .sum::<f64>()
+                / block as f64
+        })
+        .filter(|power| to_lufs(*power) > ABSOLUTE_GATE_LUFS)
+        .collect::<Vec<_>>();
+
+    if powers.is_empty() {
+        return None;
+    }
+
+    let threshold = to_lufs(powers.iter().sum::<f64>() / powers.len() as f64) + RELATIVE_GATE_LU;
+    let gated = powers
+        .into_iter()
+        .filter(|power| to_lufs(*power) > threshold)
+        .collect::<Vec<_>>();
+
+    Some(to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+/// Works out how many decibels a mono WAV file has to be raised (or lowered)
+/// by for it to reach the target loudness once it is copied into each of the
+/// output's channels. BS.1770 adds the channels together, so a stereo copy is
+/// 3 LU louder than the original.
+pub fn gain_to_target(path: &Path, target_lufs: f64, channels: u32) -> Result<f64, String> {
+    let (samples, rate) = read_wav(path)?;
+    let loudness = integrated_loudness(&samples, rate)
+        .ok_or_else(|| format!("{} is silent", path.display()))?;
+
+    Ok(target_lufs - (loudness + 10.0 * (channels as f64).log10()))
+}
@@ -1,20 +1,138 @@
+// First, so that its macros can be used by every module after it
+#[macro_use]
+mod logging;
+
+mod alerts;
+mod api;
+mod availability;
+mod client;
 mod config;
+mod daemon;
+mod diagnostics;
+mod doctor;
+mod environment;
+mod events;
+mod http;
+mod i18n;
+mod loudness;
+mod metrics;
+mod monitor;
+mod notify;
+mod parts;
+mod privileges;
+mod quotas;
+mod rds;
+mod reloader;
+mod requests;
+mod retirement;
+mod rules;
+mod sd_notify;
 mod server;
+mod signals;
+mod simulate;
+mod skips;
+mod state;
+mod stations;
+mod storage;
+#[cfg(feature = "systemd-dbus")]
+mod systemd;
+mod tags;
+mod usage;
 mod utils;
+mod votes;
+mod waker;
 mod watchdog;
 mod weather;
 
 use std::fs;
-use std::path::PathBuf;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::SystemTime;
+
+/// Prints whether a configuration is valid, which for one with a weather task
+/// includes whether weather.gov knows its region, and returns whether it is
+fn check_config(config_path: &Path, config: &config::Config) -> Result<bool, String> {
+    match config.station.as_ref() {
+        Some(station) => println!(
+            "Configuration of station {} at {} is valid",
+            station,
+            config_path.display()
+        ),
+        None => println!("Configuration at {} is valid", config_path.display()),
+    }
+
+    for warning in &config.warnings {
+        println!("Warning: {}", warning);
+    }
+
+    if !config.service.weather_enabled {
+        return Ok(true);
+    }
+
+    let http_client = http::build_client(&config.http)?;
+    let region = &config.special_weather.region;
+    let success = match weather::check_region(&http_client, region) {
+        weather::RegionCheck::Valid => {
+            println!("Weather region {} is valid", region);
+            true
+        }
+        weather::RegionCheck::Invalid(reason) => {
+            println!("Weather region {} was rejected: {}", region, reason);
+            false
+        }
+        weather::RegionCheck::Unknown(reason) => {
+            println!("Could not check weather region {}: {}", region, reason);
+            false
+        }
+    };
+
+    Ok(success)
+}
 
 fn main() -> Result<(), String> {
     let mut config_path = PathBuf::from("/etc/shuffled.conf");
-    for arg in std::env::args().skip(1) {
-        config_path = PathBuf::from(arg);
+    let mut probe_only = false;
+    let mut check_only = false;
+    let mut doctor_only = false;
+    let mut daemon = false;
+    let mut station = None;
+    let mut client_args = None;
+    let mut simulate_args = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "probe" {
+            probe_only = true;
+        } else if arg == "check-config" {
+            check_only = true;
+        } else if arg == "doctor" {
+            doctor_only = true;
+        } else if arg == "--daemon" {
+            daemon = true;
+        } else if arg == "--station" {
+            station = Some(args.next().ok_or("--station needs the name of a station")?);
+        } else if arg == "client" {
+            // Everything after client belongs to the command being sent
+            client_args = Some(args.collect::<Vec<_>>());
+            break;
+        } else if arg == "simulate" {
+            simulate_args = Some(args.collect::<Vec<_>>());
+            break;
+        } else {
+            config_path = PathBuf::from(arg);
+        }
+    }
+
+    // The doctor reports a configuration that doesn't parse along with
+    // everything else, so it reads the file itself
+    if doctor_only {
+        let success = doctor::run(&config_path, station.as_deref());
+        process::exit(if success { 0 } else { 1 });
     }
 
-    eprintln!("Loading configuration...");
+    info!("main", "Loading configuration...");
     let mut config_file = fs::File::open(&config_path).or_else(|error| {
         Err(format!(
             "Could not open configuration at {}: {}",
@@ -23,23 +141,367 @@ fn main() -> Result<(), String> {
         ))
     })?;
 
-    let config = config::parse(&mut config_file)?;
+    let configs = config::parse_stations(&mut config_file)?;
+
+    // Without --station, a file with stations runs each of them in a process
+    // of its own
+    let serving = client_args.is_none() && simulate_args.is_none() && !probe_only && !check_only;
+    if station.is_none() && configs.iter().any(|config| config.station.is_some()) {
+        if check_only {
+            let results = configs
+                .iter()
+                .map(|config| check_config(&config_path, config))
+                .collect::<Result<Vec<_>, _>>()?;
+            process::exit(if results.iter().all(|valid| *valid) {
+                0
+            } else {
+                1
+            });
+        }
+
+        if serving {
+            return stations::run_stations(&config_path, daemon, configs);
+        }
+    }
+
+    let config = config::select_station(configs, station.as_deref())?;
+
+    // Only the server itself logs anywhere other than the terminal. The log
+    // file is opened here, before --daemon forks, so that a log file which
+    // can't be written is reported on the terminal.
+    if serving {
+        logging::init(
+            &config.service.log,
+            config.service.log_file.as_deref(),
+            &config.service.instance_name,
+        )?;
+    } else {
+        logging::set_levels(&config.service.log);
+    }
+
+    if !check_only {
+        for warning in &config.warnings {
+            warning!("config", "{}", warning);
+        }
+    }
+
+    if let Some(client_args) = client_args {
+        let success = client::run_client(
+            &config.service.ipc_socket,
+            config.service.auth_token.as_deref(),
+            &client_args,
+        )?;
+        process::exit(if success { 0 } else { 1 });
+    }
+
+    if let Some(simulate_args) = simulate_args {
+        return simulate::run_simulation(&config_path, config, &simulate_args);
+    }
+
+    if probe_only {
+        if !config.service.watchdog_enabled {
+            return Err("Cannot probe, the watchdog task is not enabled".to_string());
+        }
+
+        let success = watchdog::probe_once(&config.watchdog);
+        process::exit(if success { 0 } else { 1 });
+    }
+
+    if check_only {
+        let success = check_config(&config_path, &config)?;
+        process::exit(if success { 0 } else { 1 });
+    }
+
+    // The PID file is checked before forking so that a second copy says why
+    // it isn't starting on the terminal, rather than in the log. A station
+    // leaves it to the process that started all of them.
+    let pid_file = match station {
+        Some(_) => None,
+        None => config.service.pid_file.clone(),
+    };
+    if let Some(pid_file) = pid_file.as_ref() {
+        daemon::check_pid_file(pid_file)?;
+    }
+
+    // Forking only carries over the thread that forks, so this has to happen
+    // before anything starts a thread, including the HTTP client
+    if daemon {
+        daemon::daemonize(config.service.log_file.as_deref())?;
+    }
+
+    if let Some(pid_file) = pid_file.as_ref() {
+        daemon::write_pid_file(pid_file)?;
+    }
+
+    let environment = environment::Environment::detect(&config_path, &config);
+    let startup = config.startup_settings();
+
+    // The sockets are bound first so that they can be in places only root can
+    // write to, and root is given up before any thread starts so that every
+    // worker runs as the new user. That includes the HTTP client, which starts
+    // a thread of its own.
+    let listeners = server::bind_listeners(&config.service)?;
+    let api_listener = match config.api.as_ref() {
+        Some(api_config) => match TcpListener::bind(api_config.listen) {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                let _ = fs::remove_file(&config.service.ipc_socket);
+                return Err(format!(
+                    "Could not listen for API requests on {}: {}",
+                    api_config.listen, error
+                ));
+            }
+        },
+        None => None,
+    };
+
+    let metrics_listener = match config.metrics.as_ref() {
+        Some(metrics_config) => match TcpListener::bind(metrics_config.listen) {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                let _ = fs::remove_file(&config.service.ipc_socket);
+                return Err(format!(
+                    "Could not listen for metrics scrapes on {}: {}",
+                    metrics_config.listen, error
+                ));
+            }
+        },
+        None => None,
+    };
+
+    let user = config.service.user.as_deref();
+    let group = config.service.group.as_deref();
+    if user.is_some() || group.is_some() {
+        info!(
+            "main",
+            "Switching to user {} and group {}...",
+            user.unwrap_or("(unchanged)"),
+            group.unwrap_or("(the user's own)")
+        );
+
+        // The workers talk to the server over its socket, so it has to belong
+        // to the new user
+        let owned = [config.service.ipc_socket.as_path()];
+        if let Err(error) = privileges::drop_privileges(user, group, &owned) {
+            let _ = fs::remove_file(&config.service.ipc_socket);
+            return Err(error);
+        }
+    }
+
+    // The environment is only safe to change while this is the only thread
+    let notifier = sd_notify::Notifier::from_environment();
+
+    let diagnostics = Arc::new(Mutex::new(diagnostics::Diagnostics::default()));
+    diagnostics::install_panic_hook(diagnostics.clone());
+    let metrics = Arc::new(Mutex::new(metrics::Metrics::new()));
+
     let watchdog_config = config.watchdog;
     let weather_config = config.special_weather;
-    let special_working_dir = config.special_base.working_dir.to_path_buf();
+    let special_config = config.special_base.clone();
+    let last_request = Arc::new(Mutex::new(SystemTime::now()));
+    let last_restart = Arc::new(Mutex::new(None));
+    let http_client = http::build_client(&config.http)?;
+    let mut workers = waker::Workers::default();
+    let mut threads = Vec::new();
+    let events = events::EventBus::new();
 
+    // Each worker gets its own thread and uses blocking I/O. There are only
+    // ever a handful of them and they spend nearly all of their time asleep,
+    // so an async runtime wouldn't save anything worth the extra dependencies.
+    // Timeouts are set on each socket and on the shared HTTP client instead.
     if config.service.watchdog_enabled {
-        eprintln!("Spawning watchdog worker...");
-        thread::spawn(move || watchdog::watchdog_worker(watchdog_config));
+        info!("main", "Spawning watchdog worker...");
+        let alerter = alerts::Alerter::new(config.alerts.clone(), http_client.clone());
+        let watchdog_last_request = last_request.clone();
+        let watchdog_last_restart = last_restart.clone();
+        let waker = waker::Waker::new();
+        let watchdog_events = events.clone();
+        workers.watchdog = Some(waker.clone());
+        let running = workers.track("watchdog");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            watchdog::watchdog_worker(
+                watchdog_config,
+                alerter,
+                watchdog_last_request,
+                watchdog_last_restart,
+                waker,
+                watchdog_events,
+            )
+        }));
     }
 
     if config.service.weather_enabled {
-        eprintln!("Spawning weather worker...");
-        thread::spawn(move || weather::weather_worker(special_working_dir, weather_config));
+        // A region that weather.gov doesn't know would only ever fail to
+        // fetch, but the API being down shouldn't keep everything else from
+        // starting
+        match weather::check_region(&http_client, &weather_config.region) {
+            weather::RegionCheck::Valid => (),
+            weather::RegionCheck::Invalid(reason) => {
+                return Err(format!(
+                    "weather.gov rejected 'weather.region' {}: {}",
+                    weather_config.region, reason
+                ))
+            }
+            weather::RegionCheck::Unknown(reason) => warning!(
+                "weather",
+                "Could not check region {}, starting anyway: {}",
+                weather_config.region,
+                reason
+            ),
+        }
+
+        info!("main", "Spawning weather worker...");
+        let weather_client = http_client.clone();
+        let waker = waker::Waker::new();
+        let weather_events = events.clone();
+        workers.weather = Some(waker.clone());
+        let running = workers.track("weather");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            weather::weather_worker(
+                special_config,
+                weather_config,
+                weather_client,
+                waker,
+                weather_events,
+            )
+        }));
+    }
+
+    if config.service.monitor_enabled {
+        info!("main", "Spawning monitor worker...");
+        let alerter = alerts::Alerter::new(config.alerts.clone(), http_client.clone());
+        let monitor_config = config.monitor;
+        let waker = waker::Waker::new();
+        let monitor_events = events.clone();
+        workers.monitor = Some(waker.clone());
+        let running = workers.track("monitor");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            monitor::monitor_worker(monitor_config, alerter, waker, monitor_events)
+        }));
     }
 
-    eprintln!("Spawning IPC worker...");
-    server::server_worker(config.service, config.special_base);
+    if let Some(rds_config) = config.rds {
+        info!("main", "Spawning RDS worker...");
+        let rds_events = events.subscribe();
+        let running = workers.track("rds");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            rds::rds_worker(rds_config, rds_events)
+        }));
+    }
+
+    if let Some(notify_config) = config.notify {
+        info!("main", "Spawning notifier worker...");
+        let notify_client = http_client.clone();
+        let notify_events = events.subscribe();
+        let running = workers.track("notify");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            notify::notifier_worker(notify_config, notify_client, notify_events)
+        }));
+    }
+
+    if let (Some(api_config), Some(listener)) = (config.api, api_listener) {
+        info!("main", "Spawning API worker...");
+        let api_events = events.subscribe();
+        let running = workers.track("api");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            api::api_worker(api_config, listener, api_events)
+        }));
+    }
+
+    if let (Some(metrics_config), Some(listener)) = (config.metrics, metrics_listener) {
+        info!(
+            "main",
+            "Spawning metrics worker on {}...", metrics_config.listen
+        );
+        let worker_metrics = metrics.clone();
+        let metrics_events = events.subscribe();
+        let running = workers.track("metrics");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            metrics::metrics_worker(worker_metrics, listener, metrics_events)
+        }));
+    }
+
+    if let Some(stats_file) = config.service.stats_file.clone() {
+        info!("main", "Spawning usage worker...");
+        let usage_events = events.subscribe();
+        let running = workers.track("usage");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            usage::usage_worker(stats_file, usage_events)
+        }));
+    }
+
+    if let Some(delay) = config.service.auto_reload {
+        info!("main", "Spawning reload worker...");
+        let playlist_dir = config.service.playlist_dir.clone();
+        let ipc_socket = config.service.ipc_socket.clone();
+        let auth_token = config.service.auth_token.clone();
+        let reload_events = events.subscribe();
+        let running = workers.track("reloader");
+        threads.push(thread::spawn(move || {
+            let _running = running;
+            reloader::reload_worker(
+                &playlist_dir,
+                &ipc_socket,
+                auth_token.as_deref(),
+                delay,
+                reload_events,
+            )
+        }));
+    }
+
+    info!("main", "Spawning IPC worker...");
+    server::server_worker(
+        listeners,
+        config.service,
+        config.special_base,
+        server::SchedulingConfig {
+            availability: config.availability,
+            rules: config.rules,
+            quotas: config.quotas,
+            ramp: config.ramp,
+            playlist_end: config.playlist_end,
+            parts: config.parts,
+            retirement: config.retirement,
+            votes: config.votes,
+            requests: config.requests,
+        },
+        server::SharedState {
+            events,
+            last_request,
+            last_restart,
+            workers,
+            environment,
+            diagnostics,
+            metrics,
+        },
+        startup,
+        notifier,
+    );
+
+    // The server only returns once it has told the workers to stop, or if it
+    // couldn't start at all
+    for thread in threads {
+        let _ = thread.join();
+    }
+
+    if let Some(pid_file) = pid_file.as_ref() {
+        if let Err(error) = fs::remove_file(pid_file) {
+            warning!(
+                "main",
+                "Could not remove PID file {}: {}",
+                pid_file.display(),
+                error
+            );
+        }
+    }
 
     Ok(())
 }
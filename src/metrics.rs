@@ -0,0 +1,313 @@
+use crate::api::{self, HttpError};
+use crate::events::{Event, WatchdogEvent};
+use crate::skips;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How long the worker waits for a scrape before checking for new events
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a scraper can take to send its request, and to read the response
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The counters and gauges that /metrics reports. The server keeps the ones
+/// about its own state up to date, and the metrics worker counts the rest
+/// from the events that the other workers publish.
+#[derive(Debug)]
+pub struct Metrics {
+    started: SystemTime,
+
+    /// Entries returned by next-track, indexed by their kind
+    served: BTreeMap<&'static str, u64>,
+
+    /// Tracks passed over by next-track, indexed by the reason
+    skipped: BTreeMap<&'static str, u64>,
+
+    /// IPC commands that the server has handled, indexed by their name
+    commands: BTreeMap<&'static str, u64>,
+
+    /// How many tracks are on each playlist, as of the last command
+    playlists: BTreeMap<String, usize>,
+
+    watchdog_restarts: u64,
+
+    /// Forecasts that couldn't be fetched, by whether weather.gov refused or
+    /// the request failed
+    weather_failures: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    /// Starts every counter at zero. The counters whose labels are known
+    /// ahead of time are listed from the start, so that they show up before
+    /// they are first counted.
+    pub fn new() -> Self {
+        Metrics {
+            started: SystemTime::now(),
+            served: BTreeMap::new(),
+            skipped: skips::REASONS
+                .iter()
+                .map(|reason| (reason.name(), 0))
+                .collect(),
+            commands: BTreeMap::new(),
+            playlists: BTreeMap::new(),
+            watchdog_restarts: 0,
+            weather_failures: [("error", 0), ("rate-limited", 0)]
+                .iter()
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Counts a command that the server has handled
+    pub fn record_command(&mut self, command: &'static str) {
+        *self.commands.entry(command).or_insert(0) += 1;
+    }
+
+    /// Replaces the playlist sizes with the server's current ones
+    pub fn set_playlists<'a>(&mut self, playlists: impl Iterator<Item = (&'a String, usize)>) {
+        self.playlists.clear();
+        for (name, size) in playlists {
+            self.playlists.insert(name.to_string(), size);
+        }
+    }
+
+    /// Counts whatever an event says happened, if it is something that is
+    /// reported
+    fn record_event(&mut self, event: &Event) {
+        match event {
+            Event::TrackServed { requested, .. } => {
+                let kind = if *requested { "request" } else { "music" };
+                *self.served.entry(kind).or_insert(0) += 1;
+            }
+            Event::SpecialServed { kind, .. } => *self.served.entry(kind).or_insert(0) += 1,
+            Event::TrackSkipped { reason, .. } => {
+                *self.skipped.entry(reason.name()).or_insert(0) += 1
+            }
+            Event::Watchdog(WatchdogEvent::Restarted) => self.watchdog_restarts += 1,
+            Event::WeatherRateLimited(_) => {
+                *self.weather_failures.entry("rate-limited").or_insert(0) += 1
+            }
+            Event::WeatherFetchFailed => *self.weather_failures.entry("error").or_insert(0) += 1,
+            _ => (),
+        }
+    }
+
+    /// Writes every metric out in the Prometheus text format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let started = self
+            .started
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        write_header(
+            &mut text,
+            "shuffled_start_time_seconds",
+            "gauge",
+            "When shuffled started, in seconds since the epoch",
+        );
+        let _ = writeln!(text, "shuffled_start_time_seconds {}", started);
+
+        write_labeled(
+            &mut text,
+            "shuffled_tracks_served_total",
+            "counter",
+            "Entries returned by next-track",
+            "kind",
+            self.served.iter().map(|(kind, count)| (*kind, *count)),
+        );
+
+        write_labeled(
+            &mut text,
+            "shuffled_tracks_skipped_total",
+            "counter",
+            "Tracks passed over by next-track",
+            "reason",
+            self.skipped.iter().map(|(reason, count)| (*reason, *count)),
+        );
+
+        write_labeled(
+            &mut text,
+            "shuffled_rpc_commands_total",
+            "counter",
+            "IPC commands handled by the server",
+            "command",
+            self.commands
+                .iter()
+                .map(|(command, count)| (*command, *count)),
+        );
+
+        write_labeled(
+            &mut text,
+            "shuffled_playlist_tracks",
+            "gauge",
+            "Tracks on each playlist",
+            "playlist",
+            self.playlists
+                .iter()
+                .map(|(name, size)| (name.as_str(), *size as u64)),
+        );
+
+        write_header(
+            &mut text,
+            "shuffled_watchdog_restarts_total",
+            "counter",
+            "Times the watchdog restarted the stream",
+        );
+        let _ = writeln!(
+            text,
+            "shuffled_watchdog_restarts_total {}",
+            self.watchdog_restarts
+        );
+
+        write_labeled(
+            &mut text,
+            "shuffled_weather_fetch_failures_total",
+            "counter",
+            "Forecasts that could not be fetched from weather.gov",
+            "reason",
+            self.weather_failures
+                .iter()
+                .map(|(reason, count)| (*reason, *count)),
+        );
+
+        text
+    }
+}
+
+fn write_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+/// Writes a metric with one label, with a line for each of its values
+fn write_labeled<'a>(
+    text: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    write_header(text, name, kind, help);
+    for (value, count) in values {
+        let _ = writeln!(
+            text,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(value),
+            count
+        );
+    }
+}
+
+/// Escapes a label value the way the text format expects, since playlist
+/// names can contain anything
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn send_response(
+    mut stream: &TcpStream,
+    code: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Error",
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        code,
+        reason,
+        content_type,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Answers a single scrape. Anything but a GET of /metrics is turned away.
+fn process_client(metrics: &Mutex<Metrics>, stream: TcpStream) {
+    if let Err(error) = stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+    {
+        warning!("metrics", "Could not set socket timeout: {}", error);
+        return;
+    }
+
+    let result = api::read_request(&stream).and_then(|request| match request.method.as_str() {
+        "GET" if request.path == "/metrics" => Ok(()),
+        "GET" => Err(HttpError(404, "not-found")),
+        _ => Err(HttpError(405, "method-not-allowed")),
+    });
+
+    let result = match result {
+        Ok(()) => {
+            let text = metrics.lock().unwrap().render();
+            send_response(&stream, 200, "text/plain; version=0.0.4", text.as_bytes())
+        }
+        Err(HttpError(code, status)) => send_response(
+            &stream,
+            code,
+            "application/json",
+            format!("{{\"status\": \"{}\"}}\n", status).as_bytes(),
+        ),
+    };
+
+    if let Err(error) = result {
+        warning!("metrics", "Could not send response: {}", error);
+    }
+}
+
+/// Serves /metrics one scrape at a time until the server shuts down, counting
+/// the events published in between
+pub fn metrics_worker(
+    metrics: Arc<Mutex<Metrics>>,
+    listener: TcpListener,
+    events: Receiver<Event>,
+) {
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(Event::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                Ok(event) => metrics.lock().unwrap().record_event(&event),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        match api::wait_for_client(&listener, POLL_INTERVAL) {
+            Ok(false) => continue,
+            Ok(true) => (),
+            Err(error) => {
+                error!("metrics", "Could not wait for scrapes: {}", error);
+                return;
+            }
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => process_client(&metrics, stream),
+            Err(error) => warning!("metrics", "Could not accept scrape: {}", error),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::alerts::Alerter;
+use crate::config::MonitorConfig;
+use crate::events::{Event, EventBus, MonitorEvent};
+use crate::waker::{Waker, Wakeup};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// How long the library can take to list its first entry before it is
+/// considered unresponsive, as a hard NFS mount will block forever when the
+/// server is gone
+const LIBRARY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Gets how many megabytes are available to unprivileged users on the
+/// filesystem holding the path
+fn free_space_mb(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL"))?;
+
+    // Safe since the path is NUL-terminated and the struct is only read after
+    // statvfs has filled it in
+    let stats = unsafe {
+        let mut stats: libc::statvfs = mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stats) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stats
+    };
+
+    let free_bytes = (stats.f_bavail as u64).saturating_mul(stats.f_frsize as u64);
+    Ok(free_bytes / (1024 * 1024))
+}
+
+/// Checks that the music library is present and answering, which means that it
+/// is a directory with at least one entry in it. An NFS mount which has gone
+/// away either leaves behind an empty mount point or blocks, so the check is
+/// done on another thread and given up on after a timeout.
+pub fn check_library(library: &Path) -> Result<(), String> {
+    let (sender, receiver) = mpsc::channel();
+    let path = PathBuf::from(library);
+
+    // If the mount is hung this thread is stuck until it comes back, but it
+    // doesn't hold anything that the rest of the daemon needs
+    thread::spawn(move || {
+        let result = fs::read_dir(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|mut entries| match entries.next() {
+                Some(Ok(_)) => Ok(()),
+                Some(Err(error)) => Err(error.to_string()),
+                None => Err("directory is empty".to_string()),
+            });
+
+        let _ = sender.send(result);
+    });
+
+    match receiver.recv_timeout(LIBRARY_TIMEOUT) {
+        Ok(result) => result,
+        Err(_) => Err(format!(
+            "did not respond within {} seconds",
+            LIBRARY_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+/// Periodically checks the free space in the working directory and whether
+/// the music library is available, raising an alert when either becomes a
+/// problem and logging when it recovers
+pub fn monitor_worker(config: MonitorConfig, alerter: Alerter, waker: Waker, events: EventBus) {
+    let interval = Duration::from_secs(config.interval as u64 * 60);
+    let mut low_space_reported = false;
+    let mut library_reported = false;
+
+    loop {
+        match free_space_mb(&config.working_dir) {
+            Ok(free_mb) if free_mb < config.min_free_mb => {
+                if !low_space_reported {
+                    alerter.raise(
+                        "monitor",
+                        &format!(
+                            "Only {} MB free in {}",
+                            free_mb,
+                            config.working_dir.display()
+                        ),
+                    );
+                    events.publish(Event::Monitor(MonitorEvent::LowDiskSpace(free_mb)));
+                    low_space_reported = true;
+                }
+            }
+            Ok(free_mb) => {
+                if low_space_reported {
+                    info!(
+                        "monitor",
+                        "{} MB free in {} again",
+                        free_mb,
+                        config.working_dir.display()
+                    );
+                    events.publish(Event::Monitor(MonitorEvent::DiskSpaceOk));
+                    low_space_reported = false;
+                }
+            }
+            Err(error) => warning!(
+                "monitor",
+                "Could not check free space in {}: {}",
+                config.working_dir.display(),
+                error
+            ),
+        }
+
+        if let Some(library) = config.library_dir.as_ref() {
+            match check_library(library) {
+                Ok(()) if library_reported => {
+                    info!(
+                        "monitor",
+                        "Library at {} is available again",
+                        library.display()
+                    );
+                    events.publish(Event::Monitor(MonitorEvent::LibraryAvailable));
+                    library_reported = false;
+                }
+                Err(error) if !library_reported => {
+                    alerter.raise(
+                        "monitor",
+                        &format!("Library at {} is unavailable: {}", library.display(), error),
+                    );
+                    events.publish(Event::Monitor(MonitorEvent::LibraryUnavailable(error)));
+                    library_reported = true;
+                }
+                _ => (),
+            }
+        }
+
+        if waker.wait(interval) == Wakeup::Shutdown {
+            return;
+        }
+    }
+}
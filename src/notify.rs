@@ -0,0 +1,304 @@
+use crate::config::{IrcConfig, NotifyConfig};
+use crate::events::Event;
+use crate::i18n;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long the worker waits for a notice before checking on the IRC
+/// connection, which has to answer the server's pings to stay connected
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long connecting and registering with the IRC server can take
+const IRC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fills in the template for an entry that next-track served. Other events
+/// aren't posted.
+fn describe(config: &NotifyConfig, event: &Event) -> Option<String> {
+    match event {
+        Event::TrackServed { track, tags, .. } => {
+            let file = track
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            // Tracks without a title are named after their file instead
+            let text = match tags.as_ref().filter(|tags| !tags.title.is_empty()) {
+                Some(tags) => i18n::fill(
+                    &config.track_template,
+                    &[
+                        ("artist", &tags.artist),
+                        ("title", &tags.title),
+                        ("album", &tags.album),
+                        ("file", &file),
+                    ],
+                ),
+                None => i18n::fill(
+                    &config.track_template,
+                    &[
+                        ("artist", ""),
+                        ("title", &file),
+                        ("album", ""),
+                        ("file", &file),
+                    ],
+                ),
+            };
+
+            Some(text)
+        }
+        Event::SpecialServed { kind, .. } => {
+            Some(i18n::fill(&config.special_template, &[("kind", kind)]))
+        }
+        _ => None,
+    }
+}
+
+/// A connection to an IRC server that has joined the notice channel. It is
+/// made when the first notice is posted and made again whenever it drops.
+struct IrcClient {
+    config: IrcConfig,
+    connection: Option<TcpStream>,
+
+    /// Anything read from the server after the last complete line
+    incoming: Vec<u8>,
+}
+
+impl IrcClient {
+    fn new(config: IrcConfig) -> Self {
+        IrcClient {
+            config,
+            connection: None,
+            incoming: Vec::new(),
+        }
+    }
+
+    /// Connects to the server, registers and joins the channel
+    fn connect(&mut self) -> io::Result<()> {
+        let address = self
+            .config
+            .server
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))?;
+
+        let mut stream = TcpStream::connect_timeout(&address, IRC_TIMEOUT)?;
+        stream.set_write_timeout(Some(IRC_TIMEOUT))?;
+        write!(
+            stream,
+            "NICK {}\r\nUSER {} 0 * :shuffled\r\n",
+            self.config.nick, self.config.nick
+        )?;
+
+        self.incoming.clear();
+        self.connection = Some(stream);
+
+        // The channel can't be joined until the server has welcomed us
+        let deadline = Instant::now() + IRC_TIMEOUT;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server did not finish registration",
+                ));
+            }
+
+            for line in self.read_lines(POLL_INTERVAL)? {
+                match line.split(' ').nth(1) {
+                    Some("001") => {
+                        let channel = self.config.channel.to_string();
+                        self.send_line(&format!("JOIN {}", channel))?;
+                        info!(
+                            "notify",
+                            "Joined {} on {}", self.config.channel, self.config.server
+                        );
+                        return Ok(());
+                    }
+                    Some("433") => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("nickname {} is already in use", self.config.nick),
+                        ))
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Sends a single IRC command, dropping the connection if it fails
+    fn send_line(&mut self, line: &str) -> io::Result<()> {
+        let result = match &mut self.connection {
+            Some(stream) => stream.write_all(format!("{}\r\n", line).as_bytes()),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected")),
+        };
+
+        if result.is_err() {
+            self.connection = None;
+        }
+
+        result
+    }
+
+    /// Reads whatever the server has sent, waiting up to the timeout for it,
+    /// and returns the complete lines. Pings are answered along the way.
+    fn read_lines(&mut self, timeout: Duration) -> io::Result<Vec<String>> {
+        let stream = match &mut self.connection {
+            Some(stream) => stream,
+            None => return Ok(Vec::new()),
+        };
+
+        stream.set_read_timeout(Some(timeout))?;
+        let mut buffer = [0; 4096];
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                self.connection = None;
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection",
+                ));
+            }
+            Ok(count) => self.incoming.extend_from_slice(&buffer[..count]),
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut => {}
+            Err(error) => {
+                self.connection = None;
+                return Err(error);
+            }
+        }
+
+        let mut lines = Vec::new();
+        while let Some(end) = self.incoming.iter().position(|byte| *byte == b'\n') {
+            let raw = self.incoming.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&raw).trim_end().to_string();
+            if let Some(token) = line.strip_prefix("PING ") {
+                self.send_line(&format!("PONG {}", token))?;
+            } else {
+                lines.push(line);
+            }
+        }
+
+        Ok(lines)
+    }
+
+    /// Keeps the connection alive between notices
+    fn poll(&mut self) {
+        if let Err(error) = self.read_lines(Duration::from_millis(1)) {
+            warning!(
+                "notify",
+                "Lost connection to {}: {}",
+                self.config.server,
+                error
+            );
+        }
+    }
+
+    /// Posts a message in the channel, connecting first if needed
+    fn post(&mut self, text: &str) {
+        if self.connection.is_none() {
+            if let Err(error) = self.connect() {
+                warning!(
+                    "notify",
+                    "Could not connect to {}: {}",
+                    self.config.server,
+                    error
+                );
+                self.connection = None;
+                return;
+            }
+        }
+
+        // A line break would end the message and start another command
+        let text = text.replace(['\r', '\n'], " ");
+        let channel = self.config.channel.to_string();
+        if let Err(error) = self.send_line(&format!("PRIVMSG {} :{}", channel, text)) {
+            warning!("notify", "Could not post to {}: {}", channel, error);
+        }
+    }
+
+    /// Leaves the server politely
+    fn quit(&mut self) {
+        if self.connection.is_some() {
+            let _ = self.send_line("QUIT :shutting down");
+        }
+    }
+}
+
+/// Posts a message through a Discord webhook
+fn post_discord(client: &Client, webhook: &str, text: &str) {
+    let mut document = json::object::Object::new();
+    document.insert("content", json::JsonValue::String(text.to_string()));
+
+    let result = client
+        .post(webhook)
+        .header(CONTENT_TYPE, "application/json")
+        .body(json::stringify(json::JsonValue::Object(document)))
+        .send();
+
+    match result {
+        Ok(response) if !response.status().is_success() => {
+            warning!(
+                "notify",
+                "Discord webhook returned unexpected status code {}",
+                response.status().as_u16()
+            );
+        }
+        Err(error) => warning!("notify", "Could not deliver Discord webhook: {}", error),
+        _ => (),
+    }
+}
+
+/// Posts notices as the server serves entries, no more often than the
+/// configured interval. When several entries are served during the interval
+/// only the latest is posted, since the others are no longer playing. Stops
+/// once the server shuts down.
+pub fn notifier_worker(config: NotifyConfig, client: Client, events: Receiver<Event>) {
+    let mut irc = config.irc.clone().map(IrcClient::new);
+    let interval = Duration::from_secs(u64::from(config.interval));
+    let mut last_post: Option<Instant> = None;
+    let mut pending = None;
+
+    loop {
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(Event::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(event) => {
+                if let Some(text) = describe(&config, &event) {
+                    pending = Some(text);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+
+        if let Some(irc) = &mut irc {
+            irc.poll();
+        }
+
+        let ready = last_post
+            .map(|posted| posted.elapsed() >= interval)
+            .unwrap_or(true);
+
+        if !ready {
+            continue;
+        }
+
+        if let Some(text) = pending.take() {
+            if let Some(webhook) = &config.discord_webhook {
+                post_discord(&client, webhook, &text);
+            }
+
+            if let Some(irc) = &mut irc {
+                irc.post(&text);
+            }
+
+            last_post = Some(Instant::now());
+        }
+    }
+
+    if let Some(irc) = &mut irc {
+        irc.quit();
+    }
+}
@@ -0,0 +1,356 @@
+use crate::config::PartsConfig;
+use crate::utils;
+use shuffled::playlist::ShuffleStrategy;
+use shuffled::rng::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where a track falls within a work
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Part {
+    work: usize,
+    index: usize,
+}
+
+/// The works on the playlists that are split over several tracks, like the
+/// movements of a symphony or the halves of a long live track, each as the
+/// list of its parts in the order they are played
+#[derive(Debug, Default)]
+pub struct Works {
+    works: Vec<Vec<PathBuf>>,
+    parts: HashMap<PathBuf, Part>,
+}
+
+/// The artist, album and title of a work found from its tags, in lower case
+type WorkKey = (String, String, String);
+
+/// Parses a part number, either in digits or in Roman numerals up to 39
+fn parse_number(text: &str) -> Option<u32> {
+    if let Ok(number) = text.parse::<u32>() {
+        return Some(number);
+    }
+
+    let mut total = 0;
+    let mut rest = text.to_ascii_uppercase();
+    for (numeral, value) in [
+        ("XXX", 30),
+        ("XX", 20),
+        ("X", 10),
+        ("IX", 9),
+        ("V", 5),
+        ("IV", 4),
+        ("III", 3),
+        ("II", 2),
+        ("I", 1),
+    ] {
+        if let Some(stripped) = rest.strip_prefix(numeral) {
+            total += value;
+            rest = stripped.to_string();
+        }
+    }
+
+    if rest.is_empty() && total > 0 {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+/// Reads a part number like "2", "2/3" or "2 of 3", leaving off the count
+fn parse_numbering(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let number = text
+        .split_once('/')
+        .map(|(number, _)| number)
+        .or_else(|| text.split_once(" of ").map(|(number, _)| number))
+        .unwrap_or(text);
+
+    if number.trim().chars().all(|c| c.is_ascii_digit()) {
+        number.trim().parse().ok()
+    } else {
+        None
+    }
+}
+
+/// The words that a part number can follow in a title
+const PART_WORDS: &[&str] = &["pt.", "pt ", "part "];
+
+/// Takes the work's title and the part number out of a track's title, for
+/// titles that end in a part like "Echoes (Pt. 2)", "Echoes, Part 2/2" or
+/// "Echoes [2/2]", or that name a movement like "Symphony No. 5: II. Andante"
+fn split_title(title: &str) -> Option<(String, u32)> {
+    let title = title.trim();
+    split_part(title).or_else(|| split_movement(title))
+}
+
+/// Takes the work and the part number out of a title that ends in a part
+fn split_part(title: &str) -> Option<(String, u32)> {
+    // Only ASCII is lowered, so that the two line up byte for byte
+    let lower = title.to_ascii_lowercase();
+    let (body, marker, bracketed) = match title.chars().last() {
+        Some(close @ (')' | ']')) => {
+            let open = if close == ')' { '(' } else { '[' };
+            let start = title.rfind(open)?;
+            (
+                &title[..start],
+                lower[start + 1..lower.len() - 1].trim(),
+                true,
+            )
+        }
+        _ => {
+            let start = PART_WORDS
+                .iter()
+                .filter_map(|word| lower.rfind(word))
+                .filter(|start| *start == 0 || lower[..*start].ends_with(' '))
+                .max()?;
+            (&title[..start], &lower[start..], false)
+        }
+    };
+
+    let numbering = PART_WORDS.iter().find_map(|word| marker.strip_prefix(word));
+
+    // A number on its own in brackets is as likely to be anything else, so it
+    // needs a count to go with it like (2/3)
+    let numbering = match numbering {
+        Some(numbering) => numbering,
+        None if bracketed && (marker.contains('/') || marker.contains(" of ")) => marker,
+        None => return None,
+    };
+
+    let number = parse_numbering(numbering)?;
+    let work = body.trim_end_matches(|c: char| c.is_whitespace() || ",-:".contains(c));
+    if work.is_empty() {
+        None
+    } else {
+        Some((work.to_lowercase(), number))
+    }
+}
+
+/// Takes the work and the movement number out of a title like "Symphony No.
+/// 5: II. Andante con moto"
+fn split_movement(title: &str) -> Option<(String, u32)> {
+    let (work, movement) = title.split_once(": ")?;
+    let (number, name) = movement.split_once(". ")?;
+    if work.trim().is_empty() || name.trim().is_empty() {
+        return None;
+    }
+
+    parse_number(number.trim()).map(|number| (work.trim().to_lowercase(), number))
+}
+
+/// Reads the works listed in a grouping file. Each work is a list of its
+/// tracks in the order they are played, one per line, with a blank line
+/// between works. Lines starting with # are comments, and relative paths are
+/// relative to the file itself.
+pub fn read_works_file(path: &Path) -> Result<Vec<Vec<PathBuf>>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read parts file {}: {}", path.display(), error))?;
+
+    let parent = path.parent().and_then(|dir| dir.canonicalize().ok());
+    let mut works = Vec::new();
+    let mut work = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        if line.is_empty() {
+            if !work.is_empty() {
+                works.push(work);
+                work = Vec::new();
+            }
+            continue;
+        }
+
+        let track = PathBuf::from(line);
+        let track = match parent.as_ref() {
+            Some(parent) if !track.is_absolute() => parent.join(track),
+            _ => track,
+        };
+        work.push(track);
+    }
+
+    if !work.is_empty() {
+        works.push(work);
+    }
+
+    Ok(works)
+}
+
+impl Works {
+    /// Finds the works that the songs belong to, from the grouping file and,
+    /// if it's turned on, from their tags. A song listed in the file is only
+    /// part of the work that the file puts it in. Songs found from their tags
+    /// are in the same work if they have the same artist and album and their
+    /// titles name the same work, and are played in order of their part
+    /// numbers and then their track numbers.
+    pub fn find(
+        config: &PartsConfig,
+        songs: &[PathBuf],
+        tags: &HashMap<String, utils::ID3>,
+    ) -> Self {
+        let mut works = Works::default();
+        let on_playlists = songs.iter().collect::<HashSet<_>>();
+
+        if let Some(path) = config.file.as_ref() {
+            match read_works_file(path) {
+                Ok(listed) => {
+                    for work in listed {
+                        let work = work
+                            .into_iter()
+                            .filter(|track| on_playlists.contains(track))
+                            .collect::<Vec<_>>();
+                        works.add(work);
+                    }
+                }
+                Err(error) => warning!("server", "{}", error),
+            }
+        }
+
+        if !config.detect {
+            return works;
+        }
+
+        let mut found: HashMap<WorkKey, Vec<(u32, u8, PathBuf)>> = HashMap::new();
+        for song in songs {
+            if works.parts.contains_key(song) {
+                continue;
+            }
+
+            let id3 = match song.to_str().and_then(|path| tags.get(path)) {
+                Some(id3) => id3,
+                None => continue,
+            };
+
+            if let Some((work, number)) = split_title(id3.title()) {
+                let key = (
+                    id3.artist().to_lowercase(),
+                    id3.album().to_lowercase(),
+                    work,
+                );
+                let track = id3.track().unwrap_or(0);
+                found
+                    .entry(key)
+                    .or_default()
+                    .push((number, track, song.to_path_buf()));
+            }
+        }
+
+        let mut found = found.into_iter().collect::<Vec<_>>();
+        found.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, mut parts) in found {
+            parts.sort();
+            works.add(parts.into_iter().map(|(_, _, song)| song).collect());
+        }
+
+        works
+    }
+
+    /// Adds a work, as long as it has more than one part and none of them are
+    /// already in another work
+    fn add(&mut self, mut parts: Vec<PathBuf>) {
+        let mut seen = HashSet::new();
+        parts.retain(|part| !self.parts.contains_key(part) && seen.insert(part.clone()));
+        if parts.len() < 2 {
+            return;
+        }
+
+        let work = self.works.len();
+        for (index, part) in parts.iter().enumerate() {
+            self.parts.insert(part.to_path_buf(), Part { work, index });
+        }
+
+        self.works.push(parts);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.works.is_empty()
+    }
+
+    /// How many works were found
+    pub fn len(&self) -> usize {
+        self.works.len()
+    }
+
+    /// Finds the part played after a song, if it's part of a work and not the
+    /// last one
+    pub fn next_part(&self, song: &Path) -> Option<&PathBuf> {
+        let part = self.parts.get(song)?;
+        self.works[part.work].get(part.index + 1)
+    }
+
+    /// Reorders the songs so that the parts of each work among them are next
+    /// to each other and in order. Each work goes where its earliest part
+    /// was, so a shuffle places the works as randomly as any other song.
+    pub fn keep_together(&self, songs: &mut [PathBuf]) {
+        if self.is_empty() {
+            return;
+        }
+
+        // The earliest part of each work that is among the songs
+        let mut first_parts = HashMap::new();
+        for song in songs.iter() {
+            if let Some(part) = self.parts.get(song) {
+                let first = first_parts.entry(part.work).or_insert(part.index);
+                *first = (*first).min(part.index);
+            }
+        }
+
+        let present = songs.iter().cloned().collect::<HashSet<_>>();
+        let mut ordered = Vec::with_capacity(songs.len());
+        for song in songs.iter() {
+            match self.parts.get(song) {
+                None => ordered.push(song.to_path_buf()),
+                Some(part) if first_parts[&part.work] == part.index => ordered.extend(
+                    self.works[part.work]
+                        .iter()
+                        .filter(|part| present.contains(*part))
+                        .cloned(),
+                ),
+                Some(_) => (),
+            }
+        }
+
+        for (slot, song) in songs.iter_mut().zip(ordered) {
+            *slot = song;
+        }
+    }
+
+    /// Splits the songs into runs that have to stay together, which are the
+    /// parts of a work that come one after another and otherwise single songs
+    pub fn runs(&self, songs: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+        let mut runs: Vec<Vec<PathBuf>> = Vec::new();
+        for song in songs {
+            let continues = runs
+                .last()
+                .and_then(|run| run.last())
+                .and_then(|last| self.next_part(last))
+                .map(|next| next == song)
+                .unwrap_or(false);
+
+            match runs.last_mut() {
+                Some(run) if continues => run.push(song.to_path_buf()),
+                _ => runs.push(vec![song.to_path_buf()]),
+            }
+        }
+
+        runs
+    }
+}
+
+/// Shuffles with another strategy and then brings the parts of each work back
+/// together, in order
+#[derive(Debug)]
+pub struct KeepParts<'a> {
+    pub inner: Box<dyn ShuffleStrategy + 'a>,
+    pub works: &'a Works,
+}
+
+impl ShuffleStrategy for KeepParts<'_> {
+    fn shuffle(&self, songs: &mut [PathBuf], rng: &mut dyn Rng) {
+        self.inner.shuffle(songs, rng);
+        self.works.keep_together(songs);
+    }
+}
@@ -0,0 +1,723 @@
+//! The playlists that shuffled plays from, and the parser for the M3U8 files
+//! that they are read from
+
+use crate::rng::{self, Rng};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A way of putting the songs of a playlist into a random order
+pub trait ShuffleStrategy: fmt::Debug {
+    /// Reorders the songs in place, drawing on the generator for randomness
+    fn shuffle(&self, songs: &mut [PathBuf], rng: &mut dyn Rng);
+}
+
+/// Puts the songs into a uniformly random order, ignoring anything about the
+/// songs themselves
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FisherYates;
+
+impl ShuffleStrategy for FisherYates {
+    fn shuffle(&self, songs: &mut [PathBuf], rng: &mut dyn Rng) {
+        rng::shuffle(songs, rng);
+    }
+}
+
+/// How often a song has been played, and when it was last played
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PlayCount {
+    pub plays: u64,
+    pub last_played: SystemTime,
+}
+
+/// The play counts of every song that has been played, by their paths
+pub type PlayCounts = HashMap<PathBuf, PlayCount>;
+
+/// How long after a song is played that it is still held back by LeastPlayed,
+/// on top of its play count
+const RECENT_PLAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Orders the songs randomly, but with the songs that have been played the
+/// most and the most recently towards the end, so that songs which haven't
+/// come up in a while surface more often. A song's weight is one over one more
+/// than its play count, and a song played in the last day has it scaled down
+/// further by how recently, to as little as a tenth. The order is drawn by
+/// giving each song an exponentially distributed key scaled by its weight,
+/// which is the same as repeatedly picking the next song with a probability in
+/// proportion to its weight.
+#[derive(Debug)]
+pub struct LeastPlayed<'a> {
+    counts: &'a PlayCounts,
+    now: SystemTime,
+}
+
+impl<'a> LeastPlayed<'a> {
+    pub fn new(counts: &'a PlayCounts) -> Self {
+        LeastPlayed {
+            counts,
+            now: SystemTime::now(),
+        }
+    }
+
+    /// How likely the song is to be picked next, relative to the others
+    fn weight(&self, song: &Path) -> f64 {
+        let count = match self.counts.get(song) {
+            Some(count) => count,
+            None => return 1.0,
+        };
+
+        let since_played = self
+            .now
+            .duration_since(count.last_played)
+            .unwrap_or_default();
+        let recency = (since_played.as_secs_f64() / RECENT_PLAY.as_secs_f64()).clamp(0.1, 1.0);
+        recency / (1.0 + count.plays as f64)
+    }
+}
+
+impl ShuffleStrategy for LeastPlayed<'_> {
+    fn shuffle(&self, songs: &mut [PathBuf], rng: &mut dyn Rng) {
+        let mut keyed = songs
+            .iter()
+            .map(|song| {
+                // 53 random bits make a uniform float in (0, 1], which keeps
+                // the logarithm finite
+                let uniform = ((rng.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+                (-uniform.ln() / self.weight(song), song.to_path_buf())
+            })
+            .collect::<Vec<_>>();
+
+        keyed.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        for (slot, (_, song)) in songs.iter_mut().zip(keyed) {
+            *slot = song;
+        }
+    }
+}
+
+/// A single playlist and its current position
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    position: usize,
+    songs: Vec<PathBuf>,
+}
+
+impl Playlist {
+    /// Creates a new playlist that has its current position set to the first
+    /// song
+    pub fn new(songs: Vec<PathBuf>) -> Option<Playlist> {
+        if songs.is_empty() {
+            None
+        } else {
+            Some(Playlist { position: 0, songs })
+        }
+    }
+
+    /// Gets the playlist's current position
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Resets the playlist's current position to the given position
+    pub fn seek(&mut self, position: usize) {
+        self.position = position % self.songs.len();
+    }
+
+    /// Returns the playlist's current song
+    pub fn current(&self) -> &PathBuf {
+        &self.songs[self.position]
+    }
+
+    /// Returns every song in the playlist, in the order they are played
+    /// starting from the beginning of the playlist
+    pub fn songs(&self) -> &[PathBuf] {
+        &self.songs
+    }
+
+    /// Moves the song at the given offset from the current position so that it
+    /// becomes the current song, keeping the order of everything else
+    pub fn promote(&mut self, offset: usize) {
+        if offset == 0 {
+            return;
+        }
+
+        let target = (self.position + offset) % self.songs.len();
+        let song = self.songs.remove(target);
+        if target < self.position {
+            self.position -= 1;
+        }
+
+        self.songs.insert(self.position, song);
+    }
+
+    /// Moves a song so that it is the last one played before the playlist
+    /// loops back around to the current song
+    pub fn demote(&mut self, song: &Path) {
+        let index = match self.songs.iter().position(|other| other == song) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let song = self.songs.remove(index);
+        if index < self.position {
+            self.position -= 1;
+        }
+
+        self.songs.insert(self.position, song);
+        self.position = (self.position + 1) % self.songs.len();
+    }
+
+    /// Advances the current song to the next song
+    pub fn next(&mut self) {
+        self.position = (self.position + 1) % self.songs.len();
+    }
+
+    /// Shuffles the playlist with the Fisher-Yates algorithm and resets the
+    /// current position
+    pub fn shuffle(&mut self, rng: &mut impl Rng) {
+        self.shuffle_with(&FisherYates, rng);
+    }
+
+    /// Shuffles the playlist with the given strategy and resets the current
+    /// position
+    pub fn shuffle_with(&mut self, strategy: &dyn ShuffleStrategy, rng: &mut dyn Rng) {
+        strategy.shuffle(&mut self.songs, rng);
+        self.position = 0;
+    }
+
+    /// Computes a delta between this playlist and another set of songs. The
+    /// first list holds the songs that are only in the other set, and the
+    /// second holds the songs that are only on this playlist.
+    pub fn diff_playlist(&self, playlist: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut to_add = Vec::new();
+        let mut to_remove = Vec::new();
+
+        for song in self.songs.iter() {
+            if !playlist.contains(song) {
+                to_remove.push(song.to_path_buf());
+            }
+        }
+
+        for playlist_song in playlist.iter() {
+            if !self.songs.contains(playlist_song) {
+                to_add.push(playlist_song.to_path_buf());
+            }
+        }
+
+        (to_add, to_remove)
+    }
+
+    /// Adds and removes songs from the given delta lists, putting all the songs
+    /// in the add list at the end
+    pub fn merge_songs(&mut self, to_add: &[PathBuf], to_remove: &[PathBuf]) {
+        // Try to keep the current song at the current position, so that we
+        // don't miss playing any songs. We don't care about songs after the
+        // current position but we do care about those before, since removing
+        // those will shift the playlist back and cause repeats.
+        //
+        // A B C D E
+        //     ^
+        //
+        // B C D E   A being removed should shift us back
+        //   ^
+        //
+        // A B C D   E being remove doesn't matter
+        //     ^
+        //
+        // A B D     Removing the current should do nothing, since we look at
+        //     ^     current entry (now D, the old next) first and then advance it
+        let removed_before = self.songs[..self.position]
+            .iter()
+            .filter(|song| to_remove.contains(song))
+            .count();
+
+        self.songs.retain(|song| !to_remove.contains(song));
+        self.position -= removed_before;
+        self.songs.extend_from_slice(to_add);
+
+        // Removing the last song when it was current leaves the position past
+        // the end, so it wraps around like next() would have
+        if self.position >= self.songs.len() {
+            self.position = 0
+        }
+    }
+
+    /// Adds and removes songs like merge_songs, except that the added songs
+    /// are spread out at random over the next `within` songs to be played
+    /// instead of being put at the end, keeping their order. If more songs
+    /// are added than that, they are all played before anything else.
+    pub fn merge_songs_within(
+        &mut self,
+        to_add: &[PathBuf],
+        to_remove: &[PathBuf],
+        within: usize,
+        rng: &mut dyn Rng,
+    ) {
+        let runs = to_add
+            .iter()
+            .map(|song| vec![song.to_path_buf()])
+            .collect::<Vec<_>>();
+        self.merge_runs_within(&runs, to_remove, within, rng);
+    }
+
+    /// Adds and removes songs like merge_songs_within, except that the added
+    /// songs come in runs which are kept together, like the parts of a work.
+    /// Each run is spread out as though it were one song.
+    pub fn merge_runs_within(
+        &mut self,
+        to_add: &[Vec<PathBuf>],
+        to_remove: &[PathBuf],
+        within: usize,
+        rng: &mut dyn Rng,
+    ) {
+        self.merge_songs(&[], to_remove);
+
+        // Removing every song leaves nothing to put the new ones between
+        if self.songs.is_empty() {
+            self.songs.extend(to_add.iter().flatten().cloned());
+            self.position = 0;
+            return;
+        }
+
+        // Each slot of the window is taken by either a new run or the next
+        // song that was already coming up
+        let added_songs = to_add.iter().map(Vec::len).sum::<usize>();
+        let existing = within.saturating_sub(added_songs).min(self.songs.len());
+        let mut slots = vec![false; existing];
+        slots.resize(existing + to_add.len(), true);
+        rng::shuffle(&mut slots, rng);
+
+        let mut added = to_add.iter();
+        let mut offset = 0;
+        for is_new in slots {
+            if !is_new {
+                offset += 1;
+                continue;
+            }
+
+            for song in added.next().unwrap() {
+                // Offsets past the end of the vector wrap around to the songs
+                // before the current one, which moves the current one along
+                let index = self.position + offset;
+                if index <= self.songs.len() {
+                    self.songs.insert(index, song.to_path_buf());
+                } else {
+                    self.songs
+                        .insert(index - self.songs.len(), song.to_path_buf());
+                    self.position += 1;
+                }
+                offset += 1;
+            }
+        }
+    }
+}
+
+/// A group of named playlists without any position information, as they are
+/// read from disk
+pub type SimplePlaylists = HashMap<String, Vec<PathBuf>>;
+
+/// How many seconds each track plays for, as declared by the #EXTINF lines of
+/// the playlist files that list it
+pub type Durations = HashMap<PathBuf, u32>;
+
+/// The tracks listed in an M3U8 file, in order, and the durations it declares
+/// for them
+#[derive(Debug, PartialEq)]
+pub struct M3u8Playlist {
+    pub songs: Vec<PathBuf>,
+    pub durations: Durations,
+}
+
+/// Reads the duration out of an extended M3U "#EXTINF:<seconds>,<title>" line.
+/// Attributes can come between the duration and the comma, and -1 means that
+/// the duration isn't known.
+fn parse_extinf(directive: &str) -> Result<Option<u32>, ()> {
+    let info = directive.split(',').next().unwrap_or("");
+    let seconds = info
+        .split_whitespace()
+        .next()
+        .and_then(|seconds| seconds.parse::<f64>().ok())
+        .ok_or(())?;
+
+    if seconds < 0.0 {
+        Ok(None)
+    } else {
+        Ok(Some(seconds.round() as u32))
+    }
+}
+
+/// Reads an M3U8 file and returns a list of absolute paths to the audio files
+/// listed within, or an error if the playlist or files are invalid. Lines
+/// starting with # are comments or extended M3U directives, of which only the
+/// durations from #EXTINF lines are kept.
+pub fn parse_m3u8_playlist(filename: &Path) -> Result<M3u8Playlist, String> {
+    let buffer = fs::read(filename)
+        .map_err(|error| format!("Could not read playlist {}: {}", filename.display(), error))?;
+
+    let contents = String::from_utf8(buffer)
+        .map_err(|error| format!("Could not decode playlist: {}", error))?;
+
+    // m3u files that use relative paths are relative to the location of the file itself
+    let playlist_relative = filename.parent().and_then(|dir| dir.canonicalize().ok());
+
+    let mut playlist = Vec::new();
+    let mut durations = HashMap::new();
+    let mut duration = None;
+    for (index, line) in contents.split('\n').enumerate() {
+        let processed_line = line.trim().trim_start_matches('\u{feff}');
+        if processed_line.is_empty() {
+            continue;
+        }
+
+        if let Some(directive) = processed_line.strip_prefix("#EXTINF:") {
+            duration = parse_extinf(directive).map_err(|_| {
+                format!(
+                    "Could not read playlist: line {} of {} has an #EXTINF without a duration",
+                    index + 1,
+                    filename.display()
+                )
+            })?;
+            continue;
+        }
+
+        if processed_line.starts_with('#') {
+            continue;
+        }
+
+        let path = PathBuf::from(processed_line);
+        let path = if !path.is_absolute() {
+            match playlist_relative.as_ref() {
+                Some(parent) => parent.to_path_buf().join(path),
+                None => {
+                    return Err(format!(
+                        "Could not read playlist: failed to resolve relative entry {}",
+                        path.display()
+                    ))
+                }
+            }
+        } else {
+            path
+        };
+
+        if !path.is_file() {
+            return Err(format!(
+                "Could not read playlist: entry {} is not a file",
+                path.display()
+            ));
+        }
+
+        if playlist.contains(&path) {
+            return Err(format!(
+                "Could not read playlist: entry {} is a duplicate",
+                path.display()
+            ));
+        }
+
+        // Each #EXTINF describes the entry right after it
+        if let Some(seconds) = duration.take() {
+            durations.insert(path.clone(), seconds);
+        }
+
+        playlist.push(path);
+    }
+
+    if playlist.is_empty() {
+        return Err(format!(
+            "Could not read playlist: no entries in {}",
+            filename.display()
+        ));
+    }
+
+    Ok(M3u8Playlist {
+        songs: playlist,
+        durations,
+    })
+}
+
+/// The extensions of the files that are picked up as tracks when a directory
+/// is read as a playlist
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "opus", "m4a", "aac", "wav"];
+
+/// Adds every audio file under a directory to the songs, going into each of
+/// its subdirectories. Hidden files and directories are skipped, and so are
+/// symlinks to directories since they could lead back up the tree.
+fn find_audio_files(directory: &Path, songs: &mut Vec<PathBuf>) -> Result<(), String> {
+    let read_error = |error| format!("Error reading directory {}: {}", directory.display(), error);
+    for entry in directory.read_dir().map_err(read_error)? {
+        let dirent = entry.map_err(read_error)?;
+        if dirent.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = dirent.path();
+        if dirent.file_type().map_err(read_error)?.is_dir() {
+            find_audio_files(&path, songs)?;
+            continue;
+        }
+
+        let is_audio = match path.extension() {
+            Some(extension) => {
+                let extension = extension.to_string_lossy().to_ascii_lowercase();
+                AUDIO_EXTENSIONS.contains(&extension.as_str())
+            }
+            None => false,
+        };
+
+        if is_audio && path.is_file() {
+            songs.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads each directory inside the root as a playlist named after it, holding
+/// every audio file anywhere beneath it. Directories without any audio files
+/// are left out.
+pub fn read_directory_playlists(root: &Path) -> Result<SimplePlaylists, String> {
+    let reader = root
+        .read_dir()
+        .map_err(|error| format!("Error reading content root {}: {}", root.display(), error))?;
+
+    let mut playlists = HashMap::new();
+    for entry in reader {
+        let dirent = entry
+            .map_err(|error| format!("Error reading content root {}: {}", root.display(), error))?;
+
+        let name = dirent.file_name().to_string_lossy().to_string();
+        let path = dirent.path();
+        if name.starts_with('.') || !path.is_dir() {
+            continue;
+        }
+
+        let mut songs = Vec::new();
+        find_audio_files(&path, &mut songs)?;
+        if !songs.is_empty() {
+            songs.sort();
+            playlists.insert(name, songs);
+        }
+    }
+
+    Ok(playlists)
+}
+
+/// Reads every playlist: the .m3u8 files in the playlist directory and, if
+/// there is a content root, each of the directories in it. A playlist file
+/// wins over a directory with the same name.
+pub fn read_playlists(
+    directory: &Path,
+    content_root: Option<&Path>,
+) -> Result<(SimplePlaylists, Durations), String> {
+    let (mut playlists, durations) = read_m3u8_files(directory)?;
+    if let Some(root) = content_root {
+        for (name, songs) in read_directory_playlists(root)? {
+            playlists.entry(name).or_insert(songs);
+        }
+    }
+
+    if playlists.is_empty() {
+        return Err("Error reading playlist directory: no playlists".to_string());
+    }
+
+    Ok((playlists, durations))
+}
+
+/// Reads all the .m3u8 playlists available in the given directory, along with
+/// the durations that any of them declare
+pub fn read_m3u8_files(directory: &Path) -> Result<(SimplePlaylists, Durations), String> {
+    let reader = directory
+        .read_dir()
+        .map_err(|error| format!("Error reading playlist directory: {}", error))?;
+
+    let mut raw_playlists: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut durations = HashMap::new();
+    for entry in reader {
+        let dirent =
+            entry.map_err(|error| format!("Error reading playlist directory: {}", error))?;
+
+        let entry_path = dirent.path();
+        if !entry_path.is_file() {
+            continue;
+        }
+
+        let extension = entry_path.extension().map(OsStr::to_string_lossy);
+        if extension != Some(Cow::Borrowed("m3u8")) {
+            continue;
+        }
+
+        let name = entry_path
+            .file_stem()
+            .map(OsStr::to_string_lossy)
+            .ok_or(format!(
+                "Error reading playlist file {}: unreadable name",
+                entry_path.display()
+            ))?;
+
+        let playlist = parse_m3u8_playlist(&entry_path)?;
+
+        durations.extend(playlist.durations);
+        raw_playlists.insert(name.to_string(), playlist.songs);
+    }
+
+    Ok((raw_playlists, durations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Xoshiro256;
+
+    /// How many random cases each invariant is checked against
+    const CASES: u64 = 500;
+
+    fn song(number: u64) -> PathBuf {
+        PathBuf::from(format!("/music/{}.mp3", number))
+    }
+
+    /// Builds a random playlist at a random position, along with a random
+    /// set of its songs to remove and some new ones to add
+    fn random_case(rng: &mut Xoshiro256) -> (Playlist, Vec<PathBuf>, Vec<PathBuf>) {
+        let length = 1 + rng.below(20);
+        let mut playlist = Playlist::new((0..length).map(song).collect()).unwrap();
+        playlist.seek(rng.below(length) as usize);
+
+        let to_remove = playlist
+            .songs()
+            .iter()
+            .filter(|_| rng.below(3) == 0)
+            .cloned()
+            .collect();
+
+        let to_add = (0..rng.below(6)).map(|number| song(100 + number)).collect();
+        (playlist, to_remove, to_add)
+    }
+
+    /// The songs that were still to be played from the current one on, other
+    /// than those being removed
+    fn upcoming(playlist: &Playlist, to_remove: &[PathBuf]) -> Vec<PathBuf> {
+        playlist.songs()[playlist.position()..]
+            .iter()
+            .filter(|song| !to_remove.contains(song))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn merge_removing_songs_keeps_the_current_one() {
+        // Removing any song used to underflow the index offset, and removing
+        // the current song when it was last left the position past the end
+        let mut playlist = Playlist::new((0..5).map(song).collect()).unwrap();
+        playlist.seek(2);
+        playlist.merge_songs(&[], &[song(0)]);
+        assert_eq!(playlist.current(), &song(2));
+        assert_eq!(playlist.position(), 1);
+
+        playlist.seek(3);
+        playlist.merge_songs(&[], &[song(4)]);
+        assert_eq!(playlist.position(), 0);
+        assert_eq!(playlist.songs(), &[song(1), song(2), song(3)][..]);
+    }
+
+    #[test]
+    fn merge_keeps_position_in_range() {
+        for seed in 0..CASES {
+            let mut rng = Xoshiro256::from_seed(seed);
+            let (mut playlist, to_remove, to_add) = random_case(&mut rng);
+            playlist.merge_songs(&to_add, &to_remove);
+
+            if playlist.songs().is_empty() {
+                assert_eq!(playlist.position(), 0, "seed {}", seed);
+            } else {
+                assert!(
+                    playlist.position() < playlist.songs().len(),
+                    "seed {}",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn merge_skips_no_upcoming_songs() {
+        for seed in 0..CASES {
+            let mut rng = Xoshiro256::from_seed(seed);
+            let (mut playlist, to_remove, to_add) = random_case(&mut rng);
+            let expected = upcoming(&playlist, &to_remove);
+            playlist.merge_songs(&to_add, &to_remove);
+
+            // With nothing left to play before the end, the added songs come
+            // next, or the playlist starts over like next() would
+            if expected.is_empty() {
+                let first_added = playlist.songs().len() - to_add.len();
+                assert!(
+                    playlist.position() == 0 || playlist.position() == first_added,
+                    "seed {}",
+                    seed
+                );
+                continue;
+            }
+
+            let after = &playlist.songs()[playlist.position()..];
+            assert_eq!(&after[..expected.len()], &expected[..], "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn merge_preserves_order() {
+        for seed in 0..CASES {
+            let mut rng = Xoshiro256::from_seed(seed);
+            let (mut playlist, to_remove, to_add) = random_case(&mut rng);
+            let mut expected = playlist
+                .songs()
+                .iter()
+                .filter(|song| !to_remove.contains(song))
+                .cloned()
+                .collect::<Vec<_>>();
+            expected.extend_from_slice(&to_add);
+
+            playlist.merge_songs(&to_add, &to_remove);
+            assert_eq!(playlist.songs(), &expected[..], "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn merge_within_preserves_order() {
+        for seed in 0..CASES {
+            let mut rng = Xoshiro256::from_seed(seed);
+            let (mut playlist, to_remove, to_add) = random_case(&mut rng);
+            let expected = upcoming(&playlist, &to_remove);
+            let within = rng.below(10) as usize;
+            playlist.merge_songs_within(&to_add, &to_remove, within, &mut rng);
+
+            assert!(
+                playlist.songs().is_empty() || playlist.position() < playlist.songs().len(),
+                "seed {}",
+                seed
+            );
+
+            // Reading from the current position on and around, the songs
+            // that were coming up and the new ones each keep their order
+            let order = playlist.songs()[playlist.position()..]
+                .iter()
+                .chain(playlist.songs()[..playlist.position()].iter())
+                .cloned()
+                .collect::<Vec<_>>();
+            let existing = order
+                .iter()
+                .filter(|song| expected.contains(song))
+                .cloned()
+                .collect::<Vec<_>>();
+            let added = order
+                .iter()
+                .filter(|song| to_add.contains(song))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            assert_eq!(existing, expected, "seed {}", seed);
+            assert_eq!(added, to_add, "seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+/// How much room getpwnam_r and getgrnam_r get for the strings they return,
+/// which is far more than any real entry needs
+const ENTRY_BUFFER_BYTES: usize = 16384;
+
+/// A user as looked up in the passwd database
+struct Account {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+fn lookup_user(name: &str) -> Result<Account, String> {
+    let c_name = CString::new(name).map_err(|_| format!("Invalid user name '{}'", name))?;
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_BYTES];
+    let mut result = ptr::null_mut();
+
+    // Safe since the strings in the entry point into the buffer, which
+    // outlives every use of them, and the length passed is the buffer's
+    let (status, entry) = unsafe {
+        let mut entry: libc::passwd = mem::zeroed();
+        let status = libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        );
+        (status, entry)
+    };
+
+    if status != 0 {
+        return Err(format!(
+            "Could not look up user '{}': {}",
+            name,
+            io::Error::from_raw_os_error(status)
+        ));
+    }
+
+    if result.is_null() {
+        return Err(format!("There is no user named '{}'", name));
+    }
+
+    // Safe since a successful lookup always fills in the name
+    let name = unsafe { CStr::from_ptr(entry.pw_name) }.to_owned();
+    Ok(Account {
+        name,
+        uid: entry.pw_uid,
+        gid: entry.pw_gid,
+    })
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+    let c_name = CString::new(name).map_err(|_| format!("Invalid group name '{}'", name))?;
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_BYTES];
+    let mut result = ptr::null_mut();
+
+    // Safe for the same reasons as in lookup_user
+    let (status, entry) = unsafe {
+        let mut entry: libc::group = mem::zeroed();
+        let status = libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        );
+        (status, entry)
+    };
+
+    if status != 0 {
+        return Err(format!(
+            "Could not look up group '{}': {}",
+            name,
+            io::Error::from_raw_os_error(status)
+        ));
+    }
+
+    if result.is_null() {
+        return Err(format!("There is no group named '{}'", name));
+    }
+
+    Ok(entry.gr_gid)
+}
+
+/// Finds the name of the user with the given ID, if there is one
+fn lookup_user_name(uid: libc::uid_t) -> Result<Option<CString>, String> {
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_BYTES];
+    let mut result = ptr::null_mut();
+
+    // Safe for the same reasons as in lookup_user
+    let (status, entry) = unsafe {
+        let mut entry: libc::passwd = mem::zeroed();
+        let status = libc::getpwuid_r(
+            uid,
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        );
+        (status, entry)
+    };
+
+    if status != 0 {
+        return Err(format!(
+            "Could not look up user {}: {}",
+            uid,
+            io::Error::from_raw_os_error(status)
+        ));
+    }
+
+    if result.is_null() {
+        return Ok(None);
+    }
+
+    // Safe since a successful lookup always fills in the name
+    Ok(Some(unsafe { CStr::from_ptr(entry.pw_name) }.to_owned()))
+}
+
+/// Finds the ID of the user with the given name
+pub fn user_id(name: &str) -> Result<libc::uid_t, String> {
+    lookup_user(name).map(|account| account.uid)
+}
+
+/// Finds the ID of the group with the given name
+pub fn group_id(name: &str) -> Result<libc::gid_t, String> {
+    lookup_group(name)
+}
+
+/// Finds every group that a user is in according to the group database,
+/// which always includes the given group. A user without an entry is only in
+/// that group.
+pub fn user_groups(uid: libc::uid_t, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, String> {
+    let name = match lookup_user_name(uid)? {
+        Some(name) => name,
+        None => return Ok(vec![gid]),
+    };
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+
+        // Safe since the name is a valid C string and the count passed is the
+        // length of the list. When the list is too short, the count that it
+        // needs is written back instead.
+        let result =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+
+        if result >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+
+        if count as usize <= groups.len() {
+            return Err(format!("Could not list the groups of user {}", uid));
+        }
+
+        groups.resize(count as usize, 0);
+    }
+}
+
+/// Gives a file to the user and group being switched to, leaving either alone
+/// if it isn't being changed
+fn change_owner(path: &Path, uid: Option<libc::uid_t>, gid: libc::gid_t) -> Result<(), String> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid path {}", path.display()))?;
+
+    // Safe since the path is a valid C string, and -1 leaves the user as it is
+    let result = unsafe { libc::chown(c_path.as_ptr(), uid.unwrap_or(libc::uid_t::MAX), gid) };
+    if result < 0 {
+        return Err(format!(
+            "Could not change the owner of {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Switches to another user and group for good, giving them the files that
+/// they'll need to keep using first. The supplementary groups are replaced by
+/// the user's own, or by just the group if only a group is given. The group
+/// has to be changed before the user, since changing the user gives up the
+/// right to change it.
+///
+/// This has to be done before any other threads are started. The change is
+/// only made to the calling thread by the kernel, and not every libc passes it
+/// on to the others.
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    owned: &[&Path],
+) -> Result<(), String> {
+    // Safe since it takes no arguments
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(
+            "Can only switch to service.user and service.group when started as root".to_string(),
+        );
+    }
+
+    let account = user.map(lookup_user).transpose()?;
+    let gid = match (group, account.as_ref()) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some(account)) => account.gid,
+        (None, None) => return Ok(()),
+    };
+
+    for path in owned {
+        change_owner(path, account.as_ref().map(|account| account.uid), gid)?;
+    }
+
+    // Safe since the name is a valid C string and the group list holds the
+    // one group that it claims to
+    let groups_result = unsafe {
+        match account.as_ref() {
+            Some(account) => libc::initgroups(account.name.as_ptr(), gid),
+            None => libc::setgroups(1, &gid),
+        }
+    };
+
+    if groups_result < 0 {
+        return Err(format!(
+            "Could not set supplementary groups: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    // Safe since these only take IDs
+    if unsafe { libc::setgid(gid) } < 0 {
+        return Err(format!(
+            "Could not switch to group {}: {}",
+            gid,
+            io::Error::last_os_error()
+        ));
+    }
+
+    if let Some(account) = account.as_ref() {
+        if unsafe { libc::setuid(account.uid) } < 0 {
+            return Err(format!(
+                "Could not switch to user {}: {}",
+                account.uid,
+                io::Error::last_os_error()
+            ));
+        }
+
+        // Getting root back would mean the switch didn't stick
+        if account.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err("Could still switch back to root after dropping privileges".to_string());
+        }
+    }
+
+    Ok(())
+}
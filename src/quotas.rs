@@ -0,0 +1,223 @@
+use crate::config::QuotaConfig;
+use crate::skips::{Skip, SkipReason};
+use chrono::{NaiveDateTime, Timelike};
+use std::collections::{HashMap, VecDeque};
+
+/// How many completed hours of quota reports to keep around
+const REPORT_HISTORY: usize = 24;
+
+/// How long to assume a track is when nothing has been played yet this hour
+const DEFAULT_TRACK_MINUTES: u32 = 4;
+
+/// Counts how many tracks of each genre have been played from each playlist
+/// during the current hour
+#[derive(Debug)]
+pub struct QuotaTracker {
+    /// The start of the hour being counted
+    hour: NaiveDateTime,
+
+    /// The number of plays of each genre, indexed by playlist and then genre
+    played: HashMap<String, HashMap<String, u32>>,
+
+    /// The number of tracks played from any playlist during this hour
+    total: u32,
+
+    /// The reports for the most recently completed hours, most recent last
+    previous: VecDeque<json::JsonValue>,
+}
+
+/// Truncates a time to the start of its hour
+fn start_of_hour(when: NaiveDateTime) -> NaiveDateTime {
+    when.date().and_hms(when.hour(), 0, 0)
+}
+
+impl QuotaTracker {
+    /// Creates a new tracker for the hour containing the given time
+    pub fn new(now: NaiveDateTime) -> Self {
+        QuotaTracker {
+            hour: start_of_hour(now),
+            played: HashMap::new(),
+            total: 0,
+            previous: VecDeque::new(),
+        }
+    }
+
+    /// Gets how many tracks of a genre have been played from a playlist
+    /// this hour
+    fn count(&self, playlist: &str, genre: &str) -> u32 {
+        self.played
+            .get(playlist)
+            .and_then(|genres| genres.get(genre))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Starts counting a new hour if the given time is past the current one,
+    /// logging and saving the report for the hour that just finished
+    pub fn roll_over(&mut self, config: &QuotaConfig, now: NaiveDateTime) {
+        let hour = start_of_hour(now);
+        if hour == self.hour {
+            return;
+        }
+
+        for (playlist, quotas) in config.playlists.iter() {
+            for quota in quotas.iter() {
+                let played = self.count(playlist, &quota.genre);
+                if quota.min.map(|min| played < min).unwrap_or(false) {
+                    warning!(
+                        "quotas",
+                        "{} on {} played {} {} tracks, short of the minimum {}",
+                        self.hour,
+                        playlist,
+                        played,
+                        quota.genre,
+                        quota.min.unwrap()
+                    );
+                }
+
+                if quota.max.map(|max| played > max).unwrap_or(false) {
+                    warning!(
+                        "quotas",
+                        "{} on {} played {} {} tracks, over the maximum {}",
+                        self.hour,
+                        playlist,
+                        played,
+                        quota.genre,
+                        quota.max.unwrap()
+                    );
+                }
+            }
+        }
+
+        let report = self.report_hour(config);
+        self.previous.push_back(report);
+        while self.previous.len() > REPORT_HISTORY {
+            self.previous.pop_front();
+        }
+
+        self.hour = hour;
+        self.played.clear();
+        self.total = 0;
+    }
+
+    /// Counts a track as played from the given playlist
+    pub fn record(&mut self, playlist: &str, genre: Option<&str>) {
+        self.total += 1;
+        if let Some(genre) = genre {
+            *self
+                .played
+                .entry(playlist.to_string())
+                .or_default()
+                .entry(genre.to_string())
+                .or_insert(0) += 1;
+        }
+    }
+
+    /// Checks whether playing another track of the given genre would put the
+    /// playlist over any of its maximums
+    pub fn allows(
+        &self,
+        config: &QuotaConfig,
+        playlist: &str,
+        genre: Option<&str>,
+    ) -> Result<(), Skip> {
+        let (quotas, genre) = match (config.playlists.get(playlist), genre) {
+            (Some(quotas), Some(genre)) => (quotas, genre),
+            _ => return Ok(()),
+        };
+
+        for quota in quotas.iter().filter(|quota| quota.genre == genre) {
+            if let Some(max) = quota.max {
+                if self.count(playlist, genre) >= max {
+                    return Err(Skip::new(
+                        SkipReason::PlayCap,
+                        format!("already played {} {} tracks this hour", max, genre),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the genres on the playlist whose minimums won't be met this hour
+    /// unless they start being played now. This estimates how many tracks are
+    /// left in the hour from how long the tracks so far have taken.
+    pub fn urgent_genres(
+        &self,
+        config: &QuotaConfig,
+        playlist: &str,
+        now: NaiveDateTime,
+    ) -> Vec<String> {
+        let quotas = match config.playlists.get(playlist) {
+            Some(quotas) => quotas,
+            None => return Vec::new(),
+        };
+
+        let elapsed = now.minute();
+        let track_minutes = elapsed
+            .checked_div(self.total)
+            .map(|minutes| minutes.max(1))
+            .unwrap_or(DEFAULT_TRACK_MINUTES);
+
+        let slots_left = (60 - elapsed) / track_minutes;
+        quotas
+            .iter()
+            .filter(|quota| match quota.min {
+                Some(min) => {
+                    let played = self.count(playlist, &quota.genre);
+                    played < min && min - played >= slots_left
+                }
+                None => false,
+            })
+            .map(|quota| quota.genre.to_string())
+            .collect()
+    }
+
+    /// Builds a JSON report of how well each quota is being met during the
+    /// current hour
+    fn report_hour(&self, config: &QuotaConfig) -> json::JsonValue {
+        let mut playlists = json::object::Object::new();
+        for (playlist, quotas) in config.playlists.iter() {
+            let mut entries = Vec::new();
+            for quota in quotas.iter() {
+                let played = self.count(playlist, &quota.genre);
+                let mut entry = json::object::Object::new();
+                entry.insert("genre", json::JsonValue::String(quota.genre.to_string()));
+                entry.insert("played", json::JsonValue::Number(played.into()));
+
+                if let Some(min) = quota.min {
+                    entry.insert("min", json::JsonValue::Number(min.into()));
+                }
+
+                if let Some(max) = quota.max {
+                    entry.insert("max", json::JsonValue::Number(max.into()));
+                }
+
+                let satisfied = quota.min.map(|min| played >= min).unwrap_or(true)
+                    && quota.max.map(|max| played <= max).unwrap_or(true);
+                entry.insert("satisfied", json::JsonValue::Boolean(satisfied));
+                entries.push(json::JsonValue::Object(entry));
+            }
+
+            playlists.insert(playlist, json::JsonValue::Array(entries));
+        }
+
+        let mut report = json::object::Object::new();
+        report.insert("hour", json::JsonValue::String(self.hour.to_string()));
+        report.insert("playlists", json::JsonValue::Object(playlists));
+        json::JsonValue::Object(report)
+    }
+
+    /// Builds a JSON report of the current hour along with the completed hours
+    /// before it
+    pub fn report(&self, config: &QuotaConfig) -> json::JsonValue {
+        let mut report = json::object::Object::new();
+        report.insert("current", self.report_hour(config));
+        report.insert(
+            "previous",
+            json::JsonValue::Array(self.previous.iter().cloned().collect()),
+        );
+        json::JsonValue::Object(report)
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::{RdsConfig, RdsTarget};
+use crate::events::{Event, TrackTags};
+use crate::i18n;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::UdpSocket;
+use std::sync::mpsc::Receiver;
+
+/// Letters and punctuation outside of ASCII which have a close ASCII spelling.
+/// RDS encoders disagree about how to show anything else, so it is dropped.
+const FOLDED: &[(&str, &str)] = &[
+    ("ÀÁÂÃÄÅ", "A"),
+    ("àáâãäå", "a"),
+    ("Æ", "AE"),
+    ("æ", "ae"),
+    ("Ç", "C"),
+    ("ç", "c"),
+    ("ÈÉÊË", "E"),
+    ("èéêë", "e"),
+    ("ÌÍÎÏ", "I"),
+    ("ìíîï", "i"),
+    ("Ñ", "N"),
+    ("ñ", "n"),
+    ("ÒÓÔÕÖØ", "O"),
+    ("òóôõöø", "o"),
+    ("Œ", "OE"),
+    ("œ", "oe"),
+    ("ÙÚÛÜ", "U"),
+    ("ùúûü", "u"),
+    ("Ý", "Y"),
+    ("ýÿ", "y"),
+    ("ß", "ss"),
+    ("‘’", "'"),
+    ("“”", "\""),
+    ("–—", "-"),
+    ("…", "..."),
+];
+
+/// Turns a now-playing string into something that any encoder can show:
+/// printable ASCII with single spaces between words, no longer than the
+/// maximum length
+pub fn sanitize(text: &str, max_length: usize) -> String {
+    let mut folded = String::new();
+    for c in text.chars() {
+        if c.is_ascii_graphic() {
+            folded.push(c);
+        } else if c.is_whitespace() || c.is_control() {
+            folded.push(' ');
+        } else if let Some((_, replacement)) =
+            FOLDED.iter().find(|(letters, _)| letters.contains(c))
+        {
+            folded.push_str(replacement);
+        }
+    }
+
+    let words = folded.split_whitespace().collect::<Vec<_>>().join(" ");
+    let truncated = words.chars().take(max_length).collect::<String>();
+    truncated.trim_end().to_string()
+}
+
+/// Sends the now-playing text to an RDS encoder
+#[derive(Debug)]
+struct RdsOutput {
+    config: RdsConfig,
+
+    /// The socket used for UDP targets, which is bound on the first update
+    socket: Option<UdpSocket>,
+}
+
+impl RdsOutput {
+    fn new(config: RdsConfig) -> Self {
+        RdsOutput {
+            config,
+            socket: None,
+        }
+    }
+
+    /// Describes a track using its tags, or gives the station text if it
+    /// doesn't have a title (as with special entries)
+    fn describe(&self, tags: Option<&TrackTags>) -> String {
+        match tags.filter(|tags| !tags.title.is_empty()) {
+            Some(tags) => i18n::fill(
+                &self.config.template,
+                &[
+                    ("artist", &tags.artist),
+                    ("title", &tags.title),
+                    ("album", &tags.album),
+                ],
+            ),
+            None => self.config.station_text.to_string(),
+        }
+    }
+
+    /// Sends the text to the encoder, logging any errors
+    fn update(&mut self, text: &str) {
+        let text = sanitize(text, self.config.max_length);
+        if let Err(error) = self.send(&text) {
+            warning!("rds", "Could not send now-playing text: {}", error);
+        }
+    }
+
+    fn send(&mut self, text: &str) -> io::Result<()> {
+        let line = format!("{}={}\r\n", self.config.command, text);
+        match &self.config.target {
+            RdsTarget::File(path) => {
+                // Replaced all at once, so that an encoder polling the file
+                // never reads half of it
+                let mut temp_name = OsString::from(path.as_os_str());
+                temp_name.push(".tmp");
+                fs::write(&temp_name, format!("{}\n", text))?;
+                fs::rename(&temp_name, path)
+            }
+
+            RdsTarget::Serial(path) => fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|mut port| port.write_all(line.as_bytes())),
+
+            RdsTarget::Udp(address) => {
+                if self.socket.is_none() {
+                    let bind_address = if address.is_ipv6() {
+                        "[::]:0"
+                    } else {
+                        "0.0.0.0:0"
+                    };
+                    self.socket = Some(UdpSocket::bind(bind_address)?);
+                }
+
+                let socket = self.socket.as_ref().unwrap();
+                socket.send_to(line.as_bytes(), address).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Updates the encoder every time the server serves a track or special entry,
+/// until the server shuts down
+pub fn rds_worker(config: RdsConfig, events: Receiver<Event>) {
+    let mut output = RdsOutput::new(config);
+    for event in events {
+        let text = match event {
+            Event::TrackServed { tags, .. } => output.describe(tags.as_ref()),
+            Event::SpecialServed { .. } => output.describe(None),
+            Event::Shutdown => return,
+            _ => continue,
+        };
+
+        output.update(&text);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::client;
+use crate::events::Event;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// How long the worker waits for changes before checking whether the server
+/// has shut down
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the server can take to reload the playlists, which includes
+/// reading the tags of any new tracks
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The size of the fixed part of an inotify event, before the file name
+const EVENT_HEADER_BYTES: usize = 16;
+
+/// What a batch of inotify events said about the playlist directory
+#[derive(Debug, Default)]
+struct Changes {
+    /// Whether a playlist file was added, removed or written to
+    playlists: bool,
+
+    /// Whether the directory itself was removed or moved, which means that
+    /// nothing more will be heard about it
+    watch_removed: bool,
+}
+
+/// An inotify instance watching the playlist directory, which is closed when
+/// it's dropped
+struct Watch {
+    fd: libc::c_int,
+}
+
+impl Watch {
+    /// Starts watching a directory for files being written, moved and removed
+    fn new(directory: &Path) -> io::Result<Self> {
+        let path = CString::new(directory.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+
+        // Safe since it takes no pointers
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let watch = Watch { fd };
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_MOVED_TO
+            | libc::IN_MOVED_FROM
+            | libc::IN_DELETE
+            | libc::IN_DELETE_SELF
+            | libc::IN_MOVE_SELF
+            | libc::IN_ONLYDIR;
+
+        // Safe since the path is NUL terminated and outlives the call
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(watch)
+    }
+
+    /// Waits for events to arrive, returning whether there are any to read
+    fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        // Safe since exactly one entry is passed
+        let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+
+            return Err(error);
+        }
+
+        Ok(ready > 0)
+    }
+
+    /// Reads every event that is waiting. Only events for .m3u8 files count as
+    /// changes to the playlists, so that editors' swap and backup files don't
+    /// cause reloads.
+    fn read_changes(&self) -> io::Result<Changes> {
+        let mut changes = Changes::default();
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            // Safe since the length passed is the length of the buffer
+            let count = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+
+            if count < 0 {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(changes),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(error),
+                }
+            }
+
+            // The kernel only ever returns whole events, and the fields are
+            // read a byte at a time since the buffer isn't aligned for them
+            let mut events = &buffer[..count as usize];
+            while events.len() >= EVENT_HEADER_BYTES {
+                let field = |offset: usize| {
+                    u32::from_ne_bytes([
+                        events[offset],
+                        events[offset + 1],
+                        events[offset + 2],
+                        events[offset + 3],
+                    ])
+                };
+
+                let mask = field(4);
+                let name_len = field(12) as usize;
+                let name = &events[EVENT_HEADER_BYTES..EVENT_HEADER_BYTES + name_len];
+                let name = match name.iter().position(|byte| *byte == 0) {
+                    Some(end) => &name[..end],
+                    None => name,
+                };
+
+                if mask & libc::IN_Q_OVERFLOW != 0 || name.ends_with(b".m3u8") {
+                    changes.playlists = true;
+                }
+
+                if mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF | libc::IN_IGNORED) != 0 {
+                    changes.watch_removed = true;
+                }
+
+                events = &events[EVENT_HEADER_BYTES + name_len..];
+            }
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        // Safe since nothing else owns the descriptor
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Asks the server to reload the playlists over its IPC socket, the same way
+/// that reload-playlists would
+fn reload_playlists(ipc_socket: &Path, auth: Option<&str>) {
+    let mut command = json::JsonValue::new_object();
+    command["command"] = "reload-playlists".into();
+
+    let response = match client::send_command(ipc_socket, auth, &command, TIMEOUT) {
+        Ok(response) => response,
+        Err(error) => {
+            warning!("reloader", "Could not reload playlists: {}", error);
+            return;
+        }
+    };
+
+    let response = String::from_utf8_lossy(&response);
+    match json::parse(&response) {
+        Ok(document) if document["status"] == "ok" => {
+            info!("reloader", "Reloaded playlists")
+        }
+        _ => warning!(
+            "reloader",
+            "Server refused to reload playlists: {}",
+            response.trim()
+        ),
+    }
+}
+
+/// Watches the playlist directory and reloads the playlists once it has been
+/// left alone for the delay after a playlist file changes, until the server
+/// shuts down
+pub fn reload_worker(
+    playlist_dir: &Path,
+    ipc_socket: &Path,
+    auth: Option<&str>,
+    delay_sec: u32,
+    events: Receiver<Event>,
+) {
+    let watch = match Watch::new(playlist_dir) {
+        Ok(watch) => watch,
+        Err(error) => {
+            error!(
+                "reloader",
+                "Could not watch {}: {}",
+                playlist_dir.display(),
+                error
+            );
+            return;
+        }
+    };
+
+    let delay = Duration::from_secs(delay_sec as u64);
+    let mut last_change: Option<Instant> = None;
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(Event::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        let timeout = match last_change {
+            Some(changed) => delay.saturating_sub(changed.elapsed()).min(POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        };
+
+        let ready = match watch.wait(timeout) {
+            Ok(ready) => ready,
+            Err(error) => {
+                error!("reloader", "Could not wait for changes: {}", error);
+                return;
+            }
+        };
+
+        if ready {
+            let changes = match watch.read_changes() {
+                Ok(changes) => changes,
+                Err(error) => {
+                    error!("reloader", "Could not read changes: {}", error);
+                    return;
+                }
+            };
+
+            // Every change pushes the reload back, so that copying over
+            // several playlists at once only reloads them once
+            if changes.playlists {
+                last_change = Some(Instant::now());
+            }
+
+            if changes.watch_removed {
+                error!(
+                    "reloader",
+                    "{} was removed, no longer watching for changes",
+                    playlist_dir.display()
+                );
+                return;
+            }
+        }
+
+        if let Some(changed) = last_change {
+            if changed.elapsed() >= delay {
+                last_change = None;
+                reload_playlists(ipc_socket, auth);
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::config::RequestConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Where a listener's request is in moderation
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RequestState {
+    /// Waiting for an operator to approve or deny it
+    Pending,
+
+    /// Waiting to be played
+    Approved,
+}
+
+impl RequestState {
+    /// The name of the state as it appears in responses
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestState::Pending => "pending",
+            RequestState::Approved => "approved",
+        }
+    }
+}
+
+/// What a listener asked to have played
+#[derive(Debug, PartialEq, Clone)]
+pub enum RequestItem {
+    /// A track from one of the playlists
+    Track(PathBuf),
+
+    /// A short message to be read out on the air
+    Message(String),
+}
+
+impl fmt::Display for RequestItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestItem::Track(track) => write!(f, "{}", track.display()),
+            RequestItem::Message(text) => write!(f, "message \"{}\"", text),
+        }
+    }
+}
+
+/// Something that a listener asked to have played
+#[derive(Debug, PartialEq, Clone)]
+pub struct Request {
+    /// The number that operators use to approve or deny the request
+    pub id: u64,
+
+    /// What was requested
+    pub item: RequestItem,
+
+    /// Who made the request
+    pub listener: String,
+
+    pub state: RequestState,
+}
+
+/// Why a listener's request was turned down
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    /// The listener made a request too recently, and has to wait this much
+    /// longer before making another
+    RateLimited(Duration),
+
+    /// The same thing is already waiting for moderation or to be played
+    AlreadyRequested,
+}
+
+/// Keeps the requests which are waiting for moderation or to be played, along
+/// with when each listener last made a request so that they can be limited
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    /// The ID given to the last request that was made
+    last_id: u64,
+
+    /// The outstanding requests, in the order they were made
+    requests: Vec<Request>,
+
+    /// When each listener that is still waiting out the interval made their
+    /// last request
+    last_made: HashMap<String, Instant>,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        RequestQueue::default()
+    }
+
+    /// Adds a listener's request for a track or message. It has to be approved
+    /// before it is played if requests are moderated.
+    pub fn submit(
+        &mut self,
+        config: &RequestConfig,
+        listener: String,
+        item: RequestItem,
+    ) -> Result<&Request, RequestError> {
+        let now = Instant::now();
+        let interval = Duration::from_secs(u64::from(config.interval) * 60);
+        self.last_made
+            .retain(|_, made| now.duration_since(*made) < interval);
+
+        if let Some(made) = self.last_made.get(&listener) {
+            return Err(RequestError::RateLimited(
+                interval - now.duration_since(*made),
+            ));
+        }
+
+        if self.requests.iter().any(|request| request.item == item) {
+            return Err(RequestError::AlreadyRequested);
+        }
+
+        self.last_id += 1;
+        let state = if config.moderated {
+            RequestState::Pending
+        } else {
+            RequestState::Approved
+        };
+
+        info!(
+            "requests",
+            "{} requested {} as #{} ({})",
+            listener,
+            item,
+            self.last_id,
+            state.name()
+        );
+
+        self.last_made.insert(listener.clone(), now);
+        self.requests.push(Request {
+            id: self.last_id,
+            item,
+            listener,
+            state,
+        });
+
+        Ok(self.requests.last().unwrap())
+    }
+
+    /// Returns the outstanding requests, in the order they were made
+    pub fn requests(&self) -> &[Request] {
+        &self.requests
+    }
+
+    /// Lets a request be played. Returns false if there is no such request.
+    pub fn approve(&mut self, id: u64) -> bool {
+        match self.requests.iter_mut().find(|request| request.id == id) {
+            Some(request) => {
+                info!("requests", "Approved #{}, {}", id, request.item);
+                request.state = RequestState::Approved;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops a request without playing it. Returns false if there is no such
+    /// request.
+    pub fn deny(&mut self, id: u64) -> bool {
+        match self.requests.iter().position(|request| request.id == id) {
+            Some(index) => {
+                let request = self.requests.remove(index);
+                info!("requests", "Denied #{}, {}", id, request.item);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes and returns the oldest approved request, if there is one
+    pub fn next_approved(&mut self) -> Option<Request> {
+        let index = self
+            .requests
+            .iter()
+            .position(|request| request.state == RequestState::Approved)?;
+
+        Some(self.requests.remove(index))
+    }
+}
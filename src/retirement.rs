@@ -0,0 +1,87 @@
+use crate::config::RetirementConfig;
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The tracks that unretire-track has brought back, with how many plays each
+/// had when it was. They get another round of max_plays from there, and their
+/// retirement dates no longer apply.
+pub type Unretired = HashMap<PathBuf, u64>;
+
+/// Why a track isn't being played anymore
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Retirement {
+    /// The track has been played as many times as it gets
+    Plays(u64),
+
+    /// The track's retirement date has passed
+    Date(NaiveDateTime),
+}
+
+impl Retirement {
+    /// The reason as given by track-stats
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Retirement::Plays(_) => "plays",
+            Retirement::Date(_) => "date",
+        }
+    }
+}
+
+impl fmt::Display for Retirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Retirement::Plays(plays) => write!(f, "retired after {} plays", plays),
+            Retirement::Date(date) => write!(f, "retired on {}", date),
+        }
+    }
+}
+
+impl RetirementConfig {
+    /// How many plays a track gets before it is retired, if it is ever
+    fn max_plays(&self, track: &Path) -> Option<u32> {
+        self.tracks
+            .get(track)
+            .and_then(|rule| rule.max_plays)
+            .or(self.max_plays)
+    }
+
+    /// Checks whether a track has been retired as of the given time, after
+    /// being played the given number of times
+    pub fn retired(
+        &self,
+        track: &Path,
+        plays: u64,
+        unretired: &Unretired,
+        when: &NaiveDateTime,
+    ) -> Option<Retirement> {
+        let base = unretired.get(track).copied();
+        if let Some(max_plays) = self.max_plays(track) {
+            let limit = base.unwrap_or(0) + max_plays as u64;
+            if plays >= limit {
+                return Some(Retirement::Plays(plays));
+            }
+        }
+
+        if base.is_some() {
+            return None;
+        }
+
+        match self.tracks.get(track).and_then(|rule| rule.after) {
+            Some(after) if *when > after => Some(Retirement::Date(after)),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the play that was just counted is the one that retires
+    /// the track
+    pub fn retires_at(&self, track: &Path, plays: u64, unretired: &Unretired) -> bool {
+        match self.max_plays(track) {
+            Some(max_plays) => {
+                plays == unretired.get(track).copied().unwrap_or(0) + max_plays as u64
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::prelude::*;
+use std::time::SystemTime;
+
+/// A source of random numbers for shuffling. Everything that needs randomness
+/// takes one of these, so that a generator with a known seed can be swapped in
+/// when the results have to be reproducible.
+pub trait Rng {
+    /// Returns the next 64 random bits
+    fn next_u64(&mut self) -> u64;
+
+    /// Returns a random number in the range [0, bound). The bound must not be
+    /// zero.
+    fn below(&mut self, bound: u64) -> u64 {
+        // Values in the partial range at the top would make the smaller
+        // results slightly more likely, so they are drawn again
+        let limit = u64::MAX - (u64::MAX % bound);
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+}
+
+/// The xoshiro256** generator, which is fast, has a period of 2^256 - 1 and
+/// passes the usual statistical test suites. It is not suitable for anything
+/// security related, which shuffling playlists isn't.
+#[derive(Debug, Clone)]
+pub struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    /// Creates a generator whose output is entirely determined by the seed.
+    /// The seed is expanded with SplitMix64, as recommended by the authors of
+    /// xoshiro, which also keeps the state from ever being all zeros.
+    pub fn from_seed(seed: u64) -> Self {
+        let mut splitmix = seed;
+        let mut next = || {
+            splitmix = splitmix.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut value = splitmix;
+            value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            value ^ (value >> 31)
+        };
+
+        Xoshiro256 {
+            state: [next(), next(), next(), next()],
+        }
+    }
+
+    /// Creates a generator from a seed that is shared by several generators,
+    /// each with its own name, like one for every playlist. The name is
+    /// hashed into the seed with FNV-1a, so that each named generator gives
+    /// its own numbers and always the same ones for the same seed.
+    pub fn from_named_seed(seed: u64, name: &str) -> Self {
+        let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+
+        Xoshiro256::from_seed(seed ^ hash)
+    }
+
+    /// Creates a generator seeded from /dev/urandom, or from the system time
+    /// when /dev/urandom can't be read (as happens in sandboxes and containers
+    /// without a populated /dev)
+    pub fn from_entropy() -> Self {
+        let mut buffer = [0; 8];
+        let from_urandom = fs::File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(&mut buffer))
+            .is_ok();
+
+        let seed = if from_urandom {
+            u64::from_le_bytes(buffer[..].try_into().unwrap())
+        } else if let Ok(duration) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            duration.as_nanos() as u64
+        } else {
+            12345
+        };
+
+        Xoshiro256::from_seed(seed)
+    }
+}
+
+impl Rng for Xoshiro256 {
+    fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+
+        let shifted = self.state[1] << 17;
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= shifted;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+}
+
+/// Shuffles the items in place with the Fisher-Yates algorithm, which makes
+/// every ordering equally likely
+pub fn shuffle<T>(items: &mut [T], rng: &mut (impl Rng + ?Sized)) {
+    for i in (1..items.len()).rev() {
+        let j = rng.below(i as u64 + 1) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out a fixed list of values, to check what below() does with
+    /// each of them
+    struct Sequence(Vec<u64>);
+
+    impl Rng for Sequence {
+        fn next_u64(&mut self) -> u64 {
+            self.0.remove(0)
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_numbers() {
+        let mut first = Xoshiro256::from_seed(42);
+        let mut second = Xoshiro256::from_seed(42);
+        for _ in 0..1000 {
+            assert_eq!(first.next_u64(), second.next_u64());
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_numbers() {
+        let mut first = Xoshiro256::from_seed(42);
+        let mut second = Xoshiro256::from_seed(43);
+        let first = (0..16).map(|_| first.next_u64()).collect::<Vec<_>>();
+        let second = (0..16).map(|_| second.next_u64()).collect::<Vec<_>>();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn named_seeds_depend_on_the_name() {
+        let mut first = Xoshiro256::from_named_seed(42, "rock");
+        let mut again = Xoshiro256::from_named_seed(42, "rock");
+        let mut other = Xoshiro256::from_named_seed(42, "jazz");
+
+        let first = (0..16).map(|_| first.next_u64()).collect::<Vec<_>>();
+        let again = (0..16).map(|_| again.next_u64()).collect::<Vec<_>>();
+        let other = (0..16).map(|_| other.next_u64()).collect::<Vec<_>>();
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn shuffle_is_reproducible() {
+        let mut first = (0..100).collect::<Vec<_>>();
+        let mut second = first.clone();
+        shuffle(&mut first, &mut Xoshiro256::from_seed(7));
+        shuffle(&mut second, &mut Xoshiro256::from_seed(7));
+        assert_eq!(first, second);
+
+        first.sort_unstable();
+        assert_eq!(first, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn below_stays_in_bounds() {
+        let mut rng = Xoshiro256::from_seed(1);
+        for bound in &[1, 2, 3, 7, 10, 1000, u64::MAX / 3, u64::MAX] {
+            for _ in 0..1000 {
+                assert!(rng.below(*bound) < *bound);
+            }
+        }
+    }
+
+    #[test]
+    fn below_draws_again_in_the_partial_range() {
+        // 2^64 has a remainder of 4 when divided by 6, so the top four values
+        // would make 0 to 3 come up more often than 4 and 5
+        let limit = u64::MAX - (u64::MAX % 6);
+        let mut rng = Sequence(vec![u64::MAX, limit, limit - 1]);
+        assert_eq!(rng.below(6), (limit - 1) % 6);
+        assert!(rng.0.is_empty());
+    }
+
+    #[test]
+    fn below_is_not_biased() {
+        const DRAWS: usize = 60_000;
+        let mut rng = Xoshiro256::from_seed(2);
+        let mut counts = [0usize; 6];
+        for _ in 0..DRAWS {
+            counts[rng.below(6) as usize] += 1;
+        }
+
+        // Each count is about 10000, with a standard deviation of about 91
+        let expected = DRAWS / counts.len();
+        for count in counts.iter() {
+            assert!(
+                (*count as isize - expected as isize).abs() < 500,
+                "counts were {:?}",
+                counts
+            );
+        }
+    }
+}
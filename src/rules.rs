@@ -0,0 +1,305 @@
+use crate::config::{RulesConfig, SeparationRule};
+use crate::skips::{Skip, SkipReason};
+use crate::utils::ID3;
+use chrono::{Duration, NaiveDateTime, Timelike};
+use std::collections::VecDeque;
+use std::path::Path;
+
+/// A track which has been played recently, along with everything the rules
+/// need to know to compare new tracks against it
+#[derive(Debug)]
+pub struct PlayedTrack {
+    pub time: NaiveDateTime,
+    pub tags: Vec<String>,
+
+    /// The artist and album from the ID3 tags, if the track has an album
+    pub album: Option<(String, String)>,
+}
+
+/// The tracks which have been played recently, with the most recent being
+/// at the back
+pub type PlayHistory = VecDeque<PlayedTrack>;
+
+impl RulesConfig {
+    /// Finds the names of all the tags that apply to a track when it is being
+    /// played from the given playlist
+    pub fn tags_for(&self, playlist: &str, track: &Path, id3: Option<&ID3>) -> Vec<String> {
+        let genre = id3.map(|tags| String::from(tags.genre()));
+
+        self.tags
+            .iter()
+            .filter(|(_, tag)| {
+                tag.playlists.iter().any(|p| p == playlist)
+                    || tag.tracks.iter().any(|t| t == track)
+                    || tag.genres.iter().any(|g| Some(g) == genre.as_ref())
+            })
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    /// Builds the history entry for a track, as if it were played at the
+    /// given time
+    pub fn describe(
+        &self,
+        playlist: &str,
+        track: &Path,
+        id3: Option<&ID3>,
+        when: NaiveDateTime,
+    ) -> PlayedTrack {
+        let album = id3.and_then(|tags| {
+            if tags.album().is_empty() {
+                None
+            } else {
+                Some((tags.artist().to_string(), tags.album().to_string()))
+            }
+        });
+
+        PlayedTrack {
+            time: when,
+            tags: self.tags_for(playlist, track, id3),
+            album,
+        }
+    }
+
+    /// Checks a track against every rule, returning a description of the first
+    /// rule that it breaks
+    pub fn check(&self, candidate: &PlayedTrack, history: &PlayHistory) -> Result<(), Skip> {
+        for rule in self.rules.iter() {
+            match rule {
+                SeparationRule::NoRepeatTag(tag) => {
+                    let previous_has_tag = history
+                        .back()
+                        .map(|previous| previous.tags.contains(tag))
+                        .unwrap_or(false);
+
+                    if previous_has_tag && candidate.tags.contains(tag) {
+                        return Err(Skip::new(
+                            SkipReason::Separation,
+                            format!("previous track was also tagged {}", tag),
+                        ));
+                    }
+                }
+
+                SeparationRule::ForbidTagHours(tag, start, end) => {
+                    let hour = candidate.time.hour();
+                    let forbidden = if start <= end {
+                        hour >= *start && hour < *end
+                    } else {
+                        hour >= *start || hour < *end
+                    };
+
+                    if forbidden && candidate.tags.contains(tag) {
+                        return Err(Skip::new(
+                            SkipReason::Daypart,
+                            format!(
+                                "tracks tagged {} can't play between {:02}:00 and {:02}:00",
+                                tag, start, end
+                            ),
+                        ));
+                    }
+                }
+
+                SeparationRule::AlbumSeparation(minutes) => {
+                    if candidate.album.is_none() {
+                        continue;
+                    }
+
+                    let cutoff = candidate.time - Duration::minutes(*minutes as i64);
+                    let recent_match = history
+                        .iter()
+                        .any(|played| played.time > cutoff && played.album == candidate.album);
+
+                    if recent_match {
+                        return Err(Skip::new(
+                            SkipReason::Separation,
+                            format!(
+                                "a track from the same album played in the last {} minutes",
+                                minutes
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops any entries from the history that are too old for any rule to
+    /// care about. The most recent entry is always kept for back-to-back
+    /// checks.
+    pub fn trim_history(&self, history: &mut PlayHistory, now: NaiveDateTime) {
+        let lookback = self
+            .rules
+            .iter()
+            .map(|rule| match rule {
+                SeparationRule::AlbumSeparation(minutes) => *minutes,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+
+        let cutoff = now - Duration::minutes(lookback as i64);
+        while history.len() > 1 && history.front().map(|p| p.time < cutoff).unwrap_or(false) {
+            history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TagDefinition;
+    use chrono::NaiveDate;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2026, 12, 24).and_hms(hour, minute, 0)
+    }
+
+    /// Rules with a christmas tag on the christmas playlist and an explicit
+    /// tag on a single track
+    fn config(rules: Vec<SeparationRule>) -> RulesConfig {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "christmas".to_string(),
+            TagDefinition {
+                playlists: vec!["christmas".to_string()],
+                tracks: Vec::new(),
+                genres: Vec::new(),
+            },
+        );
+        tags.insert(
+            "explicit".to_string(),
+            TagDefinition {
+                playlists: Vec::new(),
+                tracks: vec![PathBuf::from("/music/explicit.mp3")],
+                genres: Vec::new(),
+            },
+        );
+
+        RulesConfig { tags, rules }
+    }
+
+    /// Reads the tags of a track from an ID3v1 footer with just the artist
+    /// and album filled in
+    fn album(artist: &str, album: &str) -> ID3 {
+        let mut footer = vec![0; 128];
+        footer[..3].copy_from_slice(b"TAG");
+        footer[33..33 + artist.len()].copy_from_slice(artist.as_bytes());
+        footer[63..63 + album.len()].copy_from_slice(album.as_bytes());
+        footer[127] = 255;
+
+        ID3::from_stream(&mut Cursor::new(footer))
+            .unwrap_or_else(|_| panic!("Could not read the footer for {}", album))
+    }
+
+    #[test]
+    fn christmas_tracks_cant_play_back_to_back() {
+        let rules = config(vec![SeparationRule::NoRepeatTag("christmas".to_string())]);
+        let track = Path::new("/music/sleigh.mp3");
+
+        let mut history = PlayHistory::new();
+        history.push_back(rules.describe("christmas", track, None, at(12, 0)));
+
+        let christmas = rules.describe("christmas", track, None, at(12, 4));
+        let skip = rules.check(&christmas, &history).unwrap_err();
+        assert_eq!(skip.reason, SkipReason::Separation);
+
+        let other = rules.describe("main", track, None, at(12, 4));
+        assert_eq!(rules.check(&other, &history), Ok(()));
+
+        // Once something else has played in between, the tag can come back
+        history.push_back(other);
+        assert_eq!(rules.check(&christmas, &history), Ok(()));
+    }
+
+    #[test]
+    fn explicit_tracks_cant_play_during_the_day() {
+        let rules = config(vec![SeparationRule::ForbidTagHours(
+            "explicit".to_string(),
+            6,
+            22,
+        )]);
+        let explicit = Path::new("/music/explicit.mp3");
+        let clean = Path::new("/music/clean.mp3");
+        let history = PlayHistory::new();
+
+        let daytime = rules.describe("main", explicit, None, at(6, 0));
+        let skip = rules.check(&daytime, &history).unwrap_err();
+        assert_eq!(skip.reason, SkipReason::Daypart);
+
+        let evening = rules.describe("main", explicit, None, at(21, 59));
+        assert!(rules.check(&evening, &history).is_err());
+
+        let night = rules.describe("main", explicit, None, at(22, 0));
+        assert_eq!(rules.check(&night, &history), Ok(()));
+
+        let clean = rules.describe("main", clean, None, at(12, 0));
+        assert_eq!(rules.check(&clean, &history), Ok(()));
+    }
+
+    #[test]
+    fn forbidden_hours_can_wrap_around_midnight() {
+        let rules = config(vec![SeparationRule::ForbidTagHours(
+            "explicit".to_string(),
+            22,
+            6,
+        )]);
+        let explicit = Path::new("/music/explicit.mp3");
+        let history = PlayHistory::new();
+
+        let late = rules.describe("main", explicit, None, at(23, 0));
+        assert!(rules.check(&late, &history).is_err());
+
+        let early = rules.describe("main", explicit, None, at(5, 59));
+        assert!(rules.check(&early, &history).is_err());
+
+        let noon = rules.describe("main", explicit, None, at(12, 0));
+        assert_eq!(rules.check(&noon, &history), Ok(()));
+    }
+
+    #[test]
+    fn albums_are_kept_apart_for_the_minimum_minutes() {
+        let rules = config(vec![SeparationRule::AlbumSeparation(60)]);
+        let track = Path::new("/music/track.mp3");
+        let abbey_road = album("The Beatles", "Abbey Road");
+
+        let mut history = PlayHistory::new();
+        history.push_back(rules.describe("main", track, Some(&abbey_road), at(12, 0)));
+        history.push_back(rules.describe("main", track, None, at(12, 5)));
+
+        let soon = rules.describe("main", track, Some(&abbey_road), at(12, 59));
+        let skip = rules.check(&soon, &history).unwrap_err();
+        assert_eq!(skip.reason, SkipReason::Separation);
+
+        let later = rules.describe("main", track, Some(&abbey_road), at(13, 0));
+        assert_eq!(rules.check(&later, &history), Ok(()));
+
+        // The same album name by another artist is another album
+        let other = album("Someone Else", "Abbey Road");
+        let other = rules.describe("main", track, Some(&other), at(12, 30));
+        assert_eq!(rules.check(&other, &history), Ok(()));
+
+        // Tracks without an album are never held back
+        let untagged = rules.describe("main", track, None, at(12, 6));
+        assert_eq!(rules.check(&untagged, &history), Ok(()));
+    }
+
+    #[test]
+    fn history_is_trimmed_to_the_longest_rule() {
+        let rules = config(vec![SeparationRule::AlbumSeparation(30)]);
+        let track = Path::new("/music/track.mp3");
+
+        let mut history = PlayHistory::new();
+        for minute in [0, 10, 40].iter() {
+            history.push_back(rules.describe("main", track, None, at(12, *minute)));
+        }
+
+        rules.trim_history(&mut history, at(12, 45));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].time, at(12, 40));
+    }
+}
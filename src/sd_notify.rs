@@ -0,0 +1,120 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Where systemd says to send notifications, when it's running shuffled as a
+/// Type=notify service
+const SOCKET_VARIABLE: &str = "NOTIFY_SOCKET";
+
+/// How often systemd expects a keepalive, in microseconds, when the unit has
+/// WatchdogSec set
+const WATCHDOG_USEC_VARIABLE: &str = "WATCHDOG_USEC";
+
+/// Which process the keepalives are expected from, if systemd says
+const WATCHDOG_PID_VARIABLE: &str = "WATCHDOG_PID";
+
+/// Sends the service's state to systemd over the socket that it passes in the
+/// environment
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+
+    /// How often to send a keepalive, which is half of what systemd waits
+    /// for so that one arriving a little late doesn't get us killed
+    ping_interval: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Notifier {
+    /// Reads the notification socket and the watchdog interval from the
+    /// environment, if systemd has set them. They are removed afterwards so
+    /// that the programs that shuffled runs don't try to notify for it.
+    ///
+    /// This has to be done before any threads are started, since changing
+    /// the environment while another thread reads it isn't safe.
+    pub fn from_environment() -> Option<Notifier> {
+        let path = env::var_os(SOCKET_VARIABLE)?;
+        let watchdog_usec = env::var(WATCHDOG_USEC_VARIABLE).ok();
+        let watchdog_pid = env::var(WATCHDOG_PID_VARIABLE).ok();
+        env::remove_var(SOCKET_VARIABLE);
+        env::remove_var(WATCHDOG_USEC_VARIABLE);
+        env::remove_var(WATCHDOG_PID_VARIABLE);
+
+        // A leading @ is an abstract socket, which has no file behind it
+        let bytes = path.to_string_lossy().into_owned();
+        let address = match bytes.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(&path),
+        };
+
+        let result = address.and_then(|address| Ok((UnixDatagram::unbound()?, address)));
+        let (socket, address) = match result {
+            Ok(socket) => socket,
+            Err(error) => {
+                warning!(
+                    "systemd",
+                    "Could not use notification socket {}: {}",
+                    bytes,
+                    error
+                );
+                return None;
+            }
+        };
+
+        // Keepalives meant for another process, like the one that started us
+        // with --daemon, aren't ours to send
+        let ours = watchdog_pid
+            .map(|pid| pid.parse::<u32>().ok() == Some(process::id()))
+            .unwrap_or(true);
+
+        let ping_interval = watchdog_usec
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && ours)
+            .map(|usec| Duration::from_micros(usec) / 2);
+
+        Some(Notifier {
+            socket,
+            address,
+            ping_interval,
+            last_ping: Instant::now(),
+        })
+    }
+
+    fn send(&self, state: &str) -> io::Result<()> {
+        self.socket
+            .send_to_addr(state.as_bytes(), &self.address)
+            .map(|_| ())
+    }
+
+    /// Tells systemd that startup is done
+    pub fn ready(&mut self) {
+        match self.send("READY=1") {
+            Ok(()) => info!("systemd", "Told systemd that shuffled is ready"),
+            Err(error) => warning!("systemd", "Could not send readiness: {}", error),
+        }
+
+        self.last_ping = Instant::now();
+    }
+
+    /// How long until the next keepalive is due, if systemd wants them
+    pub fn time_until_ping(&self) -> Option<Duration> {
+        self.ping_interval
+            .map(|interval| interval.saturating_sub(self.last_ping.elapsed()))
+    }
+
+    /// Sends a keepalive if one is due
+    pub fn ping_if_due(&mut self) {
+        if self.time_until_ping() != Some(Duration::from_secs(0)) {
+            return;
+        }
+
+        if let Err(error) = self.send("WATCHDOG=1") {
+            warning!("systemd", "Could not send keepalive: {}", error);
+        }
+
+        self.last_ping = Instant::now();
+    }
+}
@@ -1,262 +1,475 @@
-use crate::config::{ServiceConfig, SpecialBaseConfig};
+use crate::config;
+use crate::config::{
+    AnnouncerTools, AvailabilityConfig, Config, Locale, LogLevel, MissedSpecials, PartsConfig,
+    PlaylistEnd, PlaylistEndConfig, ProtocolVersion, QuotaConfig, RampConfig, RequestConfig,
+    RetirementConfig, RulesConfig, ServiceConfig, ShuffleMode, SmartPlaylist, SpecialBaseConfig,
+    StartupSettings, VoteAction, VoteConfig,
+};
+use crate::diagnostics::Diagnostics;
+use crate::environment::Environment;
+use crate::events::{Event, EventBus, TrackTags};
+use crate::i18n::Phrase;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::monitor;
+use crate::parts::{KeepParts, Works};
+use crate::privileges;
+use crate::quotas;
+use crate::requests::{Request, RequestError, RequestItem, RequestQueue};
+use crate::retirement::{Retirement, Unretired};
+use crate::rules;
+use crate::sd_notify::Notifier;
+use crate::signals::Hangups;
+use crate::skips::{Skip, SkipReason, SkipTracker};
+use crate::state::{self, AddedTracks, SpentTracks, StateFile};
+use crate::storage;
+use crate::tags;
 use crate::utils;
-use chrono::{Local, Timelike};
+use crate::votes::{Vote, VoteOutcome, VoteTracker};
+use crate::waker::Workers;
+use chrono::{DateTime, Local, NaiveDateTime, Timelike};
 use json;
-use random;
-use std::borrow::Cow;
-use std::collections::HashMap;
+use shuffled::playlist::{
+    self, Durations, FisherYates, LeastPlayed, PlayCount, PlayCounts, Playlist, ShuffleStrategy,
+    SimplePlaylists,
+};
+use shuffled::rng::Xoshiro256;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::str;
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 /// The commands that can be received from RPC, in addition to some error cases
 /// that can be reported when the commands are parsed
 #[derive(Debug, PartialEq)]
 enum RpcRequest {
     NextTrack,
-    ListPlaylists,
+    PreviousTrack(usize),
+    NowPlaying,
+    ListPlaylists(ListOptions),
     GetPlaylist,
     SwitchPlaylist(String),
-    ReloadPlaylists,
+    SeekPlaylist(String, SeekTarget),
+    ReloadPlaylists(bool),
+    PreviewReload,
+    CommitReload,
+    RollbackPlaylists(usize),
     ShufflePlaylists,
     PreviewPlaylist(String),
+    GetPlaylistTracks(String, ListOptions),
+    SearchTracks(ListOptions),
     ReloadTags,
+    QuotaReport,
+    Status,
+    Ping,
+    Wake(String),
+    Restarting,
+    Shutdown,
+    Vote(Vote, Option<String>),
+    RequestTrack(String, Option<String>),
+    SubmitMessage(String, Option<String>),
+    ListRequests(ListOptions),
+    GetHistory(ListOptions),
+    TrackStats(ListOptions, bool),
+    UnretireTrack(PathBuf),
+    TrackInfo(PathBuf),
+    ApproveRequest(u64),
+    DenyRequest(u64),
+    SetFraming(Framing),
+    SetProtocol(ProtocolVersion),
+    Unauthorized,
     InvalidRequest,
     UnknownCommand,
-    InvalidParameter,
+    InvalidParameter(String, String),
 }
 
-/// The responses that can be sent back over RPC
-#[derive(Debug, PartialEq)]
-enum RpcResponse<'a> {
-    Ok,
-    Track(PathBuf),
-    Tracks(json::JsonValue),
-    Playlists(Vec<&'a String>),
-    Playlist(&'a str),
-    NoSuchPlaylist,
-    NoPlaylistsAvailable,
-    InvalidRequest,
-    UnknownCommand,
-    InvalidParameter,
-}
-
-/// A single playlists and its current position
-#[derive(Debug)]
-struct Playlist {
-    position: usize,
-    songs: Vec<PathBuf>,
-}
-
-impl Playlist {
-    /// Creates a new playlist that has its current position set to the first
-    /// song
-    fn new(songs: Vec<PathBuf>) -> Option<Playlist> {
-        if songs.len() == 0 {
-            None
-        } else {
-            Some(Playlist { position: 0, songs })
+impl RpcRequest {
+    /// The canonical name of the command, if the request was understood
+    fn command_name(&self) -> Option<&'static str> {
+        match self {
+            RpcRequest::NextTrack => Some("next-track"),
+            RpcRequest::PreviousTrack(_) => Some("previous-track"),
+            RpcRequest::NowPlaying => Some("now-playing"),
+            RpcRequest::ListPlaylists(_) => Some("list-playlists"),
+            RpcRequest::GetPlaylist => Some("get-playlist"),
+            RpcRequest::SwitchPlaylist(_) => Some("switch-playlist"),
+            RpcRequest::SeekPlaylist(_, _) => Some("seek-playlist"),
+            RpcRequest::ReloadPlaylists(_) => Some("reload-playlists"),
+            RpcRequest::PreviewReload => Some("preview-reload"),
+            RpcRequest::CommitReload => Some("commit-reload"),
+            RpcRequest::RollbackPlaylists(_) => Some("rollback-playlists"),
+            RpcRequest::ShufflePlaylists => Some("shuffle-playlists"),
+            RpcRequest::PreviewPlaylist(_) => Some("preview-playlist"),
+            RpcRequest::GetPlaylistTracks(_, _) => Some("get-playlist-tracks"),
+            RpcRequest::SearchTracks(_) => Some("search-tracks"),
+            RpcRequest::ReloadTags => Some("reload-tags"),
+            RpcRequest::QuotaReport => Some("quota-report"),
+            RpcRequest::Status => Some("status"),
+            RpcRequest::Ping => Some("ping"),
+            RpcRequest::Wake(_) => Some("wake"),
+            RpcRequest::Restarting => Some("restarting"),
+            RpcRequest::Shutdown => Some("shutdown"),
+            RpcRequest::Vote(Vote::Skip, _) => Some("vote-skip"),
+            RpcRequest::Vote(Vote::Keep, _) => Some("vote-keep"),
+            RpcRequest::RequestTrack(_, _) => Some("request-track"),
+            RpcRequest::SubmitMessage(_, _) => Some("submit-message"),
+            RpcRequest::ListRequests(_) => Some("list-requests"),
+            RpcRequest::GetHistory(_) => Some("get-history"),
+            RpcRequest::TrackStats(_, _) => Some("track-stats"),
+            RpcRequest::UnretireTrack(_) => Some("unretire-track"),
+            RpcRequest::TrackInfo(_) => Some("track-info"),
+            RpcRequest::ApproveRequest(_) => Some("approve-request"),
+            RpcRequest::DenyRequest(_) => Some("deny-request"),
+            RpcRequest::SetFraming(_) => Some("set-framing"),
+            RpcRequest::SetProtocol(_) => Some("set-protocol"),
+            RpcRequest::Unauthorized
+            | RpcRequest::InvalidRequest
+            | RpcRequest::UnknownCommand
+            | RpcRequest::InvalidParameter(_, _) => None,
         }
     }
 
-    /// Gets the playlist's current position
-    fn position(&self) -> usize {
-        self.position
-    }
-
-    /// Resets the playlist's current position to the given position
-    fn seek(&mut self, position: usize) {
-        self.position = position % self.songs.len();
-    }
-
-    /// Returns the playlist's current song
-    fn current(&self) -> &PathBuf {
-        &self.songs[self.position]
-    }
-
-    /// Advances the current song to the next song
-    fn next(&mut self) {
-        self.position = (self.position + 1) % self.songs.len();
+    /// Whether the command changes what's played or controls shuffled itself,
+    /// which service.admin_users and service.admin_groups can restrict. That
+    /// includes preview-reload, since the reload it stages is the one that
+    /// commit-reload applies. The rest only read the state, or are the
+    /// listener features that have limits of their own.
+    fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            RpcRequest::NextTrack
+                | RpcRequest::SwitchPlaylist(_)
+                | RpcRequest::SeekPlaylist(_, _)
+                | RpcRequest::ReloadPlaylists(_)
+                | RpcRequest::PreviewReload
+                | RpcRequest::CommitReload
+                | RpcRequest::RollbackPlaylists(_)
+                | RpcRequest::ShufflePlaylists
+                | RpcRequest::ReloadTags
+                | RpcRequest::Wake(_)
+                | RpcRequest::Restarting
+                | RpcRequest::Shutdown
+                | RpcRequest::UnretireTrack(_)
+                | RpcRequest::ApproveRequest(_)
+                | RpcRequest::DenyRequest(_)
+        )
     }
+}
 
-    /// Shuffles the playlist and resets the current position
-    fn shuffle(&mut self, rng: &mut impl random::Source) {
-        shuffle(&mut self.songs, rng);
-        self.position = 0;
-    }
+/// Where seek-playlist moves a playlist to
+#[derive(Debug, PartialEq)]
+enum SeekTarget {
+    /// The index of a track, counting from the start of the playlist as
+    /// get-playlist-tracks does
+    Index(usize),
 
-    /// Computes a delta between this playlist and another set of songs
-    fn diff_playlist(&self, playlist: &Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
-        let mut to_add = Vec::new();
-        let mut to_remove = Vec::new();
+    /// The path of a track on the playlist
+    Track(PathBuf),
+}
 
-        for song in self.songs.iter() {
-            if !playlist.contains(song) {
-                to_remove.push(song.to_path_buf());
-            }
-        }
+/// What produced a track returned by next-track, so that clients can tell
+/// songs apart from the special entries
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TrackKind {
+    Music,
+    Request,
+    Message,
+    Clock,
+    Weather,
+}
 
-        for playlist_song in playlist.iter() {
-            if !self.songs.contains(playlist_song) {
-                to_add.push(playlist_song.to_path_buf());
-            }
+impl TrackKind {
+    /// The name of the kind as it appears in responses
+    fn name(&self) -> &'static str {
+        match self {
+            TrackKind::Music => "music",
+            TrackKind::Request => "request",
+            TrackKind::Message => "message",
+            TrackKind::Clock => "clock",
+            TrackKind::Weather => "weather",
         }
-
-        (to_add, to_remove)
     }
 
-    /// Updates the ID3 directory and adds adds tags for any files that do not
-    /// exist already. Any files in the directory are skipped.
-    fn update_id3_directory(&self, directory: &mut ID3Directory) {
-        for song in self.songs.iter() {
-            let path_tags = song
-                .as_path()
-                .to_str()
-                .ok_or(format!(
-                    "Could not convert path {} to UTF-8 string",
-                    song.display()
-                ))
-                .and_then(|path| {
-                    if !directory.contains_key(path) {
-                        Ok(path)
-                    } else {
-                        Err(format!("ID3 for {} already cached", path))
-                    }
-                })
-                .and_then(|path| {
-                    fs::File::open(path)
-                        .map(|file| (path, file))
-                        .or_else(|err| Err(format!("Could not open file at {}: {}", path, err)))
-                })
-                .and_then(|(path, mut file)| {
-                    utils::ID3::from_stream(&mut file)
-                        .or_else(|err| {
-                            let err_msg: String = err.into();
-                            Err(format!(
-                                "Could not parse tags from {}: {}",
-                                song.display(),
-                                err_msg
-                            ))
-                        })
-                        .map(|tags| (path, tags))
-                });
-
-            match path_tags {
-                Ok((path, tags)) => {
-                    directory.insert(path.to_string(), tags);
-                }
-                Err(error) => {
-                    eprintln!("[service] {}", error);
-                }
-            }
+    /// The name of the task that generated the track, if it is a special entry
+    fn module(&self) -> Option<&'static str> {
+        match self {
+            TrackKind::Music | TrackKind::Request => None,
+            TrackKind::Message => Some("messages"),
+            TrackKind::Clock => Some("clock"),
+            TrackKind::Weather => Some("weather"),
         }
     }
+}
 
-    /// Adds and removes songs from the given delta lists, putting all the songs
-    /// in the add list at the end
-    fn merge_songs(&mut self, to_add: &[PathBuf], to_remove: &[PathBuf]) {
-        let mut to_remove_indices = Vec::new();
-        for (idx, path) in self.songs.iter().enumerate() {
-            if to_remove.contains(path) {
-                to_remove_indices.push(idx);
-            }
-        }
-
-        let mut offset = 0;
-        for idx in to_remove_indices.iter() {
-            self.songs.remove(idx + offset);
-
-            // Try to keep the current song at the current position, so that we
-            // don't miss playing any songs. We don't care about songs after the
-            // current position but we do care about those before, since removing
-            // those will shift the playlist back and cause repeats.
-            //
-            // A B C D E
-            //     ^
-            //
-            // B C D E   A being removed should shift us back
-            //   ^
-            //
-            // A B C D   E being remove doesn't matter
-            //     ^
-            //
-            // A B D     Removing the current should do nothing, since we look at
-            //     ^     current entry (now D, the old next) first and then advance it
-            if *idx < self.position {
-                self.position -= 1
-            }
-
-            offset -= 1;
-        }
-
-        self.songs.extend_from_slice(&to_add);
+/// The responses that can be sent back over RPC
+#[derive(Debug, PartialEq)]
+enum RpcResponse<'a> {
+    Ok,
+    Pong,
+    Track(PathBuf, TrackKind),
 
-        if self.position > self.songs.len() {
-            self.position = 0
-        }
-    }
+    /// A track along with its tags and duration, for connections that asked
+    /// for them
+    DetailedTrack(PathBuf, TrackKind, json::JsonValue),
+    Tracks(json::JsonValue),
+    TrackPage(json::JsonValue, usize),
+    Playlists(Vec<&'a String>, usize),
+    Playlist(&'a str),
+    Quotas(json::JsonValue),
+    Status(json::JsonValue),
+    ReloadPreview(json::JsonValue),
+    NoSuchPlaylist,
+    NoSuchWorker,
+    Votes(VoteOutcome),
+    NoCurrentTrack,
+    Request(json::JsonValue),
+    Requests(json::JsonValue, usize),
+    History(json::JsonValue, usize),
+    TrackStats(json::JsonValue, usize),
+    TrackInfo(json::JsonValue),
+    NowPlaying(json::JsonValue),
+    NoSuchTrack,
+    AmbiguousRequest(json::JsonValue),
+    AlreadyRequested,
+    RateLimited(u64),
+    NoSuchRequest,
+    LibraryUnavailable,
+    TooManyRemoved(usize, usize),
+    NoPlaylistsAvailable,
+    NoPendingReload,
+    NoSuchSnapshot,
+    Unauthorized,
+    Forbidden,
+    InvalidRequest,
+    UnknownCommand,
+    InvalidParameter(String, String),
 }
 
 /// A group of named playlists and their current positions
 type Playlists = HashMap<String, Playlist>;
 
-/// A group of named playlists without any position information
-type SimplePlaylists = HashMap<String, Vec<PathBuf>>;
+/// The playlists as they were before a reload replaced them, along with the
+/// track lengths that were read with them
+#[derive(Debug)]
+struct PlaylistSnapshot {
+    current_playlist: String,
+    playlists: Playlists,
+    durations: Durations,
+}
 
 /// A repository of all ID3 tags organized by file
 type ID3Directory = HashMap<String, utils::ID3>;
 
+/// Updates the ID3 directory and adds adds tags for any of the files that do
+/// not exist already. Any files in the directory are skipped.
+fn update_id3_directory(songs: &[PathBuf], directory: &mut ID3Directory) {
+    for song in songs.iter() {
+        let path_tags = song
+            .as_path()
+            .to_str()
+            .ok_or(format!(
+                "Could not convert path {} to UTF-8 string",
+                song.display()
+            ))
+            .and_then(|path| {
+                if !directory.contains_key(path) {
+                    Ok(path)
+                } else {
+                    Err(format!("ID3 for {} already cached", path))
+                }
+            })
+            .and_then(|path| {
+                fs::File::open(path)
+                    .map(|file| (path, file))
+                    .map_err(|err| format!("Could not open file at {}: {}", path, err))
+            })
+            .and_then(|(path, mut file)| {
+                tags::read_tags(&mut file)
+                    .map_err(|err| format!("Could not parse tags from {}: {}", song.display(), err))
+                    .map(|tags| (path, tags))
+            });
+
+        match path_tags {
+            Ok((path, tags)) => {
+                directory.insert(path.to_string(), tags);
+            }
+            Err(error) => {
+                warning!("service", "{}", error);
+            }
+        }
+    }
+}
+
 /// An entry in the special playlist, which either reports an existing file or
 /// generates one
 #[derive(Debug)]
 enum SpecialQueueEntry {
     TimeGenerator,
-    File(PathBuf),
+    File(PathBuf, TrackKind),
+}
+
+impl SpecialQueueEntry {
+    /// The kind of track that the entry plays
+    fn kind(&self) -> TrackKind {
+        match self {
+            SpecialQueueEntry::TimeGenerator => TrackKind::Clock,
+            SpecialQueueEntry::File(_, kind) => *kind,
+        }
+    }
 }
 
-/// The path of the clock MP3 file within the special working directory
+/// The name of the clock MP3 file within the special working directory, after
+/// the prefix
 const CLOCK_MP3_FILE: &str = "clock-stereo.mp3";
 
+/// The name of the MP3 file for listener messages within the special working
+/// directory, after the prefix
+const MESSAGE_MP3_FILE: &str = "message-stereo.mp3";
+
 /// The playlist and timing for the special weather/time report queue
 #[derive(Debug)]
 struct SpecialQueue {
     entries: Vec<SpecialQueueEntry>,
     position: usize,
+
+    /// When each of the entries was last played, if it has been
+    last_played: Vec<Option<SystemTime>>,
     working_dir: PathBuf,
+    file_prefix: String,
+    tools: AnnouncerTools,
     last_play_time: SystemTime,
     interval: Duration,
+    missed: MissedSpecials,
+
+    /// How many more entries are owed for intervals that elapsed while
+    /// nothing was asking for tracks, when they are all being played
+    backlog: usize,
+    locale: Locale,
+
+    /// Whether the entries are only being simulated, in which case the clock
+    /// isn't generated and every entry is taken to be available
+    dry_run: bool,
 }
 
 impl SpecialQueue {
+    /// Builds the rotation of special entries for the enabled tasks, with the
+    /// interval starting at the given time
+    fn new(
+        service_config: &ServiceConfig,
+        special_config: SpecialBaseConfig,
+        now: SystemTime,
+    ) -> Self {
+        let mut entries = Vec::new();
+        if service_config.clock_enabled {
+            entries.push(SpecialQueueEntry::TimeGenerator);
+        }
+
+        if service_config.weather_enabled {
+            entries.push(SpecialQueueEntry::File(
+                utils::special_file(
+                    &special_config.working_dir,
+                    &special_config.file_prefix,
+                    crate::weather::WEATHER_MP3_FILE,
+                ),
+                TrackKind::Weather,
+            ));
+        }
+
+        SpecialQueue {
+            last_played: vec![None; entries.len()],
+            entries,
+            position: 0,
+            working_dir: special_config.working_dir,
+            file_prefix: special_config.file_prefix,
+            tools: special_config.tools,
+            last_play_time: now,
+            interval: Duration::from_secs(special_config.interval as u64 * 60)
+                / special_config.time_scale,
+            missed: special_config.missed,
+            backlog: 0,
+            locale: special_config.locale,
+            dry_run: false,
+        }
+    }
+
+    /// Takes on the special settings from a re-read configuration. The
+    /// entries themselves follow service.tasks, which is fixed.
+    fn apply_config(&mut self, special: SpecialBaseConfig) {
+        self.working_dir = special.working_dir;
+        self.file_prefix = special.file_prefix;
+        self.tools = special.tools;
+        self.interval = Duration::from_secs(special.interval as u64 * 60) / special.time_scale;
+        self.missed = special.missed;
+        self.locale = special.locale;
+    }
+
+    /// Counts how many whole intervals have elapsed since the previous play of
+    /// a special entry item
+    fn elapsed_intervals(&self, now: SystemTime) -> u128 {
+        match now.duration_since(self.last_play_time) {
+            Ok(delta) => delta.as_nanos() / self.interval.as_nanos().max(1),
+            Err(_) => 0,
+        }
+    }
+
     /// Checks whether enough time has elapsed since the previous play of a
-    /// special entry item
-    fn is_special_pending(&self) -> bool {
-        if self.entries.len() == 0 {
+    /// special entry item. When skipping missed entries, an interval that
+    /// elapsed more than once moves past the entries that were missed instead.
+    fn is_special_pending(&mut self, now: SystemTime) -> bool {
+        if self.entries.is_empty() {
             return false;
         }
 
-        let since_last_time =
-            if let Ok(delta) = SystemTime::now().duration_since(self.last_play_time) {
-                delta
-            } else {
-                return false;
-            };
+        if self.backlog > 0 {
+            return true;
+        }
+
+        let elapsed = self.elapsed_intervals(now);
+        if elapsed > 1 && self.missed == MissedSpecials::Skip {
+            info!("server", "Skipping {} missed special entries", elapsed);
+            self.position = (self.position + (elapsed % self.entries.len() as u128) as usize)
+                % self.entries.len();
+            self.last_play_time = now;
+            return false;
+        }
 
-        return since_last_time >= self.interval;
+        elapsed >= 1
     }
 
-    /// Updates the timer once a special item has been queued
-    fn update_timer(&mut self) {
-        self.last_play_time = SystemTime::now()
+    /// Builds the path of one of the files in the working directory
+    fn special_file(&self, name: &str) -> PathBuf {
+        utils::special_file(&self.working_dir, &self.file_prefix, name)
     }
 
-    /// Returns the path to the current special entry
-    fn current(&self) -> Option<PathBuf> {
+    /// Updates the timer once a special item has been queued. When playing
+    /// every missed entry, this also works out how many are still owed.
+    fn update_timer(&mut self, now: SystemTime) {
+        if self.missed == MissedSpecials::All {
+            self.backlog = if self.backlog > 0 {
+                self.backlog - 1
+            } else {
+                let owed = self.elapsed_intervals(now).min(self.entries.len() as u128) as usize;
+                owed.saturating_sub(1)
+            };
+        }
+
+        self.last_play_time = now
+    }
+
+    /// Returns the path to the current special entry, along with what kind of
+    /// entry it is. The clock is read out for the given time.
+    fn current(&self, now: SystemTime) -> Option<(PathBuf, TrackKind)> {
         if self.entries.len() == 0 {
             return None;
         }
@@ -264,36 +477,187 @@ impl SpecialQueue {
         match &self.entries[self.position] {
             SpecialQueueEntry::TimeGenerator => {
                 let paths = utils::FileOutputs {
-                    mono_wav: &self.working_dir.join("clock-mono.wav"),
-                    stereo_wav: &self.working_dir.join("clock-stereo.wav"),
-                    lame_mp3: &self.working_dir.join("clock-transcode.mp3"),
-                    final_mp3: &self.working_dir.join(CLOCK_MP3_FILE),
+                    mono_wav: &self.special_file("clock-mono.wav"),
+                    stereo_wav: &self.special_file("clock-stereo.wav"),
+                    lame_mp3: &self.special_file("clock-transcode.mp3"),
+                    final_mp3: &self.special_file(CLOCK_MP3_FILE),
                 };
 
-                let current_time = Local::now();
-                let announcement = format!(
-                    "The current time is {:02} {:02} hours. Repeat, the current time is {:02} {:02} hours",
-                    current_time.hour(),
-                    current_time.minute(),
-                    current_time.hour(),
-                    current_time.minute()
-                );
+                if self.dry_run {
+                    return Some((paths.final_mp3.to_path_buf(), TrackKind::Clock));
+                }
 
-                if let Err(error) = utils::read_text_announcement(&announcement, &paths, "Clock") {
-                    eprintln!("[service] {}", error);
+                let current_time = DateTime::<Local>::from(now);
+                let time = self
+                    .locale
+                    .time_words(current_time.hour(), current_time.minute());
+                let announcement = self.locale.phrase(Phrase::Clock, &[("time", &time)]);
+
+                if let Err(error) = utils::read_text_announcement(
+                    &announcement,
+                    &paths,
+                    "Clock",
+                    self.locale.voice(),
+                    &self.tools,
+                ) {
+                    warning!("service", "{}", error);
                     None
                 } else {
-                    Some(paths.final_mp3.to_path_buf())
+                    Some((paths.final_mp3.to_path_buf(), TrackKind::Clock))
                 }
             }
 
-            SpecialQueueEntry::File(path) => Some(path.clone()),
+            SpecialQueueEntry::File(path, kind) => Some((path.clone(), *kind)),
         }
     }
 
     fn next(&mut self) {
         self.position = (self.position + 1) % self.entries.len();
     }
+
+    /// Finds the first entry from the current one onwards that can be played,
+    /// and moves past it. Entries that can't be played are moved past as
+    /// well, so that one which is stuck (like a weather report that failed to
+    /// download) doesn't hold up the rest of the rotation.
+    fn take_available(&mut self, now: SystemTime) -> Option<(PathBuf, TrackKind)> {
+        for _ in 0..self.entries.len() {
+            let position = self.position;
+            let entry = self.current(now);
+            self.next();
+
+            match entry {
+                Some((special, kind)) if self.dry_run || special.is_file() => {
+                    self.last_played[position] = Some(now);
+                    return Some((special, kind));
+                }
+                Some((special, _)) => warning!(
+                    "server",
+                    "Skipping special entry, {} not available",
+                    special.display()
+                ),
+                None => warning!("server", "Skipping special entry, could not generate it"),
+            }
+        }
+
+        None
+    }
+
+    /// Describes each entry and when it was last played, for the status
+    fn report(&self) -> json::JsonValue {
+        let entries = self
+            .entries
+            .iter()
+            .zip(self.last_played.iter())
+            .map(|(entry, last_played)| {
+                let last_played = last_played
+                    .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map(|since_epoch| json::JsonValue::Number(since_epoch.as_secs().into()))
+                    .unwrap_or(json::JsonValue::Null);
+
+                let mut report = json::object::Object::new();
+                report.insert(
+                    "kind",
+                    json::JsonValue::String(entry.kind().name().to_string()),
+                );
+                report.insert("last_played", last_played);
+                json::JsonValue::Object(report)
+            })
+            .collect::<Vec<_>>();
+
+        json::JsonValue::Array(entries)
+    }
+
+    /// Reads out a message that was approved by an operator, returning the
+    /// path to the announcement. Messages are one-shot entries that play
+    /// outside of the rotation, so they don't affect the timer.
+    fn read_message(&self, text: &str) -> Option<PathBuf> {
+        let paths = utils::FileOutputs {
+            mono_wav: &self.special_file("message-mono.wav"),
+            stereo_wav: &self.special_file("message-stereo.wav"),
+            lame_mp3: &self.special_file("message-transcode.mp3"),
+            final_mp3: &self.special_file(MESSAGE_MP3_FILE),
+        };
+
+        if let Err(error) =
+            utils::read_text_announcement(text, &paths, "Message", self.locale.voice(), &self.tools)
+        {
+            warning!("service", "{}", error);
+            None
+        } else {
+            Some(paths.final_mp3.to_path_buf())
+        }
+    }
+}
+
+/// Picks out the shuffle strategy for the configured mode, which keeps the
+/// parts of each work together if there are any
+fn shuffle_strategy<'a>(
+    mode: ShuffleMode,
+    play_counts: &'a PlayCounts,
+    works: &'a Works,
+) -> Box<dyn ShuffleStrategy + 'a> {
+    let strategy: Box<dyn ShuffleStrategy> = match mode {
+        ShuffleMode::Uniform => Box::new(FisherYates),
+        ShuffleMode::Weighted => Box::new(LeastPlayed::new(play_counts)),
+    };
+
+    if works.is_empty() {
+        strategy
+    } else {
+        Box::new(KeepParts {
+            inner: strategy,
+            works,
+        })
+    }
+}
+
+/// Checks whether a track matches every part of a smart playlist's query.
+/// Tracks whose tags couldn't be read only match queries that don't look at
+/// them, and tracks that weren't added by a reload only match queries that
+/// don't look at when they were added.
+fn smart_playlist_matches(
+    query: &SmartPlaylist,
+    tags: Option<&utils::ID3>,
+    added: Option<SystemTime>,
+    now: SystemTime,
+) -> bool {
+    if let Some(days) = query.added_within_days {
+        let window = Duration::from_secs(days as u64 * 24 * 60 * 60);
+        let recent = added
+            .map(|added| now.duration_since(added).unwrap_or_default() <= window)
+            .unwrap_or(false);
+        if !recent {
+            return false;
+        }
+    }
+
+    let tags = match tags {
+        Some(tags) => tags,
+        None => {
+            return query.genres.is_empty()
+                && query.artists.is_empty()
+                && query.year_min.is_none()
+                && query.year_max.is_none()
+        }
+    };
+
+    let genre = String::from(tags.genre());
+    let matches_any = |wanted: &[String], value: &str| {
+        wanted.is_empty() || wanted.iter().any(|entry| entry.eq_ignore_ascii_case(value))
+    };
+
+    // Tracks without a year have it as 0, which is outside of any range
+    let year = tags.year();
+    matches_any(&query.genres, &genre)
+        && matches_any(&query.artists, tags.artist())
+        && query
+            .year_min
+            .map(|min| year != 0 && year >= min)
+            .unwrap_or(true)
+        && query
+            .year_max
+            .map(|max| year != 0 && year <= max)
+            .unwrap_or(true)
 }
 
 /// The current playlist and song as well as all registered playlists
@@ -302,195 +666,1572 @@ struct PlaylistQueue {
     current_playlist: String,
     playlists: Playlists,
     directory: PathBuf,
+
+    /// The directory whose subdirectories are read as playlists, if any
+    content_root: Option<PathBuf>,
+
+    /// Where the music files live, which is checked before reloading so that
+    /// a missing mount doesn't empty out the playlists
+    library_dir: Option<PathBuf>,
+
+    /// The largest percentage of tracks that a reload can remove unless forced
+    reload_max_removed: u32,
+
+    /// How the playlists are put in order, both when they are shuffled and
+    /// when a reload adds new songs to them
+    shuffle: ShuffleMode,
+
+    /// How often each music track has been played, which the weighted
+    /// shuffle and track-stats go by
+    play_counts: PlayCounts,
+
+    /// When each track that a reload put on the playlists was added, kept
+    /// for as long as a smart playlist looks back for added tracks
+    added: AddedTracks,
+
+    /// When tracks stop being played, and the ones that have been brought
+    /// back since
+    retirement: RetirementConfig,
+    unretired: Unretired,
+
+    /// The playlists which are picked out of the playlist files by their tags
+    /// whenever they are read
+    smart_playlists: HashMap<String, SmartPlaylist>,
     id3_tags: HashMap<String, utils::ID3>,
-}
 
-impl PlaylistQueue {
-    /// Shuffles all the playlists in the queue
-    fn shuffle_all(&mut self, rng: &mut impl random::Source) {
-        self.playlists
-            .iter_mut()
-            .for_each(|(_, playlist)| playlist.shuffle(rng));
-    }
+    /// How long each track plays for, according to the #EXTINF lines of the
+    /// playlist files as they were last read
+    durations: Durations,
+    availability: AvailabilityConfig,
+    rules: RulesConfig,
+    history: rules::PlayHistory,
+    quotas: QuotaConfig,
+    quota_tracker: quotas::QuotaTracker,
 
-    /// Combines a basic playlist with this one, making sure to preserve the
-    /// order and position of the current playlist as much as possible
-    fn merge_with(&mut self, playlists: &mut SimplePlaylists) {
-        if playlists.len() == 0 {
-            return;
-        }
+    /// How often tracks have been passed over today, and why
+    skip_tracker: SkipTracker,
 
-        let mut rng = utils::seeded_random();
-        let mut id3_directory = &mut self.id3_tags;
+    /// How soon the tracks that a reload adds are played
+    ramp: RampConfig,
 
-        for (disk_playlist, disk_songs) in playlists.iter_mut() {
-            if disk_songs.len() == 0 {
-                continue;
-            }
+    /// What happens at the end of each playlist, and whether the current one
+    /// played its last track with the last next-track. Whatever happens is
+    /// left until the next track is asked for, so that the track before it is
+    /// still reported as coming from the playlist that ended.
+    playlist_end: PlaylistEndConfig,
+    playlist_ended: bool,
 
-            match self.playlists.get_mut(disk_playlist) {
-                Some(our_playlist) => {
-                    let (mut to_add, to_remove) = our_playlist.diff_playlist(disk_songs);
-                    shuffle(&mut to_add, &mut rng);
-                    our_playlist.merge_songs(&to_add, &to_remove);
-                    our_playlist.update_id3_directory(&mut id3_directory);
-                }
+    /// The tracks that the one-shot playlists have played, which they pass
+    /// over until they start over
+    spent: SpentTracks,
 
-                None => {
-                    let mut added_playlist = Playlist::new(disk_songs.to_vec()).unwrap();
-                    added_playlist.shuffle(&mut rng);
-                    added_playlist.update_id3_directory(&mut id3_directory);
-                    self.playlists
-                        .insert(disk_playlist.to_string(), added_playlist);
-                }
-            }
-        }
+    /// How works split over several tracks are found, and the ones that were
+    /// found when the playlists were last read
+    parts: PartsConfig,
+    works: Works,
+    last_request: Arc<Mutex<SystemTime>>,
 
-        // Note that we don't garbage collect any removed playlist ID3 entries
-        // here, mostly because they're not large enough to really matter. If
-        // the admin notices this they can do a full playlist flush and recompute
-        // the tag cache from scratch
-        let to_remove_playlists = {
-            self.playlists
-                .keys()
-                .filter(|playlist| {
-                    !playlists.contains_key(*playlist) || self.playlists[*playlist].songs.len() == 0
-                })
-                .map(|playlist| playlist.to_string())
-                .collect::<Vec<_>>()
-        };
+    /// When the service was last restarted, which the watchdog waits out
+    /// before probing it again
+    last_restart: Arc<Mutex<Option<Instant>>>,
+    started: SystemTime,
 
-        for playlist in to_remove_playlists.iter() {
-            self.playlists.remove(playlist);
-        }
+    /// How many tracks next-track has returned since the server started, not
+    /// counting the special entries
+    tracks_served: u64,
 
-        if !self.playlists.contains_key(&self.current_playlist) {
-            self.current_playlist = self.playlists.keys().next().unwrap().to_string();
-        }
-    }
+    /// What the server was started with, for status
+    environment: Environment,
+
+    /// What the server is doing, for the panic hook to report
+    diagnostics: Arc<Mutex<Diagnostics>>,
+
+    /// The counters and gauges that the server keeps for /metrics
+    metrics: Arc<Mutex<Metrics>>,
+    workers: Workers,
+    shutdown: bool,
+    votes: VoteTracker,
+    vote_config: VoteConfig,
+    requests: RequestQueue,
+    request_config: RequestConfig,
+    events: EventBus,
+
+    /// The playlists read by the last preview-reload and the track lengths
+    /// given in them, which commit-reload merges in
+    pending_reload: Option<(SimplePlaylists, Durations)>,
+
+    /// The playlists from before each of the last few reloads, with the most
+    /// recent at the back
+    snapshots: VecDeque<PlaylistSnapshot>,
+    max_snapshots: usize,
+
+    /// The last few entries returned by next-track, with the most recent at
+    /// the back
+    served: VecDeque<PlayedTrack>,
+    max_served: usize,
+
+    /// The entry that next-track returned last, which is kept even if the
+    /// history isn't
+    now_playing: Option<PlayedTrack>,
+
+    /// Where every entry returned by next-track is logged, if anywhere
+    history_file: Option<PathBuf>,
+
+    /// The seed for the shuffles, if they have to be reproducible, and the
+    /// generator that each playlist is shuffled with
+    shuffle_seed: Option<u64>,
+    rngs: HashMap<String, Xoshiro256>,
+
+    /// The token that every request has to give, if any. It's only read at
+    /// startup, since the workers that send requests took a copy.
+    auth_token: Option<String>,
+
+    /// Who can send the admin commands over the Unix socket, if it's limited
+    admin_users: Vec<libc::uid_t>,
+    admin_groups: Vec<libc::gid_t>,
+
+    /// The shape of the responses on connections that don't ask for another
+    protocol_version: ProtocolVersion,
 }
 
-/// Shuffles a vector using the given RNG source
-fn shuffle<T>(vec: &mut Vec<T>, rng: &mut impl random::Source) {
-    vec.sort_unstable_by_key(|_| rng.read_u64());
+/// Finds the generator that shuffles the named playlist, creating it the first
+/// time. With a seed, each playlist's generator is seeded from its name as
+/// well, so that its order doesn't depend on how the others were shuffled.
+fn playlist_rng<'a>(
+    rngs: &'a mut HashMap<String, Xoshiro256>,
+    seed: Option<u64>,
+    playlist: &str,
+) -> &'a mut Xoshiro256 {
+    rngs.entry(playlist.to_string())
+        .or_insert_with(|| match seed {
+            Some(seed) => Xoshiro256::from_named_seed(seed, playlist),
+            None => Xoshiro256::from_entropy(),
+        })
 }
 
-/// Reads an M3U8 file and returns a list of absolute paths to the audio files
-/// listed within, or an error if the playlist or files are invalid
-fn parse_m3u8_playlist(filename: &Path) -> Result<Vec<PathBuf>, String> {
-    let buffer = fs::read(filename).or_else(|error| {
-        Err(format!(
-            "Could not read playlist {}: {}",
-            filename.display(),
-            error
-        ))
-    })?;
+/// An entry that was returned by next-track, and when
+#[derive(Debug, Clone)]
+struct PlayedTrack {
+    track: PathBuf,
+    kind: TrackKind,
 
-    let contents = String::from_utf8(buffer)
-        .or_else(|error| Err(format!("Could not decode playlist: {}", error)))?;
+    /// The playlist that was current when the track was played, unless it was
+    /// a special entry
+    playlist: Option<String>,
+    time: SystemTime,
+}
 
-    // m3u files that use relative paths are relative to the location of the file itself
-    let playlist_relative = filename.parent().and_then(|dir| match dir.canonicalize() {
-        Ok(parent) => Some(parent),
-        Err(_) => None,
-    });
+/// The parts of the server's state that other threads hold on to as well, or
+/// that were set up before it started
+pub struct SharedState {
+    pub events: EventBus,
+    pub last_request: Arc<Mutex<SystemTime>>,
+    pub last_restart: Arc<Mutex<Option<Instant>>>,
+    pub workers: Workers,
+    pub environment: Environment,
+    pub diagnostics: Arc<Mutex<Diagnostics>>,
+    pub metrics: Arc<Mutex<Metrics>>,
+}
 
-    let mut playlist = Vec::new();
-    for line in contents.split('\n') {
-        let processed_line = line.trim();
-        if processed_line == "" {
-            continue;
+impl PlaylistQueue {
+    /// Builds a queue without any playlists, as of the given time
+    fn new(
+        service_config: &ServiceConfig,
+        scheduling: SchedulingConfig,
+        shared: SharedState,
+        now: SystemTime,
+    ) -> Self {
+        PlaylistQueue {
+            current_playlist: String::new(),
+            playlists: HashMap::new(),
+            directory: service_config.playlist_dir.clone(),
+            content_root: service_config.content_root.clone(),
+            library_dir: service_config.library_dir.clone(),
+            reload_max_removed: service_config.reload_max_removed,
+            shuffle: service_config.shuffle,
+            shuffle_seed: service_config.shuffle_seed,
+            rngs: HashMap::new(),
+            play_counts: HashMap::new(),
+            added: HashMap::new(),
+            retirement: scheduling.retirement,
+            unretired: HashMap::new(),
+            smart_playlists: service_config.smart_playlists.clone(),
+            id3_tags: HashMap::new(),
+            durations: HashMap::new(),
+            availability: scheduling.availability,
+            rules: scheduling.rules,
+            history: VecDeque::new(),
+            quotas: scheduling.quotas,
+            quota_tracker: quotas::QuotaTracker::new(DateTime::<Local>::from(now).naive_local()),
+            skip_tracker: SkipTracker::new(DateTime::<Local>::from(now).naive_local()),
+            ramp: scheduling.ramp,
+            playlist_end: scheduling.playlist_end,
+            playlist_ended: false,
+            spent: HashMap::new(),
+            parts: scheduling.parts,
+            works: Works::default(),
+            last_request: shared.last_request,
+            last_restart: shared.last_restart,
+            started: now,
+            tracks_served: 0,
+            environment: shared.environment,
+            diagnostics: shared.diagnostics,
+            metrics: shared.metrics,
+            workers: shared.workers,
+            shutdown: false,
+            votes: VoteTracker::new(),
+            vote_config: scheduling.votes,
+            requests: RequestQueue::new(),
+            request_config: scheduling.requests,
+            events: shared.events,
+            pending_reload: None,
+            snapshots: VecDeque::new(),
+            max_snapshots: service_config.reload_snapshots,
+            served: VecDeque::new(),
+            max_served: service_config.track_history,
+            now_playing: None,
+            history_file: service_config.history_file.clone(),
+            auth_token: service_config.auth_token.clone(),
+            admin_users: service_config.admin_users.clone(),
+            admin_groups: service_config.admin_groups.clone(),
+            protocol_version: service_config.protocol_version,
         }
+    }
 
-        let path = PathBuf::from(line.trim());
-        let path = if !path.is_absolute() {
-            match playlist_relative.as_ref() {
-                Some(parent) => parent.to_path_buf().join(path),
-                None => {
-                    return Err(format!(
-                        "Could not read playlist: failed to resolve relative entry {}",
-                        path.display()
-                    ))
-                }
-            }
-        } else {
-            path
+    /// Checks whether a client can send the admin commands, going by the
+    /// credentials of its end of the Unix socket. Root and the user that
+    /// shuffled runs as can always send them, since the workers connect as
+    /// that user. So does the HTTP API, which turns the admin commands away
+    /// itself. Clients over TCP have no credentials, so they can only send
+    /// them when nobody is singled out. Members of a group count whether it's
+    /// their primary group or one of their supplementary groups.
+    fn allows_admin(&self, peer: Option<&libc::ucred>) -> bool {
+        if self.admin_users.is_empty() && self.admin_groups.is_empty() {
+            return true;
+        }
+
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return false,
         };
 
-        if !path.is_file() {
-            return Err(format!(
-                "Could not read playlist: entry {} is not a file",
-                path.display()
-            ));
+        // Safe since geteuid can't fail and has no side effects
+        if peer.uid == 0
+            || peer.uid == unsafe { libc::geteuid() }
+            || self.admin_users.contains(&peer.uid)
+            || self.admin_groups.contains(&peer.gid)
+        {
+            return true;
         }
 
-        if playlist.contains(&path) {
-            return Err(format!(
-                "Could not read playlist: entry {} is a duplicate",
-                path.display()
-            ));
+        if self.admin_groups.is_empty() {
+            return false;
         }
 
-        playlist.push(path);
+        match privileges::user_groups(peer.uid, peer.gid) {
+            Ok(groups) => groups.iter().any(|gid| self.admin_groups.contains(gid)),
+            Err(error) => {
+                warning!("server", "{}", error);
+                false
+            }
+        }
     }
 
-    if playlist.len() == 0 {
-        return Err(format!(
-            "Could not read playlist: no entries in {}",
-            filename.display()
-        ));
+    /// Checks whether a track has been retired, by its play count or its
+    /// retirement date
+    fn retirement(&self, track: &Path, now: &NaiveDateTime) -> Option<Retirement> {
+        let plays = self
+            .play_counts
+            .get(track)
+            .map(|count| count.plays)
+            .unwrap_or(0);
+        self.retirement.retired(track, plays, &self.unretired, now)
     }
 
-    Ok(playlist)
-}
+    /// Checks whether a track has to be passed over. Tracks must always exist,
+    /// be inside of their availability window and not be retired, and if
+    /// requested, must also not be voted off and pass all of the separation
+    /// rules and genre maximums.
+    fn check_playable(
+        &self,
+        song: &Path,
+        id3: Option<&utils::ID3>,
+        genre: Option<&str>,
+        now: NaiveDateTime,
+        check_rules: bool,
+    ) -> Result<(), Skip> {
+        if !song.is_file() {
+            return Err(Skip::new(
+                SkipReason::MissingFile,
+                "the file is missing".to_string(),
+            ));
+        }
 
-/// Reads all the .m3u8 playlists available in the given directory
-fn read_m3u8_files(directory: &Path) -> Result<SimplePlaylists, String> {
-    let reader = directory
-        .read_dir()
-        .or_else(|error| Err(format!("Error reading playlist directory: {}", error)))?;
+        if !self
+            .availability
+            .is_available(&self.current_playlist, song, &now)
+        {
+            return Err(Skip::new(
+                SkipReason::Unavailable,
+                "outside of its availability window".to_string(),
+            ));
+        }
 
-    let mut raw_playlists: HashMap<String, Vec<PathBuf>> = HashMap::new();
-    for entry in reader {
-        let dirent =
-            entry.or_else(|error| Err(format!("Error reading playlist directory: {}", error)))?;
+        if let Some(retirement) = self.retirement(song, &now) {
+            return Err(Skip::new(SkipReason::Retired, retirement.to_string()));
+        }
 
-        let entry_path = dirent.path();
-        if !entry_path.is_file() {
-            continue;
+        if !check_rules {
+            return Ok(());
         }
 
-        let extension = entry_path.extension().map(OsStr::to_string_lossy);
-        if extension != Some(Cow::Borrowed("m3u8")) {
-            continue;
+        if self.votes.is_blocked(song) {
+            return Err(Skip::new(
+                SkipReason::VotedOff,
+                "voted off by listeners".to_string(),
+            ));
         }
 
-        let name = entry_path
-            .file_stem()
-            .map(OsStr::to_string_lossy)
-            .ok_or(format!(
-                "Error reading playlist file {}: unreadable name",
-                entry_path.display()
-            ))?;
+        let candidate = self.rules.describe(&self.current_playlist, song, id3, now);
+        self.rules.check(&candidate, &self.history).and_then(|_| {
+            self.quota_tracker
+                .allows(&self.quotas, &self.current_playlist, genre)
+        })
+    }
 
-        let playlist = parse_m3u8_playlist(&entry_path).or_else(|error| Err(error.to_string()))?;
+    /// Finds the part of a work that comes after the last track played from
+    /// the current playlist, if it's on the playlist and hasn't been played
+    /// since it started over
+    fn next_part(&self) -> Option<&PathBuf> {
+        let last = self.now_playing.as_ref()?;
+        if last.kind != TrackKind::Music || last.playlist.as_ref() != Some(&self.current_playlist) {
+            return None;
+        }
 
-        raw_playlists.insert(name.to_string(), playlist);
-    }
+        let next = self.works.next_part(&last.track)?;
+        let spent = self
+            .spent
+            .get(&self.current_playlist)
+            .map(|spent| spent.contains(next))
+            .unwrap_or(false);
+        let on_playlist = self
+            .playlists
+            .get(&self.current_playlist)
+            .map(|playlist| playlist.songs().contains(next))
+            .unwrap_or(false);
 
-    if raw_playlists.len() == 0 {
-        return Err("Error reading playlist directory: no playlists".to_string());
+        if spent || !on_playlist {
+            None
+        } else {
+            Some(next)
+        }
     }
 
-    Ok(raw_playlists)
+    /// Finds how far ahead of the current position the next part of a work
+    /// that has started is, as long as it can be played. Only the checks that
+    /// are never relaxed apply to it, since a work that has started is played
+    /// to the end instead of being held back by the separation rules.
+    fn find_next_part(&self, now: NaiveDateTime) -> Option<usize> {
+        let next = self.next_part()?;
+        let playlist = &self.playlists[&self.current_playlist];
+        let song_count = playlist.songs().len();
+        let offset = (0..song_count).find(|offset| {
+            &playlist.songs()[(playlist.position() + offset) % song_count] == next
+        })?;
+
+        let id3 = next.to_str().and_then(|path| self.id3_tags.get(path));
+        let genre = id3.map(|tags| String::from(tags.genre()));
+        self.check_playable(next, id3, genre.as_deref(), now, false)
+            .ok()
+            .map(|_| offset)
+    }
+
+    /// Finds how far ahead of the current position the first track is that
+    /// the current playlist hasn't played yet, if it's one-shot
+    fn first_unspent(&self) -> usize {
+        let playlist = &self.playlists[&self.current_playlist];
+        let song_count = playlist.songs().len();
+        let spent = match self.spent.get(&self.current_playlist) {
+            Some(spent) => spent,
+            None => return 0,
+        };
+
+        (0..song_count)
+            .find(|offset| {
+                let song = &playlist.songs()[(playlist.position() + offset) % song_count];
+                !spent.contains(song)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Finds how far ahead of the current position the first playable track is
+    /// on the current playlist, adding the tracks that were passed over on the
+    /// way to the skips. If any genres are wanted then tracks of other genres
+    /// are passed over without counting as skips.
+    fn find_playable_track(
+        &self,
+        now: NaiveDateTime,
+        check_rules: bool,
+        wanted_genres: &[String],
+        skips: &mut Vec<(PathBuf, Skip)>,
+    ) -> Option<usize> {
+        let playlist = &self.playlists[&self.current_playlist];
+        let song_count = playlist.songs().len();
+        let spent = self.spent.get(&self.current_playlist);
+
+        for offset in 0..song_count {
+            let song = &playlist.songs()[(playlist.position() + offset) % song_count];
+            if spent.map(|spent| spent.contains(song)).unwrap_or(false) {
+                continue;
+            }
+
+            let id3 = song.to_str().and_then(|path| self.id3_tags.get(path));
+            let genre = id3.map(|tags| String::from(tags.genre()));
+
+            if !wanted_genres.is_empty()
+                && !genre
+                    .as_ref()
+                    .map(|g| wanted_genres.contains(g))
+                    .unwrap_or(false)
+            {
+                continue;
+            }
+
+            match self.check_playable(song, id3, genre.as_deref(), now, check_rules) {
+                Ok(()) => return Some(offset),
+                Err(skip) => skips.push((song.to_path_buf(), skip)),
+            }
+        }
+
+        None
+    }
+
+    /// Checks whether the current playlist is one-shot and has played every
+    /// one of its tracks
+    fn one_shot_exhausted(&self) -> bool {
+        if !self.playlist_end.is_one_shot(&self.current_playlist) {
+            return false;
+        }
+
+        match (
+            self.playlists.get(&self.current_playlist),
+            self.spent.get(&self.current_playlist),
+        ) {
+            (Some(playlist), Some(spent)) => {
+                playlist.songs().iter().all(|song| spent.contains(song))
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks whether the current playlist has either played its last track
+    /// or, if it's one-shot, all of them
+    fn playlist_finished(&self) -> bool {
+        self.playlist_ended || self.one_shot_exhausted()
+    }
+
+    /// Does whatever happens at the end of the current playlist, if it has
+    /// finished. Returns false if the playlist stops there, which it keeps
+    /// doing until another playlist is switched to. A one-shot playlist that
+    /// stops stays finished until a reload adds tracks that it hasn't played.
+    fn finish_playlist(&mut self) -> bool {
+        // A switch can land on a one-shot playlist that has already played
+        // everything, which then has to finish as well. Each playlist that
+        // finishes starts over unless it stops, so this can't go around
+        // forever.
+        for _ in 0..self.playlists.len() {
+            if !self.playlist_finished() {
+                return true;
+            }
+
+            let finished = self.current_playlist.to_string();
+            match self.playlist_end.action(&finished).clone() {
+                PlaylistEnd::Repeat => (),
+                PlaylistEnd::Reshuffle => {
+                    info!("server", "Reshuffling {}", finished);
+                    let strategy = shuffle_strategy(self.shuffle, &self.play_counts, &self.works);
+                    let rng = playlist_rng(&mut self.rngs, self.shuffle_seed, &finished);
+                    self.playlists
+                        .get_mut(&finished)
+                        .unwrap()
+                        .shuffle_with(strategy.as_ref(), rng);
+                }
+                PlaylistEnd::Switch(target) if self.playlists.contains_key(&target) => {
+                    info!(
+                        "server",
+                        "Switching to {} at the end of {}", target, finished
+                    );
+                    self.current_playlist = target.to_string();
+                    self.events.publish(Event::PlaylistSwitched {
+                        from: finished.to_string(),
+                        to: target,
+                    });
+                }
+                PlaylistEnd::Switch(target) => warning!(
+                    "server",
+                    "Starting {} over, there is no playlist named {} to switch to",
+                    finished,
+                    target
+                ),
+                PlaylistEnd::Stop => return false,
+            }
+
+            self.spent.remove(&finished);
+            self.playlist_ended = false;
+        }
+
+        true
+    }
+
+    /// Returns the next playable track on the current playlist and advances
+    /// past it. Any tracks that were skipped over stay ahead of the current
+    /// position, so they get played as soon as they are playable again.
+    ///
+    /// The next part of a work that has started comes before anything else,
+    /// as long as it exists and is available.
+    ///
+    /// Genres which are at risk of missing their hourly minimum are played
+    /// first if there are any available. Otherwise, the separation rules and
+    /// genre maximums are relaxed if no track would pass them, and then the
+    /// availability windows and retirements are ignored if there's still
+    /// nothing playable, since either is better than dead air. Only the skips
+    /// made by the search that found the track are counted.
+    fn next_available_track(&mut self, now: SystemTime) -> PathBuf {
+        let now = DateTime::<Local>::from(now).naive_local();
+        self.quota_tracker.roll_over(&self.quotas, now);
+
+        let urgent_genres =
+            self.quota_tracker
+                .urgent_genres(&self.quotas, &self.current_playlist, now);
+
+        let mut skips = Vec::new();
+        let mut offset = self.find_next_part(now);
+        if offset.is_none() && !urgent_genres.is_empty() {
+            offset = self.find_playable_track(now, true, &urgent_genres, &mut skips);
+        }
+
+        if offset.is_none() {
+            skips.clear();
+            offset = self.find_playable_track(now, true, &[], &mut skips);
+        }
+
+        if offset.is_none() {
+            warning!(
+                "server",
+                "No tracks on {} pass the separation rules, ignoring them",
+                self.current_playlist
+            );
+            skips.clear();
+            offset = self.find_playable_track(now, false, &[], &mut skips);
+        }
+
+        let offset = offset.unwrap_or_else(|| {
+            warning!(
+                "server",
+                "No tracks on {} are available, ignoring availability windows and retirements",
+                self.current_playlist
+            );
+            skips.clear();
+            self.first_unspent()
+        });
+
+        for (track, skip) in skips {
+            self.record_skip(track, skip, now);
+        }
+
+        let playlist = self.playlists.get_mut(&self.current_playlist).unwrap();
+        playlist.promote(offset);
+        let song = playlist.current().to_path_buf();
+        playlist.next();
+        self.playlist_ended = if self.playlist_end.is_one_shot(&self.current_playlist) {
+            let spent = self
+                .spent
+                .entry(self.current_playlist.to_string())
+                .or_default();
+            spent.insert(song.to_path_buf());
+            playlist.songs().iter().all(|song| spent.contains(song))
+        } else {
+            playlist.position() == 0
+        };
+
+        self.record_play(&song, now);
+        song
+    }
+
+    /// Counts a track as skipped for status and track-stats, and lets the
+    /// subscribers know why it was. The first time each day that a file is
+    /// found missing is also warned about, since that usually means something
+    /// happened to the library.
+    fn record_skip(&mut self, track: PathBuf, skip: Skip, now: NaiveDateTime) {
+        let count = self.skip_tracker.record(&track, skip.reason, now);
+        let level = match skip.reason {
+            SkipReason::MissingFile if count == 1 => LogLevel::Warning,
+            _ => LogLevel::Debug,
+        };
+
+        log!(
+            level,
+            "skips",
+            "Skipping {} on {}, {}",
+            track.display(),
+            self.current_playlist,
+            skip
+        );
+
+        self.events.publish(Event::TrackSkipped {
+            track,
+            playlist: self.current_playlist.to_string(),
+            reason: skip.reason,
+            detail: skip.detail,
+        });
+    }
+
+    /// Remembers an entry returned by next-track for previous-track and
+    /// get-history, and adds it to the end of the history file. Music and
+    /// requests are also counted towards the track's play count.
+    fn record_served(&mut self, track: &Path, kind: TrackKind, now: SystemTime) {
+        if kind.module().is_none() {
+            self.tracks_served += 1;
+            self.play_counts
+                .entry(track.to_path_buf())
+                .and_modify(|count| {
+                    count.plays += 1;
+                    count.last_played = now;
+                })
+                .or_insert(PlayCount {
+                    plays: 1,
+                    last_played: now,
+                });
+
+            let plays = self.play_counts[track].plays;
+            if self.retirement.retires_at(track, plays, &self.unretired) {
+                info!(
+                    "server",
+                    "Retiring {} after {} plays",
+                    track.display(),
+                    plays
+                );
+            }
+        }
+
+        let played = PlayedTrack {
+            track: track.to_path_buf(),
+            kind,
+            playlist: match kind.module() {
+                Some(_) => None,
+                None => Some(self.current_playlist.to_string()),
+            },
+            time: now,
+        };
+
+        if let Some(path) = self.history_file.as_ref() {
+            let line = format!(
+                "{}\n",
+                json::stringify(history_entry(&played, &self.id3_tags))
+            );
+            if let Err(error) = storage::append_line(path, &line) {
+                warning!(
+                    "server",
+                    "Could not write to history file {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        }
+
+        if self.max_served > 0 {
+            if self.served.len() == self.max_served {
+                self.served.pop_front();
+            }
+
+            self.served.push_back(played.clone());
+        }
+
+        self.now_playing = Some(played);
+    }
+
+    /// Counts a track that is about to be played towards the separation rules
+    /// and genre quotas of the current playlist
+    fn record_play(&mut self, song: &Path, now: NaiveDateTime) {
+        let id3 = song.to_str().and_then(|path| self.id3_tags.get(path));
+        let played = self.rules.describe(&self.current_playlist, song, id3, now);
+        let genre = id3.map(|tags| String::from(tags.genre()));
+
+        self.history.push_back(played);
+        self.rules.trim_history(&mut self.history, now);
+        self.quota_tracker
+            .record(&self.current_playlist, genre.as_deref());
+    }
+
+    /// Warns about any playlists or tracks whose availability windows are
+    /// about to close
+    fn report_expiries(&self) {
+        let now = Local::now().naive_local();
+        self.availability.report_expiries(
+            self.playlists
+                .iter()
+                .map(|(name, playlist)| (name, playlist.songs())),
+            &now,
+        );
+    }
+
+    /// Shuffles all the playlists in the queue
+    fn shuffle_all(&mut self) {
+        let strategy = shuffle_strategy(self.shuffle, &self.play_counts, &self.works);
+        for (name, playlist) in self.playlists.iter_mut() {
+            let rng = playlist_rng(&mut self.rngs, self.shuffle_seed, name);
+            playlist.shuffle_with(strategy.as_ref(), rng);
+        }
+    }
+
+    /// Adds the smart playlists to the playlists read from disk, each made up
+    /// of the tracks on any of them which match its query. A smart playlist
+    /// that matches nothing is left out, like a playlist file that is gone.
+    fn add_smart_playlists(&mut self, playlists: &mut SimplePlaylists) {
+        if self.smart_playlists.is_empty() {
+            return;
+        }
+
+        let mut songs = playlists.values().flatten().cloned().collect::<Vec<_>>();
+        songs.sort();
+        songs.dedup();
+
+        let uncached = songs
+            .iter()
+            .filter(|song| {
+                song.to_str()
+                    .map(|path| !self.id3_tags.contains_key(path))
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        update_id3_directory(&uncached, &mut self.id3_tags);
+
+        // Tracks that this read would add haven't been recorded yet, since
+        // that only happens once the playlists are merged
+        let now = SystemTime::now();
+        let new_songs = self.new_songs(&songs);
+
+        for (name, query) in self.smart_playlists.iter() {
+            if playlists.contains_key(name) {
+                warning!(
+                    "server",
+                    "Skipping smart playlist {}, there is a playlist file with the same name",
+                    name
+                );
+                continue;
+            }
+
+            let matches = songs
+                .iter()
+                .filter(|song| {
+                    let tags = song.to_str().and_then(|path| self.id3_tags.get(path));
+                    let added = self.added.get(*song).copied().or_else(|| {
+                        if new_songs.contains(song) {
+                            Some(now)
+                        } else {
+                            None
+                        }
+                    });
+
+                    smart_playlist_matches(query, tags, added, now)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if matches.is_empty() {
+                warning!("server", "Smart playlist {} has no matching tracks", name);
+                continue;
+            }
+
+            playlists.insert(name.to_string(), matches);
+        }
+    }
+
+    /// Notes the command that is about to run and where the current playlist
+    /// is, so that they can be reported if the server panics
+    fn record_diagnostics(&self, command: &'static str) {
+        let position = self
+            .playlists
+            .get(&self.current_playlist)
+            .map_or(0, Playlist::position);
+
+        self.diagnostics
+            .lock()
+            .unwrap()
+            .record_command(command, &self.current_playlist, position);
+    }
+
+    /// Counts a command that has been handled for /metrics, if there was one,
+    /// along with the playlist sizes that it might have changed
+    fn record_metrics(&self, command: Option<&'static str>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        if let Some(command) = command {
+            metrics.record_command(command);
+        }
+
+        metrics.set_playlists(
+            self.playlists
+                .iter()
+                .map(|(name, playlist)| (name, playlist.songs().len())),
+        );
+    }
+
+    /// Reads the playlist files for a reload, as long as the music library is
+    /// available, along with the track lengths that they give. Neither is
+    /// used until the reload is applied.
+    fn read_playlists(&mut self) -> Result<(SimplePlaylists, Durations), RpcResponse<'static>> {
+        if let Some(library) = self.library_dir.as_ref() {
+            if let Err(error) = monitor::check_library(library) {
+                warning!(
+                    "server",
+                    "Not reloading, library at {} is unavailable: {}",
+                    library.display(),
+                    error
+                );
+                return Err(RpcResponse::LibraryUnavailable);
+            }
+        }
+
+        let (mut playlists, durations) =
+            playlist::read_playlists(&self.directory, self.content_root.as_deref()).map_err(
+                |error| {
+                    warning!("server", "{}", error);
+                    RpcResponse::NoPlaylistsAvailable
+                },
+            )?;
+
+        self.add_smart_playlists(&mut playlists);
+        Ok((playlists, durations))
+    }
+
+    /// Takes on the settings from a re-read configuration that only the server
+    /// uses. The playlists aren't read again until the next reload.
+    fn apply_config(&mut self, service: ServiceConfig, scheduling: SchedulingConfig) {
+        self.directory = service.playlist_dir;
+        self.content_root = service.content_root;
+        self.library_dir = service.library_dir;
+        self.reload_max_removed = service.reload_max_removed;
+        self.shuffle = service.shuffle;
+        if self.shuffle_seed != service.shuffle_seed {
+            self.shuffle_seed = service.shuffle_seed;
+            self.rngs.clear();
+        }
+
+        self.smart_playlists = service.smart_playlists;
+        self.history_file = service.history_file;
+        self.admin_users = service.admin_users;
+        self.admin_groups = service.admin_groups;
+        self.protocol_version = service.protocol_version;
+
+        self.max_snapshots = service.reload_snapshots;
+        while self.snapshots.len() > self.max_snapshots {
+            self.snapshots.pop_front();
+        }
+
+        self.max_served = service.track_history;
+        while self.served.len() > self.max_served {
+            self.served.pop_front();
+        }
+
+        self.availability = scheduling.availability;
+        self.rules = scheduling.rules;
+        self.quotas = scheduling.quotas;
+        self.ramp = scheduling.ramp;
+        self.playlist_end = scheduling.playlist_end;
+        self.parts = scheduling.parts;
+        self.retirement = scheduling.retirement;
+        self.vote_config = scheduling.votes;
+        self.request_config = scheduling.requests;
+    }
+
+    /// Reads the playlists from disk and merges them in, unless that would
+    /// remove too many tracks and the reload isn't forced
+    fn reload(&mut self, force: bool) -> RpcResponse<'static> {
+        let (mut raw_playlists, durations) = match self.read_playlists() {
+            Ok(read) => read,
+            Err(response) => return response,
+        };
+
+        let (removed, total) = self.count_removed(&raw_playlists);
+        if !force && removed * 100 > total * self.reload_max_removed as usize {
+            warning!(
+                "server",
+                "Not reloading, it would remove {} of {} tracks",
+                removed,
+                total
+            );
+            return RpcResponse::TooManyRemoved(removed, total);
+        }
+
+        self.apply_reload(&mut raw_playlists, durations);
+        RpcResponse::Ok
+    }
+
+    /// Merges in the playlists that were read from disk, and takes on the
+    /// track lengths that they gave. Any previewed reload is dropped, since it
+    /// was computed against the old playlists.
+    fn apply_reload(&mut self, playlists: &mut SimplePlaylists, durations: Durations) {
+        self.pending_reload = None;
+        if self.max_snapshots > 0 {
+            if self.snapshots.len() == self.max_snapshots {
+                self.snapshots.pop_front();
+            }
+
+            self.snapshots.push_back(PlaylistSnapshot {
+                current_playlist: self.current_playlist.to_string(),
+                playlists: self.playlists.clone(),
+                durations: mem::take(&mut self.durations),
+            });
+        }
+
+        self.durations = durations;
+        self.merge_with(playlists);
+        self.report_expiries();
+        self.events.publish(Event::PlaylistsReloaded);
+    }
+
+    /// Puts back the playlists from before the given number of reloads,
+    /// dropping the snapshots taken since then. Returns false if fewer
+    /// reloads than that have been kept.
+    fn rollback(&mut self, steps: usize) -> bool {
+        if steps == 0 || steps > self.snapshots.len() {
+            return false;
+        }
+
+        self.snapshots.truncate(self.snapshots.len() - steps + 1);
+        let snapshot = self.snapshots.pop_back().unwrap();
+        self.current_playlist = snapshot.current_playlist;
+        self.playlists = snapshot.playlists;
+        self.durations = snapshot.durations;
+
+        for playlist in self.playlists.values() {
+            update_id3_directory(playlist.songs(), &mut self.id3_tags);
+        }
+
+        self.pending_reload = None;
+        self.report_expiries();
+        self.events.publish(Event::PlaylistsReloaded);
+        true
+    }
+
+    /// Describes the tracks that merging in the playlists from disk would add
+    /// and remove, for each playlist that would change
+    fn reload_preview(&self, playlists: &SimplePlaylists) -> json::JsonValue {
+        let mut names = self
+            .playlists
+            .keys()
+            .chain(playlists.keys())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        let mut changes = Vec::new();
+        let mut added_count = 0;
+        for name in names {
+            // Mirrors merge_with, which leaves playlists alone when their file
+            // is empty
+            let (change, added, removed) = match (self.playlists.get(name), playlists.get(name)) {
+                (_, Some(disk_songs)) if disk_songs.is_empty() => continue,
+                (Some(playlist), Some(disk_songs)) => {
+                    let (added, removed) = playlist.diff_playlist(disk_songs);
+                    if added.is_empty() && removed.is_empty() {
+                        continue;
+                    }
+                    ("changed", added, removed)
+                }
+                (Some(playlist), None) => ("removed", Vec::new(), playlist.songs().to_vec()),
+                (None, Some(disk_songs)) => ("added", disk_songs.clone(), Vec::new()),
+                (None, None) => continue,
+            };
+
+            added_count += added.len();
+            let paths = |songs: Vec<PathBuf>| {
+                songs
+                    .iter()
+                    .map(|song| json::JsonValue::String(song.to_string_lossy().to_string()))
+                    .collect::<Vec<_>>()
+            };
+
+            let mut entry = json::object::Object::new();
+            entry.insert("playlist", json::JsonValue::String(name.to_string()));
+            entry.insert("change", json::JsonValue::String(change.to_string()));
+            entry.insert("added", json::JsonValue::Array(paths(added)));
+            entry.insert("removed", json::JsonValue::Array(paths(removed)));
+            changes.push(json::JsonValue::Object(entry));
+        }
+
+        let (removed_count, total) = self.count_removed(playlists);
+        let mut preview = json::object::Object::new();
+        preview.insert("playlists", json::JsonValue::Array(changes));
+        preview.insert("added", json::JsonValue::Number(added_count.into()));
+        preview.insert("removed", json::JsonValue::Number(removed_count.into()));
+        preview.insert("total", json::JsonValue::Number(total.into()));
+        json::JsonValue::Object(preview)
+    }
+
+    /// Counts how many of the tracks in the playlists would be removed by
+    /// merging in the playlists from disk, along with the total number of
+    /// tracks. Tracks that are on several playlists are counted once for each.
+    fn count_removed(&self, playlists: &SimplePlaylists) -> (usize, usize) {
+        let mut removed = 0;
+        let mut total = 0;
+
+        for (name, playlist) in self.playlists.iter() {
+            total += playlist.songs().len();
+            removed += match playlists.get(name) {
+                // Empty playlists on disk are skipped by the merge
+                Some(disk_songs) if disk_songs.is_empty() => 0,
+                Some(disk_songs) => playlist.diff_playlist(disk_songs).1.len(),
+                None => playlist.songs().len(),
+            };
+        }
+
+        (removed, total)
+    }
+
+    /// Finds the songs which aren't on any of the playlists yet. Nothing is
+    /// new when there are no playlists at all, since the first read is what
+    /// later ones are compared against.
+    fn new_songs<'a>(&self, songs: &'a [PathBuf]) -> HashSet<&'a PathBuf> {
+        if self.playlists.is_empty() {
+            return HashSet::new();
+        }
+
+        let known = self
+            .playlists
+            .values()
+            .flat_map(|playlist| playlist.songs())
+            .collect::<HashSet<_>>();
+
+        songs.iter().filter(|song| !known.contains(song)).collect()
+    }
+
+    /// Records when the songs that a reload is about to add were added, and
+    /// forgets the ones which are gone or were added too long ago for any
+    /// smart playlist to care about
+    fn record_added(&mut self, playlists: &SimplePlaylists) {
+        let window = self
+            .smart_playlists
+            .values()
+            .filter_map(|query| query.added_within_days)
+            .max();
+
+        let window = match window {
+            Some(days) => Duration::from_secs(days as u64 * 24 * 60 * 60),
+            None => {
+                self.added.clear();
+                return;
+            }
+        };
+
+        let mut songs = playlists.values().flatten().cloned().collect::<Vec<_>>();
+        songs.sort();
+        songs.dedup();
+
+        let now = SystemTime::now();
+        for song in self.new_songs(&songs) {
+            self.added.insert(song.clone(), now);
+        }
+
+        self.added.retain(|song, added| {
+            songs.binary_search(song).is_ok()
+                && now.duration_since(*added).unwrap_or_default() <= window
+        });
+    }
+
+    /// Finds the works on the playlists that are about to be merged in,
+    /// reading the tags of any new tracks first if the works are found from
+    /// them. Playlists whose file is empty keep their tracks in the merge, so
+    /// those are looked at too.
+    fn find_works(&mut self, playlists: &SimplePlaylists) {
+        let mut songs = Vec::new();
+        for (name, disk_songs) in playlists.iter() {
+            match self.playlists.get(name) {
+                Some(playlist) if disk_songs.is_empty() => {
+                    songs.extend_from_slice(playlist.songs())
+                }
+                _ => songs.extend_from_slice(disk_songs),
+            }
+        }
+
+        songs.sort();
+        songs.dedup();
+
+        if self.parts.detect {
+            let uncached = songs
+                .iter()
+                .filter(|song| {
+                    song.to_str()
+                        .map(|path| !self.id3_tags.contains_key(path))
+                        .unwrap_or(true)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            update_id3_directory(&uncached, &mut self.id3_tags);
+        }
+
+        self.works = Works::find(&self.parts, &songs, &self.id3_tags);
+        if !self.works.is_empty() {
+            debug!(
+                "server",
+                "Keeping the parts of {} works together",
+                self.works.len()
+            );
+        }
+    }
+
+    /// Combines a basic playlist with this one, making sure to preserve the
+    /// order and position of the current playlist as much as possible
+    fn merge_with(&mut self, playlists: &mut SimplePlaylists) {
+        if playlists.len() == 0 {
+            return;
+        }
+
+        self.record_added(playlists);
+        self.find_works(playlists);
+
+        let strategy = shuffle_strategy(self.shuffle, &self.play_counts, &self.works);
+        let works = &self.works;
+        let id3_directory = &mut self.id3_tags;
+
+        for (disk_playlist, disk_songs) in playlists.iter_mut() {
+            if disk_songs.len() == 0 {
+                continue;
+            }
+
+            let ramp = self
+                .ramp
+                .playlists
+                .get(disk_playlist)
+                .copied()
+                .or(self.ramp.plays)
+                .filter(|plays| *plays > 0);
+
+            let rng = playlist_rng(&mut self.rngs, self.shuffle_seed, disk_playlist);
+            match self.playlists.get_mut(disk_playlist) {
+                Some(our_playlist) => {
+                    let (mut to_add, to_remove) = our_playlist.diff_playlist(disk_songs);
+                    strategy.shuffle(&mut to_add, rng);
+                    match ramp {
+                        Some(plays) => our_playlist.merge_runs_within(
+                            &works.runs(&to_add),
+                            &to_remove,
+                            plays as usize,
+                            rng,
+                        ),
+                        None => our_playlist.merge_songs(&to_add, &to_remove),
+                    }
+                    update_id3_directory(our_playlist.songs(), id3_directory);
+                }
+
+                None => {
+                    let mut added_playlist = Playlist::new(disk_songs.to_vec()).unwrap();
+                    added_playlist.shuffle_with(strategy.as_ref(), rng);
+                    update_id3_directory(added_playlist.songs(), id3_directory);
+                    self.playlists
+                        .insert(disk_playlist.to_string(), added_playlist);
+                }
+            }
+        }
+
+        // Note that we don't garbage collect any removed playlist ID3 entries
+        // here, mostly because they're not large enough to really matter. If
+        // the admin notices this they can do a full playlist flush and recompute
+        // the tag cache from scratch
+        let to_remove_playlists = {
+            self.playlists
+                .keys()
+                .filter(|playlist| {
+                    !playlists.contains_key(*playlist)
+                        || self.playlists[*playlist].songs().is_empty()
+                })
+                .map(|playlist| playlist.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        for playlist in to_remove_playlists.iter() {
+            self.playlists.remove(playlist);
+        }
+
+        if !self.playlists.contains_key(&self.current_playlist) {
+            self.current_playlist = self.playlists.keys().next().unwrap().to_string();
+            self.playlist_ended = false;
+        }
+
+        // Only the tracks still on a one-shot playlist stay played, so that
+        // one which is taken off and put back on again is played again
+        let playlists = &self.playlists;
+        let playlist_end = &self.playlist_end;
+        self.spent.retain(|name, spent| match playlists.get(name) {
+            Some(playlist) if playlist_end.is_one_shot(name) => {
+                let songs = playlist.songs().iter().collect::<HashSet<_>>();
+                spent.retain(|track| songs.contains(track));
+                !spent.is_empty()
+            }
+            _ => false,
+        });
+    }
+}
+
+/// How the response to a request is sent back
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ResponseMode {
+    /// The whole response is a single JSON object on one line
+    Single,
+
+    /// List responses are sent as one line per item, followed by a line that
+    /// marks the end of the list
+    Streamed,
+}
+
+/// How the responses on a connection are separated from each other
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Framing {
+    /// Each response ends with a newline
+    Lines,
+
+    /// Each response is preceded by its length in bytes, as a 4-byte big-endian
+    /// integer, so that clients don't have to look for newlines
+    Length,
+}
+
+/// How a request asked for its response to be sent
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct ResponseOptions {
+    mode: ResponseMode,
+
+    /// Whether each line is indented for people to read, instead of being
+    /// compact for other programs
+    pretty: bool,
+}
+
+impl Default for ResponseOptions {
+    fn default() -> Self {
+        ResponseOptions {
+            mode: ResponseMode::Single,
+            pretty: false,
+        }
+    }
+}
+
+/// Which part of a list a command should return. Every command that returns a
+/// list accepts the same parameters for these, so that clients can page
+/// through and search them the same way.
+#[derive(Debug, PartialEq, Default)]
+struct ListOptions {
+    offset: usize,
+    limit: Option<usize>,
+    filter: Option<String>,
+}
+
+impl ListOptions {
+    /// Reads the options out of a request that has already been validated
+    fn from_request(document: &json::JsonValue) -> Self {
+        ListOptions {
+            offset: document["offset"].as_usize().unwrap_or(0),
+            limit: document["limit"].as_usize(),
+            filter: document["filter"]
+                .as_str()
+                .map(|filter| filter.to_lowercase()),
+        }
+    }
+
+    /// Checks whether any of the given descriptions of an item contains the
+    /// filter, ignoring case. Every item matches if there is no filter.
+    fn matches(&self, fields: &[&str]) -> bool {
+        match &self.filter {
+            Some(filter) => fields
+                .iter()
+                .any(|field| field.to_lowercase().contains(filter.as_str())),
+            None => true,
+        }
+    }
+
+    /// Selects the requested page out of the items that matched the filter,
+    /// along with how many items there were in total
+    fn page<T>(&self, items: Vec<T>) -> (Vec<T>, usize) {
+        let total = items.len();
+        let page = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(total))
+            .collect();
+
+        (page, total)
+    }
+}
+
+/// The types that a request parameter can have
+#[derive(Debug, Clone, Copy)]
+enum ParamType {
+    String,
+    Boolean,
+    Count,
+}
+
+impl ParamType {
+    /// Checks whether the value is of this type
+    fn matches(&self, value: &json::JsonValue) -> bool {
+        match self {
+            ParamType::String => value.is_string(),
+            ParamType::Boolean => value.is_boolean(),
+            ParamType::Count => value.as_usize().is_some(),
+        }
+    }
+
+    /// Describes the type for error messages
+    fn name(&self) -> &'static str {
+        match self {
+            ParamType::String => "string",
+            ParamType::Boolean => "boolean",
+            ParamType::Count => "non-negative integer",
+        }
+    }
+}
+
+/// A parameter that a command accepts, besides the command itself
+struct Param {
+    name: &'static str,
+    kind: ParamType,
+    required: bool,
+}
+
+const fn required(name: &'static str, kind: ParamType) -> Param {
+    Param {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+const fn optional(name: &'static str, kind: ParamType) -> Param {
+    Param {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+/// Lets a command that returns a list have it streamed back
+const STREAM: Param = optional("stream", ParamType::Boolean);
+
+/// Lets any command have its response pretty-printed
+const PRETTY: Param = optional("pretty", ParamType::Boolean);
+
+/// The token that every command has to give when service.auth_token is set
+const AUTH: Param = optional("auth", ParamType::String);
+
+/// The parameters that make up the ListOptions
+const OFFSET: Param = optional("offset", ParamType::Count);
+const LIMIT: Param = optional("limit", ParamType::Count);
+const FILTER: Param = optional("filter", ParamType::String);
+
+/// Other names that are accepted for some commands, to make typing them by
+/// hand easier
+const COMMAND_ALIASES: &[(&str, &str)] = &[
+    ("next", "next-track"),
+    ("skip", "next-track"),
+    ("ls", "list-playlists"),
+];
+
+/// The parameters that each command accepts
+const COMMAND_SCHEMAS: &[(&str, &[Param])] = &[
+    ("next-track", &[]),
+    ("previous-track", &[optional("steps", ParamType::Count)]),
+    ("now-playing", &[]),
+    ("list-playlists", &[OFFSET, LIMIT, FILTER, STREAM]),
+    ("get-playlist", &[]),
+    ("reload-playlists", &[optional("force", ParamType::Boolean)]),
+    ("preview-reload", &[]),
+    ("commit-reload", &[]),
+    ("rollback-playlists", &[optional("steps", ParamType::Count)]),
+    ("shuffle-playlists", &[]),
+    ("reload-tags", &[]),
+    ("quota-report", &[]),
+    ("status", &[]),
+    ("ping", &[]),
+    (
+        "switch-playlist",
+        &[required("playlist", ParamType::String)],
+    ),
+    (
+        "seek-playlist",
+        &[
+            required("playlist", ParamType::String),
+            optional("index", ParamType::Count),
+            optional("track", ParamType::String),
+        ],
+    ),
+    (
+        "preview-playlist",
+        &[required("playlist", ParamType::String), STREAM],
+    ),
+    (
+        "get-playlist-tracks",
+        &[
+            required("playlist", ParamType::String),
+            OFFSET,
+            LIMIT,
+            FILTER,
+            STREAM,
+        ],
+    ),
+    ("search-tracks", &[OFFSET, LIMIT, FILTER, STREAM]),
+    ("wake", &[required("worker", ParamType::String)]),
+    ("restarting", &[]),
+    ("shutdown", &[]),
+    ("vote-skip", &[optional("listener", ParamType::String)]),
+    ("vote-keep", &[optional("listener", ParamType::String)]),
+    (
+        "request-track",
+        &[
+            required("query", ParamType::String),
+            optional("listener", ParamType::String),
+        ],
+    ),
+    (
+        "submit-message",
+        &[
+            required("text", ParamType::String),
+            optional("listener", ParamType::String),
+        ],
+    ),
+    ("list-requests", &[OFFSET, LIMIT, FILTER, STREAM]),
+    ("get-history", &[OFFSET, LIMIT, FILTER, STREAM]),
+    (
+        "track-stats",
+        &[
+            OFFSET,
+            LIMIT,
+            FILTER,
+            STREAM,
+            optional("retired", ParamType::Boolean),
+        ],
+    ),
+    ("unretire-track", &[required("track", ParamType::String)]),
+    ("track-info", &[required("track", ParamType::String)]),
+    ("approve-request", &[required("id", ParamType::Count)]),
+    ("deny-request", &[required("id", ParamType::Count)]),
+    ("set-framing", &[required("framing", ParamType::String)]),
+    ("set-protocol", &[required("version", ParamType::Count)]),
+];
+
+/// Checks that the request has every parameter that its command needs, and
+/// that the ones it has all have the right types. Returns the error to send
+/// back if it doesn't.
+fn validate_request(command: &str, document: &json::JsonValue) -> Result<(), RpcRequest> {
+    let schema = COMMAND_SCHEMAS
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map(|(_, params)| *params)
+        .ok_or(RpcRequest::UnknownCommand)?;
+
+    for param in schema.iter().chain([&PRETTY, &AUTH]) {
+        if !document.has_key(param.name) {
+            if param.required {
+                return Err(RpcRequest::InvalidParameter(
+                    param.name.to_string(),
+                    format!("is required by {}", command),
+                ));
+            }
+
+            continue;
+        }
+
+        if !param.kind.matches(&document[param.name]) {
+            return Err(RpcRequest::InvalidParameter(
+                param.name.to_string(),
+                format!("must be a {}", param.kind.name()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds a request from a command and its arguments as they were given on the
+/// command line. Arguments of the form name=value set that parameter, and the
+/// rest fill in the command's parameters in order. Values are converted to the
+/// type that the parameter needs.
+pub fn command_from_args(command: &str, args: &[String]) -> Result<json::JsonValue, String> {
+    let command = command.to_ascii_lowercase();
+    let command = COMMAND_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == command)
+        .map(|(_, canonical)| *canonical)
+        .unwrap_or(&command);
+
+    let schema = COMMAND_SCHEMAS
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map(|(_, params)| *params)
+        .ok_or_else(|| format!("Unknown command {}", command))?;
+
+    let mut document = json::JsonValue::new_object();
+    document["command"] = command.into();
+
+    let mut positional = schema.iter();
+    for arg in args {
+        let (param, value) = match arg.split_once('=') {
+            Some((name, value)) => {
+                let param = schema
+                    .iter()
+                    .chain(std::iter::once(&PRETTY))
+                    .find(|param| param.name == name)
+                    .ok_or_else(|| format!("{} has no parameter named {}", command, name))?;
+                (param, value)
+            }
+            None => {
+                let param = positional
+                    .find(|param| !document.has_key(param.name))
+                    .ok_or_else(|| format!("Too many parameters for {}", command))?;
+                (param, arg.as_str())
+            }
+        };
+
+        document[param.name] = match param.kind {
+            ParamType::String => value.into(),
+            ParamType::Boolean => match value {
+                "true" => true.into(),
+                "false" => false.into(),
+                _ => return Err(format!("{} must be true or false", param.name)),
+            },
+            ParamType::Count => value
+                .parse::<usize>()
+                .map_err(|_| format!("{} must be a {}", param.name, param.kind.name()))?
+                .into(),
+        };
+    }
+
+    Ok(document)
+}
+
+/// Checks whether a command, as it would be sent to the server, is one of the
+/// admin commands. The [api] section uses this to turn them away itself, since
+/// it connects to the socket as the user that shuffled runs as.
+pub fn is_admin_command(document: &json::JsonValue) -> bool {
+    let line = format!("{}\n", json::stringify(document.clone()));
+    try_parse_request(line.as_bytes(), None)
+        .map(|(request, _, _)| request.is_admin())
+        .unwrap_or(false)
 }
 
 /// Attempts to parse a single command out of the buffer, either failing if the
 /// buffer doesn't contain a complete command or succeeding and returning the
-/// message and the next message's starting position
-fn try_parse_request(buffer: &[u8]) -> Option<(RpcRequest, usize)> {
+/// message, how to send its response and the next message's starting position.
+/// When the server has an auth token, a command that doesn't give it is
+/// unauthorized.
+fn try_parse_request(
+    buffer: &[u8],
+    auth_token: Option<&str>,
+) -> Option<(RpcRequest, ResponseOptions, usize)> {
     let first_newline = buffer.iter().position(|byte| *byte == 10)?;
-    let default_value = Some((RpcRequest::InvalidRequest, first_newline + 1));
+    let default_value = Some((
+        RpcRequest::InvalidRequest,
+        ResponseOptions::default(),
+        first_newline + 1,
+    ));
 
     let first_line = if let Ok(line) = str::from_utf8(&buffer[..first_newline]) {
         line
@@ -508,103 +2249,359 @@ fn try_parse_request(buffer: &[u8]) -> Option<(RpcRequest, usize)> {
         return default_value;
     }
 
+    if let Some(expected) = auth_token {
+        let authorized = document["auth"]
+            .as_str()
+            .map(|given| utils::tokens_match(given, expected))
+            .unwrap_or(false);
+
+        if !authorized {
+            return Some((
+                RpcRequest::Unauthorized,
+                ResponseOptions::default(),
+                first_newline + 1,
+            ));
+        }
+    }
+
     let command = if let Some(command) = document["command"].as_str() {
-        command
+        command.to_ascii_lowercase()
     } else {
         return default_value;
     };
 
-    match command {
-        "next-track" => Some((RpcRequest::NextTrack, first_newline + 1)),
-        "list-playlists" => Some((RpcRequest::ListPlaylists, first_newline + 1)),
-        "get-playlist" => Some((RpcRequest::GetPlaylist, first_newline + 1)),
-        "reload-playlists" => Some((RpcRequest::ReloadPlaylists, first_newline + 1)),
-        "shuffle-playlists" => Some((RpcRequest::ShufflePlaylists, first_newline + 1)),
-        "reload-tags" => Some((RpcRequest::ReloadTags, first_newline + 1)),
-        "switch-playlist" => {
-            if !document.has_key("playlist") {
-                Some((RpcRequest::InvalidParameter, first_newline + 1))
-            } else {
-                match document["playlist"].as_str() {
-                    Some(playlist) => Some((
-                        RpcRequest::SwitchPlaylist(playlist.to_string()),
-                        first_newline + 1,
-                    )),
-                    None => Some((RpcRequest::InvalidParameter, first_newline + 1)),
-                }
+    let command = COMMAND_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == command)
+        .map(|(_, canonical)| *canonical)
+        .unwrap_or(&command);
+
+    // Errors are pretty-printed too if that was asked for properly
+    let mut options = ResponseOptions {
+        pretty: document["pretty"].as_bool().unwrap_or(false),
+        ..ResponseOptions::default()
+    };
+
+    if let Err(error) = validate_request(command, &document) {
+        return Some((error, options, first_newline + 1));
+    }
+
+    let playlist = document["playlist"].as_str().unwrap_or("").to_string();
+    let listener = document["listener"].as_str().map(String::from);
+    let request = match command {
+        "next-track" => RpcRequest::NextTrack,
+        "previous-track" => RpcRequest::PreviousTrack(document["steps"].as_usize().unwrap_or(1)),
+        "now-playing" => RpcRequest::NowPlaying,
+        "list-playlists" => RpcRequest::ListPlaylists(ListOptions::from_request(&document)),
+        "get-playlist" => RpcRequest::GetPlaylist,
+        "reload-playlists" => {
+            RpcRequest::ReloadPlaylists(document["force"].as_bool().unwrap_or(false))
+        }
+        "preview-reload" => RpcRequest::PreviewReload,
+        "commit-reload" => RpcRequest::CommitReload,
+        "rollback-playlists" => {
+            RpcRequest::RollbackPlaylists(document["steps"].as_usize().unwrap_or(1))
+        }
+        "shuffle-playlists" => RpcRequest::ShufflePlaylists,
+        "reload-tags" => RpcRequest::ReloadTags,
+        "quota-report" => RpcRequest::QuotaReport,
+        "ping" => RpcRequest::Ping,
+        "status" => RpcRequest::Status,
+        "switch-playlist" => RpcRequest::SwitchPlaylist(playlist),
+        "seek-playlist" => match (document["index"].as_usize(), document["track"].as_str()) {
+            (Some(index), None) => RpcRequest::SeekPlaylist(playlist, SeekTarget::Index(index)),
+            (None, Some(track)) => {
+                RpcRequest::SeekPlaylist(playlist, SeekTarget::Track(PathBuf::from(track)))
+            }
+            _ => RpcRequest::InvalidParameter(
+                "index".to_string(),
+                "exactly one of index or track must be given".to_string(),
+            ),
+        },
+        "preview-playlist" => RpcRequest::PreviewPlaylist(playlist),
+        "get-playlist-tracks" => {
+            RpcRequest::GetPlaylistTracks(playlist, ListOptions::from_request(&document))
+        }
+        "search-tracks" => RpcRequest::SearchTracks(ListOptions::from_request(&document)),
+        "wake" => RpcRequest::Wake(document["worker"].as_str().unwrap_or("").to_string()),
+        "restarting" => RpcRequest::Restarting,
+        "shutdown" => RpcRequest::Shutdown,
+        "vote-skip" => RpcRequest::Vote(Vote::Skip, listener),
+        "vote-keep" => RpcRequest::Vote(Vote::Keep, listener),
+        "request-track" => RpcRequest::RequestTrack(
+            document["query"].as_str().unwrap_or("").to_string(),
+            listener,
+        ),
+        "submit-message" => RpcRequest::SubmitMessage(
+            document["text"].as_str().unwrap_or("").to_string(),
+            listener,
+        ),
+        "list-requests" => RpcRequest::ListRequests(ListOptions::from_request(&document)),
+        "get-history" => RpcRequest::GetHistory(ListOptions::from_request(&document)),
+        "track-stats" => RpcRequest::TrackStats(
+            ListOptions::from_request(&document),
+            document["retired"].as_bool().unwrap_or(false),
+        ),
+        "unretire-track" => {
+            RpcRequest::UnretireTrack(PathBuf::from(document["track"].as_str().unwrap_or("")))
+        }
+        "track-info" => {
+            RpcRequest::TrackInfo(PathBuf::from(document["track"].as_str().unwrap_or("")))
+        }
+        "approve-request" => RpcRequest::ApproveRequest(document["id"].as_u64().unwrap_or(0)),
+        "deny-request" => RpcRequest::DenyRequest(document["id"].as_u64().unwrap_or(0)),
+        "set-framing" => match document["framing"].as_str() {
+            Some("lines") => RpcRequest::SetFraming(Framing::Lines),
+            Some("length") => RpcRequest::SetFraming(Framing::Length),
+            _ => RpcRequest::InvalidParameter(
+                "framing".to_string(),
+                "must be lines or length".to_string(),
+            ),
+        },
+        "set-protocol" => match document["version"]
+            .as_i64()
+            .and_then(ProtocolVersion::from_number)
+        {
+            Some(version) => RpcRequest::SetProtocol(version),
+            None => {
+                RpcRequest::InvalidParameter("version".to_string(), "must be 1, 2 or 3".to_string())
+            }
+        },
+        _ => RpcRequest::UnknownCommand,
+    };
+
+    if document["stream"].as_bool().unwrap_or(false) {
+        options.mode = ResponseMode::Streamed;
+    }
+
+    Some((request, options, first_newline + 1))
+}
+
+/// Serializes and sends a track, with as much about it as the protocol
+/// version has room for
+fn send_track(
+    stream: &mut impl Write,
+    path: &Path,
+    kind: TrackKind,
+    details: Option<json::JsonValue>,
+    protocol: ProtocolVersion,
+) -> io::Result<()> {
+    let path_raw = path.to_string_lossy().to_string();
+    let encoded = json::stringify(json::JsonValue::String(path_raw));
+    stream.write_all("{\"track\":".as_bytes())?;
+    stream.write_all(encoded.as_bytes())?;
+    if protocol >= ProtocolVersion::Meta {
+        stream.write_all(format!(", \"kind\": \"{}\"", kind.name()).as_bytes())?;
+        if let Some(module) = kind.module() {
+            stream.write_all(format!(", \"module\": \"{}\"", module).as_bytes())?;
+        }
+    }
+
+    if let Some(json::JsonValue::Object(details)) = details {
+        for (key, value) in details.iter() {
+            stream.write_all(format!(", \"{}\": ", key).as_bytes())?;
+            value.write(stream)?;
+        }
+    }
+
+    stream.write_all("}\n".as_bytes())
+}
+
+/// Serializes and sends a single RPC response, in the shape that the protocol
+/// version calls for
+fn send_response(
+    stream: &mut impl Write,
+    response: RpcResponse,
+    protocol: ProtocolVersion,
+) -> io::Result<()> {
+    match response {
+        RpcResponse::Ok => stream.write_all("{\"status\": \"ok\"}\n".as_bytes()),
+        RpcResponse::Pong => stream.write_all("{\"status\": \"pong\"}\n".as_bytes()),
+        RpcResponse::Track(path, kind) => send_track(stream, &path, kind, None, protocol),
+        RpcResponse::DetailedTrack(path, kind, details) => {
+            send_track(stream, &path, kind, Some(details), protocol)
+        }
+        RpcResponse::Tracks(tracks) => {
+            let encoded = json::stringify(tracks);
+            stream.write_all("{\"tracks\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all("}\n".as_bytes())
+        }
+        RpcResponse::TrackPage(tracks, total) => {
+            let encoded = json::stringify(tracks);
+            stream.write_all("{\"tracks\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all(format!(", \"total\": {}}}\n", total).as_bytes())
+        }
+        RpcResponse::Playlists(mut playlists, total) => {
+            let values = playlists
+                .drain(..)
+                .map(|playlist| json::JsonValue::String(playlist.to_string()))
+                .collect::<Vec<_>>();
+
+            let encoded = json::stringify(json::JsonValue::Array(values));
+            stream.write_all("{\"playlists\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all(format!(", \"total\": {}}}\n", total).as_bytes())
+        }
+        RpcResponse::Playlist(playlist) => {
+            let encoded = json::stringify(json::JsonValue::String(playlist.to_string()));
+            stream.write_all("{\"playlist\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all("}\n".as_bytes())
+        }
+        RpcResponse::Quotas(report) => {
+            let encoded = json::stringify(report);
+            stream.write_all("{\"quotas\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all("}\n".as_bytes())
+        }
+        RpcResponse::Status(status) => {
+            let encoded = json::stringify(status);
+            stream.write_all("{\"daemon\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all("}\n".as_bytes())
+        }
+        RpcResponse::ReloadPreview(preview) => {
+            let encoded = json::stringify(preview);
+            stream.write_all("{\"reload\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all("}\n".as_bytes())
+        }
+        RpcResponse::NoSuchPlaylist => {
+            stream.write_all("{\"status\": \"no-such-playlist\"}\n".as_bytes())
+        }
+        RpcResponse::NoSuchWorker => {
+            stream.write_all("{\"status\": \"no-such-worker\"}\n".as_bytes())
+        }
+        RpcResponse::Votes(outcome) => {
+            let track = outcome.track.to_string_lossy().to_string();
+            let encoded = json::stringify(json::JsonValue::String(track));
+            stream.write_all("{\"track\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all(
+                format!(
+                    ", \"skips\": {}, \"keeps\": {}, \"total\": {{\"skips\": {}, \"keeps\": {}}}",
+                    outcome.current.skips,
+                    outcome.current.keeps,
+                    outcome.total.skips,
+                    outcome.total.keeps
+                )
+                .as_bytes(),
+            )?;
+            if let Some(action) = outcome.action {
+                stream.write_all(format!(", \"action\": \"{}\"", action.name()).as_bytes())?;
             }
+            stream.write_all("}\n".as_bytes())
         }
-        "preview-playlist" => {
-            if !document.has_key("playlist") {
-                Some((RpcRequest::InvalidParameter, first_newline + 1))
-            } else {
-                match document["playlist"].as_str() {
-                    Some(playlist) => Some((
-                        RpcRequest::PreviewPlaylist(playlist.to_string()),
-                        first_newline + 1,
-                    )),
-                    None => Some((RpcRequest::InvalidParameter, first_newline + 1)),
-                }
-            }
+        RpcResponse::NoCurrentTrack => {
+            stream.write_all("{\"status\": \"no-current-track\"}\n".as_bytes())
         }
-        _ => Some((RpcRequest::UnknownCommand, first_newline + 1)),
-    }
-}
-
-/// Serializes and sends a single RPC response
-fn send_response(stream: &mut impl Write, response: RpcResponse) -> io::Result<()> {
-    match response {
-        RpcResponse::Ok => stream.write_all("{\"status\": \"ok\"}\n".as_bytes()),
-        RpcResponse::Track(path) => {
-            let path_raw = path.to_string_lossy().to_string();
-            let encoded = json::stringify(json::JsonValue::String(path_raw));
-            stream.write_all("{\"track\":".as_bytes())?;
+        RpcResponse::Request(request) => {
+            let encoded = json::stringify(request);
+            stream.write_all("{\"request\":".as_bytes())?;
             stream.write_all(encoded.as_bytes())?;
             stream.write_all("}\n".as_bytes())
         }
-        RpcResponse::Tracks(tracks) => {
-            let encoded = json::stringify(tracks);
-            stream.write_all("{\"tracks\":".as_bytes())?;
+        RpcResponse::Requests(requests, total) => {
+            let encoded = json::stringify(requests);
+            stream.write_all("{\"requests\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all(format!(", \"total\": {}}}\n", total).as_bytes())
+        }
+        RpcResponse::NowPlaying(played) => {
+            let encoded = json::stringify(played);
+            stream.write_all("{\"now_playing\":".as_bytes())?;
             stream.write_all(encoded.as_bytes())?;
             stream.write_all("}\n".as_bytes())
         }
-        RpcResponse::Playlists(mut playlists) => {
-            let values = playlists
-                .drain(..)
-                .map(|playlist| json::JsonValue::String(playlist.to_string()))
-                .collect::<Vec<_>>();
-
-            let encoded = json::stringify(json::JsonValue::Array(values));
-            stream.write_all("{\"playlists\":".as_bytes())?;
+        RpcResponse::History(history, total) => {
+            let encoded = json::stringify(history);
+            stream.write_all("{\"history\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all(format!(", \"total\": {}}}\n", total).as_bytes())
+        }
+        RpcResponse::TrackStats(stats, total) => {
+            let encoded = json::stringify(stats);
+            stream.write_all("{\"stats\":".as_bytes())?;
+            stream.write_all(encoded.as_bytes())?;
+            stream.write_all(format!(", \"total\": {}}}\n", total).as_bytes())
+        }
+        RpcResponse::TrackInfo(info) => {
+            let encoded = json::stringify(info);
+            stream.write_all("{\"track\":".as_bytes())?;
             stream.write_all(encoded.as_bytes())?;
             stream.write_all("}\n".as_bytes())
         }
-        RpcResponse::Playlist(playlist) => {
-            let encoded = json::stringify(json::JsonValue::String(playlist.to_string()));
-            stream.write_all("{\"playlist\":".as_bytes())?;
+        RpcResponse::NoSuchTrack => {
+            stream.write_all("{\"status\": \"no-such-track\"}\n".as_bytes())
+        }
+        RpcResponse::AmbiguousRequest(matches) => {
+            let encoded = json::stringify(matches);
+            stream.write_all("{\"status\": \"ambiguous-request\", \"matches\":".as_bytes())?;
             stream.write_all(encoded.as_bytes())?;
             stream.write_all("}\n".as_bytes())
         }
-        RpcResponse::NoSuchPlaylist => {
-            stream.write_all("{\"status\": \"no-such-playlist\"}\n".as_bytes())
+        RpcResponse::AlreadyRequested => {
+            stream.write_all("{\"status\": \"already-requested\"}\n".as_bytes())
+        }
+        RpcResponse::RateLimited(retry_after) => stream.write_all(
+            format!(
+                "{{\"status\": \"rate-limited\", \"retry_after\": {}}}\n",
+                retry_after
+            )
+            .as_bytes(),
+        ),
+        RpcResponse::NoSuchRequest => {
+            stream.write_all("{\"status\": \"no-such-request\"}\n".as_bytes())
         }
+        RpcResponse::LibraryUnavailable => {
+            stream.write_all("{\"status\": \"library-unavailable\"}\n".as_bytes())
+        }
+        RpcResponse::TooManyRemoved(removed, total) => stream.write_all(
+            format!(
+                "{{\"status\": \"too-many-removed\", \"removed\": {}, \"total\": {}}}\n",
+                removed, total
+            )
+            .as_bytes(),
+        ),
         RpcResponse::NoPlaylistsAvailable => {
             stream.write_all("{\"status\": \"no-playlists-available\"}\n".as_bytes())
         }
+
+        RpcResponse::NoPendingReload => {
+            stream.write_all("{\"status\": \"no-pending-reload\"}\n".as_bytes())
+        }
+        RpcResponse::NoSuchSnapshot => {
+            stream.write_all("{\"status\": \"no-such-snapshot\"}\n".as_bytes())
+        }
+        RpcResponse::Unauthorized => {
+            stream.write_all("{\"status\": \"unauthorized\"}\n".as_bytes())
+        }
+        RpcResponse::Forbidden => stream.write_all("{\"status\": \"forbidden\"}\n".as_bytes()),
         RpcResponse::InvalidRequest => {
             stream.write_all("{\"status\": \"invalid-request\"}\n".as_bytes())
         }
         RpcResponse::UnknownCommand => {
             stream.write_all("{\"status\": \"unknown-command\"}\n".as_bytes())
         }
-        RpcResponse::InvalidParameter => {
-            stream.write_all("{\"status\": \"invalid-parameter\"}\n".as_bytes())
+        RpcResponse::InvalidParameter(field, reason) => {
+            let field = json::stringify(json::JsonValue::String(field));
+            let reason = json::stringify(json::JsonValue::String(reason));
+            stream.write_all(
+                format!(
+                    "{{\"status\": \"invalid-parameter\", \"field\": {}, \"reason\": {}}}\n",
+                    field, reason
+                )
+                .as_bytes(),
+            )
         }
     }
 }
 
-/// Checks that the paths used for the IPC and playlist options are actually valid
-fn validate_configuration(service_config: &ServiceConfig) -> Result<(), String> {
+/// Checks that the directories the playlists are read from can be used, which
+/// is done again whenever the configuration is reloaded
+pub fn validate_playlist_dirs(service_config: &ServiceConfig) -> Result<(), String> {
     if !service_config.playlist_dir.is_absolute() {
         return Err("Playlist path must be absolute path".to_string());
     }
@@ -613,6 +2610,23 @@ fn validate_configuration(service_config: &ServiceConfig) -> Result<(), String>
         return Err("Playlist path is not a directory".to_string());
     }
 
+    if let Some(content_root) = service_config.content_root.as_ref() {
+        if !content_root.is_absolute() {
+            return Err("Content root must be absolute path".to_string());
+        }
+
+        if !content_root.is_dir() {
+            return Err("Content root is not a directory".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the paths used for the IPC and playlist options are actually valid
+fn validate_configuration(service_config: &ServiceConfig) -> Result<(), String> {
+    validate_playlist_dirs(service_config)?;
+
     let ipc_socket = service_config.ipc_socket.as_path();
     if !ipc_socket.is_absolute() {
         return Err("IPC path must be absolute path".to_string());
@@ -622,67 +2636,777 @@ fn validate_configuration(service_config: &ServiceConfig) -> Result<(), String>
         return Err("IPC path cannot be a directory".to_string());
     }
 
-    if ipc_socket.exists() {
-        return Err("IPC path already exists, is this server already running?".to_string());
+    if let Ok(metadata) = fs::symlink_metadata(ipc_socket) {
+        if !metadata.file_type().is_socket() {
+            return Err("IPC path already exists and is not a socket".to_string());
+        }
+
+        if UnixStream::connect(ipc_socket).is_ok() {
+            return Err("IPC path already exists, is this server already running?".to_string());
+        }
     }
 
     match ipc_socket.parent() {
         Some(parent) if !parent.is_dir() => return Err("IPC path does not exist".to_string()),
 
-        // Should be caught by is_dir(), the only thing that would return None
-        // parent is the root directory
-        None => return Err("IPC path cannot be a directory".to_string()),
-        _ => (),
-    }
+        // Should be caught by is_dir(), the only thing that would return None
+        // parent is the root directory
+        None => return Err("IPC path cannot be a directory".to_string()),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Serializes and sends a single RPC response, along with its sequence number,
+/// how long it took to process and the canonical name of the command (if it
+/// was understood). Every single-line response is a JSON object, so the
+/// metadata is added before the closing brace. Streamed responses carry the
+/// metadata on their last line instead. The original protocol has no metadata
+/// at all.
+fn send_response_with_meta(
+    stream: &mut impl Write,
+    response: RpcResponse,
+    options: ResponseOptions,
+    protocol: ProtocolVersion,
+    command: Option<&str>,
+    sequence: u64,
+    elapsed: Duration,
+) -> io::Result<()> {
+    if options.pretty {
+        let compact = ResponseOptions {
+            pretty: false,
+            ..options
+        };
+
+        let mut buffer = Vec::new();
+        send_response_with_meta(
+            &mut buffer,
+            response,
+            compact,
+            protocol,
+            command,
+            sequence,
+            elapsed,
+        )?;
+        return stream.write_all(prettify(&buffer).as_bytes());
+    }
+
+    let command = match command {
+        Some(name) => format!(", \"command\": \"{}\"", name),
+        None => String::new(),
+    };
+
+    let meta = format!(
+        "\"meta\": {{\"seq\": {}, \"elapsed_us\": {}{}}}",
+        sequence,
+        elapsed.as_micros(),
+        command
+    );
+
+    // The last line of a streamed response has the total before the meta
+    let with_total = |total: usize| match protocol {
+        ProtocolVersion::Original => format!("\"total\": {}", total),
+        _ => format!("\"total\": {}, {}", total, meta),
+    };
+
+    let response = match (options.mode, response) {
+        (ResponseMode::Streamed, RpcResponse::Tracks(json::JsonValue::Array(items))) => {
+            let fields = match protocol {
+                ProtocolVersion::Original => "",
+                _ => &meta,
+            };
+            return send_streamed_response(stream, items, fields);
+        }
+        (ResponseMode::Streamed, RpcResponse::TrackPage(json::JsonValue::Array(items), total)) => {
+            return send_streamed_response(stream, items, &with_total(total));
+        }
+        (ResponseMode::Streamed, RpcResponse::Requests(json::JsonValue::Array(items), total)) => {
+            return send_streamed_response(stream, items, &with_total(total));
+        }
+        (ResponseMode::Streamed, RpcResponse::History(json::JsonValue::Array(items), total)) => {
+            return send_streamed_response(stream, items, &with_total(total));
+        }
+        (ResponseMode::Streamed, RpcResponse::TrackStats(json::JsonValue::Array(items), total)) => {
+            return send_streamed_response(stream, items, &with_total(total));
+        }
+        (ResponseMode::Streamed, RpcResponse::Playlists(playlists, total)) => {
+            let items = playlists
+                .iter()
+                .map(|playlist| json::JsonValue::String(playlist.to_string()))
+                .collect();
+            return send_streamed_response(stream, items, &with_total(total));
+        }
+        (_, response) => response,
+    };
+
+    if protocol == ProtocolVersion::Original {
+        return send_response(stream, response, protocol);
+    }
+
+    let mut buffer = Vec::new();
+    send_response(&mut buffer, response, protocol)?;
+    buffer.truncate(buffer.len() - "}\n".len());
+    buffer.extend_from_slice(format!(", {}}}\n", meta).as_bytes());
+    stream.write_all(&buffer)
+}
+
+/// Indents each line of a response, which is a JSON document of its own
+fn prettify(buffer: &[u8]) -> String {
+    let mut pretty = String::new();
+    for line in String::from_utf8_lossy(buffer).lines() {
+        match json::parse(line) {
+            Ok(document) => pretty.push_str(&json::stringify_pretty(document, 2)),
+            Err(_) => pretty.push_str(line),
+        }
+        pretty.push('\n');
+    }
+
+    pretty
+}
+
+/// Sends a list response as one line per item, so that the whole list never
+/// has to be serialized at once, and then a line marking the end of the list
+/// with the number of items and the rest of the fields (including the metadata)
+fn send_streamed_response(
+    stream: &mut impl Write,
+    items: Vec<json::JsonValue>,
+    fields: &str,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(stream);
+    let count = items.len();
+    for item in items {
+        writer.write_all(b"{\"item\":")?;
+        item.write(&mut writer)?;
+        writer.write_all(b"}\n")?;
+    }
+
+    if fields.is_empty() {
+        writer.write_all(format!("{{\"end\": true, \"count\": {}}}\n", count).as_bytes())?;
+    } else {
+        writer.write_all(
+            format!("{{\"end\": true, \"count\": {}, {}}}\n", count, fields).as_bytes(),
+        )?;
+    }
+    writer.flush()
+}
+
+/// Describes a track for the commands that list the contents of a playlist,
+/// including its ID3 tags and the duration its playlist file gave it, if they
+/// are known
+fn track_entry(
+    offset: usize,
+    file: &Path,
+    id3_tags: &ID3Directory,
+    durations: &Durations,
+) -> Option<json::JsonValue> {
+    let filename = file.to_str()?;
+
+    let mut file_entry = json::object::Object::new();
+    file_entry.insert("offset", json::JsonValue::Number(offset.into()));
+
+    let json_filename = json::JsonValue::String(filename.to_string());
+    file_entry.insert("file", json_filename);
+    if let Some(duration) = durations.get(file) {
+        file_entry.insert("duration", json::JsonValue::Number((*duration).into()));
+    }
+
+    file_entry.insert("id3", tags_entry(filename, id3_tags));
+    Some(json::JsonValue::Object(file_entry))
+}
+
+/// Describes the ID3 tags and the duration of a track returned by next-track
+/// or previous-track, for connections on the detailed protocol
+fn track_details(track: &Path, id3_tags: &ID3Directory, durations: &Durations) -> json::JsonValue {
+    let mut details = json::object::Object::new();
+    details.insert("id3", tags_entry(&track.to_string_lossy(), id3_tags));
+    if let Some(duration) = durations.get(track) {
+        details.insert("duration", json::JsonValue::Number((*duration).into()));
+    }
+
+    json::JsonValue::Object(details)
+}
+
+/// Describes a track for track-info, including the playlists that it is on
+fn info_entry(
+    file: &Path,
+    playlists: &[&str],
+    id3_tags: &ID3Directory,
+    durations: &Durations,
+) -> Option<json::JsonValue> {
+    let filename = file.to_str()?;
+
+    let mut file_entry = json::object::Object::new();
+    file_entry.insert("file", json::JsonValue::String(filename.to_string()));
+    if let Some(duration) = durations.get(file) {
+        file_entry.insert("duration", json::JsonValue::Number((*duration).into()));
+    }
+
+    let playlists = playlists
+        .iter()
+        .map(|name| json::JsonValue::String(name.to_string()))
+        .collect();
+    file_entry.insert("playlists", json::JsonValue::Array(playlists));
+    file_entry.insert("id3", tags_entry(filename, id3_tags));
+    Some(json::JsonValue::Object(file_entry))
+}
+
+/// Describes the ID3 tags of a track, which is empty if they aren't known
+fn tags_entry(filename: &str, id3_tags: &ID3Directory) -> json::JsonValue {
+    let mut id3_obj = json::object::Object::new();
+    if let Some(tags) = id3_tags.get(filename) {
+        let json_title = json::JsonValue::String(tags.title().to_string());
+        id3_obj.insert("title", json_title);
+
+        let json_artist = json::JsonValue::String(tags.artist().to_string());
+        id3_obj.insert("artist", json_artist);
+
+        let json_album = json::JsonValue::String(tags.album().to_string());
+        id3_obj.insert("album", json_album);
+
+        let json_comment = json::JsonValue::String(tags.comment().to_string());
+        id3_obj.insert("comment", json_comment);
+
+        let json_year = json::JsonValue::Number(tags.year().into());
+        id3_obj.insert("year", json_year);
+
+        if let Some(track) = tags.track() {
+            let json_track = json::JsonValue::Number((*track).into());
+            id3_obj.insert("track", json_track);
+        }
+
+        let json_genre = json::JsonValue::String(tags.genre().into());
+        id3_obj.insert("genre", json_genre);
+
+        if let Some(length) = tags.length() {
+            id3_obj.insert("length", json::JsonValue::Number(length.into()));
+        }
+    }
+
+    json::JsonValue::Object(id3_obj)
+}
+
+/// Describes a track that next-track returned, for get-history and the
+/// history file
+fn history_entry(played: &PlayedTrack, id3_tags: &ID3Directory) -> json::JsonValue {
+    let filename = played.track.to_string_lossy();
+    let mut entry = json::object::Object::new();
+    entry.insert("track", json::JsonValue::String(filename.to_string()));
+    entry.insert(
+        "kind",
+        json::JsonValue::String(played.kind.name().to_string()),
+    );
+
+    if let Some(module) = played.kind.module() {
+        entry.insert("module", json::JsonValue::String(module.to_string()));
+    }
+
+    if let Some(playlist) = played.playlist.as_ref() {
+        entry.insert("playlist", json::JsonValue::String(playlist.to_string()));
+    }
+
+    let since_epoch = played
+        .time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    entry.insert(
+        "played",
+        json::JsonValue::Number(since_epoch.as_secs().into()),
+    );
+    entry.insert("id3", tags_entry(&filename, id3_tags));
+    json::JsonValue::Object(entry)
+}
+
+/// Describes how often a track has been played, whether it has been retired
+/// and why it has been skipped today, for track-stats
+fn stats_entry(
+    track: &Path,
+    count: Option<&PlayCount>,
+    retirement: Option<Retirement>,
+    skips: Option<json::JsonValue>,
+    id3_tags: &ID3Directory,
+) -> json::JsonValue {
+    let filename = track.to_string_lossy();
+    let mut entry = json::object::Object::new();
+    entry.insert("track", json::JsonValue::String(filename.to_string()));
+    entry.insert(
+        "plays",
+        json::JsonValue::Number(count.map(|count| count.plays).unwrap_or(0).into()),
+    );
+
+    if let Some(count) = count {
+        let since_epoch = count
+            .last_played
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        entry.insert(
+            "last_played",
+            json::JsonValue::Number(since_epoch.as_secs().into()),
+        );
+    }
+
+    if let Some(retirement) = retirement {
+        entry.insert(
+            "retired",
+            json::JsonValue::String(retirement.reason().to_string()),
+        );
+    }
+
+    if let Some(skips) = skips {
+        entry.insert("skipped_today", skips);
+    }
+
+    entry.insert("id3", tags_entry(&filename, id3_tags));
+    json::JsonValue::Object(entry)
+}
+
+/// Describes a listener's request for the request commands
+fn request_entry(request: &Request) -> json::JsonValue {
+    let mut entry = json::object::Object::new();
+    entry.insert("id", json::JsonValue::Number(request.id.into()));
+    match &request.item {
+        RequestItem::Track(track) => {
+            entry.insert("kind", json::JsonValue::String("track".to_string()));
+            entry.insert(
+                "track",
+                json::JsonValue::String(track.to_string_lossy().to_string()),
+            );
+        }
+        RequestItem::Message(text) => {
+            entry.insert("kind", json::JsonValue::String("message".to_string()));
+            entry.insert("message", json::JsonValue::String(text.to_string()));
+        }
+    }
+    entry.insert(
+        "listener",
+        json::JsonValue::String(request.listener.to_string()),
+    );
+    entry.insert(
+        "state",
+        json::JsonValue::String(request.state.name().to_string()),
+    );
+    json::JsonValue::Object(entry)
+}
+
+/// Copies the tags of a served track for the event bus
+fn track_tags(tags: &utils::ID3) -> TrackTags {
+    TrackTags {
+        artist: tags.artist().to_string(),
+        title: tags.title().to_string(),
+        album: tags.album().to_string(),
+        genre: String::from(tags.genre()),
+    }
+}
+
+/// Picks the entry that next-track returns: a special entry if one is due,
+/// then any approved requests, and otherwise the next playable track, as of
+/// the given time. Nothing is returned once a playlist that stops at its end
+/// has finished.
+fn serve_next_track(
+    queue: &mut PlaylistQueue,
+    special_queue: &mut SpecialQueue,
+    now: SystemTime,
+) -> Option<(PathBuf, TrackKind)> {
+    if !queue.finish_playlist() {
+        return None;
+    }
+
+    // Breaks and requests wait until a work that has started has played all of
+    // its parts
+    let local_now = DateTime::<Local>::from(now).naive_local();
+    if queue.find_next_part(local_now).is_some() {
+        let track = queue.next_available_track(now);
+        queue.votes.start_track(Some(&track));
+        return Some((track, TrackKind::Music));
+    }
+
+    if special_queue.is_special_pending(now) {
+        if let Some((special, kind)) = special_queue.take_available(now) {
+            special_queue.update_timer(now);
+            queue.votes.start_track(None);
+            return Some((special, kind));
+        }
+    }
+
+    while let Some(request) = queue.requests.next_approved() {
+        info!(
+            "server",
+            "Playing #{}, requested by {}", request.id, request.listener
+        );
+
+        match request.item {
+            RequestItem::Track(track) => {
+                queue.record_play(&track, DateTime::<Local>::from(now).naive_local());
+                queue.votes.start_track(Some(&track));
+                return Some((track, TrackKind::Request));
+            }
+            RequestItem::Message(text) => match special_queue.read_message(&text) {
+                Some(announcement) => {
+                    queue.votes.start_track(None);
+                    return Some((announcement, TrackKind::Message));
+                }
+                None => {
+                    warning!("server", "Skipping #{}, could not read it out", request.id)
+                }
+            },
+        }
+    }
+
+    let track = queue.next_available_track(now);
+    queue.votes.start_track(Some(&track));
+    Some((track, TrackKind::Music))
+}
+
+/// Adds a track or message to the request queue on behalf of a listener
+fn submit_request<'a>(
+    queue: &mut PlaylistQueue,
+    listener: String,
+    item: RequestItem,
+) -> RpcResponse<'a> {
+    match queue.requests.submit(&queue.request_config, listener, item) {
+        Ok(request) => RpcResponse::Request(request_entry(request)),
+        Err(RequestError::AlreadyRequested) => RpcResponse::AlreadyRequested,
+        Err(RequestError::RateLimited(wait)) => {
+            // Rounded up, so that retrying after that long always works
+            RpcResponse::RateLimited(wait.as_secs() + 1)
+        }
+    }
+}
+
+/// How many of the tracks that matched an ambiguous request are sent back, so
+/// that the listener can pick one
+const MAX_REQUEST_MATCHES: usize = 10;
+
+/// Checks whether a track matches the filter, by its filename or by the title,
+/// artist or album in its ID3 tags
+fn track_matches(options: &ListOptions, file: &Path, id3_tags: &ID3Directory) -> bool {
+    let filename = file.to_string_lossy();
+    match id3_tags.get(filename.as_ref()) {
+        Some(tags) => options.matches(&[&filename, tags.title(), tags.artist(), tags.album()]),
+        None => options.matches(&[&filename]),
+    }
+}
+
+/// Updates the state of the playlist queue according to the given request.
+/// Votes and requests without a listener ID are counted as coming from the
+/// connection.
+fn process_request<'a>(
+    rpc: RpcRequest,
+    queue: &'a mut PlaylistQueue,
+    special_queue: &mut SpecialQueue,
+    connection: u64,
+) -> RpcResponse<'a> {
+    match rpc {
+        RpcRequest::NextTrack => {
+            let now = SystemTime::now();
+            *queue.last_request.lock().unwrap() = now;
+            let (track, kind) = match serve_next_track(queue, special_queue, now) {
+                Some(entry) => entry,
+                None => return RpcResponse::NoPlaylistsAvailable,
+            };
+
+            let event = match kind.module() {
+                Some(_) => Event::SpecialServed {
+                    track: track.clone(),
+                    kind: kind.name(),
+                },
+                None => Event::TrackServed {
+                    track: track.clone(),
+                    playlist: queue.current_playlist.to_string(),
+                    tags: track
+                        .to_str()
+                        .and_then(|path| queue.id3_tags.get(path))
+                        .map(track_tags),
+                    requested: kind == TrackKind::Request,
+                },
+            };
+            queue.events.publish(event);
+            queue.record_served(&track, kind, now);
+
+            RpcResponse::Track(track, kind)
+        }
+
+        RpcRequest::PreviousTrack(steps) => {
+            // The player is still asking for tracks, even if it's going back
+            *queue.last_request.lock().unwrap() = SystemTime::now();
+            if steps == 0 || steps > queue.served.len() {
+                return RpcResponse::NoSuchTrack;
+            }
+
+            let played = &queue.served[queue.served.len() - steps];
+            RpcResponse::Track(played.track.to_path_buf(), played.kind)
+        }
+
+        RpcRequest::NowPlaying => match queue.now_playing.as_ref() {
+            Some(played) => RpcResponse::NowPlaying(history_entry(played, &queue.id3_tags)),
+            None => RpcResponse::NoCurrentTrack,
+        },
+
+        RpcRequest::GetHistory(options) => {
+            let id3_tags = &queue.id3_tags;
+            let played = queue
+                .served
+                .iter()
+                .rev()
+                .filter(|played| track_matches(&options, &played.track, id3_tags))
+                .collect::<Vec<_>>();
+
+            let (page, total) = options.page(played);
+            let array = page
+                .into_iter()
+                .map(|played| history_entry(played, id3_tags))
+                .collect();
+            RpcResponse::History(json::JsonValue::Array(array), total)
+        }
+
+        RpcRequest::TrackStats(options, retired_only) => {
+            // Tracks that haven't been played yet are included, since those
+            // are usually the interesting ones
+            let now = DateTime::<Local>::from(SystemTime::now()).naive_local();
+            let id3_tags = &queue.id3_tags;
+            let play_counts = &queue.play_counts;
+            let retirement = |track: &Path| queue.retirement(track, &now);
+            let mut tracks = queue
+                .playlists
+                .values()
+                .flat_map(|playlist| playlist.songs().iter())
+                .chain(play_counts.keys())
+                .filter(|track| track_matches(&options, track, id3_tags))
+                .filter(|track| !retired_only || retirement(track).is_some())
+                .collect::<Vec<_>>();
+            tracks.sort();
+            tracks.dedup();
+
+            let plays = |track: &Path| play_counts.get(track).map(|count| count.plays);
+            tracks.sort_by_key(|track| Reverse(plays(track)));
+
+            let (page, total) = options.page(tracks);
+            let array = page
+                .into_iter()
+                .map(|track| {
+                    stats_entry(
+                        track,
+                        play_counts.get(track),
+                        retirement(track),
+                        queue.skip_tracker.report_track(track, now),
+                        id3_tags,
+                    )
+                })
+                .collect();
+            RpcResponse::TrackStats(json::JsonValue::Array(array), total)
+        }
+
+        RpcRequest::UnretireTrack(track) => {
+            let known = queue
+                .playlists
+                .values()
+                .any(|playlist| playlist.songs().contains(&track));
+            if !known {
+                return RpcResponse::NoSuchTrack;
+            }
+
+            let now = DateTime::<Local>::from(SystemTime::now()).naive_local();
+            if queue.retirement(&track, &now).is_none() {
+                return RpcResponse::InvalidParameter(
+                    "track".to_string(),
+                    "is not retired".to_string(),
+                );
+            }
+
+            let plays = queue
+                .play_counts
+                .get(&track)
+                .map(|count| count.plays)
+                .unwrap_or(0);
+            info!("server", "Bringing back {}", track.display());
+            queue.unretired.insert(track, plays);
+            RpcResponse::Ok
+        }
+
+        RpcRequest::TrackInfo(track) => {
+            let mut playlists = queue
+                .playlists
+                .iter()
+                .filter(|(_, playlist)| playlist.songs().contains(&track))
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            if playlists.is_empty() {
+                return RpcResponse::NoSuchTrack;
+            }
+
+            playlists.sort_unstable();
+            match info_entry(&track, &playlists, &queue.id3_tags, &queue.durations) {
+                Some(info) => RpcResponse::TrackInfo(info),
+                None => RpcResponse::NoSuchTrack,
+            }
+        }
+
+        RpcRequest::RequestTrack(query, listener) => {
+            let listener = listener.unwrap_or_else(|| format!("connection-{}", connection));
+            let options = ListOptions {
+                filter: Some(query.to_lowercase()),
+                ..ListOptions::default()
+            };
+
+            let id3_tags = &queue.id3_tags;
+            let mut matches = queue
+                .playlists
+                .values()
+                .flat_map(|playlist| playlist.songs().iter())
+                .filter(|file| track_matches(&options, file, id3_tags))
+                .collect::<Vec<_>>();
+            matches.sort();
+            matches.dedup();
+
+            // A full path picks out a single track, even if it's also a part
+            // of the path of other tracks
+            let exact = matches
+                .iter()
+                .find(|file| file.as_os_str() == OsStr::new(&query));
+
+            let track = match (exact, matches.as_slice()) {
+                (Some(file), _) | (None, [file]) => file.to_path_buf(),
+                (None, []) => return RpcResponse::NoSuchTrack,
+                (None, _) => {
+                    let array = matches
+                        .iter()
+                        .take(MAX_REQUEST_MATCHES)
+                        .map(|file| json::JsonValue::String(file.to_string_lossy().to_string()))
+                        .collect();
+                    return RpcResponse::AmbiguousRequest(json::JsonValue::Array(array));
+                }
+            };
+
+            submit_request(queue, listener, RequestItem::Track(track))
+        }
+
+        RpcRequest::SubmitMessage(text, listener) => {
+            let listener = listener.unwrap_or_else(|| format!("connection-{}", connection));
+            let text = text.trim();
+            let max_length = queue.request_config.message_length;
+            if text.is_empty() {
+                return RpcResponse::InvalidParameter(
+                    "text".to_string(),
+                    "must not be empty".to_string(),
+                );
+            }
+
+            if text.chars().count() > max_length {
+                return RpcResponse::InvalidParameter(
+                    "text".to_string(),
+                    format!("must be at most {} characters", max_length),
+                );
+            }
+
+            submit_request(queue, listener, RequestItem::Message(text.to_string()))
+        }
+
+        RpcRequest::ListRequests(options) => {
+            let requests = queue
+                .requests
+                .requests()
+                .iter()
+                .filter(|request| {
+                    let item = request.item.to_string();
+                    options.matches(&[&item, &request.listener, request.state.name()])
+                })
+                .collect::<Vec<_>>();
+
+            let (page, total) = options.page(requests);
+            let array = page.into_iter().map(request_entry).collect();
+            RpcResponse::Requests(json::JsonValue::Array(array), total)
+        }
+
+        RpcRequest::ApproveRequest(id) => {
+            if queue.requests.approve(id) {
+                RpcResponse::Ok
+            } else {
+                RpcResponse::NoSuchRequest
+            }
+        }
 
-    Ok(())
-}
+        RpcRequest::DenyRequest(id) => {
+            if queue.requests.deny(id) {
+                RpcResponse::Ok
+            } else {
+                RpcResponse::NoSuchRequest
+            }
+        }
 
-/// Updates the state of the playlist queue according to the given request
-fn process_request<'a>(
-    rpc: RpcRequest,
-    queue: &'a mut PlaylistQueue,
-    special_queue: &mut SpecialQueue,
-) -> RpcResponse<'a> {
-    match rpc {
-        RpcRequest::NextTrack => {
-            if special_queue.is_special_pending() {
-                if let Some(special) = special_queue.current() {
-                    if special.is_file() {
-                        special_queue.next();
-                        special_queue.update_timer();
-                        return RpcResponse::Track(special);
-                    } else {
-                        eprintln!(
-                            "[server] Skipping special entry, {} not available",
-                            special.display()
-                        );
+        RpcRequest::Vote(vote, listener) => {
+            let listener = listener.unwrap_or_else(|| format!("connection-{}", connection));
+            match queue.votes.vote(&queue.vote_config, listener, vote) {
+                Some(outcome) => {
+                    if outcome.action == Some(VoteAction::Deprioritize) {
+                        queue
+                            .playlists
+                            .values_mut()
+                            .for_each(|playlist| playlist.demote(&outcome.track));
                     }
+
+                    RpcResponse::Votes(outcome)
                 }
+                None => RpcResponse::NoCurrentTrack,
             }
-
-            let current_playlist = queue.playlists.get_mut(&queue.current_playlist).unwrap();
-            let song = current_playlist.current().to_path_buf();
-            current_playlist.next();
-            RpcResponse::Track(song)
         }
 
-        RpcRequest::ListPlaylists => {
-            let playlists = queue.playlists.keys().collect::<Vec<_>>();
-            RpcResponse::Playlists(playlists)
+        RpcRequest::ListPlaylists(options) => {
+            // The playlists are sorted so that pages stay in the same order
+            // between requests
+            let mut playlists = queue
+                .playlists
+                .keys()
+                .filter(|name| options.matches(&[name]))
+                .collect::<Vec<_>>();
+            playlists.sort();
+
+            let (page, total) = options.page(playlists);
+            RpcResponse::Playlists(page, total)
         }
 
         RpcRequest::GetPlaylist => RpcResponse::Playlist(&queue.current_playlist),
 
         RpcRequest::SwitchPlaylist(target) => {
             if queue.playlists.contains_key(&target) {
-                queue.current_playlist = target;
+                let from = mem::replace(&mut queue.current_playlist, target.to_string());
+                queue.playlist_ended = false;
+                queue
+                    .events
+                    .publish(Event::PlaylistSwitched { from, to: target });
                 RpcResponse::Ok
             } else {
                 RpcResponse::NoSuchPlaylist
             }
         }
 
+        RpcRequest::SeekPlaylist(name, target) => match queue.playlists.get_mut(&name) {
+            Some(playlist) => {
+                let position = match target {
+                    SeekTarget::Index(index) if index < playlist.songs().len() => index,
+                    SeekTarget::Index(_) => {
+                        return RpcResponse::InvalidParameter(
+                            "index".to_string(),
+                            format!("must be less than {}", playlist.songs().len()),
+                        )
+                    }
+                    SeekTarget::Track(track) => {
+                        match playlist.songs().iter().position(|song| *song == track) {
+                            Some(index) => index,
+                            None => return RpcResponse::NoSuchTrack,
+                        }
+                    }
+                };
+
+                playlist.seek(position);
+                if name == queue.current_playlist {
+                    queue.playlist_ended = false;
+                }
+
+                RpcResponse::Ok
+            }
+            None => RpcResponse::NoSuchPlaylist,
+        },
+
         RpcRequest::PreviewPlaylist(playlist) => match queue.playlists.get_mut(&playlist) {
             Some(playlist) => {
                 let mut array = Vec::new();
@@ -691,41 +3415,8 @@ fn process_request<'a>(
                     let file = playlist.current().clone();
                     playlist.next();
 
-                    let mut file_entry = json::object::Object::new();
-                    if let Some(filename) = file.as_path().to_str() {
-                        file_entry.insert("offset", json::JsonValue::Number(x.into()));
-
-                        let json_filename = json::JsonValue::String(filename.to_string());
-                        file_entry.insert("file", json_filename);
-
-                        let mut id3_obj = json::object::Object::new();
-                        if let Some(tags) = queue.id3_tags.get(filename) {
-                            let json_title = json::JsonValue::String(tags.title().to_string());
-                            id3_obj.insert("title", json_title);
-
-                            let json_artist = json::JsonValue::String(tags.artist().to_string());
-                            id3_obj.insert("artist", json_artist);
-
-                            let json_album = json::JsonValue::String(tags.album().to_string());
-                            id3_obj.insert("album", json_album);
-
-                            let json_comment = json::JsonValue::String(tags.comment().to_string());
-                            id3_obj.insert("comment", json_comment);
-
-                            let json_year = json::JsonValue::Number(tags.year().into());
-                            id3_obj.insert("year", json_year);
-
-                            if let Some(track) = tags.track() {
-                                let json_track = json::JsonValue::Number((*track).into());
-                                id3_obj.insert("track", json_track);
-                            }
-
-                            let json_genre = json::JsonValue::String(tags.genre().into());
-                            id3_obj.insert("genre", json_genre);
-                        }
-
-                        file_entry.insert("id3", json::JsonValue::Object(id3_obj));
-                        array.push(json::JsonValue::Object(file_entry));
+                    if let Some(entry) = track_entry(x, &file, &queue.id3_tags, &queue.durations) {
+                        array.push(entry);
                     }
                 }
 
@@ -735,85 +3426,430 @@ fn process_request<'a>(
             None => RpcResponse::NoSuchPlaylist,
         },
 
+        RpcRequest::GetPlaylistTracks(playlist, options) => match queue.playlists.get(&playlist) {
+            Some(playlist) => {
+                let id3_tags = &queue.id3_tags;
+                let songs = playlist
+                    .songs()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, file)| track_matches(&options, file, id3_tags))
+                    .collect::<Vec<_>>();
+
+                let (page, total) = options.page(songs);
+                let array = page
+                    .into_iter()
+                    .filter_map(|(index, file)| {
+                        track_entry(index, file, id3_tags, &queue.durations)
+                    })
+                    .collect();
+
+                RpcResponse::TrackPage(json::JsonValue::Array(array), total)
+            }
+            None => RpcResponse::NoSuchPlaylist,
+        },
+
+        RpcRequest::SearchTracks(options) => {
+            // A track on several playlists is only listed once, and they're
+            // sorted so that pages stay in the same order between requests
+            let id3_tags = &queue.id3_tags;
+            let mut tracks = queue
+                .playlists
+                .values()
+                .flat_map(|playlist| playlist.songs().iter())
+                .filter(|file| track_matches(&options, file, id3_tags))
+                .collect::<Vec<_>>();
+            tracks.sort();
+            tracks.dedup();
+
+            let (page, total) = options.page(tracks);
+            let array = page
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, file)| {
+                    track_entry(options.offset + index, file, id3_tags, &queue.durations)
+                })
+                .collect();
+
+            RpcResponse::TrackPage(json::JsonValue::Array(array), total)
+        }
+
         RpcRequest::ShufflePlaylists => {
-            let mut rng = utils::seeded_random();
-            queue.shuffle_all(&mut rng);
+            queue.shuffle_all();
+            queue.playlist_ended = false;
             RpcResponse::Ok
         }
 
         RpcRequest::ReloadTags => {
-            let mut id3_directory = &mut queue.id3_tags;
+            let id3_directory = &mut queue.id3_tags;
             id3_directory.clear();
             queue
                 .playlists
                 .iter()
-                .for_each(|(_, playlist)| playlist.update_id3_directory(&mut id3_directory));
+                .for_each(|(_, playlist)| update_id3_directory(playlist.songs(), id3_directory));
             RpcResponse::Ok
         }
 
-        RpcRequest::ReloadPlaylists => {
-            let mut raw_playlists = match read_m3u8_files(queue.directory.as_ref()) {
-                Ok(playlists) => playlists,
-                Err(error) => {
-                    eprintln!("[server] {}", error);
-                    return RpcResponse::NoPlaylistsAvailable;
-                }
-            };
+        RpcRequest::ReloadPlaylists(force) => queue.reload(force),
+
+        RpcRequest::PreviewReload => match queue.read_playlists() {
+            Ok((raw_playlists, durations)) => {
+                let preview = queue.reload_preview(&raw_playlists);
+                queue.pending_reload = Some((raw_playlists, durations));
+                RpcResponse::ReloadPreview(preview)
+            }
+            Err(response) => response,
+        },
+
+        RpcRequest::RollbackPlaylists(steps) => {
+            if steps == 0 {
+                return RpcResponse::InvalidParameter(
+                    "steps".to_string(),
+                    "must be positive".to_string(),
+                );
+            }
+
+            if queue.rollback(steps) {
+                info!("server", "Rolled back the last {} reload(s)", steps);
+                RpcResponse::Ok
+            } else {
+                RpcResponse::NoSuchSnapshot
+            }
+        }
+
+        RpcRequest::CommitReload => match queue.pending_reload.take() {
+            Some((mut raw_playlists, durations)) => {
+                queue.apply_reload(&mut raw_playlists, durations);
+                RpcResponse::Ok
+            }
+            None => RpcResponse::NoPendingReload,
+        },
+
+        RpcRequest::QuotaReport => {
+            let now = Local::now().naive_local();
+            queue.quota_tracker.roll_over(&queue.quotas, now);
+            RpcResponse::Quotas(queue.quota_tracker.report(&queue.quotas))
+        }
+
+        RpcRequest::Status => {
+            let uptime = SystemTime::now()
+                .duration_since(queue.started)
+                .map(|uptime| uptime.as_secs())
+                .unwrap_or(0);
+
+            let mut status = json::object::Object::new();
+            status.insert("uptime", json::JsonValue::Number(uptime.into()));
+            status.insert(
+                "playlist",
+                json::JsonValue::String(queue.current_playlist.to_string()),
+            );
+            status.insert(
+                "playlist_stopped",
+                json::JsonValue::Boolean(
+                    queue.playlist_finished()
+                        && *queue.playlist_end.action(&queue.current_playlist) == PlaylistEnd::Stop,
+                ),
+            );
+            status.insert(
+                "tracks_served",
+                json::JsonValue::Number(queue.tracks_served.into()),
+            );
+            status.insert("specials", special_queue.report());
+            status.insert(
+                "skipped_today",
+                queue.skip_tracker.report(Local::now().naive_local()),
+            );
+            status.insert(
+                "environment",
+                queue
+                    .environment
+                    .report(queue.playlists.len(), queue.id3_tags.len()),
+            );
+
+            let mut workers = json::object::Object::new();
+            for (name, running) in queue.workers.health() {
+                let state = if running { "running" } else { "stopped" };
+                workers.insert(name, json::JsonValue::String(state.to_string()));
+            }
+            status.insert("workers", json::JsonValue::Object(workers));
+            RpcResponse::Status(json::JsonValue::Object(status))
+        }
+
+        RpcRequest::Ping => RpcResponse::Pong,
+
+        RpcRequest::Wake(worker) => match queue.workers.find(&worker) {
+            Some(waker) => {
+                debug!("server", "Waking {} worker", worker);
+                waker.wake();
+                RpcResponse::Ok
+            }
+            None => RpcResponse::NoSuchWorker,
+        },
+
+        RpcRequest::Restarting => {
+            info!(
+                "server",
+                "Service is being restarted, holding off the watchdog"
+            );
+            *queue.last_restart.lock().unwrap() = Some(Instant::now());
+            RpcResponse::Ok
+        }
 
-            queue.merge_with(&mut raw_playlists);
+        RpcRequest::Shutdown => {
+            info!("server", "Shutting down");
+            queue.shutdown = true;
             RpcResponse::Ok
         }
 
+        // The framing and the protocol version belong to the connection, so
+        // process_connection takes care of them before the request gets here
+        RpcRequest::SetFraming(_) | RpcRequest::SetProtocol(_) => RpcResponse::InvalidRequest,
+
+        RpcRequest::Unauthorized => {
+            warning!(
+                "server",
+                "Refusing a request on connection {} without the auth token",
+                connection
+            );
+            RpcResponse::Unauthorized
+        }
         RpcRequest::InvalidRequest => RpcResponse::InvalidRequest,
         RpcRequest::UnknownCommand => RpcResponse::UnknownCommand,
-        RpcRequest::InvalidParameter => RpcResponse::InvalidParameter,
+        RpcRequest::InvalidParameter(field, reason) => RpcResponse::InvalidParameter(field, reason),
+    }
+}
+
+/// Sends a response that was already serialized as a single frame
+fn send_frame(stream: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(frame)
+}
+
+/// A connection that IPC requests can be read from, which is either on the
+/// Unix socket or over TCP
+trait ClientStream: Read + Write {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()>;
+
+    /// The process, user and group on the other end, if they can be known
+    fn peer_credentials(&self) -> Option<libc::ucred>;
+}
+
+impl ClientStream for UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+
+    fn peer_credentials(&self) -> Option<libc::ucred> {
+        let mut credentials = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut size = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        // Safe since the buffer and its size are both given and the kernel
+        // writes no more than that
+        let result = unsafe {
+            libc::getsockopt(
+                self.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut size,
+            )
+        };
+
+        if result < 0 {
+            warning!(
+                "server",
+                "Could not read client credentials: {}",
+                io::Error::last_os_error()
+            );
+            None
+        } else {
+            Some(credentials)
+        }
+    }
+}
+
+impl ClientStream for TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+
+    fn peer_credentials(&self) -> Option<libc::ucred> {
+        None
+    }
+}
+
+/// Waits until one of the listeners has a client waiting to be accepted, and
+/// returns its index. Returns None if there is a timeout and nobody connected
+/// before it ran out.
+fn wait_for_client(listeners: &[RawFd], timeout: Option<Duration>) -> io::Result<Option<usize>> {
+    // Rounded up, so that the wait doesn't end just short of the timeout
+    let timeout_ms = timeout
+        .map(|timeout| timeout.as_micros().div_ceil(1000) as libc::c_int)
+        .unwrap_or(-1);
+
+    let mut fds = listeners
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        // Safe since the array holds exactly as many entries as are passed
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(error);
+        }
+
+        if ready == 0 && timeout.is_some() {
+            return Ok(None);
+        }
+
+        if let Some(index) = fds.iter().position(|fd| fd.revents != 0) {
+            return Ok(Some(index));
+        }
     }
 }
 
 /// Reads and executes commands, and sends responses, on a single connection
-/// until that connection is terminated
+/// until that connection is terminated. The sequence number is shared across
+/// all connections and counts every response sent, while the connection number
+/// identifies this connection. The framing can only be changed by the first
+/// command on the connection, and applies to every response after that one.
+/// The protocol version starts out as the configured one and can be changed
+/// at any point, starting with the response to the command that changes it.
 fn process_connection(
-    mut client: UnixStream,
+    mut client: impl ClientStream,
     queue: &mut PlaylistQueue,
     special_queue: &mut SpecialQueue,
+    sequence: &mut u64,
+    connection: u64,
 ) {
-    if let Err(error) = client.set_read_timeout(Some(Duration::from_secs(5))) {
-        eprintln!("[server] Warning, could not set socket timeout: {}", error);
-    };
-
-    if let Err(error) = client.set_write_timeout(Some(Duration::from_secs(5))) {
-        eprintln!("[server] Warning, could not set socket timeout: {}", error);
+    if let Err(error) = client.set_timeouts(Duration::from_secs(5)) {
+        warning!("server", "Could not set socket timeout: {}", error);
     };
 
+    let peer = client.peer_credentials();
+    let mut protocol = queue.protocol_version;
     let mut command_buffer = Vec::new();
     let mut read_buffer = [0; 4096];
+    let mut framing = Framing::Lines;
+    let mut next_framing = None;
+    let mut first_command = true;
 
     loop {
         let size = match client.read(&mut read_buffer) {
             Ok(0) => break,
             Ok(size) => size,
             Err(error) => {
-                eprintln!("[server] Lost connection to client: {}", error);
+                warning!("server", "Lost connection to client: {}", error);
                 break;
             }
         };
 
         command_buffer.extend_from_slice(&read_buffer[..size]);
-        match try_parse_request(&command_buffer) {
-            Some((rpc, offset)) => {
+        match try_parse_request(&command_buffer, queue.auth_token.as_deref()) {
+            Some((rpc, options, offset)) => {
                 command_buffer.drain(..offset);
-                let response = process_request(rpc, queue, special_queue);
-                match send_response(&mut client, response) {
+                let started = Instant::now();
+                let command = rpc.command_name();
+                queue.record_diagnostics(command.unwrap_or("invalid"));
+                let response = match rpc {
+                    RpcRequest::SetFraming(_) if !first_command => RpcResponse::InvalidParameter(
+                        "framing".to_string(),
+                        "can only be set by the first command".to_string(),
+                    ),
+                    RpcRequest::SetFraming(requested) => {
+                        next_framing = Some(requested);
+                        RpcResponse::Ok
+                    }
+                    RpcRequest::SetProtocol(requested) => {
+                        protocol = requested;
+                        RpcResponse::Ok
+                    }
+                    rpc if rpc.is_admin() && !queue.allows_admin(peer.as_ref()) => {
+                        match peer.as_ref() {
+                            Some(peer) => warning!(
+                                "server",
+                                "Refusing {} on connection {} from user {}, who isn't an admin",
+                                command.unwrap_or("invalid"),
+                                connection,
+                                peer.uid
+                            ),
+                            None => warning!(
+                                "server",
+                                "Refusing {} on connection {} over TCP, which can't be an admin",
+                                command.unwrap_or("invalid"),
+                                connection
+                            ),
+                        }
+                        RpcResponse::Forbidden
+                    }
+                    rpc => process_request(rpc, queue, special_queue, connection),
+                };
+
+                let response = match response {
+                    RpcResponse::Track(track, kind) if protocol >= ProtocolVersion::Detailed => {
+                        let details = track_details(&track, &queue.id3_tags, &queue.durations);
+                        RpcResponse::DetailedTrack(track, kind, details)
+                    }
+                    response => response,
+                };
+
+                first_command = false;
+                *sequence += 1;
+                let elapsed = started.elapsed();
+                let result = match framing {
+                    Framing::Lines => send_response_with_meta(
+                        &mut client,
+                        response,
+                        options,
+                        protocol,
+                        command,
+                        *sequence,
+                        elapsed,
+                    ),
+                    Framing::Length => {
+                        let mut frame = Vec::new();
+                        send_response_with_meta(
+                            &mut frame, response, options, protocol, command, *sequence, elapsed,
+                        )
+                        .and_then(|()| send_frame(&mut client, &frame))
+                    }
+                };
+
+                // The reply to set-framing is sent the old way, so that the
+                // client knows where the new framing starts
+                if let Some(requested) = next_framing.take() {
+                    framing = requested;
+                }
+
+                queue.record_metrics(Some(command.unwrap_or("invalid")));
+                match result {
+                    Ok(()) if queue.shutdown => break,
                     Ok(()) => (),
                     Err(error) => {
-                        eprintln!("[server] Could not reply to client: {}", error);
+                        warning!("server", "Could not reply to client: {}", error);
                         break;
                     }
                 }
             }
             None => {
                 if command_buffer.len() > 4096 {
-                    eprintln!("[server] Client buffer too large, dropping");
+                    warning!("server", "Client buffer too large, dropping");
                     break;
                 }
             }
@@ -821,77 +3857,434 @@ fn process_connection(
     }
 }
 
-/// Processes incoming IPC requests and maintains the set of current playlists
-pub fn server_worker(service_config: ServiceConfig, special_config: SpecialBaseConfig) {
-    if let Err(message) = validate_configuration(&service_config) {
-        eprintln!("[server] {}", message);
+/// Re-reads the configuration file after a SIGHUP and applies whatever can be
+/// changed without a restart, then reloads the playlists. Changes that need a
+/// restart are logged and left alone, and nothing is applied if the file
+/// doesn't parse.
+fn reload_config(
+    startup: &StartupSettings,
+    queue: &mut PlaylistQueue,
+    special_queue: &mut SpecialQueue,
+) {
+    let path = queue.environment.config_path.clone();
+    info!("server", "Reloading configuration from {}", path.display());
+
+    let config = match fs::File::open(&path)
+        .map_err(|error| format!("Could not open configuration: {}", error))
+        .and_then(|mut file| config::parse_station(&mut file, queue.environment.station.as_deref()))
+    {
+        Ok(config) => config,
+        Err(error) => {
+            warning!("server", "Not reloading configuration: {}", error);
+            return;
+        }
+    };
+
+    for warning in &config.warnings {
+        warning!("config", "{}", warning);
+    }
+
+    if let Err(message) = validate_playlist_dirs(&config.service) {
+        warning!("server", "Not reloading configuration: {}", message);
         return;
     }
 
-    let server = match UnixListener::bind(service_config.ipc_socket) {
-        Ok(server) => server,
+    let restart_only = startup.changes(&config.startup_settings());
+    for name in restart_only.iter() {
+        warning!(
+            "server",
+            "'{}' was changed, but only takes effect after a restart",
+            name
+        );
+    }
+
+    if !restart_only.contains(&"special") {
+        special_queue.apply_config(config.special_base);
+    }
+
+    logging::set_levels(&config.service.log);
+
+    queue.apply_config(
+        config.service,
+        SchedulingConfig {
+            availability: config.availability,
+            rules: config.rules,
+            quotas: config.quotas,
+            ramp: config.ramp,
+            playlist_end: config.playlist_end,
+            parts: config.parts,
+            retirement: config.retirement,
+            votes: config.votes,
+            requests: config.requests,
+        },
+    );
+
+    if queue.reload(false) == RpcResponse::Ok {
+        info!("server", "Reloaded configuration and playlists");
+    }
+}
+
+/// The parts of the configuration which decide what track is played next
+pub struct SchedulingConfig {
+    pub availability: AvailabilityConfig,
+    pub rules: RulesConfig,
+    pub quotas: QuotaConfig,
+    pub ramp: RampConfig,
+    pub playlist_end: PlaylistEndConfig,
+    pub parts: PartsConfig,
+    pub retirement: RetirementConfig,
+    pub votes: VoteConfig,
+    pub requests: RequestConfig,
+}
+
+/// The sockets that the server accepts IPC requests on. They're bound before
+/// anything else starts, so that shuffled can give up root afterwards.
+pub struct Listeners {
+    unix: UnixListener,
+    tcp: Option<TcpListener>,
+}
+
+/// Checks the configuration and binds the IPC socket, and the TCP socket if
+/// there is one
+pub fn bind_listeners(service_config: &ServiceConfig) -> Result<Listeners, String> {
+    validate_configuration(service_config)?;
+
+    // Nothing answers on a socket that passed validation, so it was left
+    // behind by an instance that couldn't remove it, such as one that crashed
+    // or had given up root
+    let socket = &service_config.ipc_socket;
+    if socket.exists() {
+        info!("server", "Removing stale IPC socket {}", socket.display());
+        let _ = fs::remove_file(socket);
+    }
+
+    let unix = UnixListener::bind(socket)
+        .map_err(|error| format!("Could not bind IPC socket: {}", error))?;
+
+    let tcp = match service_config.tcp_listen {
+        Some(address) => match TcpListener::bind(address) {
+            Ok(tcp_server) => Some(tcp_server),
+            Err(error) => {
+                let _ = fs::remove_file(&service_config.ipc_socket);
+                return Err(format!("Could not bind TCP socket {}: {}", address, error));
+            }
+        },
+        None => None,
+    };
+
+    Ok(Listeners { unix, tcp })
+}
+
+/// Processes incoming IPC requests and maintains the set of current playlists
+pub fn server_worker(
+    listeners: Listeners,
+    service_config: ServiceConfig,
+    special_config: SpecialBaseConfig,
+    scheduling: SchedulingConfig,
+    shared: SharedState,
+    startup: StartupSettings,
+    mut notifier: Option<Notifier>,
+) {
+    let Listeners {
+        unix: server,
+        tcp: tcp_server,
+    } = listeners;
+
+    if let Some(address) = service_config.tcp_listen {
+        info!("server", "Accepting IPC requests on TCP {}", address);
+    }
+
+    let (mut disk_playlists, durations) = match playlist::read_playlists(
+        &service_config.playlist_dir,
+        service_config.content_root.as_deref(),
+    ) {
+        Ok(files) => files,
         Err(error) => {
-            eprintln!("[server] Could not bind IPC socket: {}", error);
-            eprintln!("[server] Terminating");
+            error!("server", "{}", error);
+            error!("server", "Terminating");
             return;
         }
     };
 
-    let mut rng = utils::seeded_random();
-    let init_playlists = match read_m3u8_files(&service_config.playlist_dir) {
-        Ok(mut playlists) => playlists
-            .drain()
-            .map(|(playlist, paths)| {
-                let mut add_playlist = Playlist::new(paths).unwrap();
-                add_playlist.shuffle(&mut rng);
-                (playlist, add_playlist)
-            })
-            .collect::<HashMap<String, Playlist>>(),
+    // The saved playlists are merged with the ones on disk just like a reload,
+    // so that tracks added or removed while the server was down are picked up
+    let saved_state =
+        service_config
+            .state_file
+            .as_ref()
+            .and_then(|path| match state::load_state(path) {
+                Ok(Some(saved)) => {
+                    info!(
+                        "server",
+                        "Restoring {} playlists from {}",
+                        saved.playlists.len(),
+                        path.display()
+                    );
+                    Some(saved)
+                }
+                Ok(None) => None,
+                Err(error) => {
+                    warning!("server", "Not restoring playlists: {}", error);
+                    None
+                }
+            });
+
+    let mut queue = PlaylistQueue::new(&service_config, scheduling, shared, SystemTime::now());
+    queue.durations = durations;
+    if let Some(saved) = saved_state {
+        queue.current_playlist = saved.current_playlist.unwrap_or_default();
+        queue.playlists = saved.playlists;
+        queue.play_counts = saved.play_counts;
+        queue.added = saved.added;
+        queue.unretired = saved.unretired;
+        queue.spent = saved.spent;
+    }
+
+    queue.add_smart_playlists(&mut disk_playlists);
+    queue.merge_with(&mut disk_playlists);
+    queue.report_expiries();
+    queue.record_metrics(None);
+    queue
+        .environment
+        .log(queue.playlists.len(), queue.id3_tags.len());
+
+    let mut sequence = 0;
+    let mut connections = 0;
+    let mut special_queue = SpecialQueue::new(&service_config, special_config, SystemTime::now());
+
+    let save_interval = Duration::from_secs(service_config.state_save_interval as u64);
+    let mut state_file = service_config
+        .state_file
+        .map(|path| StateFile::new(path, save_interval));
+
+    let mut listeners = vec![server.as_raw_fd()];
+    if let Some(tcp_server) = tcp_server.as_ref() {
+        listeners.push(tcp_server.as_raw_fd());
+    }
+
+    let hangups = match Hangups::install() {
+        Ok(hangups) => {
+            listeners.push(hangups.fd());
+            Some(hangups)
+        }
         Err(error) => {
-            eprintln!("[server] {}", error);
-            eprintln!("[server] Terminating");
-            return;
+            warning!("server", "Could not handle SIGHUP: {}", error);
+            None
         }
     };
+    let hangup_index = hangups.as_ref().map(|_| listeners.len() - 1);
 
-    let mut id3_directory = HashMap::new();
-    init_playlists
+    // Every playlist has been read and the socket has been listening since
+    // before the workers started, so clients can be answered from here on
+    if let Some(notifier) = notifier.as_mut() {
+        notifier.ready();
+    }
+
+    loop {
+        // Without a TCP listener, a SIGHUP handler, a state file to save or
+        // a keepalive to send, accepting on the Unix socket does the waiting
+        let timeout = [
+            state_file.as_ref().map(StateFile::time_until_due),
+            notifier.as_ref().and_then(Notifier::time_until_ping),
+        ]
         .iter()
-        .for_each(|(_, playlist)| playlist.update_id3_directory(&mut id3_directory));
+        .flatten()
+        .min()
+        .copied();
 
-    let mut queue = PlaylistQueue {
-        current_playlist: init_playlists.keys().next().unwrap().to_string(),
-        playlists: init_playlists,
-        directory: service_config.playlist_dir,
-        id3_tags: id3_directory,
-    };
+        let ready = match timeout {
+            Some(timeout) => wait_for_client(&listeners, Some(timeout)),
+            None if listeners.len() > 1 => wait_for_client(&listeners, None),
+            None => Ok(Some(0)),
+        };
+
+        let result = match (ready, tcp_server.as_ref()) {
+            (Ok(None), _) => Ok(()),
+            (Ok(Some(index)), _) if Some(index) == hangup_index => {
+                if let Some(hangups) = hangups.as_ref() {
+                    hangups.drain();
+                }
+
+                reload_config(&startup, &mut queue, &mut special_queue);
+                Ok(())
+            }
+            (Ok(Some(0)), _) => server.accept().map(|(client, _)| {
+                connections += 1;
+                process_connection(
+                    client,
+                    &mut queue,
+                    &mut special_queue,
+                    &mut sequence,
+                    connections,
+                )
+            }),
+            (Ok(Some(_)), Some(tcp_server)) => tcp_server.accept().map(|(client, _)| {
+                connections += 1;
+                process_connection(
+                    client,
+                    &mut queue,
+                    &mut special_queue,
+                    &mut sequence,
+                    connections,
+                )
+            }),
+            (Ok(Some(_)), None) => Ok(()),
+            (Err(error), _) => Err(error),
+        };
+
+        if let Err(error) = result {
+            warning!("server", "Lost client: {}", error);
+        }
 
-    let mut special_entries = Vec::new();
-    if service_config.clock_enabled {
-        special_entries.push(SpecialQueueEntry::TimeGenerator);
+        if queue.shutdown {
+            break;
+        }
+
+        if let Some(state_file) = state_file.as_mut() {
+            state_file.save_if_due(
+                &queue.current_playlist,
+                &queue.playlists,
+                &queue.play_counts,
+                &queue.added,
+                &queue.unretired,
+                &queue.spent,
+            );
+        }
+
+        // Only sent while the loop is still coming back around, so that
+        // systemd restarts a server which is stuck on a client
+        if let Some(notifier) = notifier.as_mut() {
+            notifier.ping_if_due();
+        }
     }
 
-    if service_config.weather_enabled {
-        special_entries.push(SpecialQueueEntry::File(
-            special_config
-                .working_dir
-                .join(crate::weather::WEATHER_MP3_FILE)
-                .to_path_buf(),
-        ));
+    if let Some(state_file) = state_file.as_mut() {
+        state_file.save(
+            &queue.current_playlist,
+            &queue.playlists,
+            &queue.play_counts,
+            &queue.added,
+            &queue.unretired,
+            &queue.spent,
+        );
     }
 
-    let mut special_queue = SpecialQueue {
-        entries: special_entries,
-        position: 0,
-        working_dir: special_config.working_dir,
-        last_play_time: SystemTime::now(),
-        interval: Duration::from_secs(special_config.interval as u64 * 60),
-    };
+    queue.workers.shutdown();
+    queue.events.publish(Event::Shutdown);
+    if let Err(error) = fs::remove_file(&service_config.ipc_socket) {
+        warning!("server", "Could not remove IPC socket: {}", error);
+    }
+}
+
+/// An entry that a simulation would have returned for next-track
+#[derive(Debug)]
+pub struct SimulatedEntry {
+    pub track: PathBuf,
+    pub kind: &'static str,
+
+    /// The playlist the track came from, unless it's a special entry
+    pub playlist: Option<String>,
+
+    /// How long the track plays for in seconds, if its playlist or its tags
+    /// say
+    pub duration: Option<u32>,
+}
+
+/// The server's track selection on its own, run against whatever time it's
+/// given instead of the clock. Nothing is generated or written to disk, and
+/// no sockets are opened.
+pub struct Simulation {
+    queue: PlaylistQueue,
+    special_queue: SpecialQueue,
+}
+
+impl Simulation {
+    /// Reads and shuffles the playlists like the server does at startup, with
+    /// the special interval starting at the given time
+    pub fn new(
+        config: Config,
+        environment: Environment,
+        start: SystemTime,
+    ) -> Result<Self, String> {
+        validate_playlist_dirs(&config.service)?;
+        let (mut disk_playlists, durations) = playlist::read_playlists(
+            &config.service.playlist_dir,
+            config.service.content_root.as_deref(),
+        )?;
 
-    for stream in server.incoming() {
-        match stream {
-            Ok(client) => process_connection(client, &mut queue, &mut special_queue),
-            Err(error) => eprintln!("[server] Lost client: {}", error),
+        let shared = SharedState {
+            events: EventBus::new(),
+            last_request: Arc::new(Mutex::new(start)),
+            last_restart: Arc::new(Mutex::new(None)),
+            workers: Workers::default(),
+            environment,
+            diagnostics: Arc::new(Mutex::new(Diagnostics::default())),
+            metrics: Arc::new(Mutex::new(Metrics::new())),
+        };
+
+        let scheduling = SchedulingConfig {
+            availability: config.availability,
+            rules: config.rules,
+            quotas: config.quotas,
+            ramp: config.ramp,
+            playlist_end: config.playlist_end,
+            parts: config.parts,
+            retirement: config.retirement,
+            votes: config.votes,
+            requests: config.requests,
+        };
+
+        let mut queue = PlaylistQueue::new(&config.service, scheduling, shared, start);
+        queue.history_file = None;
+        queue.durations = durations;
+        queue.add_smart_playlists(&mut disk_playlists);
+        queue.merge_with(&mut disk_playlists);
+
+        let mut special_queue = SpecialQueue::new(&config.service, config.special_base, start);
+        special_queue.dry_run = true;
+
+        Ok(Simulation {
+            queue,
+            special_queue,
+        })
+    }
+
+    pub fn current_playlist(&self) -> &str {
+        &self.queue.current_playlist
+    }
+
+    /// Switches to another playlist, like switch-playlist
+    pub fn switch_playlist(&mut self, playlist: &str) -> Result<(), String> {
+        if !self.queue.playlists.contains_key(playlist) {
+            return Err(format!("There is no playlist named '{}'", playlist));
         }
+
+        self.queue.current_playlist = playlist.to_string();
+        self.queue.playlist_ended = false;
+        Ok(())
+    }
+
+    /// Picks the entry that next-track would return at the given time, unless
+    /// the current playlist has stopped at its end
+    pub fn next_track(&mut self, now: SystemTime) -> Option<SimulatedEntry> {
+        let (track, kind) = serve_next_track(&mut self.queue, &mut self.special_queue, now)?;
+        self.queue.record_served(&track, kind, now);
+
+        let duration = self.queue.durations.get(&track).copied().or_else(|| {
+            track
+                .to_str()
+                .and_then(|path| self.queue.id3_tags.get(path))
+                .and_then(utils::ID3::length)
+        });
+
+        Some(SimulatedEntry {
+            playlist: match kind.module() {
+                Some(_) => None,
+                None => Some(self.queue.current_playlist.to_string()),
+            },
+            track,
+            kind: kind.name(),
+            duration,
+        })
     }
 }
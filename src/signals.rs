@@ -0,0 +1,156 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The end of the pipe that the SIGHUP handler writes to, or -1 before the
+/// handler is installed
+static HANGUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Writes a byte to the pipe for each SIGHUP. Writing to a pipe is one of the
+/// few things that are safe to do in a signal handler, and errno is put back
+/// so that whatever the signal interrupted doesn't see it change.
+extern "C" fn on_hangup(_: libc::c_int) {
+    let fd = HANGUP_PIPE.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+
+    // Safe since errno is always valid for the current thread, and the byte
+    // outlives the write
+    unsafe {
+        let errno = *libc::__errno_location();
+        libc::write(fd, b"h".as_ptr() as *const libc::c_void, 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Opens a pipe that doesn't block, for a signal handler to write to
+fn open_pipe() -> io::Result<[RawFd; 2]> {
+    let mut fds = [0; 2];
+
+    // Safe since the array holds the two descriptors that are returned
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fds)
+}
+
+fn set_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+    // Safe since the handlers only do async-signal-safe things, and the rest
+    // of the struct is zeroed for an empty mask and no flags
+    let result = unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigaction(signal, &action, std::ptr::null_mut())
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Reads everything waiting in a pipe that doesn't block
+fn drain_pipe(fd: RawFd) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        // Safe since the length passed is the length of the buffer
+        let count =
+            unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+
+        if count <= 0 {
+            return bytes;
+        }
+
+        bytes.extend_from_slice(&buffer[..count as usize]);
+    }
+}
+
+/// Turns SIGHUP into something that can be waited on alongside sockets. The
+/// read end of the pipe becomes readable whenever SIGHUP has arrived since it
+/// was last drained.
+pub struct Hangups {
+    read_fd: RawFd,
+}
+
+impl Hangups {
+    /// Installs the SIGHUP handler, which replaces the default of terminating
+    /// the process
+    pub fn install() -> io::Result<Self> {
+        let fds = open_pipe()?;
+        HANGUP_PIPE.store(fds[1], Ordering::Relaxed);
+        set_handler(libc::SIGHUP, on_hangup)?;
+        Ok(Hangups { read_fd: fds[0] })
+    }
+
+    /// The descriptor to poll for SIGHUPs arriving
+    pub fn fd(&self) -> RawFd {
+        self.read_fd
+    }
+
+    /// Empties the pipe, so that several SIGHUPs in a row are only handled
+    /// once
+    pub fn drain(&self) {
+        drain_pipe(self.read_fd);
+    }
+}
+
+/// The end of the pipe that the handler for signals meant for the stations
+/// writes to, or -1 before it is installed
+static FORWARD_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Writes the number of each signal that arrives to the pipe, the same way
+/// that on_hangup does
+extern "C" fn on_forwarded(signal: libc::c_int) {
+    let fd = FORWARD_PIPE.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+
+    // Safe for the same reasons as in on_hangup
+    unsafe {
+        let errno = *libc::__errno_location();
+        let byte = signal as u8;
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Catches the signals that are passed on to each station's process, so that
+/// they can be waited on like Hangups
+pub struct Forwarded {
+    read_fd: RawFd,
+}
+
+impl Forwarded {
+    /// Installs the handler for each of the signals, which replaces what they
+    /// would have done to this process
+    pub fn install(signals: &[libc::c_int]) -> io::Result<Self> {
+        let fds = open_pipe()?;
+        FORWARD_PIPE.store(fds[1], Ordering::Relaxed);
+        for signal in signals {
+            set_handler(*signal, on_forwarded)?;
+        }
+
+        Ok(Forwarded { read_fd: fds[0] })
+    }
+
+    /// The descriptor to poll for signals arriving
+    pub fn fd(&self) -> RawFd {
+        self.read_fd
+    }
+
+    /// Returns the signals that have arrived since the last call, in the
+    /// order that they did
+    pub fn take(&self) -> Vec<libc::c_int> {
+        drain_pipe(self.read_fd)
+            .into_iter()
+            .map(libc::c_int::from)
+            .collect()
+    }
+}
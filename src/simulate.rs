@@ -0,0 +1,140 @@
+use crate::config::Config;
+use crate::environment::Environment;
+use crate::server::Simulation;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// How long a music track is taken to play for when neither its playlist nor
+/// its tags say
+const DEFAULT_TRACK_SEC: u32 = 180;
+
+/// How long a clock or weather announcement is taken to play for, since they
+/// aren't generated
+const DEFAULT_SPECIAL_SEC: u32 = 15;
+
+/// What to simulate, as given on the command line
+struct Options {
+    hours: u32,
+    start: SystemTime,
+    playlist: Option<String>,
+}
+
+const USAGE: &str =
+    "Usage: shuffled [CONFIG] simulate [--hours HOURS] [--start YYYY-MM-DDTHH:MM] [--playlist NAME]";
+
+fn parse_start(text: &str) -> Result<SystemTime, String> {
+    let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M")
+        .map_err(|error| format!("Could not parse start time '{}': {}", text, error))?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(SystemTime::from)
+        .ok_or_else(|| format!("Start time '{}' doesn't exist in the local time zone", text))
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        hours: 24,
+        start: SystemTime::now(),
+        playlist: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value\n{}", arg, USAGE))?;
+
+        match arg.as_str() {
+            "--hours" => match value.parse::<u32>() {
+                Ok(hours) if hours > 0 => options.hours = hours,
+                _ => {
+                    return Err(format!(
+                        "--hours must be a positive number, not '{}'",
+                        value
+                    ))
+                }
+            },
+            "--start" => options.start = parse_start(value)?,
+            "--playlist" => options.playlist = Some(value.to_string()),
+            _ => return Err(format!("Unknown option {}\n{}", arg, USAGE)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn format_duration(seconds: u32) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Runs next-track over and over against a virtual clock, which moves ahead by
+/// the length of each entry, and prints what would have been played. Anything
+/// the selection itself logs, like tracks being skipped for their
+/// availability windows, goes to stderr as it would on the server.
+pub fn run_simulation(config_path: &Path, config: Config, args: &[String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let environment = Environment::detect(config_path, &config);
+    let mut simulation = Simulation::new(config, environment, options.start)?;
+    if let Some(playlist) = options.playlist.as_ref() {
+        simulation.switch_playlist(playlist)?;
+    }
+
+    let end = options.start + Duration::from_secs(options.hours as u64 * 60 * 60);
+    let mut now = options.start;
+    let mut counts = BTreeMap::new();
+    let mut entries = 0;
+
+    println!(
+        "Simulating {} hours from {} on playlist '{}'",
+        options.hours,
+        DateTime::<Local>::from(options.start).format("%Y-%m-%d %H:%M"),
+        simulation.current_playlist()
+    );
+
+    while now < end {
+        let entry = match simulation.next_track(now) {
+            Some(entry) => entry,
+            None => {
+                println!(
+                    "{}  Playlist '{}' has stopped at its end",
+                    DateTime::<Local>::from(now).format("%Y-%m-%d %H:%M:%S"),
+                    simulation.current_playlist()
+                );
+                break;
+            }
+        };
+        let (seconds, estimated) = match (entry.duration, entry.playlist.as_ref()) {
+            (Some(seconds), _) => (seconds, ""),
+            (None, Some(_)) => (DEFAULT_TRACK_SEC, "~"),
+            (None, None) => (DEFAULT_SPECIAL_SEC, "~"),
+        };
+
+        println!(
+            "{}  {:<8} {:<16} {} ({}{})",
+            DateTime::<Local>::from(now).format("%Y-%m-%d %H:%M:%S"),
+            entry.kind,
+            entry.playlist.as_deref().unwrap_or("-"),
+            entry.track.display(),
+            estimated,
+            format_duration(seconds)
+        );
+
+        *counts.entry(entry.kind).or_insert(0) += 1;
+        entries += 1;
+
+        // A track that claims to be empty would otherwise stop the clock
+        now += Duration::from_secs(seconds.max(1) as u64);
+    }
+
+    let summary = counts
+        .iter()
+        .map(|(kind, count)| format!("{} {}", count, kind))
+        .collect::<Vec<_>>();
+    println!("Simulated {} entries: {}", entries, summary.join(", "));
+
+    Ok(())
+}
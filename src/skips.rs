@@ -0,0 +1,158 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Why next-track passed over a track on the playlist
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum SkipReason {
+    /// The file isn't there anymore
+    MissingFile,
+
+    /// The track or its playlist is outside of its availability window
+    Unavailable,
+
+    /// The track has been retired
+    Retired,
+
+    /// Listeners voted the track off
+    VotedOff,
+
+    /// One of the separation rules between tracks would be broken
+    Separation,
+
+    /// The track has a tag that isn't allowed at this hour
+    Daypart,
+
+    /// The playlist has already played as many tracks of the genre as it
+    /// can this hour
+    PlayCap,
+}
+
+/// Every reason, in the order that status lists them
+pub const REASONS: &[SkipReason] = &[
+    SkipReason::MissingFile,
+    SkipReason::Unavailable,
+    SkipReason::Retired,
+    SkipReason::VotedOff,
+    SkipReason::Separation,
+    SkipReason::Daypart,
+    SkipReason::PlayCap,
+];
+
+impl SkipReason {
+    /// The name of the reason as it appears in responses
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkipReason::MissingFile => "missing-file",
+            SkipReason::Unavailable => "unavailable",
+            SkipReason::Retired => "retired",
+            SkipReason::VotedOff => "voted-off",
+            SkipReason::Separation => "separation",
+            SkipReason::Daypart => "daypart",
+            SkipReason::PlayCap => "play-cap",
+        }
+    }
+}
+
+/// A track being passed over, with a description of what in particular kept
+/// it from playing
+#[derive(Debug, PartialEq, Clone)]
+pub struct Skip {
+    pub reason: SkipReason,
+    pub detail: String,
+}
+
+impl Skip {
+    pub fn new(reason: SkipReason, detail: String) -> Self {
+        Skip { reason, detail }
+    }
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.detail)
+    }
+}
+
+/// Counts how often tracks have been skipped today, for each reason and for
+/// each track
+#[derive(Debug)]
+pub struct SkipTracker {
+    /// The day being counted
+    day: NaiveDate,
+
+    totals: HashMap<SkipReason, u64>,
+    tracks: HashMap<PathBuf, HashMap<SkipReason, u64>>,
+}
+
+impl SkipTracker {
+    /// Creates a new tracker for the day containing the given time
+    pub fn new(now: NaiveDateTime) -> Self {
+        SkipTracker {
+            day: now.date(),
+            totals: HashMap::new(),
+            tracks: HashMap::new(),
+        }
+    }
+
+    /// Starts counting a new day if the given time is past the current one
+    fn roll_over(&mut self, now: NaiveDateTime) {
+        if now.date() != self.day {
+            self.day = now.date();
+            self.totals.clear();
+            self.tracks.clear();
+        }
+    }
+
+    /// Counts a track as skipped for the given reason, returning how many
+    /// times it has been skipped for it today
+    pub fn record(&mut self, track: &Path, reason: SkipReason, now: NaiveDateTime) -> u64 {
+        self.roll_over(now);
+        *self.totals.entry(reason).or_insert(0) += 1;
+
+        let count = self
+            .tracks
+            .entry(track.to_path_buf())
+            .or_default()
+            .entry(reason)
+            .or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Reports how many tracks have been skipped today for every reason
+    pub fn report(&self, now: NaiveDateTime) -> json::JsonValue {
+        let today = now.date() == self.day;
+        let mut report = json::object::Object::new();
+        for reason in REASONS {
+            let count = self
+                .totals
+                .get(reason)
+                .copied()
+                .filter(|_| today)
+                .unwrap_or(0);
+            report.insert(reason.name(), json::JsonValue::Number(count.into()));
+        }
+
+        json::JsonValue::Object(report)
+    }
+
+    /// Reports how many times a track has been skipped today for each reason
+    /// that it has been, if it has been skipped at all
+    pub fn report_track(&self, track: &Path, now: NaiveDateTime) -> Option<json::JsonValue> {
+        if now.date() != self.day {
+            return None;
+        }
+
+        let counts = self.tracks.get(track)?;
+        let mut report = json::object::Object::new();
+        for reason in REASONS {
+            if let Some(count) = counts.get(reason) {
+                report.insert(reason.name(), json::JsonValue::Number((*count).into()));
+            }
+        }
+
+        Some(json::JsonValue::Object(report))
+    }
+}
@@ -0,0 +1,311 @@
+use crate::retirement::Unretired;
+use crate::storage;
+use shuffled::playlist::{PlayCount, PlayCounts, Playlist};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// When each track was added to the playlists by a reload
+pub type AddedTracks = HashMap<PathBuf, SystemTime>;
+
+/// The tracks that each one-shot playlist has played since it last started
+/// over, indexed by playlist name
+pub type SpentTracks = HashMap<String, HashSet<PathBuf>>;
+
+/// The playlists as they were left when the state file was written
+pub struct SavedState {
+    pub current_playlist: Option<String>,
+    pub playlists: HashMap<String, Playlist>,
+    pub play_counts: PlayCounts,
+    pub added: AddedTracks,
+    pub unretired: Unretired,
+    pub spent: SpentTracks,
+}
+
+/*
+ * The state file is a JSON document holding the order and position of each
+ * playlist, along with the playlist that was playing, how often each track
+ * has been played (with the last play in seconds since the epoch) and when
+ * recently added tracks were added (also in seconds since the epoch), the
+ * play counts that retired tracks were brought back at, and the tracks that
+ * each one-shot playlist has played:
+ *
+ * {"current_playlist": "<name>",
+ *  "playlists": {
+ *    "<name>": {"position": 3, "songs": ["<path>", ...]}
+ *  },
+ *  "play_counts": {
+ *    "<path>": {"plays": 2, "last_played": 1604671200}
+ *  },
+ *  "added": {
+ *    "<path>": 1604671200
+ *  },
+ *  "unretired": {
+ *    "<path>": 200
+ *  },
+ *  "spent": {
+ *    "<name>": ["<path>", ...]
+ *  }}
+ */
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Writes out the state of the playlists as JSON
+fn encode_state(
+    current_playlist: &str,
+    playlists: &HashMap<String, Playlist>,
+    play_counts: &PlayCounts,
+    added: &AddedTracks,
+    unretired: &Unretired,
+    spent: &SpentTracks,
+) -> String {
+    let mut names = playlists.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut playlists_obj = json::JsonValue::new_object();
+    for name in names {
+        let playlist = &playlists[name];
+        let mut songs = json::JsonValue::new_array();
+        for song in playlist.songs() {
+            let _ = songs.push(song.to_string_lossy().to_string());
+        }
+
+        let mut playlist_obj = json::JsonValue::new_object();
+        playlist_obj
+            .insert("position", playlist.position())
+            .unwrap();
+        playlist_obj.insert("songs", songs).unwrap();
+        playlists_obj.insert(name, playlist_obj).unwrap();
+    }
+
+    let mut tracks = play_counts.keys().collect::<Vec<_>>();
+    tracks.sort();
+
+    let mut counts_obj = json::JsonValue::new_object();
+    for track in tracks {
+        let count = &play_counts[track];
+        let mut count_obj = json::JsonValue::new_object();
+        count_obj.insert("plays", count.plays).unwrap();
+        count_obj
+            .insert("last_played", epoch_secs(count.last_played))
+            .unwrap();
+        counts_obj
+            .insert(&track.to_string_lossy(), count_obj)
+            .unwrap();
+    }
+
+    let mut added_tracks = added.keys().collect::<Vec<_>>();
+    added_tracks.sort();
+
+    let mut added_obj = json::JsonValue::new_object();
+    for track in added_tracks {
+        added_obj
+            .insert(&track.to_string_lossy(), epoch_secs(added[track]))
+            .unwrap();
+    }
+
+    let mut unretired_tracks = unretired.keys().collect::<Vec<_>>();
+    unretired_tracks.sort();
+
+    let mut unretired_obj = json::JsonValue::new_object();
+    for track in unretired_tracks {
+        unretired_obj
+            .insert(&track.to_string_lossy(), unretired[track])
+            .unwrap();
+    }
+
+    let mut spent_names = spent.keys().collect::<Vec<_>>();
+    spent_names.sort();
+
+    let mut spent_obj = json::JsonValue::new_object();
+    for name in spent_names {
+        let mut tracks = spent[name].iter().collect::<Vec<_>>();
+        tracks.sort();
+
+        let mut tracks_array = json::JsonValue::new_array();
+        for track in tracks {
+            let _ = tracks_array.push(track.to_string_lossy().to_string());
+        }
+
+        spent_obj.insert(name, tracks_array).unwrap();
+    }
+
+    let mut document = json::JsonValue::new_object();
+    document
+        .insert("current_playlist", current_playlist)
+        .unwrap();
+    document.insert("playlists", playlists_obj).unwrap();
+    document.insert("play_counts", counts_obj).unwrap();
+    document.insert("added", added_obj).unwrap();
+    document.insert("unretired", unretired_obj).unwrap();
+    document.insert("spent", spent_obj).unwrap();
+    json::stringify_pretty(document, 2)
+}
+
+/// Reads the state file, returning None if it hasn't been written yet
+pub fn load_state(path: &Path) -> Result<Option<SavedState>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(format!("Could not read {}: {}", path.display(), error)),
+    };
+
+    let document = json::parse(&text)
+        .map_err(|error| format!("Could not parse {}: {}", path.display(), error))?;
+
+    let mut playlists = HashMap::new();
+    for (name, playlist_obj) in document["playlists"].entries() {
+        let songs = playlist_obj["songs"]
+            .members()
+            .filter_map(|song| song.as_str().map(PathBuf::from))
+            .collect::<Vec<_>>();
+
+        // An empty playlist can't be restored, so it is shuffled afresh
+        if let Some(mut playlist) = Playlist::new(songs) {
+            playlist.seek(playlist_obj["position"].as_usize().unwrap_or(0));
+            playlists.insert(name.to_string(), playlist);
+        }
+    }
+
+    // State files from before play counts were kept don't have any
+    let mut play_counts = HashMap::new();
+    for (track, count_obj) in document["play_counts"].entries() {
+        if let (Some(plays), Some(last_played)) = (
+            count_obj["plays"].as_u64(),
+            count_obj["last_played"].as_u64(),
+        ) {
+            let count = PlayCount {
+                plays,
+                last_played: SystemTime::UNIX_EPOCH + Duration::from_secs(last_played),
+            };
+            play_counts.insert(PathBuf::from(track), count);
+        }
+    }
+
+    // Nor do ones from before added tracks were kept
+    let mut added = HashMap::new();
+    for (track, added_at) in document["added"].entries() {
+        if let Some(added_at) = added_at.as_u64() {
+            added.insert(
+                PathBuf::from(track),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(added_at),
+            );
+        }
+    }
+
+    // Or ones from before tracks could be retired
+    let mut unretired = HashMap::new();
+    for (track, plays) in document["unretired"].entries() {
+        if let Some(plays) = plays.as_u64() {
+            unretired.insert(PathBuf::from(track), plays);
+        }
+    }
+
+    // Or ones from before one-shot playlists
+    let mut spent = HashMap::new();
+    for (name, tracks) in document["spent"].entries() {
+        let tracks = tracks
+            .members()
+            .filter_map(|track| track.as_str().map(PathBuf::from))
+            .collect::<HashSet<_>>();
+        spent.insert(name.to_string(), tracks);
+    }
+
+    Ok(Some(SavedState {
+        current_playlist: document["current_playlist"].as_str().map(str::to_string),
+        playlists,
+        play_counts,
+        added,
+        unretired,
+        spent,
+    }))
+}
+
+/// Keeps the state file up to date, writing it out no more often than the
+/// interval and only when something has changed since the last write
+pub struct StateFile {
+    path: PathBuf,
+    interval: Duration,
+    last_check: Instant,
+    last_contents: String,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        StateFile {
+            path,
+            interval,
+            last_check: Instant::now(),
+            last_contents: String::new(),
+        }
+    }
+
+    /// How long until the state is next due to be saved
+    pub fn time_until_due(&self) -> Duration {
+        self.interval.saturating_sub(self.last_check.elapsed())
+    }
+
+    /// Saves the state if the interval has passed since it was last checked
+    pub fn save_if_due(
+        &mut self,
+        current_playlist: &str,
+        playlists: &HashMap<String, Playlist>,
+        play_counts: &PlayCounts,
+        added: &AddedTracks,
+        unretired: &Unretired,
+        spent: &SpentTracks,
+    ) {
+        if self.last_check.elapsed() >= self.interval {
+            self.save(
+                current_playlist,
+                playlists,
+                play_counts,
+                added,
+                unretired,
+                spent,
+            );
+        }
+    }
+
+    /// Saves the state now, if it is any different from what was last saved
+    pub fn save(
+        &mut self,
+        current_playlist: &str,
+        playlists: &HashMap<String, Playlist>,
+        play_counts: &PlayCounts,
+        added: &AddedTracks,
+        unretired: &Unretired,
+        spent: &SpentTracks,
+    ) {
+        self.last_check = Instant::now();
+        let contents = encode_state(
+            current_playlist,
+            playlists,
+            play_counts,
+            added,
+            unretired,
+            spent,
+        );
+        if contents == self.last_contents {
+            return;
+        }
+
+        // Replaced all at once, so that a crash while writing doesn't leave
+        // behind half of the file
+        match storage::write_atomic(&self.path, contents.as_bytes()) {
+            Ok(()) => self.last_contents = contents,
+            Err(error) => warning!(
+                "server",
+                "Could not save state to {}: {}",
+                self.path.display(),
+                error
+            ),
+        }
+    }
+}
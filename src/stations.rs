@@ -0,0 +1,201 @@
+use crate::config::Config;
+use crate::daemon;
+use crate::logging;
+use crate::sd_notify::Notifier;
+use crate::signals::Forwarded;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+/// How long to wait for a signal before checking whether the stations are
+/// still running
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The signals that are passed on to every station. SIGHUP reloads each of
+/// them, and the others stop them when only this process was sent one.
+const FORWARDED_SIGNALS: &[libc::c_int] = &[libc::SIGHUP, libc::SIGTERM, libc::SIGINT];
+
+/// A station's process, started by running shuffled again with --station
+struct StationProcess {
+    name: String,
+    child: Child,
+}
+
+/// Waits up to the timeout for a signal to arrive on the pipe
+fn wait_for_signal(signals: &Forwarded, timeout: Duration) -> io::Result<()> {
+    let mut fd = libc::pollfd {
+        fd: signals.fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // Safe since exactly one entry is passed
+    if unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } < 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a signal to each station that is still running
+fn forward(stations: &[StationProcess], signal: libc::c_int) {
+    for station in stations {
+        // Safe since kill only reads its arguments
+        unsafe {
+            libc::kill(station.child.id() as libc::pid_t, signal);
+        }
+    }
+}
+
+/// Runs every station in the configuration, each in a shuffled process of its
+/// own, and waits until all of them have exited. The PID file, --daemon and
+/// the readiness sent to systemd belong to this process, which passes SIGHUP
+/// and any signal to stop on to the stations.
+pub fn run_stations(config_path: &Path, daemon: bool, configs: Vec<Config>) -> Result<(), String> {
+    // Options outside of the stations are the same for every one of them, and
+    // the first station is as good as any for the rest. Its instance name is
+    // this one's with the station's added on.
+    let first = &configs[0];
+    let station_suffix = format!("-{}", first.station.as_deref().unwrap_or(""));
+    let instance_name = first
+        .service
+        .instance_name
+        .strip_suffix(&station_suffix)
+        .unwrap_or(&first.service.instance_name);
+
+    logging::init(
+        &first.service.log,
+        first.service.log_file.as_deref(),
+        instance_name,
+    )?;
+
+    for config in configs.iter() {
+        for warning in &config.warnings {
+            warning!("config", "{}", warning);
+        }
+    }
+
+    let pid_file = first.service.pid_file.clone();
+    if let Some(pid_file) = pid_file.as_ref() {
+        daemon::check_pid_file(pid_file)?;
+    }
+
+    if daemon {
+        daemon::daemonize(first.service.log_file.as_deref())?;
+    }
+
+    if let Some(pid_file) = pid_file.as_ref() {
+        daemon::write_pid_file(pid_file)?;
+    }
+
+    // Taken out of the environment so that the stations leave the
+    // notifications to this process, which systemd knows as the service
+    let mut notifier = Notifier::from_environment();
+    let signals = Forwarded::install(FORWARDED_SIGNALS)
+        .map_err(|error| format!("Could not catch signals for the stations: {}", error))?;
+
+    let program = env::current_exe()
+        .map_err(|error| format!("Could not find the shuffled executable: {}", error))?;
+
+    let mut stations = Vec::new();
+    for config in configs.iter() {
+        let name = config.station.clone().unwrap_or_default();
+        let child = Command::new(&program)
+            .arg("--station")
+            .arg(&name)
+            .arg(config_path)
+            .spawn();
+
+        match child {
+            Ok(child) => {
+                info!("main", "Started station {} as process {}", name, child.id());
+                stations.push(StationProcess { name, child });
+            }
+            Err(error) => {
+                forward(&stations, libc::SIGTERM);
+                for station in stations.iter_mut() {
+                    let _ = station.child.wait();
+                }
+
+                return Err(format!("Could not start station {}: {}", name, error));
+            }
+        }
+    }
+
+    if let Some(notifier) = notifier.as_mut() {
+        notifier.ready();
+    }
+
+    // Once the stations have been told to stop, being killed by it isn't a
+    // failure
+    let mut stopping = false;
+    let mut failed = Vec::new();
+    while !stations.is_empty() {
+        let timeout = notifier
+            .as_ref()
+            .and_then(Notifier::time_until_ping)
+            .map(|until_ping| until_ping.min(POLL_INTERVAL))
+            .unwrap_or(POLL_INTERVAL);
+
+        if let Err(error) = wait_for_signal(&signals, timeout) {
+            error!("main", "Could not wait for signals: {}", error);
+            forward(&stations, libc::SIGTERM);
+            stopping = true;
+        }
+
+        for signal in signals.take() {
+            debug!("main", "Passing signal {} on to the stations", signal);
+            forward(&stations, signal);
+            stopping |= signal != libc::SIGHUP;
+        }
+
+        if let Some(notifier) = notifier.as_mut() {
+            notifier.ping_if_due();
+        }
+
+        stations.retain_mut(|station| match station.child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) if status.success() || stopping => {
+                info!("main", "Station {} has stopped", station.name);
+                false
+            }
+            Ok(Some(status)) => {
+                warning!("main", "Station {} exited with {}", station.name, status);
+                failed.push(station.name.clone());
+                false
+            }
+            Err(error) => {
+                warning!(
+                    "main",
+                    "Could not check on station {}: {}",
+                    station.name,
+                    error
+                );
+                true
+            }
+        });
+    }
+
+    if let Some(pid_file) = pid_file.as_ref() {
+        if let Err(error) = fs::remove_file(pid_file) {
+            warning!(
+                "main",
+                "Could not remove PID file {}: {}",
+                pid_file.display(),
+                error
+            );
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Stations failed: {}", failed.join(", ")))
+    }
+}
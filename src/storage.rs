@@ -0,0 +1,62 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// The name of the file that new contents are written to before they replace
+/// the file at the path
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_name = OsString::from(path.as_os_str());
+    temp_name.push(".tmp");
+    PathBuf::from(temp_name)
+}
+
+/// Flushes the directory holding a file to disk, so that a file which was
+/// just created or renamed into it is still there after a power loss
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::File::open(parent)?.sync_all(),
+        _ => fs::File::open(".")?.sync_all(),
+    }
+}
+
+/// Replaces a file with new contents, so that whatever happens it holds either
+/// all of the old contents or all of the new ones. The contents are written to
+/// a temporary file beside it and flushed to disk before it's renamed over
+/// the original, and then the rename itself is flushed.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path))
+        .and_then(|_| sync_parent(path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// Adds a line to the end of a file, creating it if it doesn't exist, and
+/// flushes it to disk. The line goes out in a single write, so a crash can at
+/// worst cut off the last line rather than mixing two together.
+pub fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    let created = !path.exists();
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    if created {
+        sync_parent(path)?;
+    }
+
+    Ok(())
+}
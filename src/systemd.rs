@@ -0,0 +1,402 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Where the system bus listens, unless overridden by the environment
+const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+
+/// How long to wait for the bus (and systemd) to answer each message
+const BUS_TIMEOUT_SEC: u64 = 10;
+
+/// The message types that can appear in the message header
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+/// The header fields that we either send or care about when receiving
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Builds up a message in the D-Bus wire format, always using little-endian
+/// byte order. Alignment is relative to the start of the buffer, so the body
+/// has to be written to its own writer.
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer { buffer: Vec::new() }
+    }
+
+    fn pad(&mut self, align: usize) {
+        while !self.buffer.len().is_multiple_of(align) {
+            self.buffer.push(0);
+        }
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn uint32(&mut self, value: u32) {
+        self.pad(4);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.uint32(value.len() as u32);
+        self.buffer.extend_from_slice(value.as_bytes());
+        self.buffer.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.byte(value.len() as u8);
+        self.buffer.extend_from_slice(value.as_bytes());
+        self.buffer.push(0);
+    }
+
+    /// Writes a header field, which is a struct of the field code and a
+    /// variant containing a string-like value
+    fn string_field(&mut self, code: u8, kind: &str, value: &str) {
+        self.pad(8);
+        self.byte(code);
+        self.signature(kind);
+        if kind == "g" {
+            self.signature(value);
+        } else {
+            self.string(value);
+        }
+    }
+}
+
+/// Reads values out of a message in the D-Bus wire format
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+    little_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, align: usize) {
+        while !self.offset.is_multiple_of(align) {
+            self.offset += 1;
+        }
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        if self.offset + size > self.buffer.len() {
+            return Err("D-Bus message was truncated".to_string());
+        }
+
+        let value = &self.buffer[self.offset..self.offset + size];
+        self.offset += size;
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        self.take(1).map(|value| value[0])
+    }
+
+    fn uint32(&mut self) -> Result<u32, String> {
+        self.align(4);
+        let bytes = self.take(4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.little_endian {
+            Ok(u32::from_le_bytes(bytes))
+        } else {
+            Ok(u32::from_be_bytes(bytes))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let size = self.uint32()? as usize;
+        let value = self.take(size + 1)?;
+        Ok(String::from_utf8_lossy(&value[..size]).to_string())
+    }
+
+    fn signature(&mut self) -> Result<String, String> {
+        let size = self.byte()? as usize;
+        let value = self.take(size + 1)?;
+        Ok(String::from_utf8_lossy(&value[..size]).to_string())
+    }
+}
+
+/// The parts of a reply from the bus that we need to interpret it
+struct Reply {
+    kind: u8,
+    reply_serial: Option<u32>,
+    error_name: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    little_endian: bool,
+}
+
+impl Reply {
+    /// Gets the first argument of the reply, if it is a string or object path
+    fn first_string(&self) -> Option<String> {
+        if !self.signature.starts_with('s') && !self.signature.starts_with('o') {
+            return None;
+        }
+
+        let mut reader = Reader {
+            buffer: &self.body,
+            offset: 0,
+            little_endian: self.little_endian,
+        };
+
+        reader.string().ok()
+    }
+}
+
+/// A connection to the system bus which has completed the authentication
+/// handshake
+struct Bus {
+    socket: UnixStream,
+    serial: u32,
+}
+
+impl Bus {
+    /// Connects to the system bus, authenticates as the current user and
+    /// registers with the bus so that we can make method calls
+    fn connect() -> Result<Self, String> {
+        let socket_path = std::env::var("DBUS_SYSTEM_BUS_ADDRESS")
+            .ok()
+            .and_then(|address| {
+                address
+                    .strip_prefix("unix:path=")
+                    .map(|path| path.split(',').next().unwrap_or(path).to_string())
+            })
+            .unwrap_or_else(|| SYSTEM_BUS_SOCKET.to_string());
+
+        let mut socket = UnixStream::connect(&socket_path).map_err(|error| {
+            format!(
+                "Could not connect to system bus at {}: {}",
+                socket_path, error
+            )
+        })?;
+
+        let timeout = Some(Duration::from_secs(BUS_TIMEOUT_SEC));
+        socket
+            .set_read_timeout(timeout)
+            .and_then(|_| socket.set_write_timeout(timeout))
+            .map_err(|error| format!("Could not set system bus timeout: {}", error))?;
+
+        let uid = fs::metadata("/proc/self")
+            .map(|meta| meta.uid())
+            .map_err(|error| format!("Could not determine current user: {}", error))?;
+
+        let uid_hex: String = uid
+            .to_string()
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        socket
+            .write_all(format!("\0AUTH EXTERNAL {}\r\n", uid_hex).as_bytes())
+            .map_err(|error| format!("Could not authenticate to system bus: {}", error))?;
+
+        // The reader can't be kept around, since it might buffer part of the
+        // first message after the handshake. The bus doesn't send anything
+        // past the OK line until we BEGIN though.
+        let mut response = String::new();
+        BufReader::new(&socket)
+            .read_line(&mut response)
+            .map_err(|error| format!("Could not authenticate to system bus: {}", error))?;
+
+        if !response.starts_with("OK ") {
+            return Err(format!(
+                "System bus rejected authentication: {}",
+                response.trim_end()
+            ));
+        }
+
+        socket
+            .write_all(b"BEGIN\r\n")
+            .map_err(|error| format!("Could not authenticate to system bus: {}", error))?;
+
+        let mut bus = Bus { socket, serial: 0 };
+        bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            &[],
+        )?;
+
+        Ok(bus)
+    }
+
+    /// Calls a method whose arguments are all strings, and waits for the
+    /// reply. Returns the first argument of the reply if there is one, or a
+    /// description of the error that the method returned.
+    fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[&str],
+    ) -> Result<Option<String>, String> {
+        self.serial += 1;
+        let serial = self.serial;
+
+        let mut body = Writer::new();
+        for arg in args.iter() {
+            body.string(arg);
+        }
+
+        let mut message = Writer::new();
+        message.byte(b'l');
+        message.byte(METHOD_CALL);
+        message.byte(0);
+        message.byte(1);
+        message.uint32(body.buffer.len() as u32);
+        message.uint32(serial);
+
+        // The length of the header field array is filled in once it has
+        // been written
+        message.uint32(0);
+        let fields_start = message.buffer.len();
+        message.string_field(FIELD_PATH, "o", path);
+        message.string_field(FIELD_INTERFACE, "s", interface);
+        message.string_field(FIELD_MEMBER, "s", member);
+        message.string_field(FIELD_DESTINATION, "s", destination);
+        if !args.is_empty() {
+            message.string_field(FIELD_SIGNATURE, "g", &"s".repeat(args.len()));
+        }
+
+        let fields_size = (message.buffer.len() - fields_start) as u32;
+        message.buffer[12..16].copy_from_slice(&fields_size.to_le_bytes());
+        message.pad(8);
+        message.buffer.extend_from_slice(&body.buffer);
+
+        self.socket
+            .write_all(&message.buffer)
+            .map_err(|error| format!("Could not send {} to system bus: {}", member, error))?;
+
+        loop {
+            let reply = self.receive()?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+
+            return match reply.kind {
+                METHOD_RETURN => Ok(reply.first_string()),
+                ERROR => {
+                    let name = reply.error_name.clone().unwrap_or_default();
+                    match reply.first_string() {
+                        Some(description) => Err(format!("{}: {}", name, description)),
+                        None => Err(name),
+                    }
+                }
+                _ => continue,
+            };
+        }
+    }
+
+    /// Reads the next message from the bus
+    fn receive(&mut self) -> Result<Reply, String> {
+        let mut fixed = [0; 16];
+        self.socket
+            .read_exact(&mut fixed)
+            .map_err(|error| format!("Could not read from system bus: {}", error))?;
+
+        let little_endian = match fixed[0] {
+            b'l' => true,
+            b'B' => false,
+            _ => return Err("System bus sent a message with unknown byte order".to_string()),
+        };
+
+        let mut reader = Reader {
+            buffer: &fixed,
+            offset: 4,
+            little_endian,
+        };
+
+        let body_size = reader.uint32()? as usize;
+        let _serial = reader.uint32()?;
+        let fields_size = reader.uint32()? as usize;
+
+        // The header is padded out to a multiple of 8 bytes before the body
+        let header_pad = (8 - (16 + fields_size) % 8) % 8;
+        let mut rest = vec![0; fields_size + header_pad + body_size];
+        self.socket
+            .read_exact(&mut rest)
+            .map_err(|error| format!("Could not read from system bus: {}", error))?;
+
+        let mut message = fixed.to_vec();
+        message.extend_from_slice(&rest);
+
+        let mut reply = Reply {
+            kind: fixed[1],
+            reply_serial: None,
+            error_name: None,
+            signature: String::new(),
+            body: message[16 + fields_size + header_pad..].to_vec(),
+            little_endian,
+        };
+
+        let mut reader = Reader {
+            buffer: &message[..16 + fields_size],
+            offset: 16,
+            little_endian,
+        };
+
+        while reader.offset < 16 + fields_size {
+            reader.align(8);
+            let code = reader.byte()?;
+            let kind = reader.signature()?;
+            match kind.as_str() {
+                "s" | "o" => {
+                    let value = reader.string()?;
+                    if code == FIELD_ERROR_NAME {
+                        reply.error_name = Some(value);
+                    }
+                }
+                "g" => {
+                    let value = reader.signature()?;
+                    if code == FIELD_SIGNATURE {
+                        reply.signature = value;
+                    }
+                }
+                "u" => {
+                    let value = reader.uint32()?;
+                    if code == FIELD_REPLY_SERIAL {
+                        reply.reply_serial = Some(value);
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "System bus sent a header field of unexpected type {}",
+                        kind
+                    ))
+                }
+            }
+        }
+
+        Ok(reply)
+    }
+}
+
+/// Asks systemd to restart a unit over the system bus, returning the object
+/// path of the job that it queued
+pub fn restart_unit(unit: &str) -> Result<String, String> {
+    let mut bus = Bus::connect()?;
+    let job = bus.call(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "RestartUnit",
+        &[unit, "replace"],
+    )?;
+
+    Ok(job.unwrap_or_default())
+}
@@ -0,0 +1,215 @@
+use crate::utils::ID3;
+use std::io;
+use std::io::prelude::*;
+
+/// The largest Ogg header packet that is read. Comment headers holding cover
+/// art can be large, but anything past this is more likely a broken file.
+const MAX_OGG_PACKET_BYTES: usize = 16 * 1024 * 1024;
+
+/// Reads the tags of an audio file, whichever format it is in. FLAC and Ogg
+/// files are recognized by their first bytes and have their Vorbis comments
+/// read, and anything else is expected to have ID3 tags.
+pub fn read_tags<T: Read + Seek>(stream: &mut T) -> Result<ID3, String> {
+    let mut magic = [0; 4];
+    let has_magic = match stream.read_exact(&mut magic) {
+        Ok(()) => true,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(format!("IO error: {}", err)),
+    };
+
+    match &magic {
+        b"fLaC" if has_magic => read_flac(stream),
+        b"OggS" if has_magic => {
+            stream
+                .seek(io::SeekFrom::Start(0))
+                .map_err(|err| format!("IO error: {}", err))?;
+            read_ogg(stream)
+        }
+        _ => ID3::from_stream(stream).map_err(String::from),
+    }
+}
+
+/// Reads a 32-bit little-endian integer out of the data at the offset
+fn read_le32(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/*
+ * Vorbis comments are the same in every format that uses them, apart from the
+ * header in front of them. All lengths are 32-bit little-endian.
+ *
+ * Size Description
+ * ---- ------------------------------------------------------------------------
+ *    4 The length of the vendor string
+ *    N The vendor string
+ *    4 The number of comments
+ *
+ * Followed by each of the comments:
+ *
+ *    4 The length of the comment
+ *    N The comment, as UTF-8 in the form NAME=value. Names aren't case-sensitive.
+ */
+
+/// Reads the comments out of a Vorbis comment block, with their names in
+/// upper case
+fn parse_vorbis_comments(data: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let truncated = || "Vorbis comments are truncated".to_string();
+    let vendor_length = read_le32(data, 0).ok_or_else(truncated)?;
+    let mut offset = 4 + vendor_length;
+    let count = read_le32(data, offset).ok_or_else(truncated)?;
+    offset += 4;
+
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let length = read_le32(data, offset).ok_or_else(truncated)?;
+        offset += 4;
+
+        let comment = data.get(offset..offset + length).ok_or_else(truncated)?;
+        offset += length;
+
+        if let Some((name, value)) = String::from_utf8_lossy(comment).split_once('=') {
+            comments.push((name.to_ascii_uppercase(), value.to_string()));
+        }
+    }
+
+    Ok(comments)
+}
+
+/*
+ * FLAC files start with "fLaC" and a series of metadata blocks, each with a
+ * 4 byte header:
+ *
+ * Size Description
+ * ---- ------------------------------------------------------------------------
+ *    1 0x80 if this is the last block, or'd with the type of the block (0 is
+ *      STREAMINFO and 4 is VORBIS_COMMENT)
+ *    3 The length of the block, big-endian
+ *
+ * STREAMINFO always comes first. Its sample rate is the 20 bits starting at
+ * byte 10, and the number of samples is the 36 bits starting in the low half
+ * of byte 13.
+ */
+
+/// Reads the Vorbis comments and the length of a FLAC file, just after the
+/// "fLaC" at the start
+fn read_flac<T: Read + Seek>(stream: &mut T) -> Result<ID3, String> {
+    let io_error = |err: io::Error| format!("IO error: {}", err);
+    let mut comments = Vec::new();
+    let mut length = None;
+
+    loop {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).map_err(io_error)?;
+
+        let last = header[0] & 0x80 != 0;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        match header[0] & 0x7f {
+            0 | 4 => {
+                let mut block = vec![0; size];
+                stream.read_exact(&mut block).map_err(io_error)?;
+
+                if header[0] & 0x7f == 4 {
+                    comments = parse_vorbis_comments(&block)?;
+                } else if block.len() >= 18 {
+                    let rate = ((block[10] as u64) << 12)
+                        | ((block[11] as u64) << 4)
+                        | ((block[12] as u64) >> 4);
+                    let samples = ((block[13] as u64 & 0x0f) << 32)
+                        | u32::from_be_bytes([block[14], block[15], block[16], block[17]]) as u64;
+
+                    // Encoders that don't know the length up front leave it 0
+                    if rate > 0 && samples > 0 {
+                        length = Some((samples / rate) as u32);
+                    }
+                }
+            }
+            _ => {
+                stream
+                    .seek(io::SeekFrom::Current(size as i64))
+                    .map_err(io_error)?;
+            }
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(ID3::from_comments(&comments, length))
+}
+
+/*
+ * Ogg files are a series of pages, each with a 27 byte header:
+ *
+ * Size Offset Description
+ * ---- ------ -------------------------------------------------------------------------------------------
+ *    4      0 Must be the ASCII text "OggS"
+ *   10      4 The version, flags and granule position, which aren't needed here
+ *    4     14 The serial number of the stream the page belongs to
+ *    8     18 The page sequence number and checksum
+ *    1     26 The number of segments on the page
+ *
+ * Followed by a byte giving the length of each segment, and then the segments.
+ * Packets are split into segments of 255 bytes, so a segment shorter than that
+ * ends a packet, which can carry on across several pages. The first packet of
+ * a stream identifies the codec and the second holds its comments, after
+ * "\x03vorbis" for Vorbis or "OpusTags" for Opus.
+ */
+
+/// Reads the first two packets of the first stream in an Ogg file
+fn read_ogg_headers<T: Read>(stream: &mut T) -> Result<Vec<Vec<u8>>, String> {
+    let io_error = |err: io::Error| format!("IO error: {}", err);
+    let mut serial = None;
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+
+    while packets.len() < 2 {
+        let mut header = [0; 27];
+        stream.read_exact(&mut header).map_err(io_error)?;
+        if &header[..4] != b"OggS" {
+            return Err("Ogg page is missing its 'OggS' magic".to_string());
+        }
+
+        let mut lacing = vec![0; header[26] as usize];
+        stream.read_exact(&mut lacing).map_err(io_error)?;
+
+        // Pages from other streams in the same file are skipped over
+        let page_serial = read_le32(&header, 14);
+        let wanted = *serial.get_or_insert(page_serial) == page_serial;
+
+        for segment_length in lacing {
+            let mut segment = vec![0; segment_length as usize];
+            stream.read_exact(&mut segment).map_err(io_error)?;
+            if !wanted || packets.len() >= 2 {
+                continue;
+            }
+
+            packet.extend_from_slice(&segment);
+            if packet.len() > MAX_OGG_PACKET_BYTES {
+                return Err("Ogg header packet is too large".to_string());
+            }
+
+            if segment_length < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+
+    Ok(packets)
+}
+
+/// Reads the Vorbis comments of an Ogg Vorbis or Opus file
+fn read_ogg<T: Read>(stream: &mut T) -> Result<ID3, String> {
+    let packets = read_ogg_headers(stream)?;
+    let comments = if packets[0].starts_with(b"\x01vorbis") && packets[1].starts_with(b"\x03vorbis")
+    {
+        &packets[1][7..]
+    } else if packets[0].starts_with(b"OpusHead") && packets[1].starts_with(b"OpusTags") {
+        &packets[1][8..]
+    } else {
+        return Err("Ogg file is neither Vorbis nor Opus".to_string());
+    };
+
+    Ok(ID3::from_comments(&parse_vorbis_comments(comments)?, None))
+}
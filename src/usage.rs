@@ -0,0 +1,241 @@
+use crate::events::{Event, WatchdogEvent};
+use crate::storage;
+use chrono::{Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How often the stats file is rewritten with the counts so far
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The first line of the stats file, naming the columns
+const HEADER: &str = "date,tracks_served,breaks,reloads,starts,stream_restarts,uptime_sec";
+
+/// What happened on one day, as a row of the stats file
+#[derive(Debug, PartialEq, Clone)]
+struct DayStats {
+    date: NaiveDate,
+
+    /// Songs returned by next-track, including requests
+    tracks_served: u64,
+
+    /// Special entries returned by next-track
+    breaks: u64,
+
+    reloads: u64,
+
+    /// Times that shuffled itself was started
+    starts: u64,
+
+    /// Times the watchdog restarted the stream
+    stream_restarts: u64,
+
+    /// How long shuffled was running, in seconds
+    uptime: u64,
+}
+
+impl DayStats {
+    fn new(date: NaiveDate) -> Self {
+        DayStats {
+            date,
+            tracks_served: 0,
+            breaks: 0,
+            reloads: 0,
+            starts: 0,
+            stream_restarts: 0,
+            uptime: 0,
+        }
+    }
+
+    /// Reads a row of the stats file, with the columns in the order of the
+    /// header
+    fn parse(line: &str) -> Option<Self> {
+        let mut columns = line.split(',');
+        let date = NaiveDate::parse_from_str(columns.next()?, "%Y-%m-%d").ok()?;
+        let mut counts = [0; 6];
+        for count in counts.iter_mut() {
+            *count = columns.next()?.trim().parse().ok()?;
+        }
+
+        if columns.next().is_some() {
+            return None;
+        }
+
+        Some(DayStats {
+            date,
+            tracks_served: counts[0],
+            breaks: counts[1],
+            reloads: counts[2],
+            starts: counts[3],
+            stream_restarts: counts[4],
+            uptime: counts[5],
+        })
+    }
+
+    fn row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{}",
+            self.date.format("%Y-%m-%d"),
+            self.tracks_served,
+            self.breaks,
+            self.reloads,
+            self.starts,
+            self.stream_restarts,
+            self.uptime
+        )
+    }
+}
+
+/// The rows of the stats file, with the current day's last
+struct UsageStats {
+    path: PathBuf,
+    days: Vec<DayStats>,
+
+    /// When the uptime was last counted up to
+    counted_until: NaiveDateTime,
+}
+
+impl UsageStats {
+    /// Reads the rows that are already in the stats file, if there is one,
+    /// and counts this start of shuffled
+    fn load(path: PathBuf, now: NaiveDateTime) -> Result<Self, String> {
+        let days = match fs::read_to_string(&path) {
+            Ok(text) => {
+                let mut days = Vec::new();
+                for (number, line) in text.lines().enumerate() {
+                    if line.trim().is_empty() || (number == 0 && line.starts_with("date")) {
+                        continue;
+                    }
+
+                    match DayStats::parse(line) {
+                        Some(day) => days.push(day),
+                        None => {
+                            return Err(format!(
+                                "Could not read line {} of the stats file {}, leaving it alone",
+                                number + 1,
+                                path.display()
+                            ))
+                        }
+                    }
+                }
+
+                days
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => {
+                return Err(format!(
+                    "Could not read stats file {}: {}",
+                    path.display(),
+                    error
+                ))
+            }
+        };
+
+        let mut stats = UsageStats {
+            path,
+            days,
+            counted_until: now,
+        };
+
+        stats.day(now.date()).starts += 1;
+        Ok(stats)
+    }
+
+    /// The row for a day, which is added if the last row is for an earlier one
+    fn day(&mut self, date: NaiveDate) -> &mut DayStats {
+        if self.days.last().map(|day| day.date) != Some(date) {
+            self.days.push(DayStats::new(date));
+        }
+
+        self.days.last_mut().unwrap()
+    }
+
+    /// Adds the time since the uptime was last counted, splitting it between
+    /// the days it falls on. Only whole seconds are counted, and the rest is
+    /// left for the next time.
+    fn count_uptime(&mut self, now: NaiveDateTime) {
+        if now < self.counted_until {
+            // The clock was set back, so there's no telling how long it's been
+            self.counted_until = now;
+            return;
+        }
+
+        while self.counted_until.date() < now.date() {
+            let midnight = self.counted_until.date().succ().and_hms(0, 0, 0);
+            let seconds = (midnight - self.counted_until).num_seconds() as u64;
+            self.day(self.counted_until.date()).uptime += seconds;
+            self.counted_until = midnight;
+        }
+
+        let seconds = (now - self.counted_until).num_seconds();
+        self.day(now.date()).uptime += seconds as u64;
+        self.counted_until += ChronoDuration::seconds(seconds);
+    }
+
+    /// Counts whatever an event says happened, if it is something that is
+    /// summarized
+    fn record_event(&mut self, event: &Event, now: NaiveDateTime) {
+        let day = self.day(now.date());
+        match event {
+            Event::TrackServed { .. } => day.tracks_served += 1,
+            Event::SpecialServed { .. } => day.breaks += 1,
+            Event::PlaylistsReloaded => day.reloads += 1,
+            Event::Watchdog(WatchdogEvent::Restarted) => day.stream_restarts += 1,
+            _ => (),
+        }
+    }
+
+    fn save(&self) {
+        let mut text = String::from(HEADER);
+        text.push('\n');
+        for day in self.days.iter() {
+            text.push_str(&day.row());
+            text.push('\n');
+        }
+
+        if let Err(error) = storage::write_atomic(&self.path, text.as_bytes()) {
+            warning!(
+                "usage",
+                "Could not write stats file {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+}
+
+/// Keeps a row of counts for each day in the stats file until the server
+/// shuts down, rewriting it every minute and on the way out
+pub fn usage_worker(path: PathBuf, events: Receiver<Event>) {
+    let mut stats = match UsageStats::load(path, Local::now().naive_local()) {
+        Ok(stats) => stats,
+        Err(error) => {
+            error!("usage", "{}", error);
+            return;
+        }
+    };
+
+    stats.save();
+    let mut last_save = Instant::now();
+    loop {
+        let event = events.recv_timeout(SAVE_INTERVAL.saturating_sub(last_save.elapsed()));
+        let now = Local::now().naive_local();
+        stats.count_uptime(now);
+
+        match event {
+            Ok(Event::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                stats.save();
+                return;
+            }
+            Ok(event) => stats.record_event(&event, now),
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+
+        if last_save.elapsed() >= SAVE_INTERVAL {
+            stats.save();
+            last_save = Instant::now();
+        }
+    }
+}
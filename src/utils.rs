@@ -1,12 +1,12 @@
-use random;
-use std::convert::TryInto;
+use crate::config::AnnouncerTools;
+use crate::loudness;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
-use std::time::SystemTime;
 
 /// Describes where the separates stages of the reader process should write
 /// their data to
@@ -18,6 +18,27 @@ pub struct FileOutputs<'a> {
     pub final_mp3: &'a Path,
 }
 
+/// Compares two tokens in a time that doesn't depend on how much of them
+/// matches, so that the token can't be guessed a byte at a time
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Builds the path of one of the files that the special processes write within
+/// the working directory, starting with the prefix if there is one
+pub fn special_file(working_dir: &Path, prefix: &str, name: &str) -> PathBuf {
+    if prefix.is_empty() {
+        working_dir.join(name)
+    } else {
+        working_dir.join(format!("{}-{}", prefix, name))
+    }
+}
+
 /// A list of all the common ID3 genres. Everything starting from Blues to
 /// HardRock is part of the ID3v1 specification while everything after HardRock
 /// is recognized by various versions of WinAmp. See the Mutagen documentation
@@ -652,7 +673,7 @@ pub enum ID3LoadError {
 impl From<ID3LoadError> for String {
     fn from(err: ID3LoadError) -> Self {
         match err {
-            ID3LoadError::NoID3Tag => "No ID3 'TAG' magic in file".to_string(),
+            ID3LoadError::NoID3Tag => "No ID3v1 or ID3v2 tag in file".to_string(),
             ID3LoadError::IOError(err) => format!("IO error: {}", err),
             ID3LoadError::DecodeError(err) => format!("Decoding error: {}", err),
             ID3LoadError::YearError(err, year) => {
@@ -672,6 +693,9 @@ pub struct ID3 {
     comment: String,
     track: Option<u8>,
     genre: ID3Genres,
+
+    /// How long the track plays for in seconds, for formats that record it
+    length: Option<u32>,
 }
 
 impl ID3 {
@@ -687,6 +711,37 @@ impl ID3 {
             comment: String::new(),
             track: None,
             genre: ID3Genres::Unknown,
+            length: None,
+        }
+    }
+
+    /// Builds the tags out of Vorbis comments, as used by Ogg and FLAC files.
+    /// The names of the comments are expected to be in upper case, and only
+    /// the first of any comment that appears more than once is used.
+    pub fn from_comments(comments: &[(String, String)], length: Option<u32>) -> Self {
+        let find = |names: &[&str]| {
+            comments
+                .iter()
+                .find(|(name, _)| names.contains(&name.as_str()))
+                .map(|(_, value)| value.trim())
+        };
+
+        ID3 {
+            title: find(&["TITLE"]).unwrap_or("").to_string(),
+            artist: find(&["ARTIST"]).unwrap_or("").to_string(),
+            album: find(&["ALBUM"]).unwrap_or("").to_string(),
+            year: find(&["DATE", "YEAR"])
+                .and_then(|date| date.get(..4))
+                .and_then(|year| year.parse().ok())
+                .unwrap_or(0),
+            comment: find(&["COMMENT", "DESCRIPTION"]).unwrap_or("").to_string(),
+            track: find(&["TRACKNUMBER"])
+                .and_then(|track| track.split('/').next())
+                .and_then(|track| track.trim().parse().ok()),
+            genre: find(&["GENRE"])
+                .map(parse_genre)
+                .unwrap_or(ID3Genres::Unknown),
+            length,
         }
     }
 
@@ -725,6 +780,198 @@ impl ID3 {
         self.genre
     }
 
+    /// Gets how long the track is in seconds, if that is known
+    pub fn length(&self) -> Option<u32> {
+        self.length
+    }
+
+    /// Generates a new ID3 struct from a stream, using the ID3v2 tag at the
+    /// start of the file and the ID3v1 tag at the end. Fields that are in both
+    /// are taken from the ID3v2 tag, since it isn't limited to 30 bytes.
+    pub fn from_stream<T: Read + Seek>(stream: &mut T) -> Result<Self, ID3LoadError> {
+        let v2 = ID3::from_v2_stream(stream)?;
+        let mut tag = match (v2, ID3::from_v1_stream(stream)) {
+            (None, v1) => return v1,
+            (Some(v2), Err(_)) => return Ok(v2),
+            (Some(v2), Ok(v1)) => (v2, v1),
+        };
+
+        let (v2, v1) = &mut tag;
+        if v2.title.is_empty() {
+            v2.title = mem::take(&mut v1.title);
+        }
+
+        if v2.artist.is_empty() {
+            v2.artist = mem::take(&mut v1.artist);
+        }
+
+        if v2.album.is_empty() {
+            v2.album = mem::take(&mut v1.album);
+        }
+
+        if v2.year == 0 {
+            v2.year = v1.year;
+        }
+
+        if v2.comment.is_empty() {
+            v2.comment = mem::take(&mut v1.comment);
+        }
+
+        if v2.track.is_none() {
+            v2.track = v1.track;
+        }
+
+        if v2.genre == ID3Genres::Unknown {
+            v2.genre = v1.genre;
+        }
+
+        Ok(tag.0)
+    }
+
+    /*
+     * ID3v2 comes at the start of the file, with a 10 byte header followed by
+     * a series of frames. All sizes are big-endian, and "syncsafe" sizes only
+     * use the low 7 bits of each byte.
+     *
+     * Size Offset Description
+     * ---- ------ -------------------------------------------------------------------------------------------
+     *   3       0 Must be the ASCII text "ID3"
+     *   1       3 The major version, 2, 3 or 4
+     *   1       4 The revision
+     *   1       5 Flags. 0x80 is unsynchronisation, 0x40 is an extended header (or compression in v2.2).
+     *   4       6 The syncsafe size of everything after the header
+     *
+     * Each frame in v2.3 and v2.4 has a 4 character ID, a 4 byte size (syncsafe in v2.4) and 2 bytes
+     * of flags, followed by its data. v2.2 frames have a 3 character ID and a 3 byte size instead,
+     * with no flags. Text frames start with a byte giving their encoding: 0 is ISO-8859-1, 1 is
+     * UTF-16 with a BOM, 2 is UTF-16BE and 3 is UTF-8.
+     */
+
+    /// Reads the ID3v2 tag from the start of a stream, if it has one. Frames
+    /// that can't be read, like compressed or encrypted ones, are skipped.
+    fn from_v2_stream<T: Read + Seek>(stream: &mut T) -> Result<Option<Self>, ID3LoadError> {
+        stream
+            .seek(io::SeekFrom::Start(0))
+            .map_err(ID3LoadError::IOError)?;
+
+        let mut header = [0; 10];
+        match stream.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(ID3LoadError::IOError(err)),
+        }
+
+        let version = header[3];
+        let flags = header[5];
+        if &header[..3] != b"ID3" || !(2..=4).contains(&version) {
+            return Ok(None);
+        }
+
+        // v2.2 never defined how its compression works
+        if version == 2 && flags & 0x40 != 0 {
+            return Ok(None);
+        }
+
+        // Files that end early still have whatever frames came before the end
+        let mut body = Vec::new();
+        stream
+            .by_ref()
+            .take(syncsafe(&header[6..10]) as u64)
+            .read_to_end(&mut body)
+            .map_err(ID3LoadError::IOError)?;
+
+        // v2.4 marks each frame as unsynchronised instead of the whole tag
+        if flags & 0x80 != 0 && version < 4 {
+            body = remove_unsync(&body);
+        }
+
+        let mut offset = 0;
+        if version >= 3 && flags & 0x40 != 0 && body.len() >= 4 {
+            offset = if version == 3 {
+                be_size(&body[..4]) + 4
+            } else {
+                syncsafe(&body[..4])
+            };
+        }
+
+        let (id_length, header_length) = if version == 2 { (3, 6) } else { (4, 10) };
+        let mut tag = ID3::new();
+        tag.year = 0;
+
+        while offset + header_length <= body.len() {
+            let frame = &body[offset..offset + header_length];
+
+            // Padding fills out the rest of the tag
+            if frame[0] == 0 {
+                break;
+            }
+
+            let size = match version {
+                2 => be_size(&frame[3..6]),
+                3 => be_size(&frame[4..8]),
+                _ => syncsafe(&frame[4..8]),
+            };
+
+            let start = offset + header_length;
+            let end = start.saturating_add(size);
+            if end > body.len() {
+                break;
+            }
+
+            offset = end;
+            let format = if version >= 3 { frame[9] } else { 0 };
+            let (skipped, extra, unsynced) = match version {
+                2 => (false, 0, false),
+                3 => (
+                    format & 0xc0 != 0,
+                    if format & 0x20 != 0 { 1 } else { 0 },
+                    false,
+                ),
+                _ => (
+                    format & 0x0c != 0,
+                    (format & 0x40 != 0) as usize + (format & 0x01 != 0) as usize * 4,
+                    format & 0x02 != 0 || flags & 0x80 != 0,
+                ),
+            };
+
+            if skipped || extra > size {
+                continue;
+            }
+
+            let data = if unsynced {
+                remove_unsync(&body[start + extra..end])
+            } else {
+                body[start + extra..end].to_vec()
+            };
+
+            let text = match decode_v2_text(&data) {
+                Some(text) => text,
+                None => continue,
+            };
+
+            match &frame[..id_length] {
+                b"TIT2" | b"TT2" => tag.title = text,
+                b"TPE1" | b"TP1" => tag.artist = text,
+                b"TALB" | b"TAL" => tag.album = text,
+                b"TYER" | b"TYE" | b"TDRC" => {
+                    if let Some(year) = text.get(..4).and_then(|year| year.parse().ok()) {
+                        tag.year = year;
+                    }
+                }
+                b"TRCK" | b"TRK" => {
+                    tag.track = text
+                        .split('/')
+                        .next()
+                        .and_then(|track| track.trim().parse().ok());
+                }
+                b"TCON" | b"TCO" => tag.genre = parse_genre(&text),
+                _ => (),
+            }
+        }
+
+        Ok(Some(tag))
+    }
+
     /*
      * ID3 has a basic format with a few fixed-width fields. The whole structure
      * adds up to 128 bytes, with all free-form text being padded out to its specified
@@ -743,8 +990,8 @@ impl ID3 {
      *   1     127 The genre number
      */
 
-    /// Generates a new ID3 struct from a stream
-    pub fn from_stream<T: Read + Seek>(stream: &mut T) -> Result<Self, ID3LoadError> {
+    /// Reads the ID3v1 tag from the end of a stream
+    fn from_v1_stream<T: Read + Seek>(stream: &mut T) -> Result<Self, ID3LoadError> {
         if let Err(err) = stream.seek(io::SeekFrom::End(-128)) {
             return Err(ID3LoadError::IOError(err));
         }
@@ -839,6 +1086,7 @@ impl ID3 {
                     Some(track_number)
                 },
                 genre: genre_number.into(),
+                length: None,
             }),
         }
     }
@@ -887,36 +1135,159 @@ fn pad_bytes(value: &str, length: usize) -> Vec<u8> {
     buffer
 }
 
-/// Reads a text announcement and outputs an ID3-tagged MP3 file
+/// Decodes a syncsafe integer, which only uses the low 7 bits of each byte
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, byte| (size << 7) | (*byte & 0x7f) as usize)
+}
+
+/// Decodes a plain big-endian integer
+fn be_size(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, byte| (size << 8) | *byte as usize)
+}
+
+/// Undoes ID3v2 unsynchronisation, which puts a zero byte after every 0xFF so
+/// that players don't mistake the tag for the start of an MP3 frame
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut after_ff = false;
+    for byte in data {
+        if !(after_ff && *byte == 0) {
+            output.push(*byte);
+        }
+
+        after_ff = *byte == 0xff;
+    }
+
+    output
+}
+
+/// Decodes the text of an ID3v2 text frame, up to the first NUL. v2.4 puts
+/// NULs between several values, in which case only the first one is used.
+fn decode_v2_text(data: &[u8]) -> Option<String> {
+    let (encoding, text) = data.split_first()?;
+    let text = match encoding {
+        0 => text
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as char)
+            .collect(),
+
+        1 | 2 => {
+            let (big_endian, text) = match text {
+                [0xfe, 0xff, rest @ ..] => (true, rest),
+                [0xff, 0xfe, rest @ ..] => (false, rest),
+                _ => (*encoding == 2, text),
+            };
+
+            let units = text
+                .chunks_exact(2)
+                .map(|pair| {
+                    if big_endian {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    }
+                })
+                .take_while(|unit| *unit != 0)
+                .collect::<Vec<_>>();
+
+            String::from_utf16_lossy(&units)
+        }
+
+        3 => {
+            let end = text
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(text.len());
+            String::from_utf8_lossy(&text[..end]).to_string()
+        }
+
+        _ => return None,
+    };
+
+    Some(text.trim().to_string())
+}
+
+/// Works out the genre from an ID3v2 content type or a Vorbis comment, which
+/// can be an ID3v1 genre number (in parentheses or not), the name of a genre,
+/// or both
+fn parse_genre(text: &str) -> ID3Genres {
+    let number = match text.strip_prefix('(') {
+        Some(rest) => rest.split(')').next().unwrap_or(""),
+        None => text,
+    };
+
+    if let Ok(number) = number.parse::<u8>() {
+        return number.into();
+    }
+
+    GENRE_TABLE
+        .iter()
+        .find(|genre| String::from(**genre).eq_ignore_ascii_case(text))
+        .cloned()
+        .unwrap_or(ID3Genres::Unknown)
+}
+
+/// Reads a text announcement with the given espeak voice and outputs an
+/// ID3-tagged MP3 file
 pub fn read_text_announcement(
     announcement: &str,
     outputs: &FileOutputs,
     title: &str,
+    voice: &str,
+    tools: &AnnouncerTools,
 ) -> Result<(), String> {
-    Command::new("/usr/bin/espeak")
+    Command::new(&tools.espeak)
+        .arg("-v")
+        .arg(voice)
         .arg("-g")
         .arg("15")
         .arg("-w")
         .arg(outputs.mono_wav)
         .arg(announcement)
         .output()
-        .or_else(|err| Err(format!("Could not invoke espeak: {}", err)))?;
+        .map_err(|err| format!("Could not invoke espeak: {}", err))?;
 
-    Command::new("/usr/bin/sox")
+    // The gain is applied while converting the format, with sox's limiter
+    // keeping any peaks that it raises from clipping
+    let mut gain = Vec::new();
+    if let Some(target) = tools.loudness_lufs {
+        match loudness::gain_to_target(outputs.mono_wav, target, tools.channels) {
+            Ok(db) => gain = vec!["gain".to_string(), "-l".to_string(), format!("{:.2}", db)],
+            Err(err) => warning!("service", "Could not match loudness of {}: {}", title, err),
+        }
+    }
+
+    Command::new(&tools.sox)
         .arg(outputs.mono_wav)
         .arg("-r")
-        .arg("44.1k")
+        .arg(tools.sample_rate.to_string())
         .arg("-c")
-        .arg("2")
+        .arg(tools.channels.to_string())
         .arg(outputs.stereo_wav)
+        .args(&gain)
         .output()
-        .or_else(|err| Err(format!("Could not invoke sox: {}", err)))?;
+        .map_err(|err| format!("Could not invoke sox: {}", err))?;
 
-    Command::new("/usr/bin/lame")
+    // lame lowers the sample rate on its own for low bitrates, unless it is
+    // told which one to use
+    let mut bitrate = Vec::new();
+    if let Some(kbps) = tools.bitrate {
+        bitrate = vec!["-b".to_string(), kbps.to_string()];
+    }
+
+    Command::new(&tools.lame)
+        .args(&bitrate)
+        .arg("--resample")
+        .arg((tools.sample_rate as f64 / 1000.0).to_string())
         .arg(outputs.stereo_wav)
         .arg(outputs.lame_mp3)
         .output()
-        .or_else(|err| Err(format!("Could not invoke lame: {}", err)))?;
+        .map_err(|err| format!("Could not invoke lame: {}", err))?;
 
     // ID3v1.1 header
     let mut mp3_options = fs::OpenOptions::new();
@@ -941,25 +1312,3 @@ pub fn read_text_announcement(
                                    outputs.final_mp3.display(),
                                    err)))
 }
-
-/// Creates a new RNG seeded either from /dev/urandom or the system time
-pub fn seeded_random() -> random::Default {
-    let (upper_seed, lower_seed) = fs::File::open("/dev/urandom")
-        .map(|mut urandom| {
-            let mut buffer = [0; 16];
-            if let Ok(16) = urandom.read(&mut buffer) {
-                let upper = u64::from_le_bytes(buffer[..8].try_into().unwrap());
-                let lower = u64::from_le_bytes(buffer[8..].try_into().unwrap());
-                (upper, lower)
-            } else if let Ok(duration) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                let upper = (duration.as_nanos() >> 64) as u64;
-                let lower = duration.as_nanos() as u64;
-                (upper, lower)
-            } else {
-                (12345, 67890)
-            }
-        })
-        .unwrap_or((12345, 67890));
-
-    random::default().seed([upper_seed, lower_seed])
-}
@@ -0,0 +1,175 @@
+use crate::config::{VoteAction, VoteConfig};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Which way a listener voted on the current track
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Vote {
+    Skip,
+    Keep,
+}
+
+/// How many votes of each kind a track has gotten
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct Feedback {
+    pub skips: u32,
+    pub keeps: u32,
+}
+
+impl Feedback {
+    fn add(&mut self, vote: Vote) {
+        match vote {
+            Vote::Skip => self.skips += 1,
+            Vote::Keep => self.keeps += 1,
+        }
+    }
+
+    fn remove(&mut self, vote: Vote) {
+        match vote {
+            Vote::Skip => self.skips -= 1,
+            Vote::Keep => self.keeps -= 1,
+        }
+    }
+}
+
+/// What a vote did, so that it can be reported back to the listener
+#[derive(Debug, PartialEq)]
+pub struct VoteOutcome {
+    /// The track that was voted on
+    pub track: PathBuf,
+
+    /// The votes on the track while it has been playing this time
+    pub current: Feedback,
+
+    /// The votes on the track every time it has been played
+    pub total: Feedback,
+
+    /// The action that this vote triggered, if it pushed the track over the
+    /// threshold
+    pub action: Option<VoteAction>,
+}
+
+impl VoteAction {
+    /// The name of the action as it appears in responses
+    pub fn name(&self) -> &'static str {
+        match self {
+            VoteAction::Deprioritize => "deprioritize",
+            VoteAction::Blocklist => "blocklist",
+        }
+    }
+}
+
+/// Keeps the votes for the track that is currently playing, along with the
+/// totals for every track that has been voted on. Each listener gets one vote
+/// per track, but can change their mind while it is playing.
+#[derive(Debug, Default)]
+pub struct VoteTracker {
+    /// The music track that was returned by the last next-track, if the last
+    /// entry wasn't a special one
+    current: Option<PathBuf>,
+
+    /// How each listener voted on the current track
+    voters: HashMap<String, Vote>,
+
+    /// Whether the action has been taken on the current track already
+    acted: bool,
+
+    /// The votes on each track across all the times it has played
+    totals: HashMap<PathBuf, Feedback>,
+
+    /// The tracks which were voted off by listeners
+    blocklist: HashSet<PathBuf>,
+}
+
+impl VoteTracker {
+    pub fn new() -> Self {
+        VoteTracker::default()
+    }
+
+    /// Starts a new voting window for a track that just started playing, or
+    /// for a special entry (which can't be voted on) if there is no track
+    pub fn start_track(&mut self, track: Option<&Path>) {
+        if let Some(previous) = self.current.take() {
+            if !self.voters.is_empty() {
+                let feedback = self.current_feedback();
+                debug!(
+                    "votes",
+                    "{} got {} skip and {} keep votes",
+                    previous.display(),
+                    feedback.skips,
+                    feedback.keeps
+                );
+            }
+        }
+
+        self.current = track.map(Path::to_path_buf);
+        self.voters.clear();
+        self.acted = false;
+    }
+
+    /// Counts the votes on the current track
+    fn current_feedback(&self) -> Feedback {
+        let mut feedback = Feedback::default();
+        for vote in self.voters.values() {
+            feedback.add(*vote);
+        }
+
+        feedback
+    }
+
+    /// Records a listener's vote on the current track, replacing any vote
+    /// they made on it before. Returns None if there is no track to vote on.
+    pub fn vote(
+        &mut self,
+        config: &VoteConfig,
+        listener: String,
+        vote: Vote,
+    ) -> Option<VoteOutcome> {
+        let track = self.current.clone()?;
+        let total = self.totals.entry(track.clone()).or_default();
+        if let Some(previous) = self.voters.insert(listener, vote) {
+            total.remove(previous);
+        }
+
+        total.add(vote);
+        let total = *total;
+
+        let current = self.current_feedback();
+        let over_threshold = config
+            .skip_threshold
+            .map(|threshold| current.skips >= current.keeps + threshold)
+            .unwrap_or(false);
+
+        let action = if over_threshold && !self.acted {
+            self.acted = true;
+            info!(
+                "votes",
+                "{} was voted off with {} skip and {} keep votes, applying {}",
+                track.display(),
+                current.skips,
+                current.keeps,
+                config.action.name()
+            );
+
+            if config.action == VoteAction::Blocklist {
+                self.blocklist.insert(track.clone());
+            }
+
+            Some(config.action)
+        } else {
+            None
+        };
+
+        Some(VoteOutcome {
+            track,
+            current,
+            total,
+            action,
+        })
+    }
+
+    /// Checks whether listeners have voted the track off
+    pub fn is_blocked(&self, track: &Path) -> bool {
+        self.blocklist.contains(track)
+    }
+}
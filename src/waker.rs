@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Why a worker stopped waiting
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Wakeup {
+    /// The whole timeout elapsed
+    Timeout,
+
+    /// Someone asked the worker to do its work right away
+    Now,
+
+    /// The process is shutting down and the worker should return
+    Shutdown,
+}
+
+#[derive(Debug, Default)]
+struct WakeState {
+    now: bool,
+    shutdown: bool,
+}
+
+/// Lets a worker wait between runs in a way that can be interrupted by other
+/// threads. Clones share the same state, so one can be kept by the worker and
+/// another given to whoever needs to wake it up.
+#[derive(Debug, Clone, Default)]
+pub struct Waker {
+    state: Arc<(Mutex<WakeState>, Condvar)>,
+}
+
+impl Waker {
+    pub fn new() -> Self {
+        Waker::default()
+    }
+
+    /// Interrupts the current wait (or the next one, if the worker is busy)
+    /// so that the worker runs immediately
+    pub fn wake(&self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().now = true;
+        condvar.notify_all();
+    }
+
+    /// Interrupts the current wait and every later one, so that the worker
+    /// stops
+    pub fn shutdown(&self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().shutdown = true;
+        condvar.notify_all();
+    }
+
+    /// Waits until the timeout elapses or the worker is woken up, whichever
+    /// comes first
+    pub fn wait(&self, timeout: Duration) -> Wakeup {
+        let (lock, condvar) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut state = lock.lock().unwrap();
+
+        loop {
+            if state.shutdown {
+                return Wakeup::Shutdown;
+            }
+
+            if state.now {
+                state.now = false;
+                return Wakeup::Now;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Wakeup::Timeout;
+            }
+
+            state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// Marks a worker as running for as long as its thread holds onto it. The
+/// mark is cleared when it's dropped, which also happens while a panicking
+/// thread unwinds.
+#[derive(Debug)]
+pub struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The wakers for each worker that is running, so that the server can pass
+/// requests on to them, along with whether each worker's thread is still
+/// going
+#[derive(Debug, Default)]
+pub struct Workers {
+    pub watchdog: Option<Waker>,
+    pub weather: Option<Waker>,
+    pub monitor: Option<Waker>,
+    threads: Vec<(&'static str, Arc<AtomicBool>)>,
+}
+
+impl Workers {
+    /// Finds the waker for a worker by the name of its task
+    pub fn find(&self, name: &str) -> Option<&Waker> {
+        match name {
+            "watchdog" => self.watchdog.as_ref(),
+            "weather" => self.weather.as_ref(),
+            "monitor" => self.monitor.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Adds a worker to the ones that status reports on. The worker's thread
+    /// has to keep what this returns until it finishes.
+    pub fn track(&mut self, name: &'static str) -> Running {
+        let running = Arc::new(AtomicBool::new(true));
+        self.threads.push((name, running.clone()));
+        Running(running)
+    }
+
+    /// Lists every worker that has been started along with whether its
+    /// thread is still running. Since the workers only return once the server
+    /// shuts down, one that has stopped before then has failed.
+    pub fn health(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        self.threads
+            .iter()
+            .map(|(name, running)| (*name, running.load(Ordering::SeqCst)))
+    }
+
+    /// Tells every worker to stop
+    pub fn shutdown(&self) {
+        let wakers = self
+            .watchdog
+            .iter()
+            .chain(self.weather.iter())
+            .chain(self.monitor.iter());
+
+        for waker in wakers {
+            waker.shutdown();
+        }
+    }
+}
@@ -1,145 +1,692 @@
-use crate::config::WatchdogConfig;
-use std::io::{Read, Write};
+use crate::alerts::Alerter;
+use crate::config::{WatchdogAction, WatchdogConfig};
+use crate::events::{Event, EventBus, WatchdogEvent};
+#[cfg(feature = "systemd-dbus")]
+use crate::systemd;
+use crate::waker::{Waker, Wakeup};
+use shuffled::rng::{Rng, Xoshiro256};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+#[cfg(not(feature = "systemd-dbus"))]
 use std::process::Command;
 use std::str;
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
 
-/// Try to connect to the Icecast server and issue an HTTP request. Any
-/// condition that prevents retrieving audio data (socket-level or bad HTTP
-/// response) returns an Err.
-fn probe_icecast(addr: &net::SocketAddr, path: &str, timeout_sec: u32) -> Result<(), ()> {
-    let timeout = Duration::from_secs(timeout_sec as u64);
+/// The most redirects that the probe will follow before giving up
+const MAX_REDIRECTS: usize = 5;
 
-    let mut sock = net::TcpStream::connect_timeout(&addr, timeout).or_else(|error| {
-        eprintln!("[watchdog] Could not connect to {}: {}", addr, error);
-        Err(())
-    })?;
+/// The largest response header that the probe is willing to read
+const MAX_HEADER_SIZE: usize = 8192;
 
-    if let Err(error) = sock.set_read_timeout(Some(timeout)) {
-        eprintln!(
-            "[watchdog] Warning, could not set socket timeout: {}",
-            error
-        );
+/// What the probe learned about the stream from a successful request
+#[derive(Debug)]
+pub struct ProbeReport {
+    /// How long it took to connect to the server that served the stream
+    pub connect_time: Duration,
+
+    /// The status line of the response that served the stream
+    pub status_line: String,
+
+    /// The URLs of each redirect that was followed, in order
+    pub redirects: Vec<String>,
+
+    /// How many bytes of audio were read after the response header
+    pub bytes_read: usize,
+}
+
+/// The status and headers of an HTTP (or ICY) response, along with whatever
+/// part of the body was read along with them
+struct ResponseHead {
+    status_line: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl ResponseHead {
+    /// Gets the value of the first header with the given name, ignoring case
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends a GET request for the URL over the socket, and reads the response up
+/// through the end of the header
+fn request_head(sock: &mut net::TcpStream, url: &Url) -> Result<ResponseHead, ()> {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => {
+            warning!("watchdog", "Cannot probe {}, it has no host", url);
+            return Err(());
+        }
     };
 
-    if let Err(error) = sock.set_write_timeout(Some(timeout)) {
-        eprintln!(
-            "[watchdog] Warning, could not set socket timeout: {}",
-            error
-        );
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
     };
 
-    let request = format!("GET {} HTTP/1.0\r\nUser-Agent: shuffled/0.1\r\n\r\n", path);
-    sock.write_all(request.as_bytes()).or_else(|error| {
-        eprintln!(
-            "[watchdog] Could not send HTTP request to {}@{}: {}",
-            path, addr, error
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: shuffled/0.1\r\nAccept: */*\r\nIcy-MetaData: 0\r\nConnection: close\r\n\r\n",
+        target, host
+    );
+
+    sock.write_all(request.as_bytes()).map_err(|error| {
+        warning!(
+            "watchdog",
+            "Could not send HTTP request to {}: {}",
+            url,
+            error
         );
-        Err(())
     })?;
 
-    let mut response = [0; 1024];
-    let mut offset = 0;
-    while offset < response.len() {
-        let consumed = sock.read(&mut response[offset..]).or_else(|error| {
-            eprintln!(
-                "[watchdog] Could not read HTTP response from {}@{}: {}",
-                path, addr, error
+    let mut response = Vec::new();
+    let mut buffer = [0; 1024];
+    let header_end = loop {
+        if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        if let Some(end) = response.windows(2).position(|w| w == b"\n\n") {
+            break end + 2;
+        }
+
+        if response.len() > MAX_HEADER_SIZE {
+            warning!("watchdog", "HTTP response header from {} is too large", url);
+            return Err(());
+        }
+
+        let consumed = sock.read(&mut buffer).map_err(|error| {
+            warning!(
+                "watchdog",
+                "Could not read HTTP response from {}: {}",
+                url,
+                error
             );
-            Err(())
         })?;
 
         if consumed == 0 {
-            eprintln!(
-                "[watchdog] Unexpected EOF when reading HTTP response from {}@{}",
-                path, addr
+            warning!(
+                "watchdog",
+                "Unexpected EOF when reading HTTP response from {}",
+                url
             );
             return Err(());
         }
 
-        let just_received = &response[offset..offset + consumed];
-        offset += consumed;
-        if let Some(_) = just_received.iter().position(|x| *x == 10) {
-            break;
-        }
-    }
+        response.extend_from_slice(&buffer[..consumed]);
+    };
 
-    let status_slice = &response[..offset];
-    let status_start = status_slice.iter().position(|x| *x == 32).ok_or_else(|| {
-        eprintln!(
-            "[watchdog] Could not find first space character in HTTP response to {}@{}",
-            path, addr
+    let head_text = str::from_utf8(&response[..header_end]).map_err(|error| {
+        warning!(
+            "watchdog",
+            "Could not decode HTTP response from {}: {}",
+            url,
+            error
         );
-        ()
     })?;
 
-    let status_end = status_slice[status_start + 1..]
-        .iter()
-        .position(|x| *x == 32)
+    let mut lines = head_text.lines();
+    let status_line = lines.next().unwrap_or("").trim_end().to_string();
+    let mut status_parts = status_line.split_whitespace();
+    let version = status_parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/") && version != "ICY" {
+        warning!(
+            "watchdog",
+            "Could not parse HTTP status line from {}: {}",
+            url,
+            status_line
+        );
+        return Err(());
+    }
+
+    let status = status_parts
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
         .ok_or_else(|| {
-            eprintln!(
-                "[watchdog] Could not find second space character in HTTP response to {}@{}",
-                path, addr
+            warning!(
+                "watchdog",
+                "Could not parse HTTP status from {}: {}",
+                url,
+                status_line
             );
-            ()
-        })?
-        + status_start
-        + 1;
-
-    let status = str::from_utf8(&status_slice[status_start + 1..status_end]).or_else(|error| {
-        eprintln!(
-            "[watchdog] Could not decode HTTP response from {}@{}: {}",
-            path, addr, error
-        );
-        Err(())
-    })?;
+        })?;
 
-    match u16::from_str_radix(status, 10) {
-        Ok(status) if status >= 200 && status < 300 => Ok(()),
-        Ok(status) => {
-            eprintln!(
-                "[watchdog] {}@{} returned HTTP status {}",
-                path, addr, status
+    let headers = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => {
+                    Some((key.trim().to_string(), value.trim().to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+
+    Ok(ResponseHead {
+        status_line,
+        status,
+        headers,
+        body: response[header_end..].to_vec(),
+    })
+}
+
+/// Reads from the socket until at least the given number of bytes of audio
+/// have been received, decoding the chunk headers if the response is chunked.
+/// Returns how many bytes of audio were read.
+fn read_audio_sample(
+    sock: &mut net::TcpStream,
+    head: &ResponseHead,
+    url: &Url,
+    min_bytes: usize,
+) -> Result<usize, ()> {
+    let chunked = head
+        .header("Transfer-Encoding")
+        .map(|encoding| encoding.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false);
+
+    // Only the part of a chunked body that hasn't been decoded yet is kept,
+    // so that a large minimum doesn't hold the whole sample in memory
+    let mut body = head.body.clone();
+    let mut audio_bytes = 0;
+    let mut chunk_left = 0;
+    let mut buffer = [0; 1024];
+    loop {
+        if chunked {
+            loop {
+                if chunk_left > 0 {
+                    let taken = chunk_left.min(body.len());
+                    body.drain(..taken);
+                    audio_bytes += taken;
+                    chunk_left -= taken;
+                    if chunk_left > 0 {
+                        break;
+                    }
+                }
+
+                let line_end = match body.iter().position(|x| *x == b'\n') {
+                    Some(line_end) => line_end,
+                    None => break,
+                };
+
+                let size_line = String::from_utf8_lossy(&body[..line_end]).to_string();
+                body.drain(..=line_end);
+
+                // The line break after each chunk's data reads as a blank line
+                let size_text = size_line.split(';').next().unwrap_or("").trim();
+                if size_text.is_empty() {
+                    continue;
+                }
+
+                match usize::from_str_radix(size_text, 16) {
+                    Ok(0) => {
+                        warning!(
+                            "watchdog",
+                            "Stream at {} ended after {} bytes of audio",
+                            url,
+                            audio_bytes
+                        );
+                        return Err(());
+                    }
+                    Ok(size) => chunk_left = size,
+                    Err(_) => {
+                        warning!(
+                            "watchdog",
+                            "Could not parse chunk size from {}: {}",
+                            url,
+                            size_text
+                        );
+                        return Err(());
+                    }
+                }
+            }
+        } else {
+            audio_bytes += body.len();
+            body.clear();
+        }
+
+        if audio_bytes >= min_bytes {
+            return Ok(audio_bytes);
+        }
+
+        let consumed = sock.read(&mut buffer).map_err(|error| {
+            warning!("watchdog", "Could not read audio from {}: {}", url, error);
+        })?;
+
+        if consumed == 0 {
+            warning!(
+                "watchdog",
+                "Stream at {} ended after {} bytes of audio",
+                url,
+                audio_bytes
+            );
+            return Err(());
+        }
+
+        body.extend_from_slice(&buffer[..consumed]);
+    }
+}
+
+/// Checks that the response is served as the configured media type, if there
+/// is one. Parameters after the media type, like the charset, are ignored.
+fn check_content_type(config: &WatchdogConfig, head: &ResponseHead, url: &Url) -> Result<(), ()> {
+    let expected = match config.content_type.as_ref() {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+
+    let media_type = head.header("Content-Type").map(|value| {
+        value
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase()
+    });
+
+    match media_type {
+        Some(media_type) if &media_type == expected => Ok(()),
+        Some(media_type) => {
+            warning!(
+                "watchdog",
+                "{} is served as {}, expected {}",
+                url,
+                media_type,
+                expected
             );
             Err(())
         }
-        Err(_) => {
-            eprintln!(
-                "[watchdog] Could not parse HTTP status from {}@{}: {}",
-                path, addr, status
+        None => {
+            warning!(
+                "watchdog",
+                "{} has no Content-Type, expected {}",
+                url,
+                expected
             );
             Err(())
         }
     }
 }
 
-/// Restarts the ezstream service via systemd
-fn restart_ezstream(service: &str) {
-    match Command::new("/bin/systemctl")
+/// Try to connect to the Icecast server and issue an HTTP request, following
+/// any redirects to the stream's real location. Any condition that prevents
+/// retrieving audio data (socket-level or bad HTTP response) returns an Err.
+pub fn probe_icecast(config: &WatchdogConfig, timeout_sec: u32) -> Result<ProbeReport, ()> {
+    let timeout = Duration::from_secs(timeout_sec as u64);
+    let mut url = config.url.clone();
+    let mut addr = config.addr;
+    let mut redirects = Vec::new();
+
+    loop {
+        let connect_start = Instant::now();
+        let mut sock = net::TcpStream::connect_timeout(&addr, timeout).map_err(|error| {
+            warning!("watchdog", "Could not connect to {}: {}", addr, error);
+        })?;
+        let connect_time = connect_start.elapsed();
+
+        if let Err(error) = sock.set_read_timeout(Some(timeout)) {
+            warning!("watchdog", "Could not set socket timeout: {}", error);
+        };
+
+        if let Err(error) = sock.set_write_timeout(Some(timeout)) {
+            warning!("watchdog", "Could not set socket timeout: {}", error);
+        };
+
+        let head = request_head(&mut sock, &url)?;
+        match head.status {
+            200..=299 => {
+                check_content_type(config, &head, &url)?;
+                let bytes_read = read_audio_sample(&mut sock, &head, &url, config.min_body_bytes)?;
+                return Ok(ProbeReport {
+                    connect_time,
+                    status_line: head.status_line,
+                    redirects,
+                    bytes_read,
+                });
+            }
+
+            300..=399 => {
+                if redirects.len() >= MAX_REDIRECTS {
+                    warning!(
+                        "watchdog",
+                        "Gave up on {} after {} redirects",
+                        config.url,
+                        MAX_REDIRECTS
+                    );
+                    return Err(());
+                }
+
+                let location = head.header("Location").ok_or_else(|| {
+                    warning!(
+                        "watchdog",
+                        "{} returned HTTP status {} without a Location",
+                        url,
+                        head.status
+                    );
+                })?;
+
+                let target = url.join(location).map_err(|error| {
+                    warning!(
+                        "watchdog",
+                        "{} redirected to invalid location {}: {}",
+                        url,
+                        location,
+                        error
+                    );
+                })?;
+
+                if target.scheme() != "http" {
+                    warning!(
+                        "watchdog",
+                        "{} redirected to {}, only HTTP is supported",
+                        url,
+                        target
+                    );
+                    return Err(());
+                }
+
+                addr = target
+                    .socket_addrs(|| Some(80))
+                    .ok()
+                    .and_then(|addrs| addrs.first().cloned())
+                    .ok_or_else(|| {
+                        warning!("watchdog", "Could not resolve redirect to {}", target);
+                    })?;
+
+                redirects.push(target.to_string());
+                url = target;
+            }
+
+            _ => {
+                warning!("watchdog", "{} returned {}", url, head.status_line);
+                return Err(());
+            }
+        }
+    }
+}
+
+/// Sends a status request to the server over its IPC socket, and checks that
+/// it responds within the timeout
+fn probe_server(socket: &Path, auth: Option<&str>, timeout_sec: u32) -> Result<(), String> {
+    let timeout = Duration::from_secs(timeout_sec as u64);
+    let mut sock = UnixStream::connect(socket)
+        .map_err(|error| format!("Could not connect to {}: {}", socket.display(), error))?;
+
+    sock.set_read_timeout(Some(timeout))
+        .and_then(|_| sock.set_write_timeout(Some(timeout)))
+        .map_err(|error| format!("Could not set socket timeout: {}", error))?;
+
+    let mut request = json::JsonValue::new_object();
+    request["command"] = "status".into();
+    if let Some(auth) = auth {
+        request["auth"] = auth.into();
+    }
+
+    sock.write_all(format!("{}\n", json::stringify(request)).as_bytes())
+        .map_err(|error| format!("Could not send status request: {}", error))?;
+
+    let mut response = String::new();
+    BufReader::new(sock)
+        .read_line(&mut response)
+        .map_err(|error| format!("No response to status request: {}", error))?;
+
+    match json::parse(&response) {
+        Ok(document) if document.has_key("daemon") => Ok(()),
+        _ => Err(format!(
+            "Unexpected response to status request: {}",
+            response.trim()
+        )),
+    }
+}
+
+/// Restarts the ezstream service by asking systemd over the system bus,
+/// returning the error from systemd if it refused
+#[cfg(feature = "systemd-dbus")]
+fn restart_ezstream(config: &WatchdogConfig) -> Result<(), String> {
+    let service = &config.service;
+    let job = systemd::restart_unit(service)?;
+    info!("watchdog", "Queued restart of {} as {}", service, job);
+    Ok(())
+}
+
+/// Restarts the ezstream service via systemd. If systemctl fails, the error
+/// includes its exit status and anything it printed.
+#[cfg(not(feature = "systemd-dbus"))]
+fn restart_ezstream(config: &WatchdogConfig) -> Result<(), String> {
+    let service = &config.service;
+    let output = Command::new(&config.systemctl)
         .arg("restart")
         .arg(service)
-        .spawn()
-    {
-        Ok(mut child) => {
-            if let Err(error) = child.wait() {
-                eprintln!("[watchdog] systemctl invocation failed: {}", error);
+        .output()
+        .map_err(|error| format!("systemctl invocation failed: {}", error))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().chain(stderr.lines()) {
+        debug!("watchdog", "systemctl: {}", line);
+    }
+
+    if output.status.success() {
+        info!("watchdog", "Restarted {}", service);
+        return Ok(());
+    }
+
+    let status = match output.status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => "was killed by a signal".to_string(),
+    };
+
+    // Alerts are a single line, so the output has its lines joined together
+    let mut error = format!("systemctl restart {} {}", service, status);
+    if !stdout.trim().is_empty() {
+        let lines: Vec<&str> = stdout.lines().collect();
+        error.push_str(&format!(", stdout: {}", lines.join("; ")));
+    }
+
+    if !stderr.trim().is_empty() {
+        let lines: Vec<&str> = stderr.lines().collect();
+        error.push_str(&format!(", stderr: {}", lines.join("; ")));
+    }
+
+    Err(error)
+}
+
+/// Checks whether the service was restarted less than the grace period ago,
+/// returning how many seconds ago it was if so
+fn check_restart_grace(grace_sec: u32, last_restart: &Mutex<Option<Instant>>) -> Option<u64> {
+    let elapsed = last_restart.lock().unwrap().as_ref()?.elapsed();
+    if elapsed < Duration::from_secs(grace_sec as u64) {
+        Some(elapsed.as_secs())
+    } else {
+        None
+    }
+}
+
+/// Checks how long it has been since the server last got a next-track request.
+/// Returns the number of minutes if it's been longer than the dead air limit.
+fn check_dead_air(limit_min: u32, last_request: &Mutex<SystemTime>) -> Option<u64> {
+    let last_request = *last_request.lock().unwrap();
+    let idle = SystemTime::now()
+        .duration_since(last_request)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+
+    if idle >= Duration::from_secs(limit_min as u64 * 60) {
+        Some(idle.as_secs() / 60)
+    } else {
+        None
+    }
+}
+
+/// Runs a single probe against Icecast and prints what it found, for checking
+/// the watchdog configuration by hand. Returns whether the probe succeeded.
+pub fn probe_once(config: &WatchdogConfig) -> bool {
+    println!("Probing {}", config.url);
+    match probe_icecast(config, 10) {
+        Ok(report) => {
+            for redirect in report.redirects.iter() {
+                println!("Redirected to {}", redirect);
             }
+
+            println!("Connected in {} ms", report.connect_time.as_millis());
+            println!("Status: {}", report.status_line);
+            println!("Read {} bytes of audio", report.bytes_read);
+            true
+        }
+        Err(_) => {
+            println!("Probe failed");
+            false
         }
-        _ => (),
     }
 }
 
+/// Works out how long to wait before the next probe. With an offset, the
+/// probes line up with the clock so that instances given different offsets
+/// never probe at the same time. Either way, a random delay of up to the
+/// jitter is added on top. All of it is worked out in milliseconds, so that
+/// the schedule still holds when debug.time_scale shrinks it below a second.
+fn next_probe_delay(config: &WatchdogConfig, rng: &mut impl Rng) -> Duration {
+    let scale = config.time_scale as u64;
+    let interval = (config.interval as u64 * 60 * 1000 / scale).max(1);
+    let base = match config.offset {
+        Some(offset) => {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            let offset = offset as u64 * 1000 / scale;
+            let phase = (now + interval - offset) % interval;
+            Duration::from_millis(interval - phase)
+        }
+        None => Duration::from_millis(interval),
+    };
+
+    let jitter = if config.jitter > 0 {
+        rng.below(config.jitter as u64 * 1000 / scale + 1)
+    } else {
+        0
+    };
+
+    base + Duration::from_millis(jitter)
+}
+
 /// Periodically performs a probe against Icecast and restarts the ezstream
-/// service as necessary
-pub fn watchdog_worker(config: WatchdogConfig) {
-    let interval = Duration::from_secs(config.interval as u64 * 60);
+/// service as necessary (or, in alert-only mode, raises an alert instead). The server's IPC socket can also be probed, to check
+/// that it isn't stuck on a long request. If the stream is up but nothing has
+/// requested a track from the server for too long, then ezstream is probably
+/// stuck and an alert is raised (only once until the requests start again).
+/// Changes in the health of the stream and the server are also published on
+/// the event bus.
+pub fn watchdog_worker(
+    config: WatchdogConfig,
+    alerter: Alerter,
+    last_request: Arc<Mutex<SystemTime>>,
+    last_restart: Arc<Mutex<Option<Instant>>>,
+    waker: Waker,
+    events: EventBus,
+) {
+    let mut rng = Xoshiro256::from_entropy();
+    let mut dead_air_reported = false;
+    let mut unresponsive_reported = false;
+    let mut stream_down_reported = false;
+    let mut stream_down = false;
 
     loop {
-        thread::sleep(interval);
-        if let Err(_) = probe_icecast(&config.addr, &config.path, 10) {
-            restart_ezstream(&config.service);
+        if waker.wait(next_probe_delay(&config, &mut rng)) == Wakeup::Shutdown {
+            return;
+        }
+
+        if let Some(socket) = config.self_check.as_ref() {
+            match probe_server(socket, config.auth_token.as_deref(), 10) {
+                Ok(()) if unresponsive_reported => {
+                    info!("watchdog", "Server is responding again");
+                    events.publish(Event::Watchdog(WatchdogEvent::ServerResponding));
+                    unresponsive_reported = false;
+                }
+                Err(error) if !unresponsive_reported => {
+                    alerter.raise(
+                        "watchdog",
+                        &format!("Server is not responding to IPC requests: {}", error),
+                    );
+                    events.publish(Event::Watchdog(WatchdogEvent::ServerUnresponsive));
+                    unresponsive_reported = true;
+                }
+                _ => (),
+            }
+        }
+
+        // ezstream takes a little while to reconnect after a restart, and
+        // probing it in the meantime would only restart it again
+        if let Some(restarted_sec) = check_restart_grace(config.restart_grace, &last_restart) {
+            info!(
+                "watchdog",
+                "Not probing, {} was restarted {} seconds ago", config.service, restarted_sec
+            );
+            continue;
+        }
+
+        if probe_icecast(&config, 10).is_err() {
+            if !stream_down {
+                events.publish(Event::Watchdog(WatchdogEvent::StreamDown));
+                stream_down = true;
+            }
+
+            match config.action {
+                WatchdogAction::Restart => match restart_ezstream(&config) {
+                    Ok(()) => {
+                        *last_restart.lock().unwrap() = Some(Instant::now());
+                        events.publish(Event::Watchdog(WatchdogEvent::Restarted));
+                    }
+                    Err(error) => alerter.raise(
+                        "watchdog",
+                        &format!("Could not restart {}: {}", config.service, error),
+                    ),
+                },
+                WatchdogAction::Alert if !stream_down_reported => {
+                    alerter.raise("watchdog", &format!("Stream at {} is down", config.url));
+                    stream_down_reported = true;
+                }
+                WatchdogAction::Alert => (),
+            }
+            continue;
+        }
+
+        if stream_down_reported {
+            info!("watchdog", "Stream at {} is up again", config.url);
+            stream_down_reported = false;
+        }
+
+        if stream_down {
+            events.publish(Event::Watchdog(WatchdogEvent::StreamUp));
+            stream_down = false;
+        }
+
+        let dead_air = config
+            .dead_air
+            .and_then(|limit| check_dead_air(limit, &last_request));
+
+        match dead_air {
+            Some(idle_min) if !dead_air_reported => {
+                alerter.raise(
+                    "watchdog",
+                    &format!(
+                        "Stream is up but no tracks have been requested in {} minutes, ezstream may be stuck",
+                        idle_min
+                    ),
+                );
+                events.publish(Event::Watchdog(WatchdogEvent::DeadAir(idle_min)));
+                dead_air_reported = true;
+            }
+            Some(_) => (),
+            None => dead_air_reported = false,
         }
     }
 }
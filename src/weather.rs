@@ -1,12 +1,15 @@
-use crate::config::SpecialWeatherConfig;
+use crate::config::{Locale, SpecialBaseConfig, SpecialWeatherConfig, StaleWeather};
+use crate::events::{Event, EventBus};
+use crate::i18n::{self, Phrase};
 use crate::utils;
-use chrono::{DateTime, Local, Timelike};
+use crate::waker::{Waker, Wakeup};
+use chrono::{DateTime, Datelike, Local, Timelike};
 use json::JsonValue;
 use reqwest::blocking::Client;
-use reqwest::header::{ACCEPT, USER_AGENT};
-use std::fmt::Write;
-use std::path::PathBuf;
-use std::thread;
+use reqwest::header::{ACCEPT, RETRY_AFTER};
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
 use std::time::Duration;
 
 /// A textual forecast that applies to a specific region of time
@@ -18,8 +21,14 @@ struct Forecast {
     /// The end of the time when the forecast applies
     end_time: DateTime<Local>,
 
+    /// What the forecast calls the period, like "Tonight" or "Thursday"
+    name: Option<String>,
+
     /// A textual description of the forecast
     description: String,
+
+    /// The temperature given for the forecast, if there was one
+    temperature: Option<i64>,
 }
 
 /// Utility functions used for coercing JSON values to their complex types
@@ -53,7 +62,10 @@ fn parse_forecast(obj: &json::object::Object) -> Result<Forecast, ()> {
         .get("detailedForecast")
         .and_then(|val| val.as_str())
         .ok_or_else(|| {
-            eprintln!("[weather] Could not read /properties/periods/*/detailedForecast");
+            warning!(
+                "weather",
+                "Could not read /properties/periods/*/detailedForecast"
+            );
             ()
         })?;
 
@@ -61,12 +73,12 @@ fn parse_forecast(obj: &json::object::Object) -> Result<Forecast, ()> {
         .get("startTime")
         .and_then(|val| val.as_str())
         .ok_or_else(|| {
-            eprintln!("[weather] Could not read /properties/periods/*/startTime");
+            warning!("weather", "Could not read /properties/periods/*/startTime");
             ()
         })
         .and_then(|txt| {
             DateTime::parse_from_str(txt, "%Y-%m-%dT%H:%M:%S%:z").or_else(|_| {
-                eprintln!("[weather] Could not parse /properties/periods/*/startTime");
+                warning!("weather", "Could not parse /properties/periods/*/startTime");
                 Err(())
             })
         })?;
@@ -75,73 +87,213 @@ fn parse_forecast(obj: &json::object::Object) -> Result<Forecast, ()> {
         .get("endTime")
         .and_then(|val| val.as_str())
         .ok_or_else(|| {
-            eprintln!("[weather] Could not read /properties/periods/*/endTime");
+            warning!("weather", "Could not read /properties/periods/*/endTime");
             ()
         })
         .and_then(|txt| {
             DateTime::parse_from_str(txt, "%Y-%m-%dT%H:%M:%S%:z").or_else(|_| {
-                eprintln!("[weather] Could not parse /properties/periods/*/endTime");
+                warning!("weather", "Could not parse /properties/periods/*/endTime");
                 Err(())
             })
         })?;
 
+    // Older versions of the API give the temperature as a bare number, newer
+    // ones wrap it in an object with its unit
+    let temperature = obj.get("temperature").and_then(|val| match val {
+        JsonValue::Object(quantity) => quantity.get("value").and_then(|v| v.as_f64()),
+        _ => val.as_f64(),
+    });
+
+    let name = obj
+        .get("name")
+        .and_then(|val| val.as_str())
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string);
+
     Ok(Forecast {
+        name,
         description: description.to_string(),
+        temperature: temperature.map(|t| t.round() as i64),
         start_time: start_time.with_timezone(&Local),
         end_time: end_time.with_timezone(&Local),
     })
 }
 
+/// Builds the URL of the forecast for a weather.gov grid ID
+fn forecast_url(region: &str) -> String {
+    format!("https://api.weather.gov/gridpoints/{}/forecast", region)
+}
+
+/// What weather.gov said about the configured region
+pub enum RegionCheck {
+    /// The API has a forecast for the region
+    Valid,
+
+    /// The API doesn't know the region, with the reason it gave
+    Invalid(String),
+
+    /// The API couldn't be asked, so the region may or may not be valid
+    Unknown(String),
+}
+
+/// Asks weather.gov for the region's forecast, to tell a grid ID that doesn't
+/// exist apart from the API being down before the worker starts retrying
+pub fn check_region(client: &Client, region: &str) -> RegionCheck {
+    let response = match client
+        .get(&forecast_url(region))
+        .header(ACCEPT, "application/geo+json")
+        .send()
+    {
+        Ok(response) => response,
+        Err(error) => return RegionCheck::Unknown(error.to_string()),
+    };
+
+    let status = response.status().as_u16();
+    if (200..300).contains(&status) {
+        return RegionCheck::Valid;
+    }
+
+    // Errors come back as a JSON problem report, whose detail is usually
+    // more helpful than the status code
+    let detail = response
+        .text()
+        .ok()
+        .and_then(|entity| json::parse(&entity).ok())
+        .and_then(|document| {
+            document["detail"]
+                .as_str()
+                .or_else(|| document["title"].as_str())
+                .map(str::to_string)
+        });
+
+    let reason = match detail {
+        Some(detail) => format!("{} (status {})", detail, status),
+        None => format!("status {}", status),
+    };
+
+    match status {
+        400 | 404 => RegionCheck::Invalid(reason),
+        _ => RegionCheck::Unknown(reason),
+    }
+}
+
+/// The range that a Retry-After header is held to, so that the API is neither
+/// asked again in a tight loop nor left alone for days if it sends something
+/// absurd
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(60);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long to wait after being rate limited when the API doesn't say
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Why a forecast couldn't be fetched
+#[derive(Debug, PartialEq)]
+enum FetchError {
+    /// The request failed or the response couldn't be read
+    Failed,
+
+    /// The API is rate limiting requests or temporarily unavailable, and
+    /// should be asked again after this long
+    RateLimited(Duration),
+}
+
+/// Reads a Retry-After header, which is either a number of seconds or the
+/// date to retry after
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let retry_at = DateTime::parse_from_rfc2822(value).ok()?;
+    let remaining = retry_at.with_timezone(&Local) - Local::now();
+    Some(remaining.to_std().unwrap_or_default())
+}
+
 /// Fetches the current forecast from the weather.gov API and unpacks the
 /// resulting JSON into a series of Forecast entries containing the forecast
-/// strings and the time slots they apply to
-fn fetch_forecasts(url: &str) -> Result<Vec<Forecast>, ()> {
-    let client = Client::new();
+/// strings and the time slots they apply to, along with when the forecast was
+/// issued if the API said
+fn fetch_forecasts(
+    client: &Client,
+    url: &str,
+) -> Result<(Vec<Forecast>, Option<DateTime<Local>>), FetchError> {
     let response = client
         .get(url)
         .header(ACCEPT, "application/geo+json")
-        .header(USER_AGENT, "shuffled Weather Fetcher")
         .send()
-        .or_else(|error| {
-            eprintln!("[weather] Could not fetch forecast: {}", error);
-            Err(())
+        .map_err(|error| {
+            warning!("weather", "Could not fetch forecast: {}", error);
+            FetchError::Failed
         })?;
 
     let status = response.status();
+    if status.as_u16() == 429 || status.as_u16() == 503 {
+        let delay = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after)
+            .unwrap_or(DEFAULT_RETRY_AFTER)
+            .clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER);
+
+        warning!(
+            "weather",
+            "API is rate limiting requests (status {}), retrying in {} seconds",
+            status.as_u16(),
+            delay.as_secs()
+        );
+        return Err(FetchError::RateLimited(delay));
+    }
+
     if !(200..300).contains(&status.as_u16()) {
-        eprintln!(
-            "[weather] API returned unexpected status code {}",
+        warning!(
+            "weather",
+            "API returned unexpected status code {}",
             status.as_u16()
         );
-        return Err(());
+        return Err(FetchError::Failed);
     }
 
-    let entity = response.text().or_else(|error| {
-        eprintln!("[weather] Could not decode API response: {}", error);
-        return Err(());
+    let entity = response.text().map_err(|error| {
+        warning!("weather", "Could not decode API response: {}", error);
+        FetchError::Failed
     })?;
 
-    let document = json::parse(&entity).or_else(|error| {
-        eprintln!("[weather] Could not parse API response: {}", error);
-        return Err(());
+    let document = json::parse(&entity).map_err(|error| {
+        warning!("weather", "Could not parse API response: {}", error);
+        FetchError::Failed
     })?;
 
-    let raw_periods = document
+    let properties = document
         .as_object()
         .and_then(|obj| obj.get("properties"))
         .and_then(|val| val.as_object())
-        .and_then(|obj| obj.get("periods"))
+        .ok_or_else(|| {
+            warning!("weather", "Could not read /properties");
+            FetchError::Failed
+        })?;
+
+    let issued = properties
+        .get("updateTime")
+        .and_then(|val| val.as_str())
+        .and_then(|txt| DateTime::parse_from_str(txt, "%Y-%m-%dT%H:%M:%S%:z").ok())
+        .map(|time| time.with_timezone(&Local));
+
+    let raw_periods = properties
+        .get("periods")
         .and_then(|val| val.as_array())
         .ok_or_else(|| {
-            eprintln!("[weather] Could not read /properties/periods");
-            ()
+            warning!("weather", "Could not read /properties/periods");
+            FetchError::Failed
         })?;
 
     let mut periods = raw_periods
         .iter()
         .map(|raw| {
             let obj = raw.as_object().ok_or_else(|| {
-                eprintln!("[weather] Could not read /properties/periods/*");
+                warning!("weather", "Could not read /properties/periods/*");
                 ()
             })?;
 
@@ -151,92 +303,392 @@ fn fetch_forecasts(url: &str) -> Result<Vec<Forecast>, ()> {
 
     for (i, period) in periods.iter().enumerate() {
         if period.is_err() {
-            eprintln!("[weather] Parsing error occurred in entry {}", i);
-            return Err(());
+            warning!("weather", "Parsing error occurred in entry {}", i);
+            return Err(FetchError::Failed);
         }
     }
 
-    Ok(periods
+    let periods = periods
         .drain(..)
         .map(|period| period.unwrap())
-        .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    Ok((periods, issued))
+}
+
+/// Abbreviations in the forecast text that espeak reads poorly, along with
+/// what they should be read as
+const DEFAULT_SUBSTITUTIONS: &[(&str, &str)] = &[
+    ("mph", "miles per hour"),
+    ("in.", "inches"),
+    ("N", "north"),
+    ("NNE", "north northeast"),
+    ("NE", "northeast"),
+    ("ENE", "east northeast"),
+    ("E", "east"),
+    ("ESE", "east southeast"),
+    ("SE", "southeast"),
+    ("SSE", "south southeast"),
+    ("S", "south"),
+    ("SSW", "south southwest"),
+    ("SW", "southwest"),
+    ("WSW", "west southwest"),
+    ("W", "west"),
+    ("WNW", "west northwest"),
+    ("NW", "northwest"),
+    ("NNW", "north northwest"),
+];
+
+/// Combines the built-in substitutions with the configured ones, which take
+/// priority. Longer words come first so that they match before their
+/// prefixes.
+fn build_substitutions(config: &SpecialWeatherConfig) -> Vec<(String, String)> {
+    let mut substitutions = DEFAULT_SUBSTITUTIONS
+        .iter()
+        .filter(|(word, _)| !config.substitutions.contains_key(*word))
+        .map(|(word, replacement)| (word.to_string(), replacement.to_string()))
+        .chain(config.substitutions.clone())
+        .collect::<Vec<_>>();
+
+    substitutions.sort_by_key(|(word, _)| Reverse(word.len()));
+    substitutions
+}
+
+/// Replaces each whole word in the text that has a substitution. A word is only
+/// replaced if it isn't part of a larger word on either side.
+fn substitute_words(text: &str, substitutions: &[(String, String)]) -> String {
+    let mut buffer = String::new();
+    let mut rest = text;
+    let mut at_boundary = true;
+
+    while let Some(next) = rest.chars().next() {
+        let found = if at_boundary {
+            substitutions.iter().find(|(word, _)| {
+                rest.starts_with(word.as_str())
+                    && (word.ends_with(|c: char| !c.is_alphanumeric())
+                        || !rest[word.len()..].starts_with(char::is_alphanumeric))
+            })
+        } else {
+            None
+        };
+
+        match found {
+            Some((word, replacement)) => {
+                buffer.push_str(replacement);
+                rest = &rest[word.len()..];
+                at_boundary = !word.ends_with(char::is_alphanumeric);
+            }
+            None => {
+                buffer.push(next);
+                rest = &rest[next.len_utf8()..];
+                at_boundary = !next.is_alphanumeric();
+            }
+        }
+    }
+
+    buffer
 }
 
-/// Generates a single weather string from a slice of a complete forecast.
+/// Finds the forecasts that start within the given time range, along with the
+/// one that is in effect at the start of it
+fn forecasts_in_range(
+    forecasts: &[Forecast],
+    start_time: DateTime<Local>,
+    end_time: DateTime<Local>,
+) -> Vec<&Forecast> {
+    forecasts
+        .iter()
+        .filter(|forecast| {
+            (forecast.start_time >= start_time && forecast.start_time < end_time)
+                || (forecast.start_time < start_time && forecast.end_time >= start_time)
+        })
+        .collect()
+}
+
+/// Generates a single weather string from a slice of a complete forecast. The
+/// high and low temperatures across the slice lead the report, if the
+/// forecasts had any. Each period is introduced by its name if it has one and
+/// names are wanted, and otherwise by the hour it starts, along with the date
+/// for periods on later days.
 fn generate_weather_string(
     forecasts: &Vec<Forecast>,
     start_time: DateTime<Local>,
     end_time: DateTime<Local>,
+    locale: Locale,
+    template: Option<&str>,
+    substitutions: &[(String, String)],
+    period_names: bool,
 ) -> String {
     let mut buffer = String::new();
+    let range_forecasts = forecasts_in_range(forecasts, start_time, end_time);
 
-    let range_forecasts = forecasts.iter().filter(|forecast| {
-        (forecast.start_time >= start_time && forecast.start_time < end_time)
-            || (forecast.start_time < start_time && forecast.end_time >= start_time)
-    });
+    let temperatures = range_forecasts
+        .iter()
+        .filter_map(|forecast| forecast.temperature);
+    let high = temperatures.clone().max();
+    let low = temperatures.min();
 
     for forecast in range_forecasts {
-        write!(
-            &mut buffer,
-            "At {:02}, {} ",
-            forecast.start_time.hour(),
-            &forecast.description
-        )
-        .unwrap();
+        let description = substitute_words(&forecast.description, substitutions);
+        let period = match forecast.name.as_ref().filter(|_| period_names) {
+            Some(name) => locale.phrase(
+                Phrase::WeatherNamedPeriod,
+                &[("name", name), ("forecast", &description)],
+            ),
+            None => {
+                let time = locale.time_words(forecast.start_time.hour(), 0);
+
+                // The hour alone would be ambiguous for periods on later days
+                if forecast.start_time.date() == start_time.date() {
+                    locale.phrase(
+                        Phrase::WeatherPeriod,
+                        &[("time", &time), ("forecast", &description)],
+                    )
+                } else {
+                    let month = forecast.start_time.month();
+                    let date = locale.date_words(month, forecast.start_time.day());
+                    locale.phrase(
+                        Phrase::WeatherLaterPeriod,
+                        &[("date", &date), ("time", &time), ("forecast", &description)],
+                    )
+                }
+            }
+        };
+        buffer.push_str(&period);
     }
 
-    buffer
+    let (high, low) = match (high, low) {
+        (Some(high), Some(low)) => (high.to_string(), low.to_string()),
+        _ => return buffer,
+    };
+
+    let args = [
+        ("high", high.as_str()),
+        ("low", low.as_str()),
+        ("periods", buffer.as_str()),
+    ];
+    match template {
+        Some(template) => i18n::fill(template, &args),
+        None => locale.phrase(Phrase::WeatherReport, &args),
+    }
+}
+
+/// How many words a minute espeak reads at its default speed
+const ESPEAK_WORDS_PER_MINUTE: f64 = 175.0;
+
+/// Estimates how many seconds espeak will take to read the text
+fn estimate_length_sec(text: &str) -> f64 {
+    text.split_whitespace().count() as f64 * 60.0 / ESPEAK_WORDS_PER_MINUTE
+}
+
+/// Works out the longest that the report can be in seconds, if it is limited
+fn length_budget(special: &SpecialBaseConfig, config: &SpecialWeatherConfig) -> Option<f64> {
+    let from_interval = config
+        .max_length_pct
+        .map(|percent| (special.interval as f64 * 60.0) * percent as f64 / 100.0);
+
+    match (
+        config.max_length.map(|seconds| seconds as f64),
+        from_interval,
+    ) {
+        (Some(seconds), Some(interval)) => Some(seconds.min(interval)),
+        (seconds, interval) => seconds.or(interval),
+    }
 }
 
-/// The path of the weather MP3 file within the special working directory
+/// The name of the weather MP3 file within the special working directory,
+/// after the prefix
 pub const WEATHER_MP3_FILE: &str = "weather-stereo.mp3";
 
-/// Perdiodically queries the Weather.gov API and produces an audio summary of
-/// the forecast which can be played in the stream
-pub fn weather_worker(working_dir: PathBuf, config: SpecialWeatherConfig) {
-    let url = format!(
-        "https://api.weather.gov/gridpoints/{}/forecast",
-        config.region
-    );
+/// Takes the report out of rotation once its forecast is too old, either by
+/// removing it or by replacing it with an announcement that the weather is
+/// unavailable
+fn retire_report(
+    special: &SpecialBaseConfig,
+    config: &SpecialWeatherConfig,
+    temp_files: &utils::FileOutputs,
+) {
+    match config.stale {
+        StaleWeather::Skip => match fs::remove_file(temp_files.final_mp3) {
+            Ok(()) => info!("weather", "Removed the report, the weather will be skipped"),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => warning!("weather", "Could not remove the report: {}", error),
+        },
+        StaleWeather::Announce => {
+            let announcement = special.locale.phrase(Phrase::WeatherUnavailable, &[]);
+            match utils::read_text_announcement(
+                &announcement,
+                temp_files,
+                "w",
+                special.locale.voice(),
+                &special.tools,
+            ) {
+                Ok(()) => info!("weather", "Replaced the report with an apology"),
+                Err(error) => warning!("weather", "{}", error),
+            }
+        }
+    }
+}
 
+/// Perdiodically queries the Weather.gov API and produces an audio summary of
+/// the forecast which can be played in the stream. Waking the worker fetches a
+/// new forecast right away. Each new report is published on the event bus.
+pub fn weather_worker(
+    special: SpecialBaseConfig,
+    config: SpecialWeatherConfig,
+    client: Client,
+    waker: Waker,
+    events: EventBus,
+) {
+    let url = forecast_url(&config.region);
+
+    let working_dir = &special.working_dir;
+    let file_prefix = &special.file_prefix;
+    let locale = special.locale;
     let temp_files = utils::FileOutputs {
-        mono_wav: &working_dir.join("weather-mono.wav"),
-        stereo_wav: &working_dir.join("weather-stereo.wav"),
-        lame_mp3: &working_dir.join("weather-transcode.mp3"),
-        final_mp3: &working_dir.join(WEATHER_MP3_FILE),
+        mono_wav: &utils::special_file(working_dir, file_prefix, "weather-mono.wav"),
+        stereo_wav: &utils::special_file(working_dir, file_prefix, "weather-stereo.wav"),
+        lame_mp3: &utils::special_file(working_dir, file_prefix, "weather-transcode.mp3"),
+        final_mp3: &utils::special_file(working_dir, file_prefix, WEATHER_MP3_FILE),
     };
 
-    let wait_interval = Duration::from_secs(60 * 60);
+    let substitutions = build_substitutions(&config);
+    let budget = length_budget(&special, &config);
+    let wait_interval = Duration::from_secs(60 * 60) / special.time_scale;
     let mut sleep_intervals = if temp_files.lame_mp3.is_file() { 1 } else { 0 };
 
+    // Set when the API asks for a different wait than the usual hour
+    let mut retry_after = None;
+
+    // A report left over from before a restart is at least as old as the file
+    let mut issued = fs::metadata(temp_files.final_mp3)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(DateTime::<Local>::from);
+    let mut retired = false;
+    let max_age = config
+        .max_age
+        .map(|hours| chrono::Duration::hours(hours as i64));
+
     loop {
         if sleep_intervals > 0 {
-            thread::sleep(wait_interval);
-            sleep_intervals -= 1;
+            match waker.wait(retry_after.take().unwrap_or(wait_interval)) {
+                Wakeup::Timeout => sleep_intervals -= 1,
+                Wakeup::Now => sleep_intervals = 0,
+                Wakeup::Shutdown => return,
+            }
+        }
+
+        // The report can go stale while waiting, or while the API is failing
+        if let (Some(max_age), Some(issued_at)) = (max_age, issued) {
+            if !retired && Local::now() - issued_at > max_age {
+                warning!(
+                    "weather",
+                    "The forecast from {} is too old to read",
+                    issued_at.format("%Y-%m-%d %H:%M")
+                );
+                retire_report(&special, &config, &temp_files);
+                retired = true;
+            }
         }
 
         if sleep_intervals > 0 {
             continue;
         }
 
-        let forecasts = if let Ok(forecasts) = fetch_forecasts(&url) {
-            forecasts
-        } else {
-            sleep_intervals = 1;
-            continue;
+        let (forecasts, issued_at) = match fetch_forecasts(&client, &url) {
+            Ok(result) => result,
+            Err(FetchError::RateLimited(delay)) => {
+                events.publish(Event::WeatherRateLimited(delay.as_secs()));
+                retry_after = Some(delay);
+                sleep_intervals = 1;
+                continue;
+            }
+            Err(FetchError::Failed) => {
+                events.publish(Event::WeatherFetchFailed);
+                sleep_intervals = 1;
+                continue;
+            }
         };
 
+        // Without an issue time there's nothing to go on but when it arrived
+        let issued_at = issued_at.unwrap_or_else(Local::now);
+        if let Some(max_age) = max_age {
+            if Local::now() - issued_at > max_age {
+                warning!(
+                    "weather",
+                    "API returned a forecast from {}, which is too old to read",
+                    issued_at.format("%Y-%m-%d %H:%M")
+                );
+                if !retired {
+                    retire_report(&special, &config, &temp_files);
+                    retired = true;
+                }
+
+                sleep_intervals = 1;
+                continue;
+            }
+        }
+
         let start_time = Local::now();
         let end_time = start_time + chrono::Duration::hours(config.duration as i64);
-        let forecast_str = generate_weather_string(&forecasts, start_time, end_time);
-        if let Err(error) =
-            utils::read_text_announcement(&forecast_str, &temp_files, "w")
-        {
-            eprintln!("[weather] {}", error);
+        let generate = |end_time| {
+            generate_weather_string(
+                &forecasts,
+                start_time,
+                end_time,
+                locale,
+                config.template.as_deref(),
+                &substitutions,
+                config.period_names,
+            )
+        };
+
+        let mut forecast_str = generate(end_time);
+        if let Some(budget) = budget {
+            // Periods are dropped from the end until the report fits, though
+            // the first one is always kept
+            let periods = forecasts_in_range(&forecasts, start_time, end_time);
+            let mut kept = periods.len();
+            while kept > 1 && estimate_length_sec(&forecast_str) > budget {
+                kept -= 1;
+                forecast_str = generate(periods[kept].start_time);
+            }
+
+            if kept < periods.len() {
+                info!(
+                    "weather",
+                    "Reading {} of {} forecast periods to fit within {:.0} seconds",
+                    kept,
+                    periods.len(),
+                    budget
+                );
+            }
+
+            if estimate_length_sec(&forecast_str) > budget {
+                info!(
+                    "weather",
+                    "Report takes about {:.0} seconds, over the limit of {:.0}",
+                    estimate_length_sec(&forecast_str),
+                    budget
+                );
+            }
+        }
+        if let Err(error) = utils::read_text_announcement(
+            &forecast_str,
+            &temp_files,
+            "w",
+            locale.voice(),
+            &special.tools,
+        ) {
+            warning!("weather", "{}", error);
             sleep_intervals = 1;
             continue;
         }
 
+        events.publish(Event::WeatherRefreshed(temp_files.final_mp3.to_path_buf()));
+        issued = Some(issued_at);
+        retired = false;
         sleep_intervals = config.interval;
     }
 }